@@ -0,0 +1,57 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// (C) Copyright 2024 Greg Whiteley
+
+//! Benchmark [`Exec::run`] over a large generated `.upbuild` file, with
+//! a [`MockRunner`] that runs nothing, to measure the overhead of upbuild
+//! itself.
+
+use std::path::Path;
+
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion};
+use upbuild_rs::{testing::MockRunner, ClassicFile, Config, Exec};
+
+const ENTRIES: usize = 800;
+
+// A file in the style of our generators - mostly plain commands with
+// some directory changes and defaulted arguments
+fn generate() -> String {
+    (0..ENTRIES)
+        .map(|i| {
+            let mut entry = format!("make\n-C\ncomponent{}\n-j8\nBUILD_MODE=host_debug\n", i);
+            if i % 4 == 0 {
+                entry.push_str("@cd=build\n");
+            }
+            if i % 8 == 0 {
+                entry.push_str("--\ntest\n");
+            }
+            entry
+        })
+        .collect::<Vec<_>>()
+        .join("&&\n")
+}
+
+fn exec_run(c: &mut Criterion) {
+    let data = generate();
+    let file = ClassicFile::parse_lines(data.lines()).expect("generated file should parse");
+    let runner = MockRunner::new();
+    let exec = Exec::new(Box::new(runner.clone()));
+    let cfg = Config::default();
+    let path = Path::new(".upbuild");
+
+    let mut group = c.benchmark_group(format!("run {} entries", ENTRIES));
+    for (name, provided_args) in [("no args", vec![]), ("provided args", vec![String::from("all")])] {
+        group.bench_with_input(BenchmarkId::from_parameter(name), &provided_args, |b, provided_args| {
+            // a fresh queue each run, without counting the recording of the last
+            let setup = || {
+                runner.reset();
+                runner.push_results(std::iter::repeat_with(|| Ok(0)).take(ENTRIES));
+            };
+            b.iter_batched(setup, |_| exec.run(path, &file, &cfg, provided_args).expect("run should succeed"),
+                           BatchSize::PerIteration)
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, exec_run);
+criterion_main!(benches);
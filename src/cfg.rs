@@ -2,14 +2,205 @@
 // (C) Copyright 2024 Greg Whiteley
 
 use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use super::ci::Ci;
+use super::completion::{Completion, Shell};
+use super::find::ONE_FILESYSTEM_ENV;
+use super::history::NO_HISTORY_ENV;
+use super::list::ListFormat;
+use super::notify::NOTIFY_ENV;
+use super::output::parse_size;
+use super::scan::Pattern;
+use super::tags::{ParseError, TagExpr};
+use super::{Error, Result};
+
+/// Environment variable carrying the nesting level of recursive invocations
+pub(crate) const LEVEL_ENV: &str = "UPBUILD_LEVEL";
+
+/// Environment variable naming the `.upbuild` file to use instead of
+/// searching, see `--ub-file=`
+pub(crate) const FILE_ENV: &str = "UPBUILD_FILE";
+
+/// Environment variable giving the selection to use without
+/// `--ub-select=`, `--ub-reject=` or `--ub-tags=` - comma-separated
+/// tags, those starting `!` rejected
+pub(crate) const TAGS_ENV: &str = "UPBUILD_TAGS";
+
+/// Number of runs shown by `--ub-history` without a count
+const DEFAULT_HISTORY: usize = 10;
+
+/// Most of an `@outfile` displayed without `--ub-outfile-limit=`
+const DEFAULT_OUTFILE_LIMIT: u64 = 4 << 20;
+
+/// Most jobs `--ub-jobs=` accepts - the jobserver's tokens have to fit
+/// in a pipe
+const MAX_JOBS: usize = 4096;
+
+/// Runs `@container` commands without `--ub-container-engine=`
+const DEFAULT_CONTAINER_ENGINE: &str = "docker";
+
+/// Names upbuild may be installed as, and the flags each implies - a
+/// command running `upbuild` is a recursive call, and the others are
+/// with `@recurse`
+pub(crate) const ALIASES: &[(&str, &[&str])] = &[
+    ("upbuild", &[]),
+    ("ub", &[]),
+    ("upbuild-print", &["--ub-print"]),
+];
+
+/// Whether a command may run upbuild itself, by any of its [`ALIASES`]
+pub(crate) fn is_upbuild(exe: &str) -> bool {
+    ALIASES.iter().any(|(name, _)| *name == exe)
+}
+
+// The flags implied by the name upbuild was run as, or a recursive
+// call names, by its file stem so that a path or .exe doesn't matter
+pub(crate) fn implied_flags(argv0: &str) -> &'static [&'static str] {
+    let stem = Path::new(argv0).file_stem().and_then(|s| s.to_str());
+    ALIASES.iter()
+        .find(|(name, _)| Some(*name) == stem)
+        .map_or(&[], |(_, flags)| flags)
+}
+
+/// The prefix for upbuild's own messages at the given recursion level,
+/// see [`Config::prefix`]
+pub(crate) fn prefix(level: usize) -> String {
+    if level == 0 {
+        return String::from("upbuild");
+    }
+    format!("upbuild[{}]", level)
+}
+
+/// The recursion level from `UPBUILD_LEVEL`, for messages from outside
+/// a run, which has a [`Config`] to say
+pub(crate) fn env_level() -> usize {
+    std::env::var(LEVEL_ENV).ok().and_then(|l| l.parse().ok()).unwrap_or(0)
+}
+
+/// What an option in [`OPTIONS`] takes after `=`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum OptionValue {
+    /// Nothing
+    None,
+    /// Optionally a value, as for `--ub-history[=N]`
+    Optional,
+    /// A value that can't be completed
+    Required,
+    /// A tag from the `.upbuild` files
+    Tag,
+    /// One of the given values
+    Choice(&'static [&'static str]),
+}
+
+/// The `--ub-*` options and what they take, for shell completion
+pub(crate) const OPTIONS: &[(&str, OptionValue)] = &[
+    ("--ub-print", OptionValue::None),
+    ("--ub-print-null", OptionValue::None),
+    ("--ub-print-env", OptionValue::None),
+    ("--ub-add", OptionValue::None),
+    ("--ub-select", OptionValue::Tag),
+    ("--ub-reject", OptionValue::Tag),
+    ("--ub-tags", OptionValue::Required),
+    ("--ub-strict-tags", OptionValue::None),
+    ("--ub-allow-empty", OptionValue::None),
+    ("--ub-trace", OptionValue::None),
+    ("--ub-ignore-mkdir-errors", OptionValue::None),
+    ("--ub-detach", OptionValue::None),
+    ("--ub-batch", OptionValue::None),
+    ("--ub-stream", OptionValue::None),
+    ("--ub-no-vcs-boundary", OptionValue::None),
+    ("--ub-chain", OptionValue::None),
+    ("--ub-discover", OptionValue::None),
+    ("--ub-discover-run", OptionValue::None),
+    ("--ub-one-file-system", OptionValue::None),
+    ("--ub-file", OptionValue::Required),
+    ("--ub-history", OptionValue::Optional),
+    ("--ub-no-history", OptionValue::None),
+    ("--ub-notify", OptionValue::None),
+    ("--ub-no-notify", OptionValue::None),
+    ("--ub-time", OptionValue::None),
+    ("--ub-stats", OptionValue::None),
+    ("--ub-stats-reset", OptionValue::None),
+    ("--ub-scan", OptionValue::None),
+    ("--ub-scan-error", OptionValue::Required),
+    ("--ub-scan-warning", OptionValue::Required),
+    ("--ub-outfile-limit", OptionValue::Required),
+    ("--ub-color", OptionValue::Choice(&["auto", "always", "never"])),
+    ("--ub-ci", OptionValue::Choice(&["github", "gitlab", "auto", "none"])),
+    ("--ub-lock", OptionValue::None),
+    ("--ub-lock-timeout", OptionValue::Required),
+    ("--ub-jobs", OptionValue::Required),
+    ("--ub-container-engine", OptionValue::Required),
+    ("--ub-show", OptionValue::Required),
+    ("--ub-list", OptionValue::Choice(&["text", "json", "tsv"])),
+    ("--ub-disable", OptionValue::Required),
+    ("--ub-enable", OptionValue::Required),
+    ("--ub-rm", OptionValue::Required),
+    ("--ub-move", OptionValue::Required),
+    ("--ub-completion", OptionValue::Choice(&["bash", "powershell"])),
+    ("--ub-completion-list-tags", OptionValue::None),
+];
+
+/// When to keep colour in displayed output, see `--ub-color=`
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Color {
+    /// Only when stdout is a terminal
+    #[default]
+    Auto,
+    /// Leave escape sequences alone
+    Always,
+    /// Remove escape sequences
+    Never,
+}
 
 /// Config object to hold the result of parsing the command-line arguments
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Config {
     pub(crate) print: bool,
+    pub(crate) print_null: bool,
+    pub(crate) print_env: bool,
     pub(crate) select: HashSet<String>,
     pub(crate) reject: HashSet<String>,
+    pub(crate) tags: Option<(String, std::result::Result<TagExpr, ParseError>)>,
+    pub(crate) strict_tags: bool,
+    pub(crate) allow_empty: bool,
     pub(crate) add: bool,
+    pub(crate) trace: bool,
+    pub(crate) ignore_mkdir_errors: bool,
+    pub(crate) detach: bool,
+    pub(crate) batch: bool,
+    pub(crate) stream: bool,
+    pub(crate) vcs_boundary: bool,
+    pub(crate) chain: bool,
+    pub(crate) discover: bool,
+    pub(crate) discover_run: bool,
+    pub(crate) one_file_system: bool,
+    pub(crate) file: Option<PathBuf>,
+    pub(crate) history: Option<usize>,
+    pub(crate) no_history: bool,
+    pub(crate) notify: bool,
+    pub(crate) time: bool,
+    pub(crate) stats: bool,
+    pub(crate) stats_reset: bool,
+    pub(crate) scan: bool,
+    pub(crate) scan_errors: Vec<Pattern>,
+    pub(crate) scan_warnings: Vec<Pattern>,
+    pub(crate) outfile_limit: Option<u64>,
+    pub(crate) color: Color,
+    pub(crate) lock: bool,
+    pub(crate) lock_timeout: Option<Duration>,
+    pub(crate) container_engine: String,
+    pub(crate) ci: Option<Ci>,
+    pub(crate) completion: Option<Completion>,
+    pub(crate) jobs: Option<usize>,
+    pub(crate) rm: Option<String>,
+    pub(crate) disable: Option<(String, bool)>,
+    pub(crate) show: Option<String>,
+    pub(crate) list: Option<ListFormat>,
+    pub(crate) move_command: Option<(String, String)>,
+    pub(crate) level: usize,
     pub(crate) argv0: String,
 }
 
@@ -20,24 +211,444 @@ impl Config {
         self.print
     }
 
+    /// returns true if `--ub-print-null` was selected, to print the
+    /// commands NUL-separated
+    pub fn print_null(&self) -> bool {
+        self.print_null
+    }
+
+    /// returns true if `--ub-print-env` was selected, to show the
+    /// environment of each command rather than run them
+    pub fn print_env(&self) -> bool {
+        self.print_env
+    }
+
+    /// returns the tags selected with `--ub-select=`, or by
+    /// `UPBUILD_TAGS` without any selection flags
+    pub fn select(&self) -> &HashSet<String> {
+        &self.select
+    }
+
+    /// returns the tags rejected with `--ub-reject=`, or by
+    /// `UPBUILD_TAGS` without any selection flags
+    pub fn reject(&self) -> &HashSet<String> {
+        &self.reject
+    }
+
+    /// returns the expression given to `--ub-tags=`, which replaces
+    /// `--ub-select=` and `--ub-reject=` - an error if it doesn't
+    /// parse or they are given too
+    pub fn tags(&self) -> Result<Option<&TagExpr>> {
+        match &self.tags {
+            None => Ok(None),
+            Some((expr, Err((pos, why)))) =>
+                Err(Error::InvalidTagExpression(expr.clone(), *pos, why.to_string())),
+            Some(_) if !self.select.is_empty() || !self.reject.is_empty() =>
+                Err(Error::ConflictingFlags(String::from("--ub-tags= can't be combined with --ub-select= or --ub-reject="))),
+            Some((_, Ok(parsed))) => Ok(Some(parsed)),
+        }
+    }
+
+    /// returns true if `--ub-strict-tags` was selected, making tags
+    /// given to `--ub-select=` or `--ub-reject=` that aren't in the
+    /// file an error rather than a warning
+    pub fn strict_tags(&self) -> bool {
+        self.strict_tags
+    }
+
+    /// returns true if `--ub-allow-empty` was selected, so running no
+    /// commands at all isn't an error
+    pub fn allow_empty(&self) -> bool {
+        self.allow_empty
+    }
+
+    /// returns how deep in recursive calls this upbuild is, from
+    /// `UPBUILD_LEVEL` - 0 at the top
+    pub fn level(&self) -> usize {
+        self.level
+    }
+
+    /// returns the prefix for upbuild's own messages - `upbuild`, or
+    /// `upbuild[N]` in recursive calls as make does
+    pub fn prefix(&self) -> String {
+        prefix(self.level)
+    }
+
+    /// returns the name upbuild was run as, used to run recursive
+    /// calls
+    pub fn argv0(&self) -> &str {
+        &self.argv0
+    }
+
     /// returns true if `--ub-add` was provided
     pub fn add(&self) -> bool {
         self.add
     }
+
+    /// returns the command to describe given by `--ub-show=`
+    pub fn show(&self) -> Option<&str> {
+        self.show.as_deref()
+    }
+
+    /// returns the command given by `--ub-disable=` (with true) or
+    /// `--ub-enable=` (with false)
+    pub fn disable(&self) -> Option<(&str, bool)> {
+        self.disable.as_ref().map(|(c, d)| (c.as_str(), *d))
+    }
+
+    /// returns the command to remove given by `--ub-rm=`
+    pub fn rm(&self) -> Option<&str> {
+        self.rm.as_deref()
+    }
+
+    /// returns the command and the position to move it to given by
+    /// `--ub-move=FROM:TO`
+    pub fn move_command(&self) -> Option<(&str, &str)> {
+        self.move_command.as_ref().map(|(f, t)| (f.as_str(), t.as_str()))
+    }
+
+    /// returns the number of entries requested by `--ub-history[=N]`
+    pub fn history(&self) -> Option<usize> {
+        self.history
+    }
+
+    /// returns false if recording history was disabled via
+    /// `--ub-no-history` or `UPBUILD_NO_HISTORY`
+    pub fn record_history(&self) -> bool {
+        !self.no_history
+    }
+
+    /// returns true if the end of the run should be announced, see
+    /// `--ub-notify`
+    pub fn notify(&self) -> bool {
+        self.notify
+    }
+
+    /// returns true if `--ub-time` was provided
+    pub fn time(&self) -> bool {
+        self.time
+    }
+
+    /// returns true if `--ub-stats` was provided
+    pub fn stats(&self) -> bool {
+        self.stats
+    }
+
+    /// returns true if `--ub-stats-reset` was provided
+    pub fn stats_reset(&self) -> bool {
+        self.stats_reset
+    }
+
+    /// returns true if commands should run as the file is read, see
+    /// `--ub-stream`
+    pub fn stream(&self) -> bool {
+        self.stream
+    }
+
+    /// returns false if the search for `.upbuild` may leave the
+    /// repository it starts in, see `--ub-no-vcs-boundary`
+    pub fn vcs_boundary(&self) -> bool {
+        self.vcs_boundary
+    }
+
+    /// returns true if the search for `.upbuild` stays on the
+    /// filesystem it starts on, see `--ub-one-file-system` and
+    /// `UPBUILD_ONE_FILESYSTEM`
+    pub fn one_file_system(&self) -> bool {
+        self.one_file_system
+    }
+
+    /// returns the `.upbuild` file to use instead of searching for
+    /// one, from `--ub-file=PATH` or `UPBUILD_FILE`
+    pub fn file(&self) -> Option<&Path> {
+        self.file.as_deref()
+    }
+
+    /// returns true if every `.upbuild` from the current directory up
+    /// should run, see `--ub-chain`
+    pub fn chain(&self) -> bool {
+        self.chain
+    }
+
+    /// returns true if the `.upbuild` files below the current
+    /// directory should be listed, or run with `--ub-discover-run`,
+    /// see `--ub-discover`
+    pub fn discover(&self) -> bool {
+        self.discover
+    }
+
+    /// returns true if each `.upbuild` file below the current
+    /// directory should run in turn, see `--ub-discover-run`
+    pub fn discover_run(&self) -> bool {
+        self.discover_run
+    }
+
+    /// returns true if runs should be serialised with `--ub-lock`
+    pub fn lock(&self) -> bool {
+        self.lock
+    }
+
+    /// returns the CI service to format output for, see `--ub-ci=`
+    pub fn ci(&self) -> Option<Ci> {
+        self.ci
+    }
+
+    /// returns the format to list the commands in, see
+    /// `--ub-list[=FORMAT]`
+    pub fn list(&self) -> Option<ListFormat> {
+        self.list
+    }
+
+    /// returns the shell completion support requested, see
+    /// `--ub-completion[=SHELL]` and `--ub-completion-list-tags`
+    pub fn completion(&self) -> Option<Completion> {
+        self.completion
+    }
+
+    /// returns the total number of jobs from `--ub-jobs=N`, shared
+    /// with nested `make`s via a jobserver
+    pub fn jobs(&self) -> Option<usize> {
+        self.jobs
+    }
+
+    /// returns how long to wait for the lock, from
+    /// `--ub-lock-timeout=SECS`, or None to wait as long as it takes
+    pub fn lock_timeout(&self) -> Option<Duration> {
+        self.lock_timeout
+    }
+
+    /// returns the `--ub-select=`/`--ub-reject=` flags reproducing
+    /// this selection in a recursive invocation
+    pub(crate) fn propagated_args(&self) -> Vec<String> {
+        let mut select: Vec<_> = self.select.iter().collect();
+        let mut reject: Vec<_> = self.reject.iter().collect();
+        select.sort();
+        reject.sort();
+        select.into_iter().map(|t| format!("--ub-select={}", t))
+            .chain(reject.into_iter().map(|t| format!("--ub-reject={}", t)))
+            .chain(self.tags.iter().map(|(expr, _)| format!("--ub-tags={}", expr)))
+            .collect()
+    }
 }
 
 impl Default for Config {
     fn default() -> Self {
         Self {
             print: false,
+            print_null: false,
+            print_env: false,
             select: Default::default(),
             reject: Default::default(),
+            tags: None,
+            strict_tags: false,
+            allow_empty: false,
             add: false,
+            trace: false,
+            ignore_mkdir_errors: false,
+            detach: false,
+            batch: false,
+            stream: false,
+            vcs_boundary: true,
+            chain: false,
+            discover: false,
+            discover_run: false,
+            one_file_system: false,
+            file: None,
+            history: None,
+            no_history: false,
+            notify: false,
+            time: false,
+            stats: false,
+            stats_reset: false,
+            scan: false,
+            scan_errors: Vec::new(),
+            scan_warnings: Vec::new(),
+            outfile_limit: Some(DEFAULT_OUTFILE_LIMIT),
+            color: Color::Auto,
+            lock: false,
+            lock_timeout: None,
+            container_engine: String::from(DEFAULT_CONTAINER_ENGINE),
+            ci: None,
+            completion: None,
+            jobs: None,
+            rm: None,
+            disable: None,
+            show: None,
+            list: None,
+            move_command: None,
+            level: 0,
             argv0: String::from("upbuild"),
         }
     }
 }
 
+/// Builder for a [`Config`] without going through command-line
+/// arguments.  Anything not set is as for no arguments.
+///
+/// ```
+/// # use upbuild_rs::Config;
+/// let cfg = Config::builder()
+///     .select(["host"])
+///     .reject(["slow"])
+///     .argv0("mytool")
+///     .build();
+/// assert!(cfg.select().contains("host"));
+/// assert_eq!(cfg.argv0(), "mytool");
+/// ```
+#[derive(Debug, Default, Clone)]
+pub struct ConfigBuilder {
+    cfg: Config,
+}
+
+/// Start from an existing [`Config`], such as one parsed from the
+/// command line, to derive variants of it
+///
+/// ```
+/// # use upbuild_rs::{Config, ConfigBuilder};
+/// let (_, base) = Config::parse(["upbuild", "--ub-reject=slow"].into_iter().map(String::from));
+/// let host = ConfigBuilder::from(base.clone()).select(["host"]).build();
+/// assert_eq!(host.reject(), base.reject());
+/// ```
+impl From<Config> for ConfigBuilder {
+    fn from(cfg: Config) -> Self {
+        ConfigBuilder { cfg }
+    }
+}
+
+impl ConfigBuilder {
+
+    /// Print commands rather than running them, as `--ub-print`
+    pub fn print(&mut self, print: bool) -> &mut Self {
+        self.cfg.print = print;
+        self
+    }
+
+    /// Print commands NUL-separated rather than running them, as
+    /// `--ub-print-null`
+    pub fn print_null(&mut self, print_null: bool) -> &mut Self {
+        self.cfg.print = print_null;
+        self.cfg.print_null = print_null;
+        self
+    }
+
+    /// Select the given tags, as `--ub-select=`, no longer rejecting them
+    pub fn select<I, T>(&mut self, tags: I) -> &mut Self
+    where
+        I: IntoIterator<Item=T>,
+        T: Into<String>
+    {
+        for tag in tags {
+            let tag = tag.into();
+            self.cfg.reject.remove(&tag);
+            self.cfg.select.insert(tag);
+        }
+        self
+    }
+
+    /// Reject the given tags, as `--ub-reject=`, no longer selecting them
+    pub fn reject<I, T>(&mut self, tags: I) -> &mut Self
+    where
+        I: IntoIterator<Item=T>,
+        T: Into<String>
+    {
+        for tag in tags {
+            let tag = tag.into();
+            self.cfg.select.remove(&tag);
+            self.cfg.reject.insert(tag);
+        }
+        self
+    }
+
+    /// Select by the given expression, as `--ub-tags=` - an
+    /// expression that doesn't parse is reported by
+    /// [`Config::tags`] and by running with the config
+    pub fn tags(&mut self, expr: &str) -> &mut Self {
+        self.cfg.tags = Some((expr.to_string(), TagExpr::parse_at(expr)));
+        self
+    }
+
+    /// Allow the selection to leave no commands to run, as
+    /// `--ub-allow-empty`
+    pub fn allow_empty(&mut self, allow_empty: bool) -> &mut Self {
+        self.cfg.allow_empty = allow_empty;
+        self
+    }
+
+    /// Fail rather than warn for selected or rejected tags that aren't
+    /// in the file, as `--ub-strict-tags`
+    pub fn strict_tags(&mut self, strict_tags: bool) -> &mut Self {
+        self.cfg.strict_tags = strict_tags;
+        self
+    }
+
+    /// The name to run recursive calls as
+    pub fn argv0<T: Into<String>>(&mut self, argv0: T) -> &mut Self {
+        self.cfg.argv0 = argv0.into();
+        self
+    }
+
+    /// Report how each command is run, as `--ub-trace`
+    pub fn trace(&mut self, trace: bool) -> &mut Self {
+        self.cfg.trace = trace;
+        self
+    }
+
+    /// Run commands detached from the terminal, as `--ub-detach`
+    pub fn detach(&mut self, detach: bool) -> &mut Self {
+        self.cfg.detach = detach;
+        self
+    }
+
+    /// Run commands with stdin from the null device, as `--ub-batch`
+    pub fn batch(&mut self, batch: bool) -> &mut Self {
+        self.cfg.batch = batch;
+        self
+    }
+
+    /// Carry on when `@mkdir` fails, as `--ub-ignore-mkdir-errors`
+    pub fn ignore_mkdir_errors(&mut self, ignore: bool) -> &mut Self {
+        self.cfg.ignore_mkdir_errors = ignore;
+        self
+    }
+
+    /// Scan every `@outfile` for errors and warnings, as `--ub-scan`
+    pub fn scan(&mut self, scan: bool) -> &mut Self {
+        self.cfg.scan = scan;
+        self
+    }
+
+    /// Most of an `@outfile` to display, as `--ub-outfile-limit=`, or
+    /// None for all of it
+    pub fn outfile_limit(&mut self, limit: Option<u64>) -> &mut Self {
+        self.cfg.outfile_limit = limit;
+        self
+    }
+
+    /// Use the given `.upbuild` file rather than searching for one, as
+    /// `--ub-file=`
+    pub fn file<P: Into<PathBuf>>(&mut self, file: P) -> &mut Self {
+        self.cfg.file = Some(file.into());
+        self
+    }
+
+    #[cfg(test)]
+    pub(crate) fn color(&mut self, color: Color) -> &mut Self {
+        self.cfg.color = color;
+        self
+    }
+
+    #[cfg(test)]
+    pub(crate) fn level(&mut self, level: usize) -> &mut Self {
+        self.cfg.level = level;
+        self
+    }
+
+    /// Create the [`Config`]
+    pub fn build(&self) -> Config {
+        self.cfg.clone()
+    }
+}
+
 fn apply_tags(arg: &str, add: &mut HashSet<String> , drop: &mut HashSet<String>) -> bool {
     match arg.split_once('=') {
         Some((_, arg)) => {
@@ -56,6 +667,11 @@ fn apply_tags(arg: &str, add: &mut HashSet<String> , drop: &mut HashSet<String>)
 /// remaining command-line arguments to the caller.
 impl Config {
 
+    /// Start building a [`Config`] directly, see [`ConfigBuilder`]
+    pub fn builder() -> ConfigBuilder {
+        ConfigBuilder::default()
+    }
+
     /// Parse the given parameters
     ///
     /// ```
@@ -66,33 +682,232 @@ impl Config {
     pub fn parse<T>(args: T) -> (std::iter::Peekable<T>, Config)
     where
         T: Iterator<Item=String>
+    {
+        Self::parse_with_env(args, |k| std::env::var(k).ok())
+    }
+
+    // Set a flag that takes no value, given without its leading --,
+    // returning whether it was one
+    fn set_flag(&mut self, flag: &str) -> bool {
+        match flag {
+            "ub-print" => {
+                self.print = true;
+            },
+            "ub-print-null" => {
+                self.print = true;
+                self.print_null = true;
+            },
+            "ub-print-env" => {
+                self.print_env = true;
+            },
+            "ub-add" => {
+                self.add = true;
+            },
+            "ub-trace" => {
+                self.trace = true;
+            },
+            "ub-strict-tags" => {
+                self.strict_tags = true;
+            },
+            "ub-allow-empty" => {
+                self.allow_empty = true;
+            },
+            "ub-ignore-mkdir-errors" => {
+                self.ignore_mkdir_errors = true;
+            },
+            "ub-detach" => {
+                self.detach = true;
+            },
+            "ub-batch" => {
+                self.batch = true;
+            },
+            "ub-stream" => {
+                self.stream = true;
+            },
+            "ub-no-vcs-boundary" => {
+                self.vcs_boundary = false;
+            },
+            "ub-chain" => {
+                self.chain = true;
+            },
+            "ub-discover" => {
+                self.discover = true;
+            },
+            "ub-discover-run" => {
+                self.discover = true;
+                self.discover_run = true;
+            },
+            "ub-one-file-system" => {
+                self.one_file_system = true;
+            },
+            "ub-history" => {
+                self.history = Some(DEFAULT_HISTORY);
+            },
+            "ub-no-history" => {
+                self.no_history = true;
+            },
+            "ub-notify" => {
+                self.notify = true;
+            },
+            "ub-no-notify" => {
+                self.notify = false;
+            },
+            "ub-time" => {
+                self.time = true;
+            },
+            "ub-stats" => {
+                self.stats = true;
+            },
+            "ub-stats-reset" => {
+                self.stats_reset = true;
+            },
+            "ub-scan" => {
+                self.scan = true;
+            },
+            "ub-lock" => {
+                self.lock = true;
+            },
+            "ub-list" => {
+                self.list = Some(ListFormat::Text);
+            },
+            "ub-completion" => {
+                self.completion = Some(Completion::PrintCompletion(Shell::Bash));
+            },
+            "ub-completion-list-tags" => {
+                self.completion = Some(Completion::ListTags);
+            },
+            _ => return false,
+        }
+        true
+    }
+
+    // Parse the given parameters, looking up environment variables
+    // via the provided function
+    pub(crate) fn parse_with_env<T, E>(args: T, env: E) -> (std::iter::Peekable<T>, Config)
+    where
+        T: Iterator<Item=String>,
+        E: Fn(&str) -> Option<String>
     {
         let mut args = args.peekable();
         let mut cfg = Config { ..Default::default() };
 
+        if let Some(level) = env(LEVEL_ENV) {
+            cfg.level = level.parse().unwrap_or(0);
+        }
+
+        if let Some(v) = env(NO_HISTORY_ENV) {
+            cfg.no_history = !v.is_empty() && v != "0";
+        }
+
+        if let Some(v) = env(NOTIFY_ENV) {
+            cfg.notify = !v.is_empty() && v != "0";
+        }
+
+        if let Some(v) = env(ONE_FILESYSTEM_ENV) {
+            cfg.one_file_system = !v.is_empty() && v != "0";
+        }
+
+        cfg.file = env(FILE_ENV).filter(|f| !f.is_empty()).map(PathBuf::from);
+        let mut selected = false;
+
         if let Some(arg) = args.next() {
             cfg.argv0 = arg;
         }
+        for flag in implied_flags(&cfg.argv0) {
+            cfg.set_flag(flag.trim_start_matches("--"));
+        }
 
         while let Some(arg) = args.peek() {
             if let Some(s) = arg.strip_prefix("--") {
                 match s {
-                    "ub-print" => {
-                        cfg.print = true;
-                    },
-                    "ub-add" => {
-                        cfg.add = true;
-                    },
                     "" => { args.next(); break; },
+                    s if cfg.set_flag(s) => {},
                     _ => {
                         if arg.starts_with("--ub-select=") {
                             if ! apply_tags(arg, &mut cfg.select, &mut cfg.reject) {
                                 break;
                             }
+                            selected = true;
                         } else if arg.starts_with("--ub-reject=") {
                             if ! apply_tags(arg, &mut cfg.reject, &mut cfg.select) {
                                 break;
                             }
+                            selected = true;
+                        } else if let Some(expr) = arg.strip_prefix("--ub-tags=") {
+                            cfg.tags = Some((expr.to_string(), TagExpr::parse_at(expr)));
+                            selected = true;
+                        } else if let Some(p) = arg.strip_prefix("--ub-scan-error=").filter(|p| !p.is_empty()) {
+                            cfg.scan_errors.push(Pattern::new("--ub-scan-error", p));
+                        } else if let Some(p) = arg.strip_prefix("--ub-scan-warning=").filter(|p| !p.is_empty()) {
+                            cfg.scan_warnings.push(Pattern::new("--ub-scan-warning", p));
+                        } else if let Some(n) = arg.strip_prefix("--ub-outfile-limit=") {
+                            match parse_size(n) {
+                                Some(n) => cfg.outfile_limit = Some(n),
+                                None => break,
+                            }
+                        } else if let Some(c) = arg.strip_prefix("--ub-color=") {
+                            match c {
+                                "auto" => cfg.color = Color::Auto,
+                                "always" => cfg.color = Color::Always,
+                                "never" => cfg.color = Color::Never,
+                                _ => break,
+                            }
+                        } else if let Some(s) = arg.strip_prefix("--ub-completion=") {
+                            match s {
+                                "bash" => cfg.completion = Some(Completion::PrintCompletion(Shell::Bash)),
+                                "powershell" => cfg.completion = Some(Completion::PrintCompletion(Shell::PowerShell)),
+                                _ => break,
+                            }
+                        } else if let Some(f) = arg.strip_prefix("--ub-list=") {
+                            match f {
+                                "text" => cfg.list = Some(ListFormat::Text),
+                                "json" => cfg.list = Some(ListFormat::Json),
+                                "tsv" => cfg.list = Some(ListFormat::Tsv),
+                                _ => break,
+                            }
+                        } else if let Some(c) = arg.strip_prefix("--ub-ci=") {
+                            match c {
+                                "github" => cfg.ci = Some(Ci::GitHub),
+                                "gitlab" => cfg.ci = Some(Ci::GitLab),
+                                "auto" => cfg.ci = Ci::detect(&env),
+                                "none" => cfg.ci = None,
+                                _ => break,
+                            }
+                        } else if let Some(c) = arg.strip_prefix("--ub-show=").filter(|c| !c.is_empty()) {
+                            cfg.show = Some(c.to_string());
+                        } else if let Some(c) = arg.strip_prefix("--ub-disable=").filter(|c| !c.is_empty()) {
+                            cfg.disable = Some((c.to_string(), true));
+                        } else if let Some(c) = arg.strip_prefix("--ub-enable=").filter(|c| !c.is_empty()) {
+                            cfg.disable = Some((c.to_string(), false));
+                        } else if let Some(c) = arg.strip_prefix("--ub-rm=").filter(|c| !c.is_empty()) {
+                            cfg.rm = Some(c.to_string());
+                        } else if let Some(m) = arg.strip_prefix("--ub-move=") {
+                            match m.split_once(':').filter(|(f, t)| !f.is_empty() && !t.is_empty()) {
+                                Some((f, t)) => cfg.move_command = Some((f.to_string(), t.to_string())),
+                                None => break,
+                            }
+                        } else if let Some(f) = arg.strip_prefix("--ub-file=").filter(|f| !f.is_empty()) {
+                            cfg.file = Some(PathBuf::from(f));
+                        } else if let Some(e) = arg.strip_prefix("--ub-container-engine=").filter(|e| !e.is_empty()) {
+                            cfg.container_engine = e.to_string();
+                        } else if let Some(n) = arg.strip_prefix("--ub-lock-timeout=") {
+                            match n.parse::<f64>().ok().filter(|n| (0.0..=f64::from(u32::MAX)).contains(n)) {
+                                Some(n) => {
+                                    cfg.lock = true;
+                                    cfg.lock_timeout = Some(Duration::from_secs_f64(n));
+                                },
+                                None => break,
+                            }
+                        } else if let Some(n) = arg.strip_prefix("--ub-jobs=") {
+                            match n.parse().ok().filter(|n| (1..=MAX_JOBS).contains(n)) {
+                                Some(n) => cfg.jobs = Some(n),
+                                None => break,
+                            }
+                        } else if let Some(n) = arg.strip_prefix("--ub-history=") {
+                            match n.parse() {
+                                Ok(n) => cfg.history = Some(n),
+                                Err(_) => break,
+                            }
                         } else {
                             break;
                         }
@@ -104,6 +919,19 @@ impl Config {
             }
             args.next();
         }
+
+        // only a baseline - any selection given as a flag replaces it,
+        // so it isn't added again to that propagated to recursive calls
+        if !selected {
+            if let Some(v) = env(TAGS_ENV) {
+                for tag in v.split(',').map(str::trim) {
+                    match tag.strip_prefix('!') {
+                        Some(t) => apply_tags(&format!("={}", t), &mut cfg.reject, &mut cfg.select),
+                        None => apply_tags(&format!("={}", tag), &mut cfg.select, &mut cfg.reject),
+                    };
+                }
+            }
+        }
         (args, cfg)
     }
 }
@@ -122,10 +950,395 @@ mod tests {
     }
 
     fn do_parse<const N: usize>(a: [&str; N]) -> (Vec<String>, Config) {
-        let (v, args) = Config::parse(args(a));
+        let (v, args) = Config::parse_with_env(args(a), |_| None);
         (v.collect(), args)
     }
 
+    #[test]
+    fn test_builder() {
+        assert_eq!(Config::builder().build(), Config::default());
+
+        let cfg = Config::builder()
+            .select(["host", "slow"])
+            .reject(["slow"])
+            .argv0("mytool")
+            .print(true)
+            .outfile_limit(None)
+            .build();
+        let (_, parsed) = do_parse(["--ub-select=host", "--ub-select=slow", "--ub-reject=slow", "--ub-print",
+                                    "--ub-outfile-limit=0"]);
+        assert_eq!(cfg, Config { argv0: String::from("mytool"), outfile_limit: None, ..parsed });
+        assert_eq!(cfg.select(), &HashSet::from([String::from("host")]));
+        assert_eq!(cfg.reject(), &HashSet::from([String::from("slow")]));
+        assert_eq!(cfg.argv0(), "mytool");
+    }
+
+    #[test]
+    fn test_clone_default() {
+        // all the ways to get the default agree
+        let (_, parsed) = do_parse([]);
+        assert_eq!(parsed, Config::default());
+        assert_eq!(Config::builder().build(), Config::default());
+        assert_eq!(ConfigBuilder::from(Config::default()).build(), Config::default());
+
+        // variants share what wasn't changed
+        let (_, base) = do_parse(["--ub-reject=slow", "--ub-batch"]);
+        let host = ConfigBuilder::from(base.clone()).select(["host"]).build();
+        let target = ConfigBuilder::from(base.clone()).select(["target"]).build();
+        assert_eq!(base.clone(), base);
+        assert_ne!(host, base);
+        assert_ne!(host, target);
+        assert_eq!(host.reject(), target.reject());
+        assert_eq!(host, Config { select: HashSet::from([String::from("host")]), ..base.clone() });
+        assert_eq!(ConfigBuilder::from(host).reject(["host"]).build(),
+                   Config { reject: HashSet::from([String::from("slow"), String::from("host")]), ..base });
+    }
+
+    #[test]
+    fn test_options() {
+        // every option listed for completion is parsed
+        for (name, value) in OPTIONS {
+            let samples = match value {
+                OptionValue::None => vec![name.to_string()],
+                OptionValue::Optional => vec![name.to_string(), format!("{}=1", name)],
+                OptionValue::Required if *name == "--ub-move" => vec![format!("{}=1:2", name)],
+                OptionValue::Required => vec![format!("{}=1", name)],
+                OptionValue::Tag => vec![format!("{}=tag", name)],
+                OptionValue::Choice(choices) => choices.iter().map(|c| format!("{}={}", name, c)).collect(),
+            };
+            for sample in samples {
+                let (v, _) = do_parse([sample.as_str()]);
+                assert!(v.is_empty(), "{} not parsed", sample);
+            }
+        }
+    }
+
+    #[test]
+    fn test_parse_level() {
+        let (_, cfg) = Config::parse_with_env(args([]), |k| {
+            if k == "UPBUILD_LEVEL" { Some("2".to_string()) } else { None }
+        });
+        assert_eq!(cfg, Config { level: 2, ..Config::default() });
+        assert_eq!((cfg.level(), cfg.prefix()), (2, String::from("upbuild[2]")));
+
+        let (_, cfg) = Config::parse_with_env(args([]), |k| {
+            if k == "UPBUILD_LEVEL" { Some("junk".to_string()) } else { None }
+        });
+        assert_eq!(cfg, Config::default());
+        assert_eq!((cfg.level(), cfg.prefix()), (0, String::from("upbuild")));
+    }
+
+    #[test]
+    fn test_parse_scan() {
+        let (v, cfg) = do_parse(["--ub-scan", "--ub-scan-error=FAILED", "--ub-scan-warning=deprecated",
+                                 "--ub-scan-error=panicked"]);
+        assert!(v.is_empty(), "!is_empty: was {:?}", v);
+        assert_eq!(cfg, Config {
+            scan: true,
+            scan_errors: vec![Pattern::new("--ub-scan-error", "FAILED"), Pattern::new("--ub-scan-error", "panicked")],
+            scan_warnings: vec![Pattern::new("--ub-scan-warning", "deprecated")],
+            ..Config::default()
+        });
+
+        // a bad pattern is still taken, and reported before running
+        let (v, cfg) = do_parse(["--ub-scan-error=(", "all"]);
+        assert_eq!(v, ["all"]);
+        assert_eq!(cfg.scan_errors, [Pattern::new("--ub-scan-error", "(")]);
+
+        // empty pattern isn't an option
+        let (v, cfg) = do_parse(["--ub-scan-error="]);
+        assert_eq!(v, ["--ub-scan-error="]);
+        assert_eq!(cfg, Config::default());
+    }
+
+    #[test]
+    fn test_parse_ci() {
+        assert_eq!(Config::default().ci(), None);
+
+        let (v, cfg) = do_parse(["--ub-ci=github"]);
+        assert!(v.is_empty(), "!is_empty: was {:?}", v);
+        assert_eq!(cfg.ci(), Some(Ci::GitHub));
+
+        let (_, cfg) = do_parse(["--ub-ci=gitlab"]);
+        assert_eq!(cfg.ci(), Some(Ci::GitLab));
+
+        let (_, cfg) = do_parse(["--ub-ci=github", "--ub-ci=none"]);
+        assert_eq!(cfg.ci(), None);
+
+        let env = |k: &str| if k == "GITLAB_CI" { Some("true".to_string()) } else { None };
+        let (_, cfg) = Config::parse_with_env(args(["--ub-ci=auto"]), env);
+        assert_eq!(cfg.ci(), Some(Ci::GitLab));
+        let (_, cfg) = Config::parse_with_env(args([]), env);
+        assert_eq!(cfg.ci(), None);
+
+        let (v, cfg) = do_parse(["--ub-ci=jenkins"]);
+        assert_eq!(v, ["--ub-ci=jenkins"]);
+        assert_eq!(cfg, Config::default());
+    }
+
+    #[test]
+    fn test_parse_container_engine() {
+        assert_eq!(Config::default().container_engine, "docker");
+
+        let (v, cfg) = do_parse(["--ub-container-engine=podman"]);
+        assert!(v.is_empty(), "!is_empty: was {:?}", v);
+        assert_eq!(cfg.container_engine, "podman");
+
+        let (v, cfg) = do_parse(["--ub-container-engine="]);
+        assert_eq!(v, ["--ub-container-engine="]);
+        assert_eq!(cfg, Config::default());
+    }
+
+    #[test]
+    fn test_parse_rm() {
+        assert_eq!(Config::default().rm(), None);
+
+        let (v, cfg) = do_parse(["--ub-rm=3"]);
+        assert!(v.is_empty(), "!is_empty: was {:?}", v);
+        assert_eq!(cfg.rm(), Some("3"));
+
+        let (_, cfg) = do_parse(["--ub-rm=deploy"]);
+        assert_eq!(cfg.rm(), Some("deploy"));
+
+        let (v, cfg) = do_parse(["--ub-rm="]);
+        assert_eq!(v, ["--ub-rm="]);
+        assert_eq!(cfg.rm(), None);
+
+        let (_, cfg) = do_parse(["--ub-disable=deploy"]);
+        assert_eq!(cfg.disable(), Some(("deploy", true)));
+        let (_, cfg) = do_parse(["--ub-disable=deploy", "--ub-enable=2"]);
+        assert_eq!(cfg.disable(), Some(("2", false)));
+        let (v, cfg) = do_parse(["--ub-enable="]);
+        assert_eq!(v, ["--ub-enable="]);
+        assert_eq!(cfg.disable(), None);
+
+        let (v, cfg) = do_parse(["--ub-show=deploy", "all"]);
+        assert_eq!(v, ["all"]);
+        assert_eq!(cfg.show(), Some("deploy"));
+    }
+
+    #[test]
+    fn test_parse_move() {
+        assert_eq!(Config::default().move_command(), None);
+
+        let (v, cfg) = do_parse(["--ub-move=4:1"]);
+        assert!(v.is_empty(), "!is_empty: was {:?}", v);
+        assert_eq!(cfg.move_command(), Some(("4", "1")));
+
+        let (_, cfg) = do_parse(["--ub-move=deploy:2"]);
+        assert_eq!(cfg.move_command(), Some(("deploy", "2")));
+
+        for bad in ["--ub-move=", "--ub-move=4", "--ub-move=4:", "--ub-move=:1"] {
+            let (v, cfg) = do_parse([bad]);
+            assert_eq!(v, [bad]);
+            assert_eq!(cfg.move_command(), None);
+        }
+    }
+
+    #[test]
+    fn test_parse_jobs() {
+        assert_eq!(Config::default().jobs(), None);
+
+        let (v, cfg) = do_parse(["--ub-jobs=8", "make"]);
+        assert_eq!(v, ["make"]);
+        assert_eq!(cfg.jobs(), Some(8));
+
+        for bad in ["--ub-jobs=", "--ub-jobs=0", "--ub-jobs=-1", "--ub-jobs=4097", "--ub-jobs=lots"] {
+            let (v, cfg) = do_parse([bad]);
+            assert_eq!(v, [bad]);
+            assert_eq!(cfg.jobs(), None);
+        }
+    }
+
+    #[test]
+    fn test_parse_lock() {
+        assert!(!Config::default().lock());
+
+        let (v, cfg) = do_parse(["--ub-lock"]);
+        assert!(v.is_empty(), "!is_empty: was {:?}", v);
+        assert!(cfg.lock());
+        assert_eq!(cfg.lock_timeout(), None);
+
+        let (_, cfg) = do_parse(["--ub-lock-timeout=2.5"]);
+        assert!(cfg.lock());
+        assert_eq!(cfg.lock_timeout(), Some(Duration::from_millis(2500)));
+
+        for bad in ["--ub-lock-timeout=", "--ub-lock-timeout=-1", "--ub-lock-timeout=inf", "--ub-lock-timeout=1e30", "--ub-lock-timeout=NaN", "--ub-lock-timeout=soon"] {
+            let (v, cfg) = do_parse([bad]);
+            assert_eq!(v, [bad]);
+            assert_eq!(cfg, Config::default());
+        }
+    }
+
+    #[test]
+    fn test_parse_color() {
+        assert_eq!(Config::default().color, Color::Auto);
+
+        let (v, cfg) = do_parse(["--ub-color=never"]);
+        assert!(v.is_empty(), "!is_empty: was {:?}", v);
+        assert_eq!(cfg.color, Color::Never);
+
+        let (_, cfg) = do_parse(["--ub-color=always", "--ub-color=auto"]);
+        assert_eq!(cfg.color, Color::Auto);
+
+        let (_, cfg) = do_parse(["--ub-color=always"]);
+        assert_eq!(cfg.color, Color::Always);
+
+        let (v, cfg) = do_parse(["--ub-color=sometimes"]);
+        assert_eq!(v, ["--ub-color=sometimes"]);
+        assert_eq!(cfg, Config::default());
+    }
+
+    #[test]
+    fn test_parse_outfile_limit() {
+        assert_eq!(Config::default().outfile_limit, Some(4 << 20));
+
+        let (v, cfg) = do_parse(["--ub-outfile-limit=100"]);
+        assert!(v.is_empty(), "!is_empty: was {:?}", v);
+        assert_eq!(cfg.outfile_limit, Some(100));
+
+        let (_, cfg) = do_parse(["--ub-outfile-limit=16M"]);
+        assert_eq!(cfg.outfile_limit, Some(16 << 20));
+
+        let (_, cfg) = do_parse(["--ub-outfile-limit=0"]);
+        assert_eq!(cfg.outfile_limit, Some(0));
+
+        let (v, cfg) = do_parse(["--ub-outfile-limit=lots"]);
+        assert_eq!(v, ["--ub-outfile-limit=lots"]);
+        assert_eq!(cfg, Config::default());
+    }
+
+    #[test]
+    fn test_parse_notify() {
+        let (v, cfg) = do_parse(["--ub-notify"]);
+        assert!(v.is_empty(), "!is_empty: was {:?}", v);
+        assert!(cfg.notify());
+
+        let env = |k: &str| if k == "UPBUILD_NOTIFY" { Some("1".to_string()) } else { None };
+        let (_, cfg) = Config::parse_with_env(args([]), env);
+        assert!(cfg.notify());
+
+        let (_, cfg) = Config::parse_with_env(args(["--ub-no-notify"]), env);
+        assert!(!cfg.notify());
+    }
+
+    #[test]
+    fn test_parse_tags_env() {
+        let tags = |v: &str, a: &[&str]| {
+            let env = |k: &str| (k == "UPBUILD_TAGS").then(|| v.to_string());
+            let a = ["upbuild"].iter().chain(a).map(|x| x.to_string());
+            let (_, cfg) = Config::parse_with_env(a, env);
+            let mut select: Vec<_> = cfg.select().iter().cloned().collect();
+            let mut reject: Vec<_> = cfg.reject().iter().cloned().collect();
+            select.sort();
+            reject.sort();
+            (select, reject)
+        };
+        let t = |t: &[&str]| t.iter().map(|x| x.to_string()).collect::<Vec<_>>();
+
+        assert_eq!(tags("ci", &[]), (t(&["ci"]), t(&[])));
+        assert_eq!(tags(" ci, !slow,,host ", &[]), (t(&["ci", "host"]), t(&["slow"])));
+        assert_eq!(tags("ci,!ci", &[]), (t(&[]), t(&["ci"])));
+        assert_eq!(tags("!", &[]), (t(&[]), t(&[])));
+
+        // any selection flag replaces it entirely
+        assert_eq!(tags("ci,!slow", &["--ub-select=docs"]), (t(&["docs"]), t(&[])));
+        assert_eq!(tags("ci,!slow", &["--ub-reject=ci"]), (t(&[]), t(&["ci"])));
+        assert_eq!(tags("ci,!slow", &["--ub-tags=docs"]), (t(&[]), t(&[])));
+        // but not those that aren't flags
+        assert_eq!(tags("ci", &["--ub-select="]), (t(&["ci"]), t(&[])));
+        assert_eq!(tags("ci", &["--", "--ub-select=docs"]), (t(&["ci"]), t(&[])));
+    }
+
+    #[test]
+    fn test_parse_one_file_system() {
+        for (v, expected) in [("1", true), ("yes", true), ("0", false), ("", false)] {
+            let env = |k: &str| if k == "UPBUILD_ONE_FILESYSTEM" { Some(v.to_string()) } else { None };
+            let (_, cfg) = Config::parse_with_env(args([]), env);
+            assert_eq!(cfg.one_file_system(), expected, "{:?}", v);
+        }
+        assert!(!Config::default().one_file_system());
+    }
+
+    #[test]
+    fn test_parse_aliases() {
+        let parse = |argv0: &str, a: &[&str]| {
+            let v: Vec<String> = [argv0].iter().chain(a).map(|x| x.to_string()).collect();
+            let (rest, cfg) = Config::parse_with_env(v.into_iter(), |_| None);
+            (rest.collect::<Vec<_>>(), cfg)
+        };
+        for argv0 in ["ub", "/usr/local/bin/ub", "upbuild.exe", "ub.exe"] {
+            assert_eq!(parse(argv0, &["all"]), (vec![String::from("all")], Config { argv0: argv0.to_string(), ..Config::default() }));
+        }
+
+        // printing, with any other flags and args as usual
+        for argv0 in ["upbuild-print", "/usr/local/bin/upbuild-print", "upbuild-print.exe"] {
+            let (rest, cfg) = parse(argv0, &["--ub-select=host", "all"]);
+            assert_eq!(rest, ["all"]);
+            assert!(cfg.print());
+            assert!(!cfg.print_null());
+            assert_eq!(cfg.select(), &HashSet::from([String::from("host")]));
+            assert_eq!(cfg.argv0(), argv0);
+            let (_, cfg) = parse(argv0, &["--ub-print-null"]);
+            assert!(cfg.print_null());
+        }
+
+        // only the names themselves
+        for argv0 in ["upbuild-printer", "ubx", "print"] {
+            assert!(!parse(argv0, &[]).1.print());
+        }
+        assert!(is_upbuild("ub"));
+        assert!(is_upbuild("upbuild-print"));
+        assert!(!is_upbuild("/usr/bin/upbuild"));
+        assert!(!is_upbuild("ubx"));
+    }
+
+    #[test]
+    fn test_parse_file() {
+        assert_eq!(Config::default().file(), None);
+
+        let (v, cfg) = do_parse(["--ub-file=../build/.upbuild"]);
+        assert!(v.is_empty(), "!is_empty: was {:?}", v);
+        assert_eq!(cfg.file(), Some(Path::new("../build/.upbuild")));
+
+        let (v, cfg) = do_parse(["--ub-file="]);
+        assert_eq!(v, ["--ub-file="]);
+        assert_eq!(cfg, Config::default());
+
+        // the flag wins over the environment, and empty is unset
+        let env = |k: &str| if k == "UPBUILD_FILE" { Some("/pinned/.upbuild".to_string()) } else { None };
+        let (_, cfg) = Config::parse_with_env(args([]), env);
+        assert_eq!(cfg.file(), Some(Path::new("/pinned/.upbuild")));
+        let (_, cfg) = Config::parse_with_env(args(["--ub-file=other"]), env);
+        assert_eq!(cfg.file(), Some(Path::new("other")));
+        let (_, cfg) = Config::parse_with_env(args([]), |k| if k == "UPBUILD_FILE" { Some(String::new()) } else { None });
+        assert_eq!(cfg.file(), None);
+    }
+
+    #[test]
+    fn test_parse_history() {
+        let (v, cfg) = do_parse(["--ub-history"]);
+        assert!(v.is_empty(), "!is_empty: was {:?}", v);
+        assert_eq!(cfg, Config { history: Some(10), ..Config::default() });
+
+        let (v, cfg) = do_parse(["--ub-history=3", "--ub-no-history"]);
+        assert!(v.is_empty(), "!is_empty: was {:?}", v);
+        assert_eq!(cfg, Config { history: Some(3), no_history: true, ..Config::default() });
+        assert!(!cfg.record_history());
+
+        // not a count - so passed through
+        let (v, cfg) = do_parse(["--ub-history=x"]);
+        assert_eq!(v, ["--ub-history=x"]);
+        assert_eq!(cfg, Config::default());
+
+        for (value, record) in [("1", false), ("yes", false), ("", true), ("0", true)] {
+            let (_, cfg) = Config::parse_with_env(args([]), |k| {
+                if k == "UPBUILD_NO_HISTORY" { Some(value.to_string()) } else { None }
+            });
+            assert_eq!(cfg.record_history(), record, "UPBUILD_NO_HISTORY={}", value);
+        }
+    }
+
     #[test]
     fn test_parse() {
         let (v, args) = do_parse([]);
@@ -144,6 +1357,105 @@ mod tests {
         assert!(v.is_empty(), "!is_empty: was {:?}", v);
         assert_eq!(args, Config { print: true, ..Config::default() });
 
+        let (v, args) = do_parse(["--ub-print-null"]);
+        assert!(v.is_empty(), "!is_empty: was {:?}", v);
+        assert_eq!(args, Config { print: true, print_null: true, ..Config::default() });
+
+        let (v, args) = do_parse(["--ub-print-env"]);
+        assert!(v.is_empty(), "!is_empty: was {:?}", v);
+        assert_eq!(args, Config { print_env: true, ..Config::default() });
+
+        let (v, args) = do_parse(["--ub-trace", "--ub-print"]);
+        assert!(v.is_empty(), "!is_empty: was {:?}", v);
+        assert_eq!(args, Config { print: true, trace: true, ..Config::default() });
+
+        let (v, args) = do_parse(["--ub-time", "--ub-stats", "--ub-stats-reset"]);
+        assert!(v.is_empty(), "!is_empty: was {:?}", v);
+        assert_eq!(args, Config { time: true, stats: true, stats_reset: true, ..Config::default() });
+
+        let (v, args) = do_parse(["--ub-strict-tags"]);
+        assert!(v.is_empty(), "!is_empty: was {:?}", v);
+        assert_eq!(args, Config { strict_tags: true, ..Config::default() });
+
+        let (v, args) = do_parse(["--ub-tags=host & !slow", "make"]);
+        assert_eq!(v, ["make"]);
+        assert_eq!(args.tags().unwrap().unwrap().to_string(), "host & !slow");
+        assert_eq!(args.propagated_args(), ["--ub-tags=host & !slow"]);
+        assert_eq!(args, Config::builder().tags("host & !slow").build());
+
+        // reported once the config is used
+        let (v, args) = do_parse(["--ub-tags=host &", "make"]);
+        assert_eq!(v, ["make"]);
+        assert_eq!(args.tags(), Err(Error::InvalidTagExpression(String::from("host &"), 6, String::from("expected a tag"))));
+        let (_, args) = do_parse(["--ub-select=docs", "--ub-tags=host"]);
+        assert!(matches!(args.tags(), Err(Error::ConflictingFlags(_))));
+
+        let (v, args) = do_parse(["--ub-allow-empty"]);
+        assert!(v.is_empty(), "!is_empty: was {:?}", v);
+        assert_eq!(args, Config { allow_empty: true, ..Config::default() });
+
+        let (v, args) = do_parse(["--ub-batch"]);
+        assert!(v.is_empty(), "!is_empty: was {:?}", v);
+        assert_eq!(args, Config { batch: true, ..Config::default() });
+
+        let (v, args) = do_parse(["--ub-completion-list-tags"]);
+        assert!(v.is_empty(), "!is_empty: was {:?}", v);
+        assert_eq!(args, Config { completion: Some(Completion::ListTags), ..Config::default() });
+
+        let (v, args) = do_parse(["--ub-completion"]);
+        assert!(v.is_empty(), "!is_empty: was {:?}", v);
+        assert_eq!(args, Config { completion: Some(Completion::PrintCompletion(Shell::Bash)), ..Config::default() });
+
+        let (v, args) = do_parse(["--ub-completion=powershell"]);
+        assert!(v.is_empty(), "!is_empty: was {:?}", v);
+        assert_eq!(args, Config { completion: Some(Completion::PrintCompletion(Shell::PowerShell)), ..Config::default() });
+
+        let (v, args) = do_parse(["--ub-completion=fish"]);
+        assert_eq!(v, ["--ub-completion=fish"]);
+        assert_eq!(args, Config::default());
+
+        let (v, args) = do_parse(["--ub-list"]);
+        assert!(v.is_empty(), "!is_empty: was {:?}", v);
+        assert_eq!(args.list(), Some(ListFormat::Text));
+        let (_, args) = do_parse(["--ub-list=json"]);
+        assert_eq!(args.list(), Some(ListFormat::Json));
+        let (v, args) = do_parse(["--ub-list=xml"]);
+        assert_eq!(v, ["--ub-list=xml"]);
+        assert_eq!(args, Config::default());
+
+        let (v, args) = do_parse(["--ub-stream"]);
+        assert!(v.is_empty(), "!is_empty: was {:?}", v);
+        assert_eq!(args, Config { stream: true, ..Config::default() });
+
+        let (v, args) = do_parse(["--ub-no-vcs-boundary"]);
+        assert!(v.is_empty(), "!is_empty: was {:?}", v);
+        assert_eq!(args, Config { vcs_boundary: false, ..Config::default() });
+        assert!(Config::default().vcs_boundary());
+
+        let (v, args) = do_parse(["--ub-chain"]);
+        assert!(v.is_empty(), "!is_empty: was {:?}", v);
+        assert_eq!(args, Config { chain: true, ..Config::default() });
+
+        let (v, args) = do_parse(["--ub-discover"]);
+        assert!(v.is_empty(), "!is_empty: was {:?}", v);
+        assert_eq!(args, Config { discover: true, ..Config::default() });
+
+        let (v, args) = do_parse(["--ub-discover-run"]);
+        assert!(v.is_empty(), "!is_empty: was {:?}", v);
+        assert_eq!(args, Config { discover: true, discover_run: true, ..Config::default() });
+
+        let (v, args) = do_parse(["--ub-one-file-system"]);
+        assert!(v.is_empty(), "!is_empty: was {:?}", v);
+        assert_eq!(args, Config { one_file_system: true, ..Config::default() });
+
+        let (v, args) = do_parse(["--ub-detach"]);
+        assert!(v.is_empty(), "!is_empty: was {:?}", v);
+        assert_eq!(args, Config { detach: true, ..Config::default() });
+
+        let (v, args) = do_parse(["--ub-ignore-mkdir-errors"]);
+        assert!(v.is_empty(), "!is_empty: was {:?}", v);
+        assert_eq!(args, Config { ignore_mkdir_errors: true, ..Config::default() });
+
         let (v, args) = do_parse(["--ub-print", "a", "b"]);
         assert_eq!(v, ["a", "b"]);
         assert_eq!(args, Config { print: true, ..Config::default() });
@@ -0,0 +1,212 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// (C) Copyright 2024 Greg Whiteley
+
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::SystemTime;
+
+use super::{Config, Error, Result};
+use super::exec::{OutputSettings, RetCode, RunSettings, Runner, Stage};
+use super::shell;
+use super::cfg::{env_level, prefix};
+
+/// CI service to format output for, see `--ub-ci=`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ci {
+    /// GitHub Actions workflow commands
+    GitHub,
+    /// GitLab CI collapsible sections
+    GitLab,
+}
+
+impl Ci {
+    /// Detect the CI service from its environment variables
+    pub(crate) fn detect<E: Fn(&str) -> Option<String>>(env: E) -> Option<Ci> {
+        if env("GITHUB_ACTIONS").as_deref() == Some("true") {
+            Some(Ci::GitHub)
+        } else if env("GITLAB_CI").map_or(false, |v| !v.is_empty()) {
+            Some(Ci::GitLab)
+        } else {
+            None
+        }
+    }
+
+    /// Format an error as an annotation, if the service has them
+    pub fn annotate(&self, e: &Error) -> Option<String> {
+        self.annotate_as(e, &prefix(env_level()))
+    }
+
+    /// As [`Ci::annotate`], the message prefixed as for the given
+    /// config - see [`Config::prefix`]
+    pub fn annotate_as(&self, e: &Error, prefix: &str) -> Option<String> {
+        match self {
+            Ci::GitHub => Some(format!("::error::{}", escape(&format!("{}: {}", prefix, e)))),
+            Ci::GitLab => None,
+        }
+    }
+
+    fn start(&self, id: usize, label: &str) -> String {
+        match self {
+            Ci::GitHub => format!("::group::{}", escape(label)),
+            Ci::GitLab => format!("\x1b[0Ksection_start:{}:upbuild_step_{}\r\x1b[0K{}", now(), id, label),
+        }
+    }
+
+    fn end(&self, id: usize) -> String {
+        match self {
+            Ci::GitHub => String::from("::endgroup::"),
+            Ci::GitLab => format!("\x1b[0Ksection_end:{}:upbuild_step_{}\r\x1b[0K", now(), id),
+        }
+    }
+}
+
+// A workflow command is a single line, its data escaped as the
+// runner expects - % first, so the escapes themselves aren't mangled
+fn escape(s: &str) -> String {
+    s.replace('%', "%25").replace('\r', "%0D").replace('\n', "%0A")
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+// Runner folding the output of each command into a group
+struct CiRunner {
+    inner: Box<dyn Runner>,
+    ci: Ci,
+    count: AtomicUsize,
+}
+
+impl Runner for CiRunner {
+    fn run(&self, cmd: &[String], cd: Option<&Path>, settings: &RunSettings) -> Result<RetCode> {
+        let id = self.count.fetch_add(1, Ordering::Relaxed);
+        self.inner.display(&self.ci.start(id, &shell::join(cmd)));
+        let result = self.inner.run(cmd, cd, settings);
+        self.inner.display(&self.ci.end(id));
+        result
+    }
+
+    fn run_captured(&self, cmd: &[String], cd: Option<&Path>, settings: &RunSettings, silent: bool) -> Result<(RetCode, Vec<u8>)> {
+        let id = self.count.fetch_add(1, Ordering::Relaxed);
+        self.inner.display(&self.ci.start(id, &shell::join(cmd)));
+        let result = self.inner.run_captured(cmd, cd, settings, silent);
+        self.inner.display(&self.ci.end(id));
+        result
+    }
+
+    fn run_pipeline(&self, cmds: &[Stage<'_>]) -> Result<Vec<RetCode>> {
+        let id = self.count.fetch_add(1, Ordering::Relaxed);
+        let names: Vec<_> = cmds.iter().map(|(cmd, _, _)| shell::join(cmd)).collect();
+        self.inner.display(&self.ci.start(id, &names.join(" | ")));
+        let result = self.inner.run_pipeline(cmds);
+        self.inner.display(&self.ci.end(id));
+        result
+    }
+
+    fn check_mkdir(&self, d: &Path) -> Result<()> {
+        self.inner.check_mkdir(d)
+    }
+
+    fn check_mkdir_umask(&self, d: &Path, umask: u32) -> Result<()> {
+        self.inner.check_mkdir_umask(d, umask)
+    }
+
+    fn is_dir(&self, d: &Path) -> bool {
+        self.inner.is_dir(d)
+    }
+
+    fn canonicalize(&self, d: &Path) -> std::io::Result<PathBuf> {
+        self.inner.canonicalize(d)
+    }
+
+    fn has_program(&self, name: &str) -> bool {
+        self.inner.has_program(name)
+    }
+
+    fn has_env(&self, name: &str, allow_empty: bool) -> bool {
+        self.inner.has_env(name, allow_empty)
+    }
+
+    fn display_output(&self, file: &Path, settings: &OutputSettings) -> Result<()> {
+        self.inner.display_output(file, settings)
+    }
+
+    fn find_outfiles(&self, pattern: &Path, cd: Option<&Path>) -> Vec<PathBuf> {
+        self.inner.find_outfiles(pattern, cd)
+    }
+
+    fn display(&self, s: &str) {
+        self.inner.display(s)
+    }
+
+    fn trace(&self, s: &str) {
+        self.inner.trace(s)
+    }
+}
+
+/// Wrap a runner to group each command's output for the CI service
+/// selected by `--ub-ci=`, if any.  Recursive invocations don't
+/// group as the services don't support nesting.
+pub fn ci_runner(inner: Box<dyn Runner>, cfg: &Config) -> Box<dyn Runner> {
+    match cfg.ci() {
+        Some(ci) if cfg.level == 0 => Box::new(CiRunner { inner, ci, count: AtomicUsize::new(0) }),
+        _ => inner,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::testing::MockRunner;
+
+    #[test]
+    fn test_detect() {
+        let env = |vars: &'static [(&'static str, &'static str)]| {
+            move |k: &str| vars.iter().find(|(n, _)| *n == k).map(|(_, v)| v.to_string())
+        };
+        assert_eq!(Ci::detect(env(&[("GITHUB_ACTIONS", "true")])), Some(Ci::GitHub));
+        assert_eq!(Ci::detect(env(&[("GITLAB_CI", "true")])), Some(Ci::GitLab));
+        assert_eq!(Ci::detect(env(&[("GITHUB_ACTIONS", "false")])), None);
+        assert_eq!(Ci::detect(env(&[])), None);
+    }
+
+    #[test]
+    fn test_markers() {
+        assert_eq!(Ci::GitHub.start(0, "make -j8"), "::group::make -j8");
+        assert_eq!(Ci::GitHub.end(0), "::endgroup::");
+        assert_eq!(Ci::GitHub.start(0, "printf '100%%\r\n'"), "::group::printf '100%25%25%0D%0A'");
+        let start = Ci::GitLab.start(3, "make -j8");
+        assert!(start.starts_with("\x1b[0Ksection_start:"), "{:?}", start);
+        assert!(start.ends_with(":upbuild_step_3\r\x1b[0Kmake -j8"), "{:?}", start);
+        let end = Ci::GitLab.end(3);
+        assert!(end.starts_with("\x1b[0Ksection_end:") && end.ends_with(":upbuild_step_3\r\x1b[0K"), "{:?}", end);
+
+        assert_eq!(Ci::GitHub.annotate_as(&Error::ExitWithExitCode(2), "upbuild").as_deref(), Some("::error::upbuild: Process exitted with code: 2"));
+        assert_eq!(Ci::GitHub.annotate_as(&Error::FailedToExec(vec!["x".into()], "/src".into(), std::io::ErrorKind::NotFound.into(), vec!["n".into()]), "upbuild[2]").as_deref(),
+                   Some("::error::upbuild[2]: Failed to exec 'x' in /src: entity not found%0A  note: n"));
+        assert_eq!(Ci::GitHub.annotate_as(&Error::UnknownCommand("50%\r".into()), "upbuild").as_deref(),
+                   Some("::error::upbuild: No command '50%25%0D' - expected a position from 1 or an @name"));
+        assert_eq!(Ci::GitLab.annotate_as(&Error::ExitWithExitCode(2), "upbuild"), None);
+    }
+
+    #[test]
+    fn test_ci_runner() {
+        let runner = MockRunner::new();
+        let cfg = Config { ci: Some(Ci::GitHub), ..Config::default() };
+        let r = ci_runner(Box::new(runner.clone()), &cfg);
+        runner.push_result(Ok(0));
+        r.run(&["make".to_string(), "a b".to_string()], None, &RunSettings::default()).unwrap();
+        r.display("upbuild: Leaving directory");
+        runner.expect_run(&["make", "a b"], None);
+        assert_eq!(runner.displayed(), ["::group::make 'a b'", "::endgroup::", "upbuild: Leaving directory"]);
+
+        // no nesting
+        runner.reset();
+        let cfg = Config { ci: Some(Ci::GitHub), level: 1, ..Config::default() };
+        let r = ci_runner(Box::new(runner.clone()), &cfg);
+        runner.push_result(Ok(0));
+        r.run(&["make".to_string()], None, &RunSettings::default()).unwrap();
+        runner.expect_run(&["make"], None).assert_done();
+    }
+}
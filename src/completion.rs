@@ -0,0 +1,265 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// (C) Copyright 2024 Greg Whiteley
+
+use std::collections::{BTreeSet, HashSet};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use super::{ClassicFile, Cmd, Result};
+use super::cfg::{OptionValue, OPTIONS};
+use super::find::find;
+
+/// Most `.upbuild` files followed through recursive calls when
+/// listing tags
+const MAX_RECURSION: usize = 32;
+
+/// Shell completion support requested on the command line
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Completion {
+    /// Print the completion script for the given shell
+    PrintCompletion(Shell),
+    /// List the tags `--ub-select=` and `--ub-reject=` can take, see
+    /// `--ub-completion-list-tags`
+    ListTags,
+}
+
+impl Completion {
+    /// Print the completion script, or the tags from the `.upbuild`
+    /// file found from the current directory
+    pub fn print(&self) -> Result<()> {
+        self.write(&mut std::io::stdout().lock())
+    }
+
+    pub(crate) fn write<W: Write>(&self, w: &mut W) -> Result<()> {
+        match self {
+            Completion::PrintCompletion(shell) => write!(w, "{}", shell.script())?,
+            Completion::ListTags => {
+                for tag in list_tags(&find(".")?)? {
+                    writeln!(w, "{}", tag)?;
+                }
+            },
+        }
+        Ok(())
+    }
+}
+
+/// Shell to generate a completion script for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Shell {
+    /// bash, via `complete -F`
+    Bash,
+    /// PowerShell, via `Register-ArgumentCompleter`
+    PowerShell,
+}
+
+impl Shell {
+    /// returns the completion script for this shell
+    pub fn script(&self) -> String {
+        match self {
+            Shell::Bash => generate_bash_completion(),
+            Shell::PowerShell => generate_powershell_completion(),
+        }
+    }
+}
+
+// Options as offered for completion - those taking a value end in =
+fn completions() -> Vec<String> {
+    OPTIONS.iter()
+        .map(|(name, value)| match value {
+            OptionValue::None | OptionValue::Optional => name.to_string(),
+            _ => format!("{}=", name),
+        })
+        .collect()
+}
+
+fn tag_options() -> impl Iterator<Item=&'static str> {
+    OPTIONS.iter().filter(|(_, value)| *value == OptionValue::Tag).map(|(name, _)| *name)
+}
+
+fn choice_options() -> impl Iterator<Item=(&'static str, &'static [&'static str])> {
+    OPTIONS.iter().filter_map(|(name, value)| match value {
+        OptionValue::Choice(choices) => Some((*name, *choices)),
+        _ => None,
+    })
+}
+
+/// Generate a bash completion script for upbuild, to be sourced
+pub fn generate_bash_completion() -> String {
+    let tags: Vec<_> = tag_options().map(|name| format!("{}=*", name)).collect();
+    let mut script = String::from("# bash completion for upbuild\n_upbuild() {\n");
+    // the whole word, as = may be in COMP_WORDBREAKS
+    script += "    local line=${COMP_LINE:0:COMP_POINT}\n";
+    script += "    local word=${line##*[[:space:]]}\n";
+    script += "    local prefix=\n";
+    script += "    [[ $COMP_WORDBREAKS == *=* ]] || prefix=\"${word%%=*}=\"\n";
+    script += "    local values\n";
+    script += "    case \"$word\" in\n";
+    script += &format!("        {})\n            values=$(upbuild --ub-completion-list-tags 2>/dev/null) ;;\n", tags.join("|"));
+    for (name, choices) in choice_options() {
+        script += &format!("        {}=*)\n            values=\"{}\" ;;\n", name, choices.join(" "));
+    }
+    script += "        *=*)\n            return ;;\n";
+    script += "        --*)\n";
+    script += &format!("            COMPREPLY=($(compgen -W \"{}\" -- \"$word\"))\n", completions().join(" "));
+    script += "            [[ ${#COMPREPLY[@]} == 1 && ${COMPREPLY[0]} == *= ]] && compopt -o nospace\n";
+    script += "            return ;;\n";
+    script += "        *)\n            return ;;\n";
+    script += "    esac\n";
+    script += "    COMPREPLY=($(compgen -P \"$prefix\" -W \"$values\" -- \"${word#*=}\"))\n";
+    script += "}\ncomplete -o default -F _upbuild upbuild\n";
+    script
+}
+
+/// Generate a PowerShell completion script for upbuild, to be
+/// dot-sourced or added to `$PROFILE`
+pub fn generate_powershell_completion() -> String {
+    let quoted = |v: &[&str]| v.iter().map(|x| format!("'{}'", x)).collect::<Vec<_>>().join(", ");
+    let options: Vec<_> = completions();
+    let options: Vec<_> = options.iter().map(String::as_str).collect();
+    let tags: Vec<_> = tag_options().collect();
+
+    let mut script = String::from("# PowerShell completion for upbuild\n");
+    script += "Register-ArgumentCompleter -Native -CommandName upbuild -ScriptBlock {\n";
+    script += "    param($wordToComplete, $commandAst, $cursorPosition)\n";
+    script += &format!("    $options = @({})\n", quoted(&options));
+    script += &format!("    $tagOptions = @({})\n", quoted(&tags));
+    script += "    $choices = @{\n";
+    for (name, choices) in choice_options() {
+        script += &format!("        '{}' = @({})\n", name, quoted(choices));
+    }
+    script += "    }\n";
+    script += "    if ($wordToComplete -match '^(--ub-[^=]+)=(.*)$') {\n";
+    script += "        $option = $Matches[1]\n";
+    script += "        $value = $Matches[2]\n";
+    script += "        if ($tagOptions -contains $option) {\n";
+    script += "            $values = @(upbuild --ub-completion-list-tags 2>$null)\n";
+    script += "        } elseif ($choices.ContainsKey($option)) {\n";
+    script += "            $values = $choices[$option]\n";
+    script += "        } else {\n";
+    script += "            return\n";
+    script += "        }\n";
+    script += "        $values | Where-Object { $_ -like \"$value*\" } | ForEach-Object {\n";
+    script += "            [System.Management.Automation.CompletionResult]::new(\"$option=$_\", $_, 'ParameterValue', $_)\n";
+    script += "        }\n";
+    script += "    } elseif ($wordToComplete -like '-*') {\n";
+    script += "        $options | Where-Object { $_ -like \"$wordToComplete*\" } | ForEach-Object {\n";
+    script += "            [System.Management.Automation.CompletionResult]::new($_, $_, 'ParameterName', $_)\n";
+    script += "        }\n";
+    script += "    }\n";
+    script += "}\n";
+    script
+}
+
+/// List the tags in the `.upbuild` file at the given path, and in the
+/// files its recursive calls would run, sorted and without duplicates.
+/// Only the given file has to be readable - others are skipped.
+pub fn list_tags(path: &Path) -> Result<Vec<String>> {
+    let mut tags = BTreeSet::new();
+    let mut seen = HashSet::new();
+    let mut pending = vec![(path.to_path_buf(), ClassicFile::load(path)?)];
+
+    while let Some((path, file)) = pending.pop() {
+        seen.insert(path.canonicalize().unwrap_or_else(|_| path.clone()));
+        for cmd in file.commands() {
+            tags.extend(cmd.tags().iter().cloned());
+            if seen.len() + pending.len() >= MAX_RECURSION {
+                continue;
+            }
+            if let Some(parent) = recursion_target(&path, cmd) {
+                if ! seen.contains(&parent.canonicalize().unwrap_or_else(|_| parent.clone())) {
+                    match ClassicFile::load(&parent) {
+                        Ok(file) => pending.push((parent, file)),
+                        Err(e) => debug!("not listing tags from {}: {}", parent.display(), e),
+                    }
+                }
+            }
+        }
+    }
+
+    Ok(tags.into_iter().collect())
+}
+
+// The file a recursive command in the given file would run, if any
+fn recursion_target(path: &Path, cmd: &Cmd) -> Option<PathBuf> {
+    if ! cmd.recurse() || cmd.ssh().is_some() {
+        return None;
+    }
+    let dir = match path.parent().filter(|p| !p.as_os_str().is_empty()) {
+        Some(p) => p.to_path_buf(),
+        None => PathBuf::from("."),
+    };
+    let dir = match cmd.directory() {
+        Some(d) => dir.join(d),
+        None => dir,
+    };
+    find(dir).ok()
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::testing::TempDir;
+
+    #[test]
+    fn test_scripts() {
+        for shell in [Shell::Bash, Shell::PowerShell] {
+            let script = shell.script();
+            for (name, value) in OPTIONS {
+                assert!(script.contains(name), "{:?} is missing {}", shell, name);
+                if let OptionValue::Choice(choices) = value {
+                    for c in *choices {
+                        assert!(script.contains(c), "{:?} is missing {}={}", shell, name, c);
+                    }
+                }
+            }
+            assert!(script.contains("upbuild --ub-completion-list-tags"), "{:?}", shell);
+        }
+
+        let bash = generate_bash_completion();
+        assert!(bash.contains("\"--ub-print "), "{}", bash);
+        assert!(bash.contains(" --ub-select= "), "{}", bash);
+        assert!(bash.contains("        --ub-select=*|--ub-reject=*)\n"), "{}", bash);
+        assert!(bash.contains("        --ub-color=*)\n            values=\"auto always never\" ;;\n"), "{}", bash);
+        assert!(bash.ends_with("complete -o default -F _upbuild upbuild\n"), "{}", bash);
+
+        let ps = generate_powershell_completion();
+        assert!(ps.contains("Register-ArgumentCompleter -Native -CommandName upbuild -ScriptBlock {\n"), "{}", ps);
+        assert!(ps.contains("'--ub-print', "), "{}", ps);
+        assert!(ps.contains("    $tagOptions = @('--ub-select', '--ub-reject')\n"), "{}", ps);
+        assert!(ps.contains("        '--ub-ci' = @('github', 'gitlab', 'auto', 'none')\n"), "{}", ps);
+    }
+
+    #[test]
+    fn test_print_completion() {
+        let output = |args: &[&str]| {
+            let args = ["upbuild"].iter().chain(args).map(|x| x.to_string());
+            let (rest, cfg) = crate::Config::parse_with_env(args, |_| None);
+            assert_eq!(rest.count(), 0);
+            let mut out = Vec::new();
+            cfg.completion().expect("completion requested").write(&mut out).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+        assert_eq!(output(&["--ub-completion"]), generate_bash_completion());
+        assert_eq!(output(&["--ub-completion=bash"]), generate_bash_completion());
+        assert_eq!(output(&["--ub-completion=powershell"]), generate_powershell_completion());
+    }
+
+    #[test]
+    fn test_list_tags() {
+        let dir = TempDir::new("tags");
+        std::fs::create_dir_all(dir.join("a/b/c")).unwrap();
+        let write = |p: &str, s: &str| std::fs::write(dir.join(p).join(".upbuild"), s).unwrap();
+
+        write("a/b/c", "make\n@tags=host,debug\n&&\nupbuild\n@tags=parent\n");
+        // skips a/b without a file, and recurses back to itself
+        write("a", "make\n@tags=target,host\n&&\nupbuild\n@cd=.\n&&\nupbuild\n@cd=b/c\n");
+        assert_eq!(list_tags(&dir.join("a/b/c/.upbuild")).unwrap(), ["debug", "host", "parent", "target"]);
+        assert_eq!(list_tags(&dir.join("a/.upbuild")).unwrap(), ["debug", "host", "parent", "target"]);
+
+        // unreadable parents are left out
+        write("a", "make\n@bogus\n");
+        assert_eq!(list_tags(&dir.join("a/b/c/.upbuild")).unwrap(), ["debug", "host", "parent"]);
+        assert!(list_tags(&dir.join("a/.upbuild")).is_err());
+    }
+}
@@ -2,21 +2,227 @@
 // (C) Copyright 2024 Greg Whiteley
 
 use super::exec::RetCode;
+use super::find::SearchEnd;
 
 #[derive(Debug)]
+#[non_exhaustive]
 pub enum Error {
     InvalidTag(String),
     InvalidRetMapDefinition(String),
     EmptyEntry,
     FlagBeforeCommand(String),
     NoCommands,
-    FailedToExec(std::io::Error),
+    FailedToExec(Vec<String>, String, std::io::Error, Vec<String>),
     IoFailed(std::io::Error),
-    InvalidDir(String),
-    NotFound(String),
+    InvalidDir(std::path::PathBuf),
+    NotFound(std::path::PathBuf, Vec<std::path::PathBuf>, SearchEnd),
+    UnableToOpen(std::path::PathBuf, std::io::Error),
     ExitWithExitCode(RetCode),
-    ExitWithSignal(RetCode),
-    UnableToReadOutfile(String, std::io::Error),
+    ExitWithSignal(RetCode, bool),
+    UnableToReadOutfile(String, String, std::io::Error),
+    NoOutfileMatch(String, String, String),
+    UnableToCreateDir(String, std::io::Error),
+    MissingDir(String, String),
+    UnableToReadStdin(String, std::io::Error),
+    ExecNotLast(String),
+    UnableToLock(String, std::io::Error),
+    LockTimeout(String, String),
+    MissingContainerEngine(String, String),
+    ConflictingFlags(String),
+    JobserverFailed(std::io::Error),
+    MultiplePlaceholders(String),
+    MissingArg(usize, String),
+    UnsetCapture(String, String),
+    UnableToAdd(String),
+    UnableToEdit(String),
+    UnknownCommand(String),
+    DuplicateName(String),
+    UnableToExpand(String, String),
+    MissingEnv(Vec<String>),
+    UnknownTags(Vec<String>),
+    NothingSelected(Vec<String>, Vec<String>),
+    NothingMatched(String),
+    InvalidTagExpression(String, usize, String),
+    InvalidScanPattern(String, String),
+    NoParentFile(String, Box<Error>),
+    UnconnectedPipe(String),
+    EmptyValue(String, String),
+    InCommand(usize, Option<String>, String, Box<Error>),
+}
+
+// Directories listed when .upbuild isn't found
+const SEARCHED_SHOWN: usize = 5;
+
+// Exit codes for failures other than a command's own, as used by env
+const EXIT_INTERNAL: RetCode = 125;
+const EXIT_CANNOT_RUN: RetCode = 126;
+const EXIT_NOT_FOUND: RetCode = 127;
+
+/// Coarse category of an [`Error`], stable as new errors are added
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ErrorKind {
+    /// The `.upbuild` file couldn't be understood
+    Parse,
+    /// Reading or writing a file failed
+    Io,
+    /// A command couldn't be run
+    Exec,
+    /// A command ran and failed
+    ChildFailed,
+    /// The `.upbuild` file, a directory, or a command wasn't found
+    NotFound,
+    /// The lock couldn't be taken, see `--ub-lock`
+    Lock,
+    /// The `.upbuild` file couldn't be changed as asked
+    Edit,
+}
+
+impl Error {
+    /// returns the category of the error
+    pub fn kind(&self) -> ErrorKind {
+        match self.inner() {
+            Error::InvalidTag(_) | Error::InvalidRetMapDefinition(_) | Error::EmptyEntry |
+            Error::FlagBeforeCommand(_) | Error::NoCommands | Error::ConflictingFlags(_) |
+            Error::MultiplePlaceholders(_) | Error::DuplicateName(_) | Error::UnableToExpand(_, _) |
+            Error::InvalidTagExpression(_, _, _) | Error::InvalidScanPattern(_, _) |
+            Error::UnconnectedPipe(_) | Error::EmptyValue(_, _)
+                => ErrorKind::Parse,
+
+            Error::IoFailed(_) | Error::UnableToReadOutfile(_, _, _) | Error::UnableToCreateDir(_, _) |
+            Error::UnableToReadStdin(_, _) | Error::JobserverFailed(_) | Error::NoOutfileMatch(_, _, _)
+                => ErrorKind::Io,
+
+            Error::FailedToExec(_, _, _, _) | Error::MissingDir(_, _) | Error::ExecNotLast(_) |
+            Error::MissingContainerEngine(_, _) | Error::MissingArg(_, _) | Error::MissingEnv(_) |
+            Error::UnsetCapture(_, _)
+                => ErrorKind::Exec,
+
+            Error::ExitWithExitCode(_) | Error::ExitWithSignal(_, _)
+                => ErrorKind::ChildFailed,
+
+            Error::InvalidDir(_) | Error::NotFound(_, _, _) | Error::UnknownCommand(_) |
+            Error::UnknownTags(_) | Error::NothingSelected(_, _) | Error::NothingMatched(_)
+                => ErrorKind::NotFound,
+
+            Error::UnableToOpen(_, e) if e.kind() == std::io::ErrorKind::NotFound
+                => ErrorKind::NotFound,
+            Error::UnableToOpen(_, _)
+                => ErrorKind::Io,
+
+            Error::UnableToLock(_, _) | Error::LockTimeout(_, _)
+                => ErrorKind::Lock,
+
+            Error::UnableToAdd(_) | Error::UnableToEdit(_)
+                => ErrorKind::Edit,
+
+            Error::NoParentFile(_, _)
+                => ErrorKind::NotFound,
+
+            Error::InCommand(_, _, _, e) => e.kind(),
+        }
+    }
+
+    /// returns the exit code of a command that failed, after any
+    /// `@retmap`.  None for other errors, including a command killed
+    /// by a signal.
+    pub fn exit_code(&self) -> Option<RetCode> {
+        match self.inner() {
+            Error::ExitWithExitCode(c) => Some(*c),
+            _ => None,
+        }
+    }
+
+    /// returns the signal that killed a command
+    pub fn signal(&self) -> Option<RetCode> {
+        match self.inner() {
+            Error::ExitWithSignal(s, _) => Some(*s),
+            _ => None,
+        }
+    }
+
+    /// returns the code upbuild should exit with for this error.  A
+    /// failed command's exit code is passed through, and one killed by
+    /// a signal gives 128 plus the signal.  Otherwise, following
+    /// `env` and `timeout`, 127 when a command couldn't be found, 126
+    /// when it was found but couldn't be run, and 125 for upbuild's
+    /// own failures.
+    pub fn exit_status(&self) -> RetCode {
+        match self.inner() {
+            Error::ExitWithExitCode(c) => *c,
+            Error::ExitWithSignal(s, _) => 128 + s,
+            Error::FailedToExec(_, _, e, _) if e.kind() == std::io::ErrorKind::NotFound => EXIT_NOT_FOUND,
+            Error::MissingContainerEngine(_, _) => EXIT_NOT_FOUND,
+            Error::FailedToExec(_, _, _, _) => EXIT_CANNOT_RUN,
+            _ => EXIT_INTERNAL,
+        }
+    }
+
+    /// returns the error without the context of the command it
+    /// happened in, see `Error::InCommand`
+    pub fn inner(&self) -> &Error {
+        match self {
+            Error::InCommand(_, _, _, e) => e.inner(),
+            e => e,
+        }
+    }
+}
+
+// Conventional name of a signal.  Only those numbered the same on
+// all unix platforms, plus the rest on linux.
+#[cfg(target_family = "unix")]
+fn signal_name(s: RetCode) -> Option<&'static str> {
+    Some(match s {
+        1 => "SIGHUP",
+        2 => "SIGINT",
+        3 => "SIGQUIT",
+        4 => "SIGILL",
+        5 => "SIGTRAP",
+        6 => "SIGABRT",
+        8 => "SIGFPE",
+        9 => "SIGKILL",
+        11 => "SIGSEGV",
+        13 => "SIGPIPE",
+        14 => "SIGALRM",
+        15 => "SIGTERM",
+        _ => return linux_signal_name(s),
+    })
+}
+
+#[cfg(target_os = "linux")]
+fn linux_signal_name(s: RetCode) -> Option<&'static str> {
+    Some(match s {
+        7 => "SIGBUS",
+        10 => "SIGUSR1",
+        12 => "SIGUSR2",
+        16 => "SIGSTKFLT",
+        17 => "SIGCHLD",
+        18 => "SIGCONT",
+        19 => "SIGSTOP",
+        20 => "SIGTSTP",
+        21 => "SIGTTIN",
+        22 => "SIGTTOU",
+        23 => "SIGURG",
+        24 => "SIGXCPU",
+        25 => "SIGXFSZ",
+        26 => "SIGVTALRM",
+        27 => "SIGPROF",
+        28 => "SIGWINCH",
+        29 => "SIGIO",
+        30 => "SIGPWR",
+        31 => "SIGSYS",
+        _ => return None,
+    })
+}
+
+#[cfg(all(target_family = "unix", not(target_os = "linux")))]
+fn linux_signal_name(_s: RetCode) -> Option<&'static str> {
+    None
+}
+
+#[cfg(not(target_family = "unix"))]
+fn signal_name(_s: RetCode) -> Option<&'static str> {
+    None
 }
 
 impl std::fmt::Display for Error {
@@ -32,20 +238,106 @@ impl std::fmt::Display for Error {
                 write!(f, "Found tag before command {}", s),
             Error::NoCommands =>
                 write!(f, "No commands in file"),
-            Error::FailedToExec(e) =>
-                 write!(f, "Failed to exec: {}", e),
+            Error::FailedToExec(argv, dir, e, notes) => {
+                let program = argv.first().map_or("", String::as_str);
+                write!(f, "Failed to exec '{}' in {}: {}", program, dir, e)?;
+                if argv.len() > 1 {
+                    write!(f, "\n  command: {}", crate::shell::join(argv))?;
+                }
+                notes.iter().try_for_each(|n| write!(f, "\n  note: {}", n))
+            },
             Error::IoFailed(e) =>
                 write!(f, "{}", e),
             Error::InvalidDir(p) =>
-                write!(f, "Invalid directory '{}'", p),
-            Error::NotFound(p) =>
-                write!(f, "Unable to locate .upbuild from '{}'", p),
+                write!(f, "Invalid directory '{}'", p.display()),
+            Error::NotFound(p, searched, end) => {
+                write!(f, "Unable to locate .upbuild from '{}'", p.display())?;
+                if !searched.is_empty() {
+                    let shown: Vec<_> = searched.iter().take(SEARCHED_SHOWN).map(|d| d.display().to_string()).collect();
+                    write!(f, "\n  searched: {}", shown.join(", "))?;
+                    if searched.len() > SEARCHED_SHOWN {
+                        write!(f, " ... and {} more", searched.len() - SEARCHED_SHOWN)?;
+                    }
+                }
+                write!(f, "\n  stopped: {}", end)
+            },
+            Error::UnableToOpen(p, e) =>
+                write!(f, "Unable to open .upbuild file '{}': {}", p.display(), e),
             Error::ExitWithExitCode(c) =>
                  write!(f, "Process exitted with code: {}", c),
-            Error::ExitWithSignal(c) =>
-                 write!(f, "Process exitted with signal: {}", c),
-            Error::UnableToReadOutfile(file, e) =>
+            Error::ExitWithSignal(s, core) => {
+                write!(f, "Process exitted with signal: {}", s)?;
+                match (signal_name(*s), core) {
+                    (Some(name), true) => write!(f, " ({}, core dumped)", name),
+                    (Some(name), false) => write!(f, " ({})", name),
+                    (None, true) => write!(f, " (core dumped)"),
+                    (None, false) => Ok(()),
+                }
+            },
+            Error::UnableToReadOutfile(file, cmd, e) if cmd.is_empty() =>
                 write!(f, "Unable to read @outfile={}: {}", file, e),
+            Error::UnableToReadOutfile(file, cmd, e) =>
+                write!(f, "Unable to read @outfile={} of {}: {}", file, cmd, e),
+            Error::NoOutfileMatch(pattern, dir, cmd) =>
+                write!(f, "No file in {} matches @outfile={} after {}", dir, pattern, cmd),
+            Error::UnableToCreateDir(dir, e) =>
+                write!(f, "Failed to create directory {}: {}", dir, e),
+            Error::MissingDir(dir, cmd) =>
+                write!(f, "Directory '{}' does not exist, unable to run: {}", dir, cmd),
+            Error::UnableToReadStdin(file, e) =>
+                write!(f, "Unable to read @stdin={}: {}", file, e),
+            Error::ExecNotLast(cmd) =>
+                write!(f, "@exec can only be used on the last command to run: {}", cmd),
+            Error::UnableToLock(file, e) =>
+                write!(f, "Unable to lock {}: {}", file, e),
+            Error::LockTimeout(file, holder) =>
+                write!(f, "Timed out waiting for {} to be unlocked (held by {})", file, holder),
+            Error::ConflictingFlags(s) =>
+                write!(f, "Conflicting flags, {}", s),
+            Error::MissingContainerEngine(engine, cmd) =>
+                write!(f, "'{}' was not found on PATH, unable to run @container command: {}", engine, cmd),
+            Error::JobserverFailed(e) =>
+                write!(f, "Jobserver failed: {}", e),
+            Error::MultiplePlaceholders(cmd) =>
+                write!(f, "Only one @args-here is allowed per command: {}", cmd),
+            Error::UnableToAdd(s) =>
+                write!(f, "Unable to add to .upbuild, {}", s),
+            Error::UnableToEdit(s) =>
+                write!(f, "Unable to edit .upbuild, {}", s),
+            Error::UnknownCommand(which) =>
+                write!(f, "No command '{}' - expected a position from 1 or an @name", which),
+            Error::DuplicateName(name) =>
+                write!(f, "More than one command has @name={}", name),
+            Error::UnableToExpand(path, why) =>
+                write!(f, "Unable to expand ~ in {}: {}", path, why),
+            Error::MissingArg(n, cmd) =>
+                write!(f, "Argument ${} was not provided, unable to run: {}", n, cmd),
+            Error::UnsetCapture(name, cmd) =>
+                write!(f, "${{{}}} is used before a command with @capture={} has run, unable to run: {}", name, name, cmd),
+            Error::NothingSelected(select, reject) if select.is_empty() && reject.is_empty() =>
+                write!(f, "No commands to run, every command is @disable or @manual"),
+            Error::NothingSelected(select, reject) =>
+                write!(f, "No commands matched selection {{{}}} with rejection {{{}}}", select.join(","), reject.join(",")),
+            Error::NothingMatched(expr) =>
+                write!(f, "No commands matched --ub-tags={}", expr),
+            Error::InvalidTagExpression(expr, pos, why) =>
+                write!(f, "Invalid --ub-tags expression:\n  {}\n  {}^ {}", expr, " ".repeat(*pos), why),
+            Error::InvalidScanPattern(option, why) =>
+                write!(f, "Invalid {}: {}", option, why),
+            Error::UnknownTags(tags) =>
+                write!(f, "No command has the tags given to --ub-select/--ub-reject/--ub-tags: {}", tags.join(", ")),
+            Error::MissingEnv(names) =>
+                write!(f, "Required environment variables are not set (see @env-required): {}", names.join(", ")),
+            Error::NoParentFile(cmd, e) =>
+                write!(f, "No .upbuild above this one for {} to run, give it a @cd: {}", cmd, e),
+            Error::UnconnectedPipe(cmd) =>
+                write!(f, "@pipe needs the command after it to run too, to feed into: {}", cmd),
+            Error::EmptyValue(flag, line) =>
+                write!(f, "{} needs a value that isn't empty or only spaces: {}", flag, line),
+            Error::InCommand(index, Some(name), program, e) =>
+                write!(f, "while running [{}: {} {}]: {}", index, program, name, e),
+            Error::InCommand(index, None, program, e) =>
+                write!(f, "while running [{}: {}]: {}", index, program, e),
         }
     }
 }
@@ -56,13 +348,78 @@ impl std::error::Error for Error {
             Error::InvalidTag(_) | Error::InvalidRetMapDefinition(_) |
             Error::EmptyEntry | Error::FlagBeforeCommand(_) |
             Error::NoCommands | Error::ExitWithExitCode(_) |
-            Error::ExitWithSignal(_) | Error::InvalidDir(_) | Error::NotFound(_) |
-            Error::UnableToReadOutfile(_, _)
+            Error::ExitWithSignal(_, _) | Error::InvalidDir(_) | Error::NotFound(_, _, _) |
+            Error::UnableToReadOutfile(_, _, _) | Error::NoOutfileMatch(_, _, _) | Error::UnableToCreateDir(_, _) |
+            Error::MissingDir(_, _) | Error::UnableToReadStdin(_, _) |
+            Error::ExecNotLast(_) | Error::UnableToLock(_, _) | Error::LockTimeout(_, _) |
+            Error::MissingContainerEngine(_, _) | Error::ConflictingFlags(_) |
+            Error::JobserverFailed(_) | Error::MultiplePlaceholders(_) |
+            Error::MissingArg(_, _) | Error::UnsetCapture(_, _) | Error::UnableToAdd(_) |
+            Error::UnableToEdit(_) | Error::UnknownCommand(_) | Error::DuplicateName(_) |
+            Error::UnableToExpand(_, _) | Error::MissingEnv(_) | Error::UnknownTags(_) |
+            Error::NothingSelected(_, _) | Error::NothingMatched(_) | Error::InvalidTagExpression(_, _, _) |
+            Error::InvalidScanPattern(_, _) | Error::UnconnectedPipe(_) | Error::EmptyValue(_, _)
 
                 => None,
 
-            Error::FailedToExec(ref e) => Some(e),
+            Error::FailedToExec(_, _, ref e, _) => Some(e),
             Error::IoFailed(ref e) => Some(e),
+            Error::UnableToOpen(_, ref e) => Some(e),
+            Error::NoParentFile(_, ref e) => Some(e.as_ref()),
+            Error::InCommand(_, _, _, ref e) => Some(e.as_ref()),
+        }
+    }
+}
+
+// Errors wrapping io::Error compare by its kind, as io::Error itself
+// isn't comparable
+impl PartialEq for Error {
+    fn eq(&self, other: &Self) -> bool {
+        use std::io::Error as IoError;
+        let io = |a: &IoError, b: &IoError| a.kind() == b.kind();
+        match (self, other) {
+            (Error::InvalidTag(a), Error::InvalidTag(b)) => a == b,
+            (Error::InvalidRetMapDefinition(a), Error::InvalidRetMapDefinition(b)) => a == b,
+            (Error::EmptyEntry, Error::EmptyEntry) => true,
+            (Error::FlagBeforeCommand(a), Error::FlagBeforeCommand(b)) => a == b,
+            (Error::NoCommands, Error::NoCommands) => true,
+            (Error::FailedToExec(a, c, e, n), Error::FailedToExec(b, d, f, m)) => a == b && c == d && io(e, f) && n == m,
+            (Error::IoFailed(e), Error::IoFailed(f)) => io(e, f),
+            (Error::InvalidDir(a), Error::InvalidDir(b)) => a == b,
+            (Error::NotFound(a, c, e), Error::NotFound(b, d, f)) => a == b && c == d && e == f,
+            (Error::UnableToOpen(a, e), Error::UnableToOpen(b, f)) => a == b && io(e, f),
+            (Error::ExitWithExitCode(a), Error::ExitWithExitCode(b)) => a == b,
+            (Error::ExitWithSignal(a, c), Error::ExitWithSignal(b, d)) => a == b && c == d,
+            (Error::UnableToReadOutfile(a, c, e), Error::UnableToReadOutfile(b, d, f)) => a == b && c == d && io(e, f),
+            (Error::NoOutfileMatch(a, c, e), Error::NoOutfileMatch(b, d, f)) => a == b && c == d && e == f,
+            (Error::UnableToCreateDir(a, e), Error::UnableToCreateDir(b, f)) => a == b && io(e, f),
+            (Error::MissingDir(a, c), Error::MissingDir(b, d)) => a == b && c == d,
+            (Error::UnableToReadStdin(a, e), Error::UnableToReadStdin(b, f)) => a == b && io(e, f),
+            (Error::ExecNotLast(a), Error::ExecNotLast(b)) => a == b,
+            (Error::UnableToLock(a, e), Error::UnableToLock(b, f)) => a == b && io(e, f),
+            (Error::LockTimeout(a, c), Error::LockTimeout(b, d)) => a == b && c == d,
+            (Error::MissingContainerEngine(a, c), Error::MissingContainerEngine(b, d)) => a == b && c == d,
+            (Error::ConflictingFlags(a), Error::ConflictingFlags(b)) => a == b,
+            (Error::JobserverFailed(e), Error::JobserverFailed(f)) => io(e, f),
+            (Error::MultiplePlaceholders(a), Error::MultiplePlaceholders(b)) => a == b,
+            (Error::MissingArg(n, a), Error::MissingArg(m, b)) => n == m && a == b,
+            (Error::UnsetCapture(a, c), Error::UnsetCapture(b, d)) => a == b && c == d,
+            (Error::UnableToAdd(a), Error::UnableToAdd(b)) => a == b,
+            (Error::UnableToEdit(a), Error::UnableToEdit(b)) => a == b,
+            (Error::UnknownCommand(a), Error::UnknownCommand(b)) => a == b,
+            (Error::DuplicateName(a), Error::DuplicateName(b)) => a == b,
+            (Error::UnableToExpand(a, c), Error::UnableToExpand(b, d)) => a == b && c == d,
+            (Error::MissingEnv(a), Error::MissingEnv(b)) => a == b,
+            (Error::UnknownTags(a), Error::UnknownTags(b)) => a == b,
+            (Error::NothingSelected(a, c), Error::NothingSelected(b, d)) => a == b && c == d,
+            (Error::NothingMatched(a), Error::NothingMatched(b)) => a == b,
+            (Error::InvalidTagExpression(a, n, c), Error::InvalidTagExpression(b, m, d)) => a == b && n == m && c == d,
+            (Error::InvalidScanPattern(a, c), Error::InvalidScanPattern(b, d)) => a == b && c == d,
+            (Error::NoParentFile(a, e), Error::NoParentFile(b, f)) => a == b && e == f,
+            (Error::UnconnectedPipe(a), Error::UnconnectedPipe(b)) => a == b,
+            (Error::EmptyValue(a, c), Error::EmptyValue(b, d)) => a == b && c == d,
+            (Error::InCommand(i, n, p, e), Error::InCommand(j, m, q, f)) => i == j && n == m && p == q && e == f,
+            _ => false,
         }
     }
 }
@@ -72,3 +429,170 @@ impl From<std::io::Error> for Error {
         Error::IoFailed(err)
     }
 }
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_kind() {
+        assert_eq!(Error::EmptyEntry.kind(), ErrorKind::Parse);
+        assert_eq!(Error::from(std::io::Error::from(std::io::ErrorKind::PermissionDenied)).kind(), ErrorKind::Io);
+        assert_eq!(Error::MissingArg(1, "git tag".into()).kind(), ErrorKind::Exec);
+        assert_eq!(Error::NotFound(".".into(), Vec::new(), SearchEnd::Root).kind(), ErrorKind::NotFound);
+        assert_eq!(Error::LockTimeout("x".into(), "y".into()).kind(), ErrorKind::Lock);
+
+        assert_eq!(Error::ExitWithExitCode(3).kind(), ErrorKind::ChildFailed);
+        assert_eq!(Error::ExitWithExitCode(3).exit_code(), Some(3));
+        assert_eq!(Error::ExitWithExitCode(3).signal(), None);
+        assert_eq!(Error::ExitWithSignal(9, false).kind(), ErrorKind::ChildFailed);
+        assert_eq!(Error::ExitWithSignal(9, false).exit_code(), None);
+        assert_eq!(Error::ExitWithSignal(9, false).signal(), Some(9));
+        assert_eq!(Error::EmptyEntry.exit_code(), None);
+    }
+
+    #[test]
+    fn test_exit_status() {
+        use std::io::ErrorKind::{NotFound, PermissionDenied, InvalidData};
+        let exec = |k: std::io::ErrorKind| Error::FailedToExec(vec!["make".into()], "/src".into(), k.into(), Vec::new());
+        for (e, status) in [
+            (Error::ExitWithExitCode(0), 0),
+            (Error::ExitWithExitCode(1), 1),
+            (Error::ExitWithExitCode(2), 2),
+            (Error::ExitWithExitCode(125), 125),
+            (Error::ExitWithExitCode(300), 300),
+            (Error::ExitWithSignal(9, false), 137),
+            (Error::ExitWithSignal(11, true), 139),
+            (Error::InCommand(2, None, "make".into(), Box::new(Error::ExitWithSignal(2, false))), 130),
+            (exec(NotFound), 127),
+            (Error::MissingContainerEngine("podman".into(), "make".into()), 127),
+            (exec(PermissionDenied), 126),
+            (exec(InvalidData), 126),
+            (Error::NotFound(".".into(), Vec::new(), SearchEnd::Root), 125),
+            (Error::NoCommands, 125),
+            (Error::EmptyEntry, 125),
+            (Error::IoFailed(PermissionDenied.into()), 125),
+            (Error::MissingDir("build".into(), "make".into()), 125),
+            (Error::MissingArg(1, "git tag".into()), 125),
+            (Error::MissingEnv(vec!["CC".into()]), 125),
+            (Error::LockTimeout("x".into(), "y".into()), 125),
+            (Error::UnknownCommand("4".into()), 125),
+        ] {
+            assert_eq!(e.exit_status(), status, "{:?}", e);
+        }
+    }
+
+    #[test]
+    fn test_signal_display() {
+        let unix = cfg!(target_family = "unix");
+        let expected = |s: &str, name: &str| if unix { format!("{} ({})", s, name) } else { s.to_string() };
+        assert_eq!(Error::ExitWithSignal(11, false).to_string(),
+                   expected("Process exitted with signal: 11", "SIGSEGV"));
+        assert_eq!(Error::ExitWithSignal(6, true).to_string(),
+                   expected("Process exitted with signal: 6", "SIGABRT, core dumped"));
+        assert_eq!(Error::ExitWithSignal(9, false).to_string(),
+                   expected("Process exitted with signal: 9", "SIGKILL"));
+        assert_eq!(Error::ExitWithSignal(99, true).to_string(), "Process exitted with signal: 99 (core dumped)");
+        assert_eq!(Error::ExitWithSignal(99, false).to_string(), "Process exitted with signal: 99");
+        if cfg!(target_os = "linux") {
+            assert_eq!(Error::ExitWithSignal(7, false).to_string(), "Process exitted with signal: 7 (SIGBUS)");
+        }
+    }
+
+    #[test]
+    fn test_context() {
+        let exec = |argv: &[&str]| Error::FailedToExec(argv.iter().map(|a| a.to_string()).collect(), "/src/build".into(),
+                                                       std::io::ErrorKind::PermissionDenied.into(), vec!["n".into()]);
+        assert_eq!(exec(&["./configure"]).to_string(),
+                   "Failed to exec './configure' in /src/build: permission denied\n  note: n");
+        assert_eq!(exec(&["./configure", "--prefix=/opt/x y"]).to_string(),
+                   "Failed to exec './configure' in /src/build: permission denied\n  command: ./configure '--prefix=/opt/x y'\n  note: n");
+
+        let outfile = |cmd: &str| Error::UnableToReadOutfile("log.txt".into(), cmd.into(), std::io::ErrorKind::NotFound.into());
+        assert_eq!(outfile("").to_string(), "Unable to read @outfile=log.txt: entity not found");
+        assert_eq!(outfile("uv4 -b x").to_string(), "Unable to read @outfile=log.txt of uv4 -b x: entity not found");
+        let e = Error::NoOutfileMatch("logs/*.log".into(), "build".into(), "uv4 -b x".into());
+        assert_eq!(e.to_string(), "No file in build matches @outfile=logs/*.log after uv4 -b x");
+        assert_eq!(e.kind(), ErrorKind::Io);
+        let e = Error::UnsetCapture("VERSION".into(), "git tag '${VERSION}'".into());
+        assert_eq!(e.to_string(), "${VERSION} is used before a command with @capture=VERSION has run, unable to run: git tag '${VERSION}'");
+        assert_eq!(e.kind(), ErrorKind::Exec);
+        let e = Error::UnconnectedPipe("gen --all".into());
+        assert_eq!(e.to_string(), "@pipe needs the command after it to run too, to feed into: gen --all");
+        assert_eq!(e.kind(), ErrorKind::Parse);
+        let e = Error::EmptyValue("@cd".into(), "@cd=  ".into());
+        assert_eq!(e.to_string(), "@cd needs a value that isn't empty or only spaces: @cd=  ");
+        assert_eq!(e.kind(), ErrorKind::Parse);
+
+        let failed = Error::InCommand(3, Some("cross".into()), "make".into(), Box::new(Error::ExitWithExitCode(2)));
+        assert_eq!(failed.to_string(), "while running [3: make cross]: Process exitted with code: 2");
+        assert_eq!(failed.inner(), &Error::ExitWithExitCode(2));
+        assert_eq!(failed.kind(), ErrorKind::ChildFailed);
+        assert_eq!(failed.exit_code(), Some(2));
+        assert_eq!(failed.exit_status(), 2);
+        assert!(std::error::Error::source(&failed).is_some());
+
+        let killed = Error::InCommand(1, None, "make".into(), Box::new(Error::ExitWithSignal(9, false)));
+        assert_eq!(killed.to_string(), format!("while running [1: make]: {}", Error::ExitWithSignal(9, false)));
+        assert_eq!(killed.signal(), Some(9));
+        assert_eq!(killed.exit_code(), None);
+        assert_eq!(killed.exit_status(), 137);
+    }
+
+    #[test]
+    fn test_not_found_display() {
+        let dirs = |n: usize| (0..n).map(|i| std::path::PathBuf::from(format!("/d{}", i))).collect::<Vec<_>>();
+        assert_eq!(Error::NotFound(".".into(), dirs(2), SearchEnd::Repository).to_string(),
+                   "Unable to locate .upbuild from '.'\n  searched: /d0, /d1\n  stopped: reached the top of the repository (see --ub-no-vcs-boundary)");
+        assert_eq!(Error::NotFound("src".into(), dirs(8), SearchEnd::Root).to_string(),
+                   "Unable to locate .upbuild from 'src'\n  searched: /d0, /d1, /d2, /d3, /d4 ... and 3 more\n  stopped: reached the root of the filesystem");
+        assert_eq!(Error::NotFound(".".into(), Vec::new(), SearchEnd::MaxDepth).to_string(),
+                   "Unable to locate .upbuild from '.'\n  stopped: gave up after 128 directories");
+
+        let e = Error::NoParentFile("upbuild".into(), Box::new(Error::NotFound("..".into(), Vec::new(), SearchEnd::Root)));
+        assert_eq!(e.to_string(), "No .upbuild above this one for upbuild to run, give it a @cd: Unable to locate .upbuild from '..'\n  stopped: reached the root of the filesystem");
+        assert_eq!(e.kind(), ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn test_nothing_selected_display() {
+        let e = Error::NothingSelected(vec!["docs".into(), "release".into()], vec!["host".into()]);
+        assert_eq!(e.to_string(), "No commands matched selection {docs,release} with rejection {host}");
+        assert_eq!(e.kind(), ErrorKind::NotFound);
+        assert_eq!(e.exit_status(), 125);
+        assert_eq!(Error::NothingSelected(Vec::new(), vec!["host".into()]).to_string(), "No commands matched selection {} with rejection {host}");
+        assert_eq!(Error::NothingSelected(Vec::new(), Vec::new()).to_string(), "No commands to run, every command is @disable or @manual");
+        assert_eq!(Error::NothingMatched("host & !slow".into()).to_string(), "No commands matched --ub-tags=host & !slow");
+    }
+
+    #[test]
+    fn test_invalid_tag_expression_display() {
+        let e = Error::InvalidTagExpression("host & | slow".into(), 7, "expected a tag, ! or (".into());
+        assert_eq!(e.to_string(), "Invalid --ub-tags expression:\n  host & | slow\n         ^ expected a tag, ! or (");
+        assert_eq!(e.kind(), ErrorKind::Parse);
+        assert_eq!(e.exit_status(), 125);
+
+        let e = Error::InvalidScanPattern("--ub-scan-error=(".into(), "unclosed group".into());
+        assert_eq!(e.to_string(), "Invalid --ub-scan-error=(: unclosed group");
+        assert_eq!(e.kind(), ErrorKind::Parse);
+    }
+
+    #[test]
+    fn test_eq() {
+        use std::io::ErrorKind::{NotFound, PermissionDenied};
+        assert_eq!(Error::ExitWithExitCode(1), Error::ExitWithExitCode(1));
+        assert_ne!(Error::ExitWithExitCode(1), Error::ExitWithExitCode(2));
+        assert_ne!(Error::ExitWithExitCode(1), Error::ExitWithSignal(1, false));
+        assert_eq!(Error::MissingArg(1, "a".into()), Error::MissingArg(1, "a".into()));
+        assert_ne!(Error::MissingArg(1, "a".into()), Error::MissingArg(1, "b".into()));
+
+        // io errors by kind, whatever the message
+        assert_eq!(Error::IoFailed(std::io::Error::new(NotFound, "a")), Error::IoFailed(NotFound.into()));
+        assert_ne!(Error::IoFailed(NotFound.into()), Error::IoFailed(PermissionDenied.into()));
+        assert_eq!(Error::UnableToCreateDir("d".into(), PermissionDenied.into()),
+                   Error::UnableToCreateDir("d".into(), PermissionDenied.into()));
+        assert_ne!(Error::UnableToCreateDir("d".into(), PermissionDenied.into()),
+                   Error::UnableToCreateDir("e".into(), PermissionDenied.into()));
+    }
+}
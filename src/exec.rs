@@ -2,12 +2,45 @@
 // (C) Copyright 2024 Greg Whiteley
 
 use super::{Error, Result, Config};
-use super::file::ClassicFile;
+use super::file::{ClassicFile, Cmd};
+use super::shell;
+use super::scan::Scanner;
+use super::output;
+use super::umask;
+use super::signals::{self, Forward, Interrupts};
+use super::cfg::{self, Color, FILE_ENV, LEVEL_ENV};
+use super::find::find_from;
+
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::{Component, Path, PathBuf};
+use std::io::{Read, Write};
+use std::process::{ChildStdout, Command, ExitStatus, Stdio};
+
+/// Return code of a command
+pub type RetCode = isize;
 
-use std::path::{Path, PathBuf};
-use std::process::Command;
+// Values captured by `@capture`, by name - None until the command
+// capturing it has run
+type Captures = BTreeMap<String, Option<String>>;
+
+// What a file's commands leave for the commands after them
+struct Progress {
+    // where the last command ran, so a change can be announced
+    last_dir: Option<PathBuf>,
+    captured: Captures,
+    // a command's failure, held to see if a fallback follows it
+    failed: Option<Error>,
+}
 
-pub type RetCode = isize;
+// The directory announced as entered, and how each directory given has
+// been announced during the run - resolved once, until an @mkdir may
+// have created it
+#[derive(Default)]
+struct Entered {
+    dir: Option<PathBuf>,
+    announced: HashMap<PathBuf, PathBuf>,
+}
 
 /// Create a normal runner for [`Exec`] that actually runs the commands
 pub fn process_runner() -> Box<dyn Runner> {
@@ -16,7 +49,18 @@ pub fn process_runner() -> Box<dyn Runner> {
 
 /// Create a runner for [`Exec`] that just prints the commands
 pub fn print_runner() -> Box<dyn Runner> {
-   Box::new(PrintRunner {})
+   Box::new(PrintRunner { null: false })
+}
+
+/// Create a runner for [`Exec`] that prints the commands for scripts,
+/// as `--ub-print-null`: each argument ends in a NUL, and each command
+/// in another.  Commands run elsewhere are preceded by a `cd` record
+/// for the directory, `@mkdir` directories by a `mkdir` record,
+/// `@umask` by a `umask` record and `@retmap` by a `retmap` record,
+/// `@outfile` is followed by an `outfile` record, and the commands of a
+/// `@pipe` are separated by a `|` record.
+pub fn print_null_runner() -> Box<dyn Runner> {
+   Box::new(PrintRunner { null: true })
 }
 
 /// The Exec struct implements the actual iteration through the
@@ -24,909 +68,4145 @@ pub fn print_runner() -> Box<dyn Runner> {
 /// applying arguments and tags.
 pub struct Exec {
     runner: Box<dyn Runner>,
+    hooks: Box<dyn ExecHooks>,
 }
 
-pub trait Runner {
-    /// Run a given command in the provided directory
-    fn run(&self, cmd: Vec<String>, cd: &Option<PathBuf>) -> Result<RetCode>;
-
-    /// Create given directory if it doesn't exist
-    fn check_mkdir(&self, d: &Path) -> Result<()>;
+/// Settings for running a single command via [`Runner::run`]
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct RunSettings {
+    /// Additional environment variables to set
+    pub env: Vec<(String, String)>,
+    /// Run in a separate session (unix) or process group (windows) so
+    /// signals sent to the terminal's foreground group don't reach it
+    pub detach: bool,
+    /// Where the command's stdin comes from
+    pub stdin: StdinSource,
+    /// Report how the command is run, see `--ub-trace`
+    pub trace: bool,
+    /// How deep in recursive calls the upbuild running the command is,
+    /// to prefix its own messages - see [`Config::level`]
+    pub level: usize,
+    /// Replace the current process with the command where supported,
+    /// see `@exec`
+    pub exec: bool,
+    /// File mode creation mask to run with, see `@umask` - ignored on
+    /// Windows
+    pub umask: Option<u32>,
+    /// Exit codes mapped to others once the command has run, see
+    /// `@retmap`, in order.  [`Exec`] applies them, runners need only
+    /// show them.
+    pub retmap: Vec<(RetCode, RetCode)>,
+}
 
-    /// Display output from a file defined by @outfile
-    fn display_output(&self, file: &Path) -> Result<()>;
+/// Source of a command's stdin, see `@stdin=` and `--ub-batch`
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub enum StdinSource {
+    /// Share upbuild's stdin
+    #[default]
+    Inherit,
+    /// Connect to the null device so reads fail immediately
+    Null,
+    /// Read from the given file, relative to the run directory
+    File(PathBuf),
+}
 
-    /// Output additional data
-    fn display(&self, s: &str);
+/// Settings for displaying an `@outfile` via [`Runner::display_output`]
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct OutputSettings {
+    /// Stop after (roughly) this many bytes, see `--ub-outfile-limit=`
+    pub limit: Option<u64>,
+    /// Remove ANSI escape sequences, see `--ub-color=`
+    pub strip_ansi: bool,
 }
 
-impl Exec {
+/// A selected command resolved by [`Exec::plan`], ready to be run
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PlannedCommand {
+    /// Position of the command in the `.upbuild` file, from 0
+    pub index: usize,
+    /// The command's `@name`, if any
+    pub name: Option<String>,
+    /// The command line after applying provided args, as passed to
+    /// [`ExecHooks::before_command`]
+    pub args: Vec<String>,
+    /// The command line actually run - `args` wrapped to run via the
+    /// container engine for `@container` or via ssh for `@ssh`
+    pub argv: Vec<String>,
+    /// Directory to run in, None for the current directory
+    pub run_dir: Option<PathBuf>,
+    /// Whether `run_dir` comes from a local `@cd` and so should be
+    /// checked before running
+    pub check_dir: bool,
+    /// Directory to create first, see `@mkdir`
+    pub mkdir: Option<PathBuf>,
+    /// Program that must be found to run the command, such as the
+    /// container engine for `@container`
+    pub requires: Option<String>,
+    /// Environment variables that must be set to run the command, and
+    /// whether each may be empty, see `@env-required`
+    pub env_required: Vec<(String, bool)>,
+    /// Settings to run the command with, including environment
+    pub settings: RunSettings,
+    /// Return codes to map, see `@retmap`
+    pub retmap: HashMap<RetCode, RetCode>,
+    /// File to display after the command succeeds, see `@outfile`.
+    /// A pattern is left as written, to be matched once the command
+    /// has run.
+    pub outfile: Option<PathBuf>,
+    /// Whether to display every file an `outfile` pattern matches,
+    /// see `@outfile-all`
+    pub outfile_all: bool,
+    /// Settings to display `outfile` with
+    pub output: OutputSettings,
+    /// Whether to scan `outfile` for warnings and errors
+    pub scan: bool,
+    /// Variable to capture stdout into for later commands, see
+    /// `@capture`
+    pub capture: Option<String>,
+    /// Whether captured output isn't shown, see `@silent`
+    pub silent: bool,
+    /// Whether stdout feeds the next command's stdin, see `@pipe`
+    pub pipe: bool,
+    /// Whether the command only runs if the one before it failed, as
+    /// it follows a `||`
+    pub fallback: bool,
+}
 
-    /// Create a new executor with the given Runner as environment
-    pub fn new(runner: Box<dyn Runner>) -> Self {
-        Self { runner }
+impl PlannedCommand {
+    /// The return code after applying `@retmap`
+    pub fn map_code(&self, c: RetCode) -> RetCode {
+        *self.retmap.get(&c).unwrap_or(&c)
     }
+}
 
-    fn relative_dir(path: &Path) -> Option<PathBuf> {
-        if let Some(parent) = path.parent() {
-            if parent == Path::new(".") || parent == Path::new("") {
-                return None;
-            }
-            return Some(parent.into())
-        }
-        None
+/// A command in a pipeline given to [`Runner::run_pipeline`] - its
+/// arguments, directory and settings as for [`Runner::run`]
+pub type Stage<'a> = (&'a [String], Option<&'a Path>, &'a RunSettings);
+
+/// The environment [`Exec`] uses to run commands and display output.
+/// Runners must be `Send + Sync` so an [`Exec`] can be moved to, or
+/// shared between, threads.
+pub trait Runner: Send + Sync {
+    /// Run a given command in the provided directory, with the
+    /// given settings applied
+    fn run(&self, cmd: &[String], cd: Option<&Path>, settings: &RunSettings) -> Result<RetCode>;
+
+    /// Run a command as [`Runner::run`], also returning its stdout,
+    /// which is shown as it runs unless `silent`, see `@capture`.
+    /// Runners that can't capture output run it as usual and return
+    /// nothing.
+    fn run_captured(&self, cmd: &[String], cd: Option<&Path>, settings: &RunSettings, _silent: bool) -> Result<(RetCode, Vec<u8>)> {
+        Ok((self.run(cmd, cd, settings)?, Vec::new()))
     }
 
-    // Show entering message
-    fn show_entering(&self, working_dir: &Option<PathBuf>) {
-        if let Some(ref d) = working_dir {
-            let dd = d.canonicalize(); // full path
-            let dir = dd.as_ref().unwrap_or(d); // or fallback to d
-            self.runner.display(format!("upbuild: Entering directory `{}'", dir.display()).as_str());
-        }
+    /// Run commands with each one's stdout feeding the next one's
+    /// stdin, see `@pipe`, returning the exit code of each.  Runners
+    /// that can't connect commands run them in turn.
+    fn run_pipeline(&self, cmds: &[Stage<'_>]) -> Result<Vec<RetCode>> {
+        cmds.iter().map(|(cmd, cd, settings)| self.run(cmd, *cd, settings)).collect()
     }
 
-    fn show_entering_always(&self, working_dir: &Option<PathBuf>) {
-        if working_dir.is_none() {
-            let dot = Some(PathBuf::from("."));
-            return self.show_entering(&dot);
-        }
-        self.show_entering(working_dir)
-    }
+    /// Create given directory if it doesn't exist
+    fn check_mkdir(&self, d: &Path) -> Result<()>;
 
-    fn run_dir(main_working_dir: &Option<PathBuf>, cmd_dir: Option<PathBuf>) -> Option<PathBuf> {
-        match cmd_dir {
-            Some(d) => {
-                match main_working_dir {
-                    Some(m) => Some(m.join(d)), // join squashes LHS if RHS is absolute
-                    None => Some(d),
-                }
-            },
-            None => main_working_dir.clone() // TODO clones
-        }
+    /// Create given directory if it doesn't exist, with the
+    /// permissions the command's `@umask` would give it - by default
+    /// as [`Runner::check_mkdir`], ignoring the mask
+    fn check_mkdir_umask(&self, d: &Path, _umask: u32) -> Result<()> {
+        self.check_mkdir(d)
     }
 
-    /// Run the given classic file, args, and config
-    pub fn run(&self, path: &Path, file: &ClassicFile, cfg: &Config, provided_args: &[String]) -> Result<()> {
-        let main_working_dir = Exec::relative_dir(path);
-        self.show_entering(&main_working_dir);
-
-        let mut last_dir = main_working_dir.clone(); // TODO clones
-
-        let argv0 = &cfg.argv0;
-        for cmd in &file.commands {
-            if ! cmd.enabled_with_reject(&cfg.select, &cfg.reject) {
-                continue;
-            }
-            let args = Self::with_args(cmd.args(), provided_args,
-                                       if cmd.recurse() {
-                                           Some(argv0)
-                                       } else {
-                                           None
-                                       }
-            );
-
-            let mk_dir = cmd.mk_dir();
-            if mk_dir.is_some() {
-                if let Some(d) = Self::run_dir(&main_working_dir, mk_dir) {
-                    if let Err(x) = self.runner.check_mkdir(&d) {
-                        eprintln!("Failed to create directory {}: {}", d.display(), x)
-                    }
-                }
-            }
+    /// Check the directory a command will be run from exists
+    fn is_dir(&self, d: &Path) -> bool {
+        d.is_dir()
+    }
 
-            let cmd_dir = cmd.directory();
-            let run_dir = Self::run_dir(&main_working_dir, cmd_dir);
+    /// Resolve a directory to the absolute path announced on entering
+    /// it - called once per directory during a run
+    fn canonicalize(&self, d: &Path) -> std::io::Result<PathBuf> {
+        d.canonicalize()
+    }
 
-            if run_dir != last_dir {
-                self.show_entering_always(&run_dir); // after initial cd always show any change
-                last_dir.clone_from(&run_dir); // TODO clones
-            }
+    /// Check a program needed to run a command, such as the
+    /// container engine for `@container`, can be found
+    fn has_program(&self, name: &str) -> bool {
+        on_path(name)
+    }
 
-            let code = self.runner.run(args, &run_dir)?;
-            let c = cmd.map_code(code);
-            if c != 0 {
-                return Err(Error::ExitWithExitCode(c));
-            }
+    /// Check an environment variable needed by `@env-required` is
+    /// set, and unless `allow_empty` isn't empty
+    fn has_env(&self, name: &str, allow_empty: bool) -> bool {
+        std::env::var_os(name).map_or(false, |v| allow_empty || !v.is_empty())
+    }
 
-            if let Some(outfile) = cmd.out_file() {
-                self.runner.display_output(outfile.as_path())?;
-            }
-        }
+    /// Display output from a file defined by @outfile, with the
+    /// given settings applied
+    fn display_output(&self, file: &Path, settings: &OutputSettings) -> Result<()>;
 
-        Ok(())
+    /// Find the files an `@outfile` pattern such as `logs/*.log`
+    /// matches once the command has run in the given directory, most
+    /// recently modified first
+    fn find_outfiles(&self, pattern: &Path, cd: Option<&Path>) -> Vec<PathBuf> {
+        output::find_matches(pattern, cd)
     }
 
-    fn with_args(args: &[String], provided_args: &[String], argv0: Option<&String>) -> Vec<String> {
+    /// Output additional data
+    fn display(&self, s: &str);
 
-        let skip = if argv0.is_some() { 1 } else { 0 };
+    /// Output a `--ub-trace` line for a command about to be run
+    fn trace(&self, s: &str) {
+        eprintln!("{}", s)
+    }
+}
 
-        if provided_args.is_empty() {
+/// Callbacks made by [`Exec::run`] to allow library users to observe
+/// (and veto) the commands being run.  All methods default to doing
+/// nothing.
+pub trait ExecHooks: Send + Sync {
+    /// Called at the start of the run with the path of the `.upbuild` file
+    fn run_start(&self, _path: &Path) {}
+
+    /// Called before each selected command is run with the resolved
+    /// arguments and directory.  Return false to skip the command.
+    fn before_command(&self, _args: &[String], _dir: Option<&Path>) -> bool {
+        true
+    }
 
-            let mut first_separator = true;
-            return argv0.into_iter()
-                .chain(args.iter().skip(skip))
-                .filter(|x| {
-                    if first_separator && x == &"--" {
-                        first_separator = false;
-                        return false;
-                    }
-                    true
-                })
-                .map(String::from)
-                .collect();
-        }
+    /// Called after each command completes with the exit code (after
+    /// `@retmap` is applied) and how long it took
+    fn after_command(&self, _code: RetCode, _duration: std::time::Duration) {}
 
-        argv0.into_iter()
-            .chain(args.iter().skip(skip))
-            .take_while(|x| x != &"--")
-            .map(String::from)
-            .chain(provided_args.iter().cloned())
-            .collect()
-    }
+    /// Called instead of `after_command` when a command allowed by
+    /// `before_command` couldn't be run to completion - it failed to
+    /// start, or was killed by a signal - with the error and how long
+    /// it took
+    fn after_failure(&self, _error: &Error, _duration: std::time::Duration) {}
 
+    /// Called at the end of the run with the overall result
+    fn run_end(&self, _result: &Result<()>) {}
 }
 
-fn display_output(file: &Path) -> Result<()> {
-    std::fs::File::open(file)
-        .and_then(|mut f| std::io::copy(&mut f, &mut std::io::stdout().lock()))
-        .map_err(|e| Error::UnableToReadOutfile(file.display().to_string(), e))?;
-    Ok(())
+struct NoHooks {
 }
 
-#[derive(Default)]
-struct ProcessRunner {
+impl ExecHooks for NoHooks {
 }
 
-impl Runner for ProcessRunner {
-    fn run(&self, cmd: Vec<String>, cd: &Option<PathBuf>) -> Result<RetCode> {
-
-        if let Some((command, args)) = cmd.split_first() {
-            let mut exec = Command::new(command);
-
-            // On windows std::process::Command evaluates the
-            // executable _before_ the `current_dir()` is applied
-            if cfg!(windows) {
-                let bin = Path::new(command);
-                if bin.is_relative() && cd.is_some() {
-                    let base = cd.as_ref().unwrap();
-                    let cmd_path = base.as_path().join(command);
-
-                    // bin.is_relative() finds non-path prefixed
-                    // commands ie "hello" is non-path prefixed.  So
-                    // drop case where file-name is the entire file.
-                    // EXCEPT - that means dropping the case where we
-                    // @cd to a directory, then run locally.
-                    //
-                    // So replicate DOS behaviour manually and resolve
-                    // to the exe if it exists in the @cd dir.
-
-                    if Some(bin.as_os_str()) != bin.file_name() ||
-                        cmd_path.exists() {
-                        exec = Command::new(cmd_path);
-                    }
-                }
-            }
-            exec.args(args);
-
-            // TODO - was .inspect(), but not available in 1.63
-            if let Some(ref d) = cd.as_ref() {
-                exec.current_dir(d);
-            }
+/// Combine several hooks - each is called in turn, and a command is
+/// skipped if any of them vetoes it
+impl ExecHooks for Vec<Box<dyn ExecHooks>> {
+    fn run_start(&self, path: &Path) {
+        self.iter().for_each(|h| h.run_start(path))
+    }
 
-            let result = exec.status()
-                .map_err(Error::FailedToExec)?;
+    fn before_command(&self, args: &[String], dir: Option<&Path>) -> bool {
+        self.iter().fold(true, |run, h| h.before_command(args, dir) && run)
+    }
 
-            match result.code() {
-                Some(c) => {
-                    Ok(RetCode::try_from(c).expect("isize couldn't contain i32"))
-                },
-                None => Err(Self::no_result_code(result))
-            }
+    fn after_command(&self, code: RetCode, duration: std::time::Duration) {
+        self.iter().for_each(|h| h.after_command(code, duration))
+    }
 
-        } else {
-            Err(Error::EmptyEntry)
-        }
+    fn after_failure(&self, error: &Error, duration: std::time::Duration) {
+        self.iter().for_each(|h| h.after_failure(error, duration))
     }
 
-    fn display_output(&self, file: &Path) -> Result<()> {
-        display_output(file)
+    fn run_end(&self, result: &Result<()>) {
+        self.iter().for_each(|h| h.run_end(result))
     }
+}
 
-    fn display(&self, s: &str) {
-        println!("{}", s)
+/// Share hooks, so what they saw can be read once the run is over
+impl<T: ExecHooks + ?Sized> ExecHooks for std::sync::Arc<T> {
+    fn run_start(&self, path: &Path) {
+        self.as_ref().run_start(path)
     }
 
-    fn check_mkdir(&self, d: &Path) -> Result<()> {
-        if d.is_dir() {
-            return Ok(());
-        }
-        std::fs::create_dir_all(d).map_err(Error::IoFailed)
+    fn before_command(&self, args: &[String], dir: Option<&Path>) -> bool {
+        self.as_ref().before_command(args, dir)
     }
 
-}
+    fn after_command(&self, code: RetCode, duration: std::time::Duration) {
+        self.as_ref().after_command(code, duration)
+    }
 
-impl ProcessRunner {
-    #[cfg(target_family = "unix")]
-    fn no_result_code(result: std::process::ExitStatus) -> Error {
-        use std::os::unix::process::ExitStatusExt;
-        Error::ExitWithSignal(result.signal().unwrap().try_into().unwrap())
+    fn after_failure(&self, error: &Error, duration: std::time::Duration) {
+        self.as_ref().after_failure(error, duration)
     }
 
-    #[cfg(not(target_family = "unix"))]
-    fn no_result_code(_result: std::process::ExitStatus) -> Error {
-        Error::ExitWithSignal(127)
+    fn run_end(&self, result: &Result<()>) {
+        self.as_ref().run_end(result)
     }
 }
 
-struct PrintRunner {
-}
+impl Exec {
 
-impl Runner for PrintRunner {
-    fn run(&self, cmd: Vec<String>, _cd: &Option<PathBuf>) -> Result<RetCode> {
-        println!("{}", cmd.join(" "));
-        Ok(0)
+    /// Create a new executor with the given Runner as environment
+    pub fn new(runner: Box<dyn Runner>) -> Self {
+        Self::with_hooks(runner, Box::new(NoHooks {}))
     }
 
-    fn check_mkdir(&self, d: &Path) -> Result<()> {
-        println!("Checking existence of directory {}", d.display());
-        Ok(())
+    /// Create a new executor with the given Runner as environment,
+    /// calling the given hooks as commands are run
+    pub fn with_hooks(runner: Box<dyn Runner>, hooks: Box<dyn ExecHooks>) -> Self {
+        Self { runner, hooks }
+    }
+
+    fn relative_dir(path: &Path) -> Option<&Path> {
+        if let Some(parent) = path.parent() {
+            if parent == Path::new(".") || parent == Path::new("") {
+                return None;
+            }
+            return Some(parent)
+        }
+        None
     }
 
-    fn display_output(&self, file: &Path) -> Result<()> {
-        display_output(file)
+    // Show entering message, leaving any previously entered directory
+    fn show_entering(&self, cfg: &Config, working_dir: Option<&Path>, entered: &mut Entered) {
+        if let Some(d) = working_dir {
+            self.show_leaving(cfg, entered);
+            let dir = match entered.announced.get(d) {
+                Some(dir) => dir.clone(),
+                None => {
+                    let dir = Self::announced_dir_with(d, |p| self.runner.canonicalize(p));
+                    entered.announced.insert(d.to_path_buf(), dir.clone());
+                    dir
+                },
+            };
+            self.runner.display(format!("{}: Entering directory `{}'", cfg.prefix(), dir.display()).as_str());
+            entered.dir = Some(dir);
+        }
     }
 
-    fn display(&self, _s: &str) {
-        // PrintRunner doesn't show the commentary
+    fn announced_dir(d: &Path) -> PathBuf {
+        Self::announced_dir_with(d, Path::canonicalize)
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use std::{cell::{RefCell, RefMut}, collections::{HashSet, VecDeque}, rc::Rc};
+    // The absolute path announced for a directory.  Existing
+    // directories are canonicalized, for those that don't exist (yet)
+    // the nearest existing ancestor is canonicalized and the remainder
+    // appended, so the same directory is always announced the same way.
+    fn announced_dir_with<F>(d: &Path, canonicalize: F) -> PathBuf
+    where
+        F: Fn(&Path) -> std::io::Result<PathBuf>
+    {
+        if let Ok(dir) = canonicalize(d) {
+            return simplified(dir);
+        }
 
-    use super::*;
+        let absolute = std::env::current_dir()
+            .map(|cwd| cwd.join(d)) // join squashes LHS if RHS is absolute
+            .unwrap_or_else(|_| d.to_path_buf());
+
+        let mut normalized = PathBuf::new();
+        for c in absolute.components() {
+            match c {
+                Component::CurDir => {},
+                Component::ParentDir => { normalized.pop(); },
+                _ => normalized.push(c),
+            }
+        }
 
-    #[derive(Default, Debug, Clone)]
-    struct RunData {
-        cmd: Vec<String>,
-        cd: Option<PathBuf>,
+        let mut head = normalized.as_path();
+        let mut tail = Vec::new();
+        while let (Some(parent), Some(name)) = (head.parent(), head.file_name()) {
+            tail.push(name);
+            head = parent;
+            if let Ok(mut dir) = canonicalize(head) {
+                dir.extend(tail.iter().rev());
+                return simplified(dir);
+            }
+        }
+        normalized
     }
 
-    #[derive(Default, Debug)]
-    struct TestData {
-        run_data: VecDeque<RunData>,
-        outfile: VecDeque<PathBuf>,
-        display: VecDeque<String>,
-        result: VecDeque<Result<RetCode>>,
-        mkdir: VecDeque<PathBuf>,
+    fn show_entering_always(&self, cfg: &Config, working_dir: Option<&Path>, entered: &mut Entered) {
+        self.show_entering(cfg, working_dir.or_else(|| Some(Path::new("."))), entered)
     }
 
-    impl TestData {
-        fn clear(&mut self) {
-            self.run_data.clear();
-            self.outfile.clear();
-            self.display.clear();
-            self.result.clear();
-            self.mkdir.clear();
+    // Show leaving message for the last entered directory
+    fn show_leaving(&self, cfg: &Config, entered: &mut Entered) {
+        if let Some(dir) = entered.dir.take() {
+            self.runner.display(format!("{}: Leaving directory `{}'", cfg.prefix(), dir.display()).as_str());
         }
     }
 
-    #[derive(Debug)]
-    struct TestRunner {
-        data: Rc<RefCell<TestData>>
+    // Format the current time-of-day (UTC) as HH:MM:SS
+    fn timestamp() -> String {
+        let secs = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0) % 86400;
+        format!("{:02}:{:02}:{:02}", secs / 3600, (secs / 60) % 60, secs % 60)
     }
 
-    impl TestRunner {
-        fn new(data: Rc<RefCell<TestData>>) -> TestRunner {
-            TestRunner {
-                data
-            }
+    // Format the command as it would be run from the shell
+    fn trace_line(timestamp: &str, args: &[String], run_dir: Option<&Path>) -> String {
+        match run_dir {
+            Some(d) => format!("+ [{}] cd {} && {}", timestamp,
+                               shell::quote(&d.to_string_lossy()), shell::join(args)),
+            None => format!("+ [{}] {}", timestamp, shell::join(args)),
         }
     }
 
-    impl Runner for TestRunner {
-        fn run(&self, cmd: Vec<String>, cd: &Option<PathBuf>) -> Result<RetCode> {
-            let mut data = self.data.borrow_mut();
-            println!("run cmd={:#?} cd={:#?} result={:#?}", cmd, cd, data.result.front());
-            data.run_data.push_back(RunData{cmd, cd: cd.clone()});
-            data.result.pop_front().expect("Result wasn't set")
+    fn run_dir<'a>(main_working_dir: Option<&'a Path>, cmd_dir: Option<&'a Path>) -> Option<Cow<'a, Path>> {
+        match cmd_dir {
+            Some(d) => {
+                match main_working_dir {
+                    Some(m) => Some(Cow::Owned(m.join(d))), // join squashes LHS if RHS is absolute
+                    None => Some(Cow::Borrowed(d)),
+                }
+            },
+            None => main_working_dir.map(Cow::Borrowed)
         }
+    }
 
-        fn display_output(&self, file: &Path) -> Result<()> {
-            let mut data = self.data.borrow_mut();
-            data.outfile.push_back(PathBuf::from(file));
-            Ok(())
+    // Where the command runs locally.  A recursive call without @cd
+    // runs in the directory of the next .upbuild up, not just the
+    // parent, so the levels between don't each start an upbuild only
+    // to search further.
+    fn command_dir<'a>(main_working_dir: Option<&'a Path>, cmd: &'a Cmd, cfg: &Config) -> Result<Option<Cow<'a, Path>>> {
+        // @cd is a remote path for @ssh commands
+        if cmd.ssh().is_some() {
+            return Ok(main_working_dir.map(Cow::Borrowed));
         }
+        let dir = Self::run_dir(main_working_dir, cmd.directory());
+        if !cmd.runs_in_parent_file() {
+            return Ok(dir);
+        }
+        let start = dir.unwrap_or(Cow::Borrowed(Path::new("..")));
+        let found = find_from(&start, cfg).map_err(|e| Error::NoParentFile(shell::join(cmd.args()), Box::new(e)))?;
+        Ok(found.parent().map(|d| Cow::Owned(d.to_path_buf())))
+    }
 
-        fn display(&self, s: &str) {
-            let mut data = self.data.borrow_mut();
-            data.display.push_back(String::from(s));
+    // Wrap args to run in the given container image, with the
+    // .upbuild directory mounted at /work
+    fn container_args(engine: &str, image: &str, base: &Path, dir: &Path, env: &[(String, String)], args: &[String]) -> Vec<String> {
+        let (mount, workdir) = match dir.strip_prefix(base) {
+            Ok(rel) => (base, rel),
+            // outside the tree - mount the run directory itself
+            Err(_) => (dir, Path::new("")),
+        };
+        let mut workdir_path = String::from("/work");
+        for c in workdir.components() {
+            workdir_path.push('/');
+            workdir_path.push_str(&c.as_os_str().to_string_lossy());
         }
 
-        fn check_mkdir(&self, d: &Path) -> Result<()> {
-            let mut data = self.data.borrow_mut();
-            data.mkdir.push_back(PathBuf::from(d));
-            Ok(())
+        let mut wrapped = vec![engine.to_string(), "run".into(), "--rm".into(),
+                               "-v".into(), format!("{}:/work", mount.display()),
+                               "-w".into(), workdir_path];
+        for (k, v) in env {
+            wrapped.push("-e".into());
+            wrapped.push(format!("{}={}", k, v));
         }
+        wrapped.push(image.to_string());
+        wrapped.extend(args.iter().cloned());
+        wrapped
     }
 
-    struct TestRun {
-        test_data: Rc<RefCell<TestData>>,
-        cfg: Config,
+    // Wrap args to run on the given host, changing to the (remote)
+    // directory first
+    fn ssh_args(host: &str, dir: Option<&Path>, args: &[String]) -> Vec<String> {
+        let mut remote = String::new();
+        if let Some(d) = dir {
+            remote = format!("cd {} && ", shell::quote(&d.to_string_lossy()));
+        }
+        remote += &shell::join(args);
+        vec!["ssh".into(), host.to_string(), "--".into(), remote]
     }
 
-    impl TestRun {
-        fn new() -> TestRun {
-            TestRun {
-                test_data: Rc::new(RefCell::new(TestData::default())),
-                cfg: Config::default(),
+    /// Run the given classic file, args, and config
+    pub fn run(&self, path: &Path, file: &ClassicFile, cfg: &Config, provided_args: &[String]) -> Result<()> {
+        self.run_with(path, cfg, |entered, scans| {
+            let count = self.run_commands(path, file, cfg, provided_args, entered, scans)?;
+            Self::check_count(count, cfg)
+        })
+    }
+
+    /// Run each of the given files in turn, nearest first as from
+    /// [`crate::find_all`], stopping at the first failure.  Recursive
+    /// commands are skipped as the files they would run are already
+    /// part of the chain.
+    pub fn run_chain(&self, paths: &[PathBuf], cfg: &Config, provided_args: &[String]) -> Result<()> {
+        let first = match paths.first() {
+            Some(p) => p,
+            None => return Ok(()),
+        };
+        self.run_with(first, cfg, |entered, scans| {
+            let mut count = 0;
+            for path in paths {
+                count += self.run_commands(path, &ClassicFile::load(path)?, cfg, provided_args, entered, scans)?;
             }
-        }
+            Self::check_count(count, cfg)
+        })
+    }
 
-        fn override_argv0<T: Into<String>>(&mut self, a: T) -> &mut Self {
-            self.cfg.argv0 = a.into();
-            self
+    /// Run each of the given files in turn, as from
+    /// [`crate::discover`], each as if run on its own from its
+    /// directory.  All of them run whatever happens, with a line
+    /// reporting how each went, and the first failure is returned.
+    /// Recursive commands are skipped as the files they would run are
+    /// likely to be among those given.
+    pub fn run_each(&self, paths: &[PathBuf], cfg: &Config, provided_args: &[String]) -> Result<()> {
+        // not every project need have the selected commands
+        let cfg = &Config { allow_empty: true, ..cfg.clone() };
+        let mut first_failure = None;
+        for path in paths {
+            let result = ClassicFile::load(path).and_then(|file| self.run(path, &file, cfg, provided_args));
+            match result {
+                Ok(()) => self.runner.display(&format!("{}: {}: ok", cfg.prefix(), path.display())),
+                Err(e) => {
+                    self.runner.display(&format!("{}: {}: failed", cfg.prefix(), path.display()));
+                    first_failure.get_or_insert(e);
+                },
+            }
         }
+        first_failure.map_or(Ok(()), Err)
+    }
 
-        fn select<const N: usize>(&mut self, tags: [&str ;N]) -> &mut Self {
-            self.cfg.select = HashSet::from(tags.map(|x| x.to_string()));
-            self
-        }
+    /// Run commands as they are parsed, see
+    /// [`ClassicFile::stream_lines`], with the given args and config.
+    /// Commands start before the rest of the file has been read, so
+    /// an error later in the file is only reported once the commands
+    /// before it have run.  The rest of the file is still read before
+    /// an `@exec` command replaces upbuild.
+    pub fn run_stream<I>(&self, path: &Path, commands: I, cfg: &Config, provided_args: &[String]) -> Result<()>
+    where
+        I: Iterator<Item=Result<Cmd>>
+    {
+        self.run_with(path, cfg, |entered, scans| {
+            let count = self.stream_commands(path, commands, cfg, provided_args, entered, scans)?;
+            Self::check_count(count, cfg)
+        })
+    }
 
-        fn reject<const N: usize>(&mut self, tags: [&str ;N]) -> &mut Self {
-            self.cfg.reject = HashSet::from(tags.map(|x| x.to_string()));
-            self
+    // Running nothing at all is an error, unless --ub-allow-empty - it's
+    // more likely a mistyped selection than intended.  Recursive calls
+    // are given the whole selection, so their files needn't match it.
+    fn check_count(count: usize, cfg: &Config) -> Result<()> {
+        if count > 0 || cfg.allow_empty || cfg.level > 0 {
+            return Ok(());
         }
-
-        // REVIEW - above calls are mutable, below are not, so you need to chain
-        // them first
-
-        fn add_return_data(&self, result: Result<RetCode>) -> &Self {
-            let mut data: RefMut<'_, _> = self.test_data.borrow_mut();
-            data.result.push_back(result);
-            self
+        if let Some((expr, _)) = &cfg.tags {
+            return Err(Error::NothingMatched(expr.clone()));
         }
+        let sorted = |tags: &HashSet<String>| {
+            let mut tags: Vec<_> = tags.iter().cloned().collect();
+            tags.sort_unstable();
+            tags
+        };
+        Err(Error::NothingSelected(sorted(&cfg.select), sorted(&cfg.reject)))
+    }
 
-        fn run<const N: usize>(&self, file_data: &str, provided_args: [&str; N], expected_result: Result<()>) -> &Self {
-            let provided_args: Vec<String> = provided_args.into_iter().map(String::from).collect();
-            self.run_(file_data, |e,f| e.run(Path::new(".upbuild"), f, &self.cfg, &provided_args), expected_result)
+    // Wrap running commands with the hooks, directory messages and
+    // scan results
+    fn run_with<F>(&self, path: &Path, cfg: &Config, run: F) -> Result<()>
+    where
+        F: FnOnce(&mut Entered, &mut Vec<String>) -> Result<()>
+    {
+        // a bad --ub-tags= or scan pattern is reported before anything
+        // starts
+        cfg.tags()?;
+        Scanner::new(cfg)?;
+        self.hooks.run_start(path);
+        let mut entered = Entered::default();
+        let mut scans = Vec::new();
+        let result = run(&mut entered, &mut scans);
+        self.show_leaving(cfg, &mut entered);
+        for s in scans {
+            self.runner.display(&s);
         }
+        self.hooks.run_end(&result);
+        result
+    }
 
-        fn run_with_path<const N: usize>(&self, path: &str, file_data: &str, provided_args: [&str; N], expected_result: Result<()>) -> &Self {
-            let provided_args: Vec<String> = provided_args.into_iter().map(String::from).collect();
-            self.run_(file_data, |e,f| e.run(Path::new(path), f, &self.cfg, &provided_args), expected_result)
+    // Run the selected commands from the file, returning how many
+    fn run_commands(&self, path: &Path, file: &ClassicFile, cfg: &Config, provided_args: &[String],
+                    entered: &mut Entered, scans: &mut Vec<String>) -> Result<usize> {
+        let main_working_dir = Exec::relative_dir(path);
+        let plan = Self::plan(path, file, cfg, provided_args)?;
+        self.check_env(&plan)?;
+        let scanner = Scanner::new(cfg)?;
+        self.show_entering(cfg, main_working_dir, entered);
+
+        let mut progress = Progress {
+            last_dir: main_working_dir.map(Path::to_path_buf),
+            captured: Self::captures(file.commands()),
+            failed: None,
+        };
+        let count = plan.len();
+        let mut group = Vec::new();
+        for planned in plan {
+            let piped = planned.pipe;
+            group.push(planned);
+            if ! piped {
+                self.run_planned(std::mem::take(&mut group), cfg, &scanner, entered, &mut progress, scans)?;
+            }
         }
+        progress.failed.map_or(Ok(count), Err)
+    }
 
-        fn run_without_args(&self, file_data: &str, expected_result: Result<()>) -> &Self {
-            self.run(file_data, [], expected_result)
+    // Fail before anything runs if any of the commands' @env-required
+    // variables aren't set, naming all of them
+    fn check_env(&self, plan: &[PlannedCommand]) -> Result<()> {
+        let mut missing: Vec<String> = Vec::new();
+        for (name, allow_empty) in plan.iter().flat_map(|p| &p.env_required) {
+            if ! missing.contains(name) && ! self.runner.has_env(name, *allow_empty) {
+                missing.push(name.clone());
+            }
         }
+        if missing.is_empty() {
+            Ok(())
+        } else {
+            Err(Error::MissingEnv(missing))
+        }
+    }
 
-        fn run_<F>(&self, file_data: &str, f: F, expected_result: Result<()>) -> &Self
-        where
-            F: FnOnce(Exec, &ClassicFile) -> Result<()>
-        {
-            let file = ClassicFile::parse_lines(file_data.lines()).unwrap();
-            let runner = Box::new(TestRunner::new(self.test_data.clone()));
-
-            let e = Exec::new(runner);
+    fn stream_commands<I>(&self, path: &Path, commands: I, cfg: &Config, provided_args: &[String],
+                          entered: &mut Entered, scans: &mut Vec<String>) -> Result<usize>
+    where
+        I: Iterator<Item=Result<Cmd>>
+    {
+        let main_working_dir = Exec::relative_dir(path);
+        let strip_ansi = Self::strip_ansi(cfg);
+        let scanner = Scanner::new(cfg)?;
+        // entering is shown with the first command, as run does once planned
+        let mut count = 0;
+
+        let mut progress = Progress {
+            last_dir: main_working_dir.map(Path::to_path_buf),
+            captured: Captures::new(),
+            failed: None,
+        };
+        let mut group: Vec<PlannedCommand> = Vec::new();
+        let mut piped: Option<Cmd> = None;
+        let mut previous_runs = false;
+        let mut commands = commands.enumerate();
+        while let Some((index, cmd)) = commands.next() {
+            let cmd = cmd.map_err(|e| progress.failed.take().unwrap_or(e))?;
+            // a failure stands unless a fallback follows it
+            if ! cmd.fallback() {
+                if let Some(e) = progress.failed.take() {
+                    return Err(e);
+                }
+            }
+            // only what's been read so far is known to be captured
+            progress.captured.extend(Self::captures(std::slice::from_ref(&cmd)));
+            if let Some(p) = piped.take() {
+                Self::check_fed(&p, &cmd, cfg)?;
+            }
+            previous_runs = Self::runs(&cmd, cfg, previous_runs);
+            if ! previous_runs {
+                continue;
+            }
 
-            match expected_result {
-                Ok(_) => { f(e, &file).expect("Should pass"); },
-                Err(err) => {
-                    let ret = f(e, &file).expect_err("Should fail");
-                    if let Error::ExitWithExitCode(exp_c) = err {
-                        match ret {
-                            Error::ExitWithExitCode(c) => {
-                                assert_eq!(c, exp_c);
-                            },
-                            _ => panic!("unmatched exit code {:?}", err)
-                        }
-                    } else if let Error::ExitWithSignal(exp_sig) = err {
-                        match ret {
-                            Error::ExitWithSignal(sig) => {
-                                assert_eq!(sig, exp_sig);
-                            },
-                            _ => panic!("unmatched exit signal {:?}", err)
-                        }
-                    } else {
-                        panic!("handled unexpected error {:?}", err)
+            // nothing can run after an @exec replaces upbuild
+            if cmd.exec() {
+                for (_, rest) in commands.by_ref() {
+                    if Self::selected(&rest?, cfg) {
+                        return Err(Error::ExecNotLast(shell::join(cmd.args())));
                     }
-                },
+                }
             }
 
-            {
-                let data: RefMut<'_, _> = self.test_data.borrow_mut();
-                assert!(data.result.is_empty(), "Didn't exhaust results {:#?}", data.result);
+            let planned = Self::plan_command(main_working_dir, index, &cmd, cfg, provided_args, strip_ansi)?;
+            // without the whole file only this command can be checked
+            self.check_env(std::slice::from_ref(&planned))?;
+            if count == 0 {
+                self.show_entering(cfg, main_working_dir, entered);
+            }
+            count += 1;
+            group.push(planned);
+            if cmd.pipe() {
+                // run once the command it feeds has been read
+                piped = Some(cmd);
+            } else {
+                self.run_planned(std::mem::take(&mut group), cfg, &scanner, entered, &mut progress, scans)?;
             }
-            self
         }
-
-        fn verify_cd_comment(&self, expected: &str) -> &Self {
-            let mut data: RefMut<'_, _> = self.test_data.borrow_mut();
-            let s = data.display.pop_front().expect("Expected results");
-            assert_eq!(s, expected);
-            self
+        if count == 0 {
+            self.show_entering(cfg, main_working_dir, entered);
         }
+        progress.failed.map_or(Ok(count), Err)
+    }
 
-        fn verify_cd_dir<S: AsRef<str>>(&self, dir: S) -> &Self {
-            let expected = format!("upbuild: Entering directory `{}'", dir.as_ref());
-            self.verify_cd_comment(expected.as_str())
+    // Run a command, or a pipeline of them, naming the command in any
+    // error.  The hooks see a pipeline as a single command.
+    fn run_planned(&self, group: Vec<PlannedCommand>, cfg: &Config, scanner: &Scanner,
+                   entered: &mut Entered, progress: &mut Progress, scans: &mut Vec<String>) -> Result<()> {
+        // a fallback runs only in place of the failure before it
+        match (group[0].fallback, progress.failed.take()) {
+            (true, None) => return Ok(()),
+            (false, Some(e)) => return Err(e),
+            _ => (),
         }
 
-        fn verify_return_data<const N: usize>(&self, cmd: [&str; N], cd: Option<PathBuf>) -> &Self {
-            let mut data: RefMut<'_, _> = self.test_data.borrow_mut();
-            let result = data.run_data.pop_front().expect("Expected results");
-            assert_eq!(result.cmd, cmd);
-            assert_eq!(result.cd, cd);
-            self
+        // --ub-print shows the references, as nothing has been captured
+        let group = if cfg.print {
+            group
+        } else {
+            group.into_iter()
+                .map(|planned| {
+                    let named = Self::in_command(&planned);
+                    Self::with_captured(planned, &progress.captured).map_err(named)
+                })
+                .collect::<Result<Vec<_>>>()?
+        };
+        let args = Self::pipeline_args(&group);
+        if ! self.hooks.before_command(&args, group[0].run_dir.as_deref()) {
+            return Ok(());
         }
 
-        fn verify_outfile(&self, expected: &str) -> &Self {
-            let mut data: RefMut<'_, _> = self.test_data.borrow_mut();
-            let outfile = data.outfile.pop_front();
-            assert_eq!(PathBuf::from(expected), outfile.expect("expected outfile"));
-            self
+        // the hooks hear of every command they allowed, even if it fails
+        let start = std::time::Instant::now();
+        let results = match self.run_group(&group, cfg, entered, progress) {
+            Ok(results) => results,
+            Err(e) => {
+                self.hooks.after_failure(&e, start.elapsed());
+                return Err(e);
+            },
+        };
+
+        // any command failing fails the pipeline
+        let codes: Vec<RetCode> = group.iter().zip(&results).map(|(p, (code, _))| p.map_code(*code)).collect();
+        let failed = group.iter().zip(&codes).find(|(_, c)| **c != 0);
+        self.hooks.after_command(failed.map_or(0, |(_, c)| *c), start.elapsed());
+        if let Some((planned, c)) = failed {
+            progress.failed = Some(Self::in_command(planned)(Error::ExitWithExitCode(*c)));
+            return Ok(());
         }
 
-        fn verify_mkdir(&self, expected: &str) -> &Self {
-            let mut data: RefMut<'_, _> = self.test_data.borrow_mut();
-            let outfile = data.mkdir.pop_front();
-            assert_eq!(PathBuf::from(expected), outfile.expect("expected mkdir"));
-            self
+        for (planned, (_, output)) in group.iter().zip(results) {
+            if let (Some(name), false) = (&planned.capture, cfg.print) {
+                progress.captured.insert(name.clone(), Some(Self::captured_value(&output)));
+            }
+            self.show_outfiles(planned, cfg, scanner, scans).map_err(Self::in_command(planned))?;
         }
+        Ok(())
+    }
 
-        fn verify_complete(&self) {
-            let data: RefMut<'_, _> = self.test_data.borrow_mut();
-            assert!(data.run_data.is_empty(), "Didn't exhaust run_data {:#?}", data.run_data);
-            assert!(data.outfile.is_empty(), "Didn't exhaust outfile {:#?}", data.outfile);
-            assert!(data.display.is_empty(), "Didn't exhaust display {:#?}", data.display);
-            assert!(data.result.is_empty());
-            assert!(data.mkdir.is_empty(), "Didn't exhaust mkdir {:#?}", data.mkdir);
+    // Prepare and run a command, or a pipeline of them, returning each
+    // one's exit code and any captured output
+    fn run_group(&self, group: &[PlannedCommand], cfg: &Config, entered: &mut Entered,
+                 progress: &mut Progress) -> Result<Vec<(RetCode, Vec<u8>)>> {
+        for planned in group {
+            self.prepare(planned, cfg, entered, progress).map_err(Self::in_command(planned))?;
         }
 
-        fn done(&self) {
-            self.verify_complete();
-            let mut data: RefMut<'_, _> = self.test_data.borrow_mut();
-            data.clear();
+        match group {
+            [planned] => {
+                let run_dir = planned.run_dir.as_deref();
+                let result = match &planned.capture {
+                    Some(_) if !cfg.print => self.runner.run_captured(&planned.argv, run_dir, &planned.settings, planned.silent),
+                    _ => self.runner.run(&planned.argv, run_dir, &planned.settings).map(|c| (c, Vec::new())),
+                };
+                Ok(vec![result.map_err(Self::in_command(planned))?])
+            },
+            _ => {
+                let stages: Vec<Stage<'_>> = group.iter().map(|p| (p.argv.as_slice(), p.run_dir.as_deref(), &p.settings)).collect();
+                Ok(self.runner.run_pipeline(&stages).map_err(Self::in_command(&group[0]))?
+                   .into_iter().map(|c| (c, Vec::new())).collect())
+            },
         }
     }
 
-    fn args_vec<const N: usize>(provided_args: [&str; N]) -> Vec<String> {
-        provided_args.into_iter().map(String::from).collect()
+    // Wraps an error to name the command it came from
+    fn in_command(planned: &PlannedCommand) -> impl FnOnce(Error) -> Error {
+        let (index, name) = (planned.index + 1, planned.name.clone());
+        let program = planned.args.first().cloned().unwrap_or_default();
+        move |e| Error::InCommand(index, name, program, Box::new(e))
     }
 
-    #[test]
-    fn test_exec_uv4() {
-
-        let file_data = include_str!("../tests/uv4.upbuild");
-        let uv4_run = ["uv4", "-j0", "-b", "project.uvproj", "-o", "log.txt"];
-        TestRun::new()
-            .add_return_data(Ok(0))
-            .run_without_args(file_data, Ok(()))
-            .verify_return_data(uv4_run, None)
-            .verify_outfile("log.txt")
-            .done();
+    // The args of a pipeline joined by |, as one command
+    fn pipeline_args(group: &[PlannedCommand]) -> Cow<'_, [String]> {
+        match group {
+            [planned] => Cow::Borrowed(&planned.args),
+            _ => Cow::Owned(group.iter()
+                            .map(|p| p.args.clone())
+                            .collect::<Vec<_>>()
+                            .join(&String::from("|"))),
+        }
+    }
 
-        // 1 should map to 0
-        TestRun::new()
-            .add_return_data(Ok(1))
-            .run_without_args(file_data, Ok(()))
-            .verify_return_data(uv4_run, None)
-            .verify_outfile("log.txt")
-            .done();
+    // Get ready to run a command - its directories and program are
+    // checked, and any change of directory announced
+    fn prepare(&self, planned: &PlannedCommand, cfg: &Config, entered: &mut Entered, progress: &mut Progress) -> Result<()> {
+        let run_dir = planned.run_dir.as_deref();
+        if let Some(d) = &planned.mkdir {
+            // created as the command would, upbuild's own files aren't
+            let created = match planned.settings.umask {
+                Some(mask) => self.runner.check_mkdir_umask(d, mask),
+                None => self.runner.check_mkdir(d),
+            };
+            match created {
+                // announced from where it now is
+                Ok(()) => entered.announced.clear(),
+                Err(x) if !cfg.ignore_mkdir_errors => return Err(x),
+                Err(x) => eprintln!("{}: {}", cfg.prefix(), x),
+            }
+        }
+
+        // catch a bad @cd here rather than as a confusing failure to exec
+        if let (Some(d), true) = (run_dir, planned.check_dir) {
+            if ! self.runner.is_dir(d) {
+                return Err(Error::MissingDir(d.display().to_string(), shell::join(&planned.args)));
+            }
+        }
+
+        if let Some(program) = &planned.requires {
+            if ! self.runner.has_program(program) {
+                return Err(Error::MissingContainerEngine(program.clone(), shell::join(&planned.args)));
+            }
+        }
+
+        if planned.run_dir != progress.last_dir {
+            self.show_entering_always(cfg, run_dir, entered); // after initial cd always show any change
+            progress.last_dir = planned.run_dir.clone();
+        }
+
+        if cfg.trace {
+            self.runner.trace(&Self::trace_line(&Self::timestamp(), &planned.argv, run_dir));
+        }
+        Ok(())
+    }
+
+    // Display, and maybe scan, the command's @outfile once it has run
+    fn show_outfiles(&self, planned: &PlannedCommand, cfg: &Config, scanner: &Scanner, scans: &mut Vec<String>) -> Result<()> {
+        for outfile in self.outfiles(planned, planned.run_dir.as_deref())? {
+            self.runner.display_output(&outfile, &planned.output).map_err(|e| match e {
+                Error::UnableToReadOutfile(file, _, e) => Error::UnableToReadOutfile(file, shell::join(&planned.argv), e),
+                e => e,
+            })?;
+            if planned.scan {
+                match scanner.scan_file(&outfile) {
+                    Ok(counts) => scans.push(format!("{}: {}: {}", cfg.prefix(), shell::join(&planned.argv), counts)),
+                    Err(e) => eprintln!("{}: Unable to scan @outfile={}: {}", cfg.prefix(), outfile.display(), e),
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // The files to display for the command's @outfile - a pattern is
+    // matched now the command has run, in the directory it ran in
+    fn outfiles(&self, planned: &PlannedCommand, run_dir: Option<&Path>) -> Result<Vec<PathBuf>> {
+        let pattern = match &planned.outfile {
+            Some(f) if output::is_pattern(f) => f,
+            Some(f) => return Ok(vec![f.clone()]),
+            None => return Ok(Vec::new()),
+        };
+        let mut found = self.runner.find_outfiles(pattern, run_dir);
+        if found.is_empty() {
+            let dir = run_dir.unwrap_or_else(|| Path::new(".")).display().to_string();
+            return Err(Error::NoOutfileMatch(pattern.display().to_string(), dir, shell::join(&planned.argv)));
+        }
+        if !planned.outfile_all {
+            found.truncate(1);
+        }
+        Ok(found)
+    }
+
+    // The names the commands capture into, none of them captured yet
+    fn captures(commands: &[Cmd]) -> Captures {
+        commands.iter().filter_map(Cmd::capture).map(|name| (name.to_string(), None)).collect()
+    }
+
+    // Substitute ${NAME} for the values captured so far, and give them
+    // to the command as environment variables.  A name no command
+    // captures is left as it is, for a shell to expand.
+    fn with_captured(mut planned: PlannedCommand, captured: &Captures) -> Result<PlannedCommand> {
+        if captured.is_empty() {
+            return Ok(planned);
+        }
+        let command = shell::join(&planned.args);
+        for arg in planned.args.iter_mut().chain(planned.argv.iter_mut()) {
+            if arg.contains("${") {
+                *arg = Self::expand_captured(arg, captured)
+                    .map_err(|name| Error::UnsetCapture(name, command.clone()))?;
+            }
+        }
+        planned.settings.env.extend(captured.iter().filter_map(|(k, v)| Some((k.clone(), v.clone()?))));
+        Ok(planned)
+    }
+
+    // The arg with captured values substituted, or the name of one
+    // that's used before it's captured
+    fn expand_captured(arg: &str, captured: &Captures) -> std::result::Result<String, String> {
+        let mut out = String::with_capacity(arg.len());
+        let mut rest = arg;
+        while let Some(at) = rest.find("${") {
+            out.push_str(&rest[..at]);
+            rest = &rest[at + 2..];
+            let name = rest.find('}').map(|end| &rest[..end]);
+            match name.map(|n| (n, captured.get(n))) {
+                Some((n, Some(Some(value)))) => {
+                    out.push_str(value);
+                    rest = &rest[n.len() + 1..];
+                },
+                Some((n, Some(None))) => return Err(n.to_string()),
+                _ => out.push_str("${"),
+            }
+        }
+        out.push_str(rest);
+        Ok(out)
+    }
+
+    // Captured output as a value, without the line end
+    fn captured_value(output: &[u8]) -> String {
+        let value = String::from_utf8_lossy(output);
+        let value = value.strip_suffix('\n').unwrap_or(&value);
+        value.strip_suffix('\r').unwrap_or(value).to_string()
+    }
+
+    // Whether the command runs with the config's selection
+    fn selected(cmd: &Cmd, cfg: &Config) -> bool {
+        if (cfg.chain || cfg.discover_run) && cmd.recurse() {
+            debug!("skipping recursion in chain {}", shell::join(cmd.args()));
+            return false;
+        }
+        let enabled = Self::skip_reason(cmd, cfg).is_none();
+        if enabled {
+            debug!("selected {}", shell::join(cmd.args()));
+        } else {
+            debug!("skipping {}", shell::join(cmd.args()));
+        }
+        enabled
+    }
+
+    // Whether the command runs, given whether the one before it did -
+    // a fallback needs that to have run, to fail
+    fn runs(cmd: &Cmd, cfg: &Config, previous_runs: bool) -> bool {
+        (previous_runs || !cmd.fallback()) && Self::selected(cmd, cfg)
+    }
+
+    // Which of the commands run with the config's selection
+    pub(crate) fn running(commands: &[Cmd], cfg: &Config) -> Vec<bool> {
+        let mut previous_runs = false;
+        commands.iter()
+            .map(|cmd| {
+                previous_runs = Self::runs(cmd, cfg, previous_runs);
+                previous_runs
+            })
+            .collect()
+    }
+
+    // Why the command won't run with the config's selection - by
+    // --ub-tags= if given, otherwise --ub-select=/--ub-reject=
+    fn skip_reason(cmd: &Cmd, cfg: &Config) -> Option<&'static str> {
+        match &cfg.tags {
+            Some((_, Ok(expr))) => cmd.skip_reason_by(expr),
+            _ => cmd.skip_reason(&cfg.select, &cfg.reject),
+        }
+    }
+
+    fn strip_ansi(cfg: &Config) -> bool {
+        match cfg.color {
+            Color::Always => false,
+            Color::Never => true,
+            Color::Auto => !output::stdout_is_terminal(),
+        }
+    }
+
+    /// Resolve the commands [`Exec::run`] would run for the given
+    /// classic file, args, and config - after selection by tags,
+    /// with directories resolved and provided args applied - without
+    /// running anything
+    pub fn plan(path: &Path, file: &ClassicFile, cfg: &Config, provided_args: &[String]) -> Result<Vec<PlannedCommand>> {
+        cfg.tags()?;
+        let main_working_dir = Exec::relative_dir(path);
+
+        // nothing can run after an @exec replaces upbuild
+        let running = Self::running(&file.commands, cfg);
+        let enabled: Vec<_> = file.commands.iter()
+            .enumerate()
+            .filter(|(i, _)| running[*i])
+            .collect();
+        if let Some((_, earlier)) = enabled.split_last() {
+            if let Some((_, cmd)) = earlier.iter().find(|(_, cmd)| cmd.exec()) {
+                return Err(Error::ExecNotLast(shell::join(cmd.args())));
+            }
+        }
+
+        for ((piped, fed), runs) in file.commands.iter().zip(file.commands.iter().skip(1)).zip(&running) {
+            if piped.pipe() && *runs {
+                Self::check_fed(piped, fed, cfg)?;
+            }
+        }
+
+        let strip_ansi = Self::strip_ansi(cfg);
+        enabled.into_iter()
+            .map(|(index, cmd)| Self::plan_command(main_working_dir, index, cmd, cfg, provided_args, strip_ansi))
+            .collect()
+    }
+
+    // A command with @pipe needs the next to run too, reading its output
+    fn check_fed(piped: &Cmd, fed: &Cmd, cfg: &Config) -> Result<()> {
+        if ! Self::selected(fed, cfg) {
+            return Err(Error::UnconnectedPipe(shell::join(piped.args())));
+        }
+        if fed.stdin().is_some() || fed.exec() {
+            return Err(Error::ConflictingFlags(format!("@stdin and @exec can't be used on a command fed by @pipe: {}",
+                                                       shell::join(fed.args()))));
+        }
+        Ok(())
+    }
+
+    fn plan_command(main_working_dir: Option<&Path>, index: usize, cmd: &Cmd, cfg: &Config,
+                    provided_args: &[String], strip_ansi: bool) -> Result<PlannedCommand> {
+        let args = Self::command_args(cmd, cfg, provided_args)?.into_owned();
+
+        let run_dir = Self::command_dir(main_working_dir, cmd, cfg)?.map(Cow::into_owned);
+        debug!("running in {}", run_dir.as_deref().unwrap_or_else(|| Path::new(".")).display());
+
+        let settings = RunSettings {
+            // recursive invocations are one level deeper, and search
+            // for their own file rather than rerunning a pinned one
+            env: if cmd.recurse() {
+                let mut env = vec![(String::from(LEVEL_ENV), (cfg.level + 1).to_string())];
+                if cfg.file.is_some() {
+                    env.push((String::from(FILE_ENV), String::new()));
+                }
+                env
+            } else {
+                Vec::new()
+            },
+            detach: cfg.detach || cmd.detach(),
+            stdin: match cmd.stdin() {
+                Some(s) => s.clone(),
+                None if cfg.batch => StdinSource::Null,
+                None => StdinSource::Inherit,
+            },
+            trace: cfg.trace,
+            level: cfg.level,
+            exec: cmd.exec(),
+            umask: cmd.umask(),
+            retmap: sorted_retmap(cmd.retmap()),
+        };
+
+        let argv = match cmd.container() {
+            Some(image) => {
+                let base = Self::announced_dir(main_working_dir.unwrap_or_else(|| Path::new(".")));
+                let dir = Self::announced_dir(run_dir.as_deref().unwrap_or_else(|| Path::new(".")));
+                Self::container_args(&cfg.container_engine, image, &base, &dir, &settings.env, &args)
+            },
+            None => args.clone(),
+        };
+        let argv = match cmd.ssh() {
+            Some(host) => Self::ssh_args(host, cmd.directory(), &argv),
+            None => argv,
+        };
+
+        Ok(PlannedCommand {
+            index,
+            name: cmd.name().map(String::from),
+            args,
+            argv,
+            check_dir: cmd.ssh().is_none() && cmd.directory().is_some(),
+            run_dir,
+            mkdir: cmd.mk_dir().and_then(|d| Self::run_dir(main_working_dir, Some(d))).map(Cow::into_owned),
+            requires: cmd.container().map(|_| cfg.container_engine.clone()),
+            env_required: cmd.env_required().to_vec(),
+            settings,
+            retmap: cmd.retmap().clone(),
+            outfile: cmd.out_file().map(Path::to_path_buf),
+            outfile_all: cmd.outfile_all(),
+            output: OutputSettings {
+                limit: cmd.outfile_limit().or(cfg.outfile_limit).filter(|l| *l != 0),
+                strip_ansi,
+            },
+            scan: (cfg.scan || cmd.scan()) && !cfg.print,
+            capture: cmd.capture().map(String::from),
+            silent: cmd.silent(),
+            pipe: cmd.pipe(),
+            fallback: cmd.fallback(),
+        })
+    }
+
+    // The args to run a command with, given those provided
+    fn command_args<'a>(cmd: &'a Cmd, cfg: &Config, provided_args: &[String]) -> Result<Cow<'a, [String]>> {
+        // each level takes the same arguments unless told otherwise
+        let forwarded = if cmd.recurse() && ! cmd.forward_args() { &[] } else { provided_args };
+        let args = Self::with_args(cmd.args(), forwarded,
+                                   if cmd.recurse() {
+                                       Some(&cfg.argv0)
+                                   } else {
+                                       None
+                                   },
+                                   cmd.positional_args()
+        )?;
+        if ! cmd.recurse() {
+            return Ok(args);
+        }
+        // the parent .upbuild gets the flags of the name it was called
+        // by, and the same selection
+        let mut flags: Vec<String> = cfg::implied_flags(&cmd.args()[0]).iter().map(|f| f.to_string()).collect();
+        if cmd.propagate() {
+            flags.extend(cfg.propagated_args());
+        }
+        Ok(Self::with_propagated(args, flags))
+    }
+
+    /// Describe a command - given by position or `@name` - for
+    /// `--ub-show`: its lines from the file, where and whether it
+    /// would run, and the command line it would run given the
+    /// provided args
+    pub fn show(path: &Path, file: &ClassicFile, cfg: &Config, which: &str, provided_args: &[String]) -> Result<Vec<String>> {
+        cfg.tags()?;
+        let index = file.find_command(which)?;
+        let cmd = &file.commands[index];
+        let mut lines: Vec<String> = cmd.source().to_vec();
+        lines.push(String::new());
+
+        let dir = Self::announced_dir(&Self::command_dir(Self::relative_dir(path), cmd, cfg)?.unwrap_or(Cow::Borrowed(Path::new("."))));
+        lines.push(match (cmd.ssh(), cmd.directory()) {
+            (Some(host), Some(d)) => format!("directory: {} on {}", d.display(), host),
+            (Some(host), None) => format!("directory: home on {}", host),
+            (None, _) => format!("directory: {}", dir.display()),
+        });
+
+        let mut tags: Vec<_> = cmd.tags().iter().map(String::as_str).collect();
+        tags.sort_unstable();
+        lines.push(format!("tags: {}", if tags.is_empty() { String::from("none") } else { tags.join(",") }));
+
+        let retmap = sorted_retmap(cmd.retmap());
+        lines.push(if retmap.is_empty() {
+            String::from("retmap: none")
+        } else {
+            format!("retmap: {}", retmap_text(&retmap))
+        });
+
+        lines.push(match Self::skip_reason(cmd, cfg) {
+            Some(reason) => format!("runs: no, {}", reason),
+            None if cmd.fallback() && !Self::running(&file.commands, cfg)[index] => String::from("runs: no, the command it falls back from doesn't run"),
+            None if cmd.fallback() => String::from("runs: if the command before it fails"),
+            None => String::from("runs: yes"),
+        });
+        lines.push(format!("command: {}", shell::join(&Self::command_args(cmd, cfg, provided_args)?)));
+        Ok(lines)
+    }
+
+    /// Describe the environment each command that would run gets for
+    /// `--ub-print-env`: the variables it's given that differ from
+    /// those upbuild has, as looked up by `env`, and what sets them
+    pub fn print_env<E: Fn(&str) -> Option<String>>(path: &Path, file: &ClassicFile, cfg: &Config,
+                                                     provided_args: &[String], env: E) -> Result<Vec<String>> {
+        let mut lines = Vec::new();
+        for planned in Self::plan(path, file, cfg, provided_args)? {
+            lines.push(format!("{}: {}", planned.index + 1, shell::join(&planned.args)));
+            let mut vars: Vec<_> = planned.settings.env.iter()
+                .filter(|(k, v)| env(k).as_ref() != Some(v))
+                .map(|(k, v)| format!("    {}={} ({})", k, v, Self::env_source(k)))
+                .collect();
+            // the jobserver's are only known once it's running
+            if cfg!(feature = "jobs") && cfg.jobs.is_some() {
+                vars.push(String::from("    MAKEFLAGS=<jobserver> (--ub-jobs=)"));
+            }
+            if vars.is_empty() {
+                vars.push(String::from("    unchanged"));
+            }
+            lines.extend(vars);
+        }
+        Ok(lines)
+    }
+
+    // What sets a variable upbuild gives a command
+    fn env_source(name: &str) -> &'static str {
+        match name {
+            LEVEL_ENV | FILE_ENV => "recursive call",
+            _ => "upbuild",
+        }
+    }
+
+    // Insert flags after argv0 of a recursive invocation
+    fn with_propagated(args: Cow<'_, [String]>, flags: Vec<String>) -> Cow<'_, [String]> {
+        if flags.is_empty() {
+            return args;
+        }
+        let mut args = args.into_owned();
+        args.splice(1..1, flags);
+        Cow::Owned(args)
+    }
+
+    // Apply provided_args (and argv0 for recursion) to the args,
+    // borrowing the original where there is nothing to change
+    fn with_args<'a>(args: &'a [String], provided_args: &[String], argv0: Option<&String>, positional: bool) -> Result<Cow<'a, [String]>> {
+        Cmd::args_with(args, provided_args, argv0.map(String::as_str), positional)
+    }
+
+}
+
+#[derive(Default)]
+struct ProcessRunner {
+}
+
+impl Runner for ProcessRunner {
+    fn run(&self, cmd: &[String], cd: Option<&Path>, settings: &RunSettings) -> Result<RetCode> {
+        self.start(cmd, cd, settings, |mut c| {
+            if settings.exec {
+                Self::replace(&mut c)
+            } else if settings.detach {
+                let forward = Forward::start();
+                let mut child = c.spawn()?;
+                forward.to(&child);
+                child.wait()
+            } else {
+                c.status()
+            }
+        })
+    }
+
+    fn run_captured(&self, cmd: &[String], cd: Option<&Path>, settings: &RunSettings, silent: bool) -> Result<(RetCode, Vec<u8>)> {
+        let mut output = Vec::new();
+        let code = self.start(cmd, cd, settings, |c| Self::capture(c, &mut output, silent, settings.detach))?;
+        Ok((code, output))
+    }
+
+    fn run_pipeline(&self, cmds: &[Stage<'_>]) -> Result<Vec<RetCode>> {
+        let _interrupts = cmds.iter().any(|(_, _, settings)| !settings.detach).then(Interrupts::ignore);
+        let mut children = Vec::new();
+        let mut feed: Option<ChildStdout> = None;
+        for (i, (cmd, cd, settings)) in cmds.iter().enumerate() {
+            let spawned = Self::stage(cmd, *cd, settings, feed.take(), i + 1 < cmds.len())
+                .and_then(|mut c| c.spawn().map_err(|e| Self::exec_failure(cmd, *cd, e)));
+            match spawned {
+                Ok(mut child) => {
+                    feed = child.stdout.take();
+                    children.push(child);
+                },
+                Err(e) => {
+                    // those already started see their output closed
+                    for mut child in children {
+                        let _ = child.wait();
+                    }
+                    return Err(e);
+                },
+            }
+        }
+        children.into_iter()
+            .map(|mut child| Self::ret_code(child.wait()?))
+            .collect()
+    }
+
+    fn display_output(&self, file: &Path, settings: &OutputSettings) -> Result<()> {
+        output::display_output(file, settings)
+    }
+
+    fn display(&self, s: &str) {
+        println!("{}", s)
+    }
+
+    fn check_mkdir(&self, d: &Path) -> Result<()> {
+        if d.is_dir() {
+            return Ok(());
+        }
+        std::fs::create_dir_all(d)
+            .map_err(|e| Error::UnableToCreateDir(d.display().to_string(), e))
+    }
+
+    fn check_mkdir_umask(&self, d: &Path, mask: u32) -> Result<()> {
+        umask::create_dir_all(d, mask)
+            .map_err(|e| Error::UnableToCreateDir(d.display().to_string(), e))
+    }
+
+}
+
+// Whether the program can be found on the search path
+fn on_path(name: &str) -> bool {
+    let path = match std::env::var_os("PATH") {
+        Some(p) => p,
+        None => return false,
+    };
+    std::env::split_paths(&path).any(|d| {
+        d.join(name).is_file() || (cfg!(windows) && d.join(format!("{}.exe", name)).is_file())
+    })
+}
+
+// Levenshtein distance, for suggesting similarly named commands
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut diag = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let next = (row[j + 1] + 1).min(row[j] + 1).min(diag + usize::from(ca != *cb));
+            diag = row[j + 1];
+            row[j + 1] = next;
+        }
+    }
+    row[b.len()]
+}
+
+// The closest name to the given command on the search path, if there
+// is a reasonably close one
+fn similar_command(command: &str, path: &std::ffi::OsStr) -> Option<String> {
+    let limit = (command.chars().count() / 3).clamp(1, 2);
+    let mut best: Option<(usize, String)> = None;
+    for dir in std::env::split_paths(path) {
+        let entries = match std::fs::read_dir(dir) {
+            Ok(e) => e,
+            Err(_) => continue,
+        };
+        for entry in entries.flatten() {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            let name = if cfg!(windows) {
+                Path::new(&name).file_stem().map_or(name.clone(), |s| s.to_string_lossy().into_owned())
+            } else {
+                name
+            };
+            let d = edit_distance(command, &name);
+            if d == 0 || d > limit {
+                continue;
+            }
+            if best.as_ref().map_or(true, |(bd, bn)| (d, &name) < (*bd, bn)) {
+                best = Some((d, name));
+            }
+        }
+    }
+    best.map(|(_, name)| name)
+}
+
+/// Longest path Windows accepts without the `\\?\` prefix, and the
+/// longest working directory it will start a process in
+const MAX_PATH: usize = 260;
+
+/// Drop the `\\?\` prefix from a Windows path where the path works
+/// without it, as `canonicalize` adds it and it's both unfamiliar when
+/// announced and not accepted everywhere.  `\\?\UNC\server\share`
+/// becomes `\\server\share`.  Other paths are returned as they are.
+#[cfg(windows)]
+pub(crate) fn simplified(path: PathBuf) -> PathBuf {
+    use std::path::Prefix;
+    let plain = match (path.to_str(), path.components().next()) {
+        (Some(s), Some(Component::Prefix(p))) => match p.kind() {
+            Prefix::VerbatimDisk(_) => s[4..].to_string(),
+            Prefix::VerbatimUNC(_, _) => format!("\\\\{}", &s[8..]),
+            _ => return path,
+        },
+        _ => return path,
+    };
+    if plain.len() >= MAX_PATH {
+        return path;
+    }
+    PathBuf::from(plain)
+}
+
+#[cfg(not(windows))]
+pub(crate) fn simplified(path: PathBuf) -> PathBuf {
+    path
+}
+
+impl ProcessRunner {
+    // Start the command with the given function - via sh for a script
+    // without a #! line - returning its exit code
+    fn start<F>(&self, cmd: &[String], cd: Option<&Path>, settings: &RunSettings, mut start: F) -> Result<RetCode>
+    where
+        F: FnMut(Command) -> std::io::Result<ExitStatus>
+    {
+        if let Some((command, args)) = cmd.split_first() {
+            // Ctrl-C is for the command, which upbuild then reports
+            let _interrupts = (!settings.detach && !settings.exec).then(Interrupts::ignore);
+            let result = match start(Self::command(command, args, cd, settings)?) {
+                Err(e) if Self::is_enoexec(&e) => {
+                    // a script without a #! line - run it like a shell would
+                    if settings.trace {
+                        self.trace(&format!("{}: {}: no #! line, running with sh", cfg::prefix(settings.level), command));
+                    }
+                    start(Self::command("sh", cmd, cd, settings)?)
+                },
+                result => result,
+            };
+            let result = result.map_err(|e| Self::exec_failure(cmd, cd, e))?;
+
+            Self::ret_code(result)
+
+        } else {
+            Err(Error::EmptyEntry)
+        }
+    }
+
+    // A command of a pipeline, reading the previous command's stdout
+    // and, unless it's the last, with its own piped to the next
+    fn stage(cmd: &[String], cd: Option<&Path>, settings: &RunSettings, feed: Option<ChildStdout>, piped: bool) -> Result<Command> {
+        let (command, args) = cmd.split_first().ok_or(Error::EmptyEntry)?;
+        let mut c = Self::command(command, args, cd, settings)?;
+        if let Some(feed) = feed {
+            c.stdin(feed);
+        }
+        if piped {
+            c.stdout(Stdio::piped());
+        }
+        Ok(c)
+    }
+
+    // Run the command with stdout piped, collecting it and passing it
+    // on as it comes unless silent
+    fn capture(mut c: Command, output: &mut Vec<u8>, silent: bool, detach: bool) -> std::io::Result<ExitStatus> {
+        let forward = detach.then(Forward::start);
+        let mut child = c.stdout(Stdio::piped()).spawn()?;
+        if let Some(ref forward) = forward {
+            forward.to(&child);
+        }
+        let copied = Self::tee(child.stdout.take().expect("stdout is piped"), output, silent);
+        let status = child.wait()?;
+        copied.map(|_| status)
+    }
+
+    fn tee<R: Read>(mut r: R, output: &mut Vec<u8>, silent: bool) -> std::io::Result<()> {
+        let mut buf = [0; 8192];
+        loop {
+            let n = match r.read(&mut buf) {
+                Ok(0) => return Ok(()),
+                Ok(n) => n,
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+            output.extend_from_slice(&buf[..n]);
+            if !silent {
+                let mut stdout = std::io::stdout().lock();
+                stdout.write_all(&buf[..n])?;
+                stdout.flush()?;
+            }
+        }
+    }
+
+    // Explain why a command couldn't be run
+    fn exec_failure(cmd: &[String], cd: Option<&Path>, e: std::io::Error) -> Error {
+        let command = cmd[0].as_str();
+        let dir = Exec::announced_dir(cd.unwrap_or_else(|| Path::new(".")));
+        let mut notes = Vec::new();
+        let bin = Path::new(command);
+        let local = dir.join(bin.components().filter(|c| *c != Component::CurDir).collect::<PathBuf>());
+
+        match e.kind() {
+            std::io::ErrorKind::NotFound if bin.file_name() == Some(bin.as_os_str()) => {
+                notes.push(format!("'{}' was not found on PATH", command));
+                if local.is_file() {
+                    notes.push(format!("{} exists - did you mean ./{}?", local.display(), command));
+                } else if let Some(name) = std::env::var_os("PATH").and_then(|p| similar_command(command, &p)) {
+                    notes.push(format!("did you mean '{}'?", name));
+                }
+            },
+            std::io::ErrorKind::NotFound if !local.exists() => {
+                notes.push(format!("{} does not exist", local.display()));
+            },
+            std::io::ErrorKind::PermissionDenied if local.is_file() => {
+                notes.push(format!("{} is not executable", local.display()));
+            },
+            _ if cfg!(windows) && dir.as_os_str().len() >= MAX_PATH => {
+                notes.push(format!("the directory is longer than the {} characters Windows allows to run in", MAX_PATH));
+            },
+            _ => {},
+        }
+        Error::FailedToExec(cmd.to_vec(), dir.display().to_string(), e, notes)
+    }
+
+    // Build the command to run with the given settings applied
+    fn command<S: AsRef<std::ffi::OsStr>>(command: S, args: &[String], cd: Option<&Path>, settings: &RunSettings) -> Result<Command> {
+        let mut exec = Command::new(&command);
+
+        // On windows std::process::Command evaluates the
+        // executable _before_ the `current_dir()` is applied
+        if cfg!(windows) {
+            let bin = Path::new(&command);
+            if bin.is_relative() && cd.is_some() {
+                let base = cd.unwrap();
+                let cmd_path = base.join(bin);
+
+                // bin.is_relative() finds non-path prefixed
+                // commands ie "hello" is non-path prefixed.  So
+                // drop case where file-name is the entire file.
+                // EXCEPT - that means dropping the case where we
+                // @cd to a directory, then run locally.
+                //
+                // So replicate DOS behaviour manually and resolve
+                // to the exe if it exists in the @cd dir.
+
+                if Some(bin.as_os_str()) != bin.file_name() ||
+                    cmd_path.exists() {
+                    exec = Command::new(cmd_path);
+                }
+            }
+        }
+        exec.args(args);
+        exec.envs(settings.env.iter().cloned());
+        signals::restore_in_child(&mut exec);
+        if settings.detach {
+            Self::detach(&mut exec);
+        }
+        if let Some(mask) = settings.umask {
+            umask::apply(&mut exec, mask);
+        }
+        match settings.stdin {
+            StdinSource::Inherit => {},
+            StdinSource::Null => { exec.stdin(std::process::Stdio::null()); },
+            StdinSource::File(ref f) => {
+                let f = cd.map(|d| d.join(f)).unwrap_or_else(|| f.clone());
+                let input = std::fs::File::open(&f)
+                    .map_err(|e| Error::UnableToReadStdin(f.display().to_string(), e))?;
+                exec.stdin(input);
+            },
+        }
+
+        // TODO - was .inspect(), but not available in 1.63
+        if let Some(d) = cd {
+            // a \\?\ path isn't accepted as a working directory
+            exec.current_dir(simplified(d.to_path_buf()));
+        }
+        Ok(exec)
+    }
+
+    // Replace upbuild with the command, only returning on failure
+    #[cfg(target_family = "unix")]
+    fn replace(exec: &mut Command) -> std::io::Result<std::process::ExitStatus> {
+        use std::os::unix::process::CommandExt;
+        Err(exec.exec())
+    }
+
+    // Elsewhere just run it, forwarding the exit code as usual
+    #[cfg(not(target_family = "unix"))]
+    fn replace(exec: &mut Command) -> std::io::Result<std::process::ExitStatus> {
+        exec.status()
+    }
+
+    #[cfg(target_family = "unix")]
+    fn is_enoexec(e: &std::io::Error) -> bool {
+        // ENOEXEC is 8 on Linux, the BSDs and macOS
+        e.raw_os_error() == Some(8)
+    }
+
+    #[cfg(not(target_family = "unix"))]
+    fn is_enoexec(_e: &std::io::Error) -> bool {
+        false
+    }
+
+    #[cfg(target_family = "unix")]
+    fn detach(exec: &mut Command) {
+        use std::os::unix::process::CommandExt;
+        extern "C" {
+            fn setsid() -> i32;
+        }
+        // SAFETY: setsid() is async-signal-safe and touches no memory
+        unsafe {
+            exec.pre_exec(|| {
+                if setsid() == -1 {
+                    return Err(std::io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+    }
+
+    #[cfg(windows)]
+    fn detach(exec: &mut Command) {
+        use std::os::windows::process::CommandExt;
+        const CREATE_NEW_PROCESS_GROUP: u32 = 0x00000200;
+        exec.creation_flags(CREATE_NEW_PROCESS_GROUP);
+    }
+
+    #[cfg(not(any(target_family = "unix", windows)))]
+    fn detach(_exec: &mut Command) {
+    }
+
+    // The command's exit code in full - on Windows an NTSTATUS such as
+    // 0xC0000005 is negative, and TerminateProcess can give any code
+    fn ret_code(status: ExitStatus) -> Result<RetCode> {
+        match status.code() {
+            Some(c) => Ok(c as RetCode),
+            None => Err(Self::no_result_code(status)),
+        }
+    }
+
+    #[cfg(target_family = "unix")]
+    fn no_result_code(result: std::process::ExitStatus) -> Error {
+        use std::os::unix::process::ExitStatusExt;
+        Error::ExitWithSignal(result.signal().unwrap().try_into().unwrap(), result.core_dumped())
+    }
+
+    #[cfg(not(target_family = "unix"))]
+    fn no_result_code(_result: std::process::ExitStatus) -> Error {
+        Error::ExitWithSignal(127, false)
+    }
+}
+
+// A @retmap in order, to be shown the same way each time
+fn sorted_retmap(retmap: &HashMap<RetCode, RetCode>) -> Vec<(RetCode, RetCode)> {
+    let mut sorted: Vec<_> = retmap.iter().map(|(a, b)| (*a, *b)).collect();
+    sorted.sort_unstable();
+    sorted
+}
+
+// A @retmap as written in the file
+fn retmap_text(retmap: &[(RetCode, RetCode)]) -> String {
+    retmap.iter().map(|(a, b)| format!("{}=>{}", a, b)).collect::<Vec<_>>().join(",")
+}
+
+struct PrintRunner {
+    null: bool,
+}
+
+impl PrintRunner {
+    // The command as printed - for --ub-print-null, a record for the
+    // directory, if any, and one for the command
+    fn render(&self, cmd: &[String], cd: Option<&Path>) -> Vec<u8> {
+        if ! self.null {
+            return format!("{}\n", cmd.join(" ")).into_bytes();
+        }
+        let mut out = cd.map(|d| Self::record("cd", d)).unwrap_or_default();
+        for arg in cmd {
+            out.extend(arg.as_bytes());
+            out.push(0);
+        }
+        out.push(0);
+        out
+    }
+
+    // A pipeline as one line joined by |, or for --ub-print-null each
+    // command's records with a | record between them
+    fn render_pipeline(&self, cmds: &[Stage<'_>]) -> Vec<u8> {
+        if ! self.null {
+            let mut out: Vec<u8> = cmds.iter().flat_map(|(_, _, settings)| self.render_settings(settings)).collect();
+            let lines: Vec<_> = cmds.iter().map(|(cmd, _, _)| cmd.join(" ")).collect();
+            out.extend(format!("{}\n", lines.join(" | ")).into_bytes());
+            return out;
+        }
+        let records: Vec<_> = cmds.iter()
+            .map(|(cmd, cd, settings)| [self.render_settings(settings), self.render(cmd, *cd)].concat())
+            .collect();
+        records.join(&b"|\0\0"[..])
+    }
+
+    // The @umask a command runs with, as a comment - or a record for
+    // --ub-print-null
+    fn render_umask(&self, settings: &RunSettings) -> Vec<u8> {
+        match settings.umask {
+            None => Vec::new(),
+            Some(mask) if self.null => Self::record("umask", Path::new(&format!("{:03o}", mask))),
+            Some(mask) => format!("# umask {:03o}\n", mask).into_bytes(),
+        }
+    }
+
+    // The @retmap applied once the command has run, as a comment - or a
+    // record for --ub-print-null
+    fn render_retmap(&self, settings: &RunSettings) -> Vec<u8> {
+        match settings.retmap.as_slice() {
+            [] => Vec::new(),
+            retmap if self.null => Self::record("retmap", Path::new(&retmap_text(retmap))),
+            retmap => format!("# retmap: {}\n", retmap_text(retmap)).into_bytes(),
+        }
+    }
+
+    // What's shown ahead of a command about how it runs
+    fn render_settings(&self, settings: &RunSettings) -> Vec<u8> {
+        [self.render_umask(settings), self.render_retmap(settings)].concat()
+    }
+
+    fn render_mkdir(&self, d: &Path) -> Vec<u8> {
+        if self.null {
+            return Self::record("mkdir", d);
+        }
+        format!("Checking existence of directory {}\n", d.display()).into_bytes()
+    }
+
+    // An @outfile, shown as written as nothing has run to write it, or
+    // for a pattern to match
+    fn render_outfile(&self, file: &Path) -> Vec<u8> {
+        if self.null {
+            return Self::record("outfile", file);
+        }
+        format!("# outfile: {}\n", file.display()).into_bytes()
+    }
+
+    // A pseudo-command naming a path, as is where the platform allows
+    fn record(name: &str, path: &Path) -> Vec<u8> {
+        #[cfg(target_family = "unix")]
+        let path = std::os::unix::ffi::OsStrExt::as_bytes(path.as_os_str());
+        #[cfg(not(target_family = "unix"))]
+        let path = path.to_string_lossy();
+        #[cfg(not(target_family = "unix"))]
+        let path = path.as_bytes();
+
+        let mut out = Vec::from(name.as_bytes());
+        out.push(0);
+        out.extend(path);
+        out.extend([0, 0]);
+        out
+    }
+
+    fn print(bytes: &[u8]) -> Result<()> {
+        let mut out = std::io::stdout().lock();
+        out.write_all(bytes)?;
+        Ok(out.flush()?)
+    }
+}
+
+impl Runner for PrintRunner {
+    fn run(&self, cmd: &[String], cd: Option<&Path>, settings: &RunSettings) -> Result<RetCode> {
+        Self::print(&[self.render_settings(settings), self.render(cmd, cd)].concat())?;
+        Ok(0)
+    }
+
+    fn run_pipeline(&self, cmds: &[Stage<'_>]) -> Result<Vec<RetCode>> {
+        Self::print(&self.render_pipeline(cmds))?;
+        Ok(vec![0; cmds.len()])
+    }
+
+    fn check_mkdir(&self, d: &Path) -> Result<()> {
+        Self::print(&self.render_mkdir(d))
+    }
+
+    // @mkdir hasn't really happened - so assume it would have worked
+    fn is_dir(&self, _d: &Path) -> bool {
+        true
+    }
+
+    // just showing the command doesn't need it
+    fn has_program(&self, _name: &str) -> bool {
+        true
+    }
+
+    fn has_env(&self, _name: &str, _allow_empty: bool) -> bool {
+        true
+    }
+
+    // what's there now is from an earlier run, if anything
+    fn display_output(&self, file: &Path, _settings: &OutputSettings) -> Result<()> {
+        Self::print(&self.render_outfile(file))
+    }
+
+    // the command hasn't run, so leave the pattern for display_output
+    fn find_outfiles(&self, pattern: &Path, _cd: Option<&Path>) -> Vec<PathBuf> {
+        vec![pattern.to_path_buf()]
+    }
+
+    fn display(&self, _s: &str) {
+        // PrintRunner doesn't show the commentary
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::{ConfigBuilder, SearchEnd};
+    use crate::testing::{MockRunner, TempDir};
+
+    // A directory with a .upbuild above it for recursive calls to
+    // find, removed when dropped
+    struct ParentTree {
+        top: TempDir,
+    }
+
+    impl ParentTree {
+        fn new(name: &str) -> ParentTree {
+            let top = TempDir::new(name);
+            std::fs::create_dir_all(top.join("a")).unwrap();
+            std::fs::write(top.join(".upbuild-root"), "").unwrap();
+            std::fs::write(top.join(".upbuild"), "make\n").unwrap();
+            ParentTree { top }
+        }
+
+        // The directory below, holding the file making recursive calls
+        fn dir(&self) -> PathBuf {
+            self.top.join("a")
+        }
+
+        fn file(&self) -> String {
+            self.dir().join(".upbuild").display().to_string()
+        }
+
+        // Where recursive calls from the file run
+        fn run_dir(&self) -> PathBuf {
+            self.dir().join("..")
+        }
+    }
+
+    struct TestRun {
+        runner: MockRunner,
+        cfg: ConfigBuilder,
+    }
+
+    impl TestRun {
+        fn new() -> TestRun {
+            TestRun {
+                runner: MockRunner::new(),
+                cfg: Config::builder(),
+            }
+        }
+
+        fn override_argv0<T: Into<String>>(&mut self, a: T) -> &mut Self {
+            self.cfg.argv0(a);
+            self
+        }
+
+        fn select<const N: usize>(&mut self, tags: [&str ;N]) -> &mut Self {
+            self.cfg.select(tags);
+            self
+        }
+
+        fn reject<const N: usize>(&mut self, tags: [&str ;N]) -> &mut Self {
+            self.cfg.reject(tags);
+            self
+        }
+
+        fn level(&mut self, level: usize) -> &mut Self {
+            self.cfg.level(level);
+            self
+        }
+
+        fn tags(&mut self, expr: &str) -> &mut Self {
+            self.cfg.tags(expr);
+            self
+        }
+
+        fn allow_empty(&mut self) -> &mut Self {
+            self.cfg.allow_empty(true);
+            self
+        }
+
+        fn file(&mut self, file: &str) -> &mut Self {
+            self.cfg.file(file);
+            self
+        }
+
+        fn ignore_mkdir_errors(&mut self) -> &mut Self {
+            self.cfg.ignore_mkdir_errors(true);
+            self
+        }
+
+        fn scan(&mut self) -> &mut Self {
+            self.cfg.scan(true);
+            self
+        }
+
+        fn outfile_limit(&mut self, limit: Option<u64>) -> &mut Self {
+            self.cfg.outfile_limit(limit);
+            self
+        }
+
+        fn color(&mut self, color: Color) -> &mut Self {
+            self.cfg.color(color);
+            self
+        }
+
+        fn batch(&mut self) -> &mut Self {
+            self.cfg.batch(true);
+            self
+        }
+
+        fn detach(&mut self) -> &mut Self {
+            self.cfg.detach(true);
+            self
+        }
+
+        fn trace(&mut self) -> &mut Self {
+            self.cfg.trace(true);
+            self
+        }
+
+        // REVIEW - above calls are mutable, below are not, so you need to chain
+        // them first
+
+        fn add_return_data(&self, result: Result<RetCode>) -> &Self {
+            self.runner.push_result(result);
+            self
+        }
+
+        fn add_mkdir_result(&self, result: Result<()>) -> &Self {
+            self.runner.push_mkdir_result(result);
+            self
+        }
+
+        fn add_capture_output(&self, output: &str) -> &Self {
+            self.runner.push_capture_output(output);
+            self
+        }
+
+        fn add_outfile_result(&self, result: Result<()>) -> &Self {
+            self.runner.push_outfile_result(result);
+            self
+        }
+
+        fn missing_dir(&self, dir: &str) -> &Self {
+            self.runner.missing_dir(dir);
+            self
+        }
+
+        fn missing_program(&self, name: &str) -> &Self {
+            self.runner.missing_program(name);
+            self
+        }
+
+        fn set_env(&self, name: &str, value: &str) -> &Self {
+            self.runner.set_env(name, value);
+            self
+        }
+
+        fn run<const N: usize>(&self, file_data: &str, provided_args: [&str; N], expected_result: Result<()>) -> &Self {
+            let provided_args: Vec<String> = provided_args.into_iter().map(String::from).collect();
+            self.run_(file_data, |e,f| e.run(Path::new(".upbuild"), f, &self.cfg.build(), &provided_args), expected_result)
+        }
+
+        fn run_with_path<const N: usize>(&self, path: &str, file_data: &str, provided_args: [&str; N], expected_result: Result<()>) -> &Self {
+            let provided_args: Vec<String> = provided_args.into_iter().map(String::from).collect();
+            self.run_(file_data, |e,f| e.run(Path::new(path), f, &self.cfg.build(), &provided_args), expected_result)
+        }
+
+        fn run_without_args(&self, file_data: &str, expected_result: Result<()>) -> &Self {
+            self.run(file_data, [], expected_result)
+        }
+
+        fn run_<F>(&self, file_data: &str, f: F, expected_result: Result<()>) -> &Self
+        where
+            F: FnOnce(Exec, &ClassicFile) -> Result<()>
+        {
+            let file = ClassicFile::parse_lines(file_data.lines()).unwrap();
+            let runner = Box::new(self.runner.clone());
+
+            let e = Exec::new(runner);
+
+            match expected_result {
+                Ok(_) => { f(e, &file).expect("Should pass"); },
+                Err(err) => {
+                    let ret = f(e, &file).expect_err("Should fail");
+                    assert_eq!(ret, err);
+                },
+            }
+
+            assert_eq!(self.runner.pending_results(), 0, "Didn't exhaust results");
+            self
+        }
+
+        fn verify_captured(&self, silent: bool) -> &Self {
+            self.runner.expect_captured(silent);
+            self
+        }
+
+        fn verify_pipeline(&self, len: usize) -> &Self {
+            self.runner.expect_pipeline(len);
+            self
+        }
+
+        fn verify_cd_comment(&self, expected: &str) -> &Self {
+            self.runner.expect_display(expected);
+            self
+        }
+
+        fn prefix(&self) -> String {
+            self.cfg.build().prefix()
+        }
+
+        fn verify_cd_dir<S: AsRef<str>>(&self, dir: S) -> &Self {
+            let expected = format!("{}: Entering directory `{}'", self.prefix(), dir.as_ref());
+            self.verify_cd_comment(expected.as_str())
+        }
+
+        fn verify_leave_dir<S: AsRef<str>>(&self, dir: S) -> &Self {
+            let expected = format!("{}: Leaving directory `{}'", self.prefix(), dir.as_ref());
+            self.verify_cd_comment(expected.as_str())
+        }
+
+        // The file's directory entered and left, then the one the
+        // recursive call ran in
+        fn verify_recursed(&self, from: &Path, to: &Path) -> &Self {
+            let announced = |d: &Path| d.canonicalize().unwrap().display().to_string();
+            self.verify_cd_dir(announced(from))
+                .verify_leave_dir(announced(from))
+                .verify_cd_dir(announced(to))
+                .verify_leave_dir(announced(to))
+        }
+
+        fn verify_return_data<const N: usize>(&self, cmd: [&str; N], cd: Option<PathBuf>) -> &Self {
+            self.verify_return_data_env(cmd, cd, [])
+        }
+
+        fn next_run<const N: usize>(&self, cmd: [&str; N]) -> RunSettings {
+            let result = self.runner.take_run().expect("Expected results");
+            assert_eq!(result.cmd, cmd);
+            result.settings
+        }
+
+        fn verify_return_data_env<const N: usize, const M: usize>(&self, cmd: [&str; N], cd: Option<PathBuf>,
+                                                                  env: [(&str, &str); M]) -> &Self {
+            let result = self.runner.take_run().expect("Expected results");
+            assert_eq!(result.cmd, cmd);
+            assert_eq!(result.cd, cd);
+            assert_eq!(result.settings.env, env.map(|(k, v)| (k.to_string(), v.to_string())));
+            self
+        }
+
+        fn verify_detached<const N: usize>(&self, cmd: [&str; N], detach: bool) -> &Self {
+            assert_eq!(self.next_run(cmd).detach, detach);
+            self
+        }
+
+        fn verify_exec<const N: usize>(&self, cmd: [&str; N], exec: bool) -> &Self {
+            assert_eq!(self.next_run(cmd).exec, exec);
+            self
+        }
+
+        fn verify_stdin<const N: usize>(&self, cmd: [&str; N], stdin: StdinSource) -> &Self {
+            assert_eq!(self.next_run(cmd).stdin, stdin);
+            self
+        }
+
+        fn verify_trace(&self, expected: &str) -> &Self {
+            self.runner.expect_trace(expected);
+            self
+        }
+
+        fn verify_outfile(&self, expected: &str) -> &Self {
+            self.runner.expect_outfile(expected);
+            self
+        }
+
+        fn next_outfile(&self, expected: &str) -> OutputSettings {
+            let (outfile, settings) = self.runner.take_outfile().expect("expected outfile");
+            assert_eq!(PathBuf::from(expected), outfile);
+            settings
+        }
+
+        fn verify_outfile_strip_ansi(&self, expected: &str, strip_ansi: bool) -> &Self {
+            assert_eq!(strip_ansi, self.next_outfile(expected).strip_ansi);
+            self
+        }
+
+        fn verify_outfile_limit(&self, expected: &str, limit: Option<u64>) -> &Self {
+            assert_eq!(limit, self.next_outfile(expected).limit);
+            self
+        }
+
+        fn verify_mkdir(&self, expected: &str) -> &Self {
+            self.runner.expect_mkdir(expected);
+            self
+        }
+
+        fn done(&self) {
+            self.runner.assert_done();
+            self.runner.reset();
+        }
+    }
+
+    // Directories are announced as absolute paths, existing or not
+    fn absolute(p: &str) -> String {
+        PathBuf::from(".").canonicalize().unwrap().join(p).display().to_string()
+    }
+
+    fn absolute_from(base: &str, p: &str) -> String {
+        PathBuf::from(base).join(p).display().to_string()
+    }
+
+    // The error from the command at the given position, from 1
+    fn in_command(index: usize, program: &str, e: Error) -> Error {
+        Error::InCommand(index, None, program.to_string(), Box::new(e))
+    }
+
+    fn args_vec_slice(args: &[&str]) -> Vec<String> {
+        args.iter().map(|x| x.to_string()).collect()
+    }
+
+    fn args_vec<const N: usize>(provided_args: [&str; N]) -> Vec<String> {
+        provided_args.into_iter().map(String::from).collect()
+    }
+
+    #[test]
+    fn test_exec_uv4() {
+
+        let file_data = include_str!("../tests/uv4.upbuild");
+        let uv4_run = ["uv4", "-j0", "-b", "project.uvproj", "-o", "log.txt"];
+        TestRun::new()
+            .add_return_data(Ok(0))
+            .run_without_args(file_data, Ok(()))
+            .verify_return_data(uv4_run, None)
+            .verify_outfile("log.txt")
+            .done();
+
+        // 1 should map to 0
+        TestRun::new()
+            .add_return_data(Ok(1))
+            .run_without_args(file_data, Ok(()))
+            .verify_return_data(uv4_run, None)
+            .verify_outfile("log.txt")
+            .done();
 
         // 2 should fail though
         TestRun::new()
-            .add_return_data(Ok(2))
-            .run_without_args(file_data, Err(Error::ExitWithExitCode(2)))
-            .verify_return_data(uv4_run, None)
+            .add_return_data(Ok(2))
+            .run_without_args(file_data, Err(in_command(1, "uv4", Error::ExitWithExitCode(2))))
+            .verify_return_data(uv4_run, None)
+            .done();
+
+        // signals should be propagated
+        TestRun::new()
+            .add_return_data(Err(Error::ExitWithSignal(6, true)))
+            .run_without_args(file_data, Err(in_command(1, "uv4", Error::ExitWithSignal(6, true))))
+            .verify_return_data(uv4_run, None)
+            .done();
+    }
+
+    #[test]
+    fn test_exec_tags() {
+        let file_data = include_str!("../tests/manual.upbuild");
+        TestRun::new()
+            .add_return_data(Ok(0))
+            .add_return_data(Ok(0))
+            .run_without_args(file_data, Ok(()))
+            .verify_return_data(["make", "tests"], None)
+            .verify_return_data(["make", "cross"], None)
+            .done();
+
+        TestRun::new()
+            .add_return_data(Ok(1))
+            .run_without_args(file_data, Err(in_command(1, "make", Error::ExitWithExitCode(1))))
+            .verify_return_data(["make", "tests"], None)
+            .done();
+
+        // select hosts tags
+        TestRun::new()
+            .select(["host"])
+            .add_return_data(Ok(0))
+            .add_return_data(Ok(0))
+            .run_without_args(file_data, Ok(()))
+            .verify_return_data(["make", "tests"], None)
+            .verify_return_data(["make", "install"], None)
+            .done();
+
+        TestRun::new()
+            .select(["release"])
+            .add_return_data(Ok(0))
+            .run_without_args(file_data, Ok(()))
+            .verify_return_data(["make", "install"], None)
+            .done();
+
+        TestRun::new()
+            .select(["target"])
+            .add_return_data(Ok(0))
+            .run_without_args(file_data, Ok(()))
+            .verify_return_data(["make", "cross"], None)
+            .done();
+
+        TestRun::new()
+            .select(["target", "host"])
+            .add_return_data(Ok(0))
+            .add_return_data(Ok(0))
+            .add_return_data(Ok(0))
+            .run_without_args(file_data, Ok(()))
+            .verify_return_data(["make", "tests"], None)
+            .verify_return_data(["make", "cross"], None)
+            .verify_return_data(["make", "install"], None)
+            .done();
+
+        TestRun::new()
+            .select(["target", "host"])
+            .add_return_data(Ok(0))
+            .add_return_data(Ok(1))
+            .run_without_args(file_data, Err(in_command(2, "make", Error::ExitWithExitCode(1))))
+            .verify_return_data(["make", "tests"], None)
+            .verify_return_data(["make", "cross"], None)
+            .done();
+
+        TestRun::new()
+            .select(["host"])
+            .reject(["release"])
+            .add_return_data(Ok(0))
+            .run_without_args(file_data, Ok(()))
+            .verify_return_data(["make", "tests"], None)
+            .done();
+
+        TestRun::new()
+            .reject(["host"])
+            .add_return_data(Ok(0))
+            .run_without_args(file_data, Ok(()))
+            .verify_return_data(["make", "cross"], None)
+            .done();
+
+        TestRun::new()
+            .select(["target"])
+            .add_return_data(Ok(0))
+            .run_without_args(file_data, Ok(()))
+            .verify_return_data(["make", "cross"], None)
+            .done();
+
+        // running nothing is an error, unless allowed
+        TestRun::new()
+            .select(["target"])
+            .reject(["host", "target"])
+            .run_without_args(file_data, Err(Error::NothingSelected(Vec::new(), vec!["host".into(), "target".into()])))
+            .done();
+
+        TestRun::new()
+            .select(["release", "docs"])
+            .reject(["host"])
+            .run_without_args(file_data, Err(Error::NothingSelected(vec!["docs".into(), "release".into()], vec!["host".into()])))
+            .done();
+
+        TestRun::new()
+            .reject(["host", "target"])
+            .allow_empty()
+            .run_without_args(file_data, Ok(()))
+            .done();
+
+        TestRun::new()
+            .run_without_args("make\n@disable\n&&\nmake\n@manual\ninstall\n", Err(Error::NothingSelected(Vec::new(), Vec::new())))
+            .done();
+
+        // recursive calls are given the whole selection
+        TestRun::new()
+            .select(["docs"])
+            .level(1)
+            .run_without_args(file_data, Ok(()))
+            .done();
+    }
+
+    #[test]
+    fn test_exec_tag_expression() {
+        let file_data = include_str!("../tests/manual.upbuild");
+        TestRun::new()
+            .tags("host & !release")
+            .add_return_data(Ok(0))
+            .run_without_args(file_data, Ok(()))
+            .verify_return_data(["make", "tests"], None)
+            .done();
+
+        // @manual commands have to be named
+        TestRun::new()
+            .tags("!target")
+            .add_return_data(Ok(0))
+            .run_without_args(file_data, Ok(()))
+            .verify_return_data(["make", "tests"], None)
+            .done();
+
+        TestRun::new()
+            .tags("(host | target) & !tests")
+            .add_return_data(Ok(0))
+            .add_return_data(Ok(0))
+            .add_return_data(Ok(0))
+            .run_without_args(file_data, Ok(()))
+            .verify_return_data(["make", "tests"], None)
+            .verify_return_data(["make", "cross"], None)
+            .verify_return_data(["make", "install"], None)
+            .done();
+
+        TestRun::new()
+            .tags("release & target")
+            .run_without_args(file_data, Err(Error::NothingMatched("release & target".into())))
+            .done();
+
+        TestRun::new()
+            .tags("host & (target")
+            .run_without_args(file_data, Err(Error::InvalidTagExpression("host & (target".into(), 14, "expected )".into())))
+            .done();
+
+        TestRun::new()
+            .tags("host")
+            .select(["target"])
+            .run_without_args(file_data, Err(Error::ConflictingFlags("--ub-tags= can't be combined with --ub-select= or --ub-reject=".into())))
+            .done();
+    }
+
+    #[test]
+    fn args() {
+        let file_data = include_str!("../tests/args.upbuild");
+        TestRun::new()
+            .add_return_data(Ok(0))
+            .add_return_data(Ok(0))
+            .run(file_data, [], Ok(()))
+            .verify_return_data(["make", "-j8", "BUILD_MODE=host_debug", "test"], None)
+            .verify_return_data(["echo", "foo"], None)
+            .done();
+
+        TestRun::new()
+            .add_return_data(Ok(0))
+            .add_return_data(Ok(0))
+            .run(file_data, ["all"], Ok(()))
+            .verify_return_data(["make", "-j8", "BUILD_MODE=host_debug", "all"], None)
+            .verify_return_data(["echo", "foo", "all"], None)
+            .done();
+
+        TestRun::new()
+            .add_return_data(Ok(0))
+            .add_return_data(Ok(0))
+            .run(file_data, ["all", "tests"], Ok(()))
+            .verify_return_data(["make", "-j8", "BUILD_MODE=host_debug", "all", "tests"], None)
+            .verify_return_data(["echo", "foo", "all", "tests"], None)
+            .done();
+    }
+
+    #[test]
+    fn args_always() {
+        // defaults between the separators, always added after the second
+        let file_data = "ctest\n--\n-j8\n--\n--output-on-failure\n&&\nmake\n--\n--\n--\n--help\n";
+        TestRun::new()
+            .add_return_data(Ok(0))
+            .add_return_data(Ok(0))
+            .run(file_data, [], Ok(()))
+            .verify_return_data(["ctest", "-j8", "--output-on-failure"], None)
+            .verify_return_data(["make", "--", "--help"], None)
+            .done();
+
+        TestRun::new()
+            .add_return_data(Ok(0))
+            .add_return_data(Ok(0))
+            .run(file_data, ["-R", "parser"], Ok(()))
+            .verify_return_data(["ctest", "-R", "parser", "--output-on-failure"], None)
+            .verify_return_data(["make", "-R", "parser", "--", "--help"], None)
+            .done();
+
+        // nothing always added without the second
+        let file_data = "ctest\n--output-on-failure\n--\n-j8\n";
+        TestRun::new()
+            .add_return_data(Ok(0))
+            .run(file_data, ["-j2"], Ok(()))
+            .verify_return_data(["ctest", "--output-on-failure", "-j2"], None)
+            .done();
+    }
+
+    #[test]
+    fn recurse() {
+        let file_data = include_str!("../tests/recurse.upbuild");
+        let tree = ParentTree::new("recurse");
+        TestRun::new()
+            .add_return_data(Ok(0))
+            .add_return_data(Ok(0))
+            .run_with_path(&tree.file(), file_data, [], Ok(()))
+            .verify_return_data(["make", "tests"], Some(tree.dir()))
+            .verify_return_data_env(["upbuild"], Some(tree.run_dir()), [("UPBUILD_LEVEL", "1")])
+            .verify_recursed(&tree.dir(), &tree.run_dir())
+            .done();
+
+        TestRun::new()
+            .override_argv0("/path/to/upbuild")
+            .add_return_data(Ok(0))
+            .add_return_data(Ok(0))
+            .run_with_path(&tree.file(), file_data, [], Ok(()))
+            .verify_return_data(["make", "tests"], Some(tree.dir()))
+            .verify_return_data_env(["/path/to/upbuild"], Some(tree.run_dir()), [("UPBUILD_LEVEL", "1")])
+            .verify_recursed(&tree.dir(), &tree.run_dir())
+            .done();
+
+        let file_data = include_str!("../tests/norecurse.upbuild");
+        TestRun::new()
+            .override_argv0("/path/to/upbuild")
+            .add_return_data(Ok(0))
+            .add_return_data(Ok(0))
+            .run(file_data, [], Ok(()))
+            .verify_return_data(["make", "tests"], None)
+            .verify_return_data_env(["/path/to/upbuild"], Some(PathBuf::from("/path/to/build")), [("UPBUILD_LEVEL", "1")])
+            .verify_cd_dir("/path/to/build")
+            .verify_leave_dir("/path/to/build")
+            .done();
+
+        // straight to the next .upbuild up, past directories without one
+        std::fs::create_dir_all(tree.dir().join("b/c")).unwrap();
+        let file_data = include_str!("../tests/recurse.upbuild");
+        let deep = tree.dir().join("b/c/.upbuild").display().to_string();
+        TestRun::new()
+            .add_return_data(Ok(0))
+            .add_return_data(Ok(0))
+            .run_with_path(&deep, file_data, [], Ok(()))
+            .verify_return_data(["make", "tests"], Some(tree.dir().join("b/c")))
+            .verify_return_data_env(["upbuild"], Some(tree.dir().join("b/c/../../..")), [("UPBUILD_LEVEL", "1")])
+            .verify_recursed(&tree.dir().join("b/c"), &tree.top)
+            .done();
+
+        // unless told where by @cd
+        TestRun::new()
+            .add_return_data(Ok(0))
+            .add_return_data(Ok(0))
+            .run_with_path(&deep, "make\n&&\nupbuild\n@cd=..\n", [], Ok(()))
+            .verify_return_data(["make"], Some(tree.dir().join("b/c")))
+            .verify_return_data_env(["upbuild"], Some(tree.dir().join("b/c/..")), [("UPBUILD_LEVEL", "1")])
+            .verify_recursed(&tree.dir().join("b/c"), &tree.dir().join("b"))
+            .done();
+
+        // and with nothing above, nothing runs
+        std::fs::remove_file(tree.top.join(".upbuild")).unwrap();
+        let not_found = Error::NotFound(tree.dir().join("b/c/.."), vec![tree.dir().join("b").canonicalize().unwrap(),
+                                                                         tree.dir().canonicalize().unwrap(),
+                                                                         tree.top.canonicalize().unwrap()], SearchEnd::Marker);
+        TestRun::new()
+            .run_with_path(&deep, file_data, [], Err(Error::NoParentFile(String::from("upbuild"), Box::new(not_found))))
+            .done();
+    }
+
+    #[test]
+    fn recurse_aliases() {
+        let tree = ParentTree::new("recurse-aliases");
+        // with @recurse, run as this upbuild was, with the flags of
+        // the name used
+        TestRun::new()
+            .override_argv0("/usr/local/bin/ub")
+            .add_return_data(Ok(0))
+            .run_with_path(&tree.file(), "ub\n@recurse\n", [], Ok(()))
+            .verify_return_data_env(["/usr/local/bin/ub"], Some(tree.run_dir()), [("UPBUILD_LEVEL", "1")])
+            .verify_recursed(&tree.dir(), &tree.run_dir())
+            .done();
+
+        TestRun::new()
+            .override_argv0("/usr/local/bin/ub")
+            .select(["host"])
+            .add_return_data(Ok(0))
+            .run_with_path(&tree.file(), "upbuild-print\n@recurse\n@tags=host\n", [], Ok(()))
+            .verify_return_data_env(["/usr/local/bin/ub", "--ub-print", "--ub-select=host"], Some(tree.run_dir()), [("UPBUILD_LEVEL", "1")])
+            .verify_recursed(&tree.dir(), &tree.run_dir())
+            .done();
+
+        // otherwise they're commands like any other
+        TestRun::new()
+            .override_argv0("/usr/local/bin/ub")
+            .add_return_data(Ok(0))
+            .add_return_data(Ok(0))
+            .run("ub\n&&\nupbuild-print\n", [], Ok(()))
+            .verify_return_data(["ub"], None)
+            .verify_return_data(["upbuild-print"], None)
+            .done();
+    }
+
+    #[test]
+    fn recurse_forwards_args() {
+        let tree = ParentTree::new("recurse-forwards-args");
+        let file_data = "make\n&&\nupbuild\n--ub-select=target\n--\ndefault\n";
+        TestRun::new()
+            .add_return_data(Ok(0))
+            .add_return_data(Ok(0))
+            .run_with_path(&tree.file(), file_data, ["clean", "--ub-print"], Ok(()))
+            .verify_return_data(["make", "clean", "--ub-print"], Some(tree.dir()))
+            .verify_return_data_env(["upbuild", "--ub-select=target", "--", "clean", "--ub-print"], Some(tree.run_dir()), [("UPBUILD_LEVEL", "1")])
+            .verify_recursed(&tree.dir(), &tree.run_dir())
+            .done();
+
+        // without provided args the entry keeps its own defaults
+        TestRun::new()
+            .add_return_data(Ok(0))
+            .add_return_data(Ok(0))
+            .run_with_path(&tree.file(), file_data, [], Ok(()))
+            .verify_return_data(["make"], Some(tree.dir()))
+            .verify_return_data_env(["upbuild", "--ub-select=target", "default"], Some(tree.run_dir()), [("UPBUILD_LEVEL", "1")])
+            .verify_recursed(&tree.dir(), &tree.run_dir())
+            .done();
+
+        let file_data = "make\n&&\nupbuild\n@no-forward-args\n";
+        TestRun::new()
+            .add_return_data(Ok(0))
+            .add_return_data(Ok(0))
+            .run_with_path(&tree.file(), file_data, ["clean"], Ok(()))
+            .verify_return_data(["make", "clean"], Some(tree.dir()))
+            .verify_return_data_env(["upbuild"], Some(tree.run_dir()), [("UPBUILD_LEVEL", "1")])
+            .verify_recursed(&tree.dir(), &tree.run_dir())
+            .done();
+    }
+
+    #[test]
+    fn recurse_propagates_selection() {
+        let file_data = include_str!("../tests/recurse.upbuild");
+        let tree = ParentTree::new("recurse-propagates-selection");
+        TestRun::new()
+            .reject(["slow", "docs"])
+            .add_return_data(Ok(0))
+            .add_return_data(Ok(0))
+            .run_with_path(&tree.file(), file_data, ["all"], Ok(()))
+            .verify_return_data(["make", "tests", "all"], Some(tree.dir()))
+            .verify_return_data_env(["upbuild", "--ub-reject=docs", "--ub-reject=slow", "--", "all"], Some(tree.run_dir()), [("UPBUILD_LEVEL", "1")])
+            .verify_recursed(&tree.dir(), &tree.run_dir())
+            .done();
+
+        let file_data = "make\n@tags=host\n&&\nupbuild\n@tags=host\n";
+        TestRun::new()
+            .select(["host"])
+            .add_return_data(Ok(0))
+            .add_return_data(Ok(0))
+            .run_with_path(&tree.file(), file_data, [], Ok(()))
+            .verify_return_data(["make"], Some(tree.dir()))
+            .verify_return_data_env(["upbuild", "--ub-select=host"], Some(tree.run_dir()), [("UPBUILD_LEVEL", "1")])
+            .verify_recursed(&tree.dir(), &tree.run_dir())
+            .done();
+
+        let file_data = "make\n@tags=host\n&&\nupbuild\n@tags=host\n@no-propagate\n";
+        TestRun::new()
+            .select(["host"])
+            .add_return_data(Ok(0))
+            .add_return_data(Ok(0))
+            .run_with_path(&tree.file(), file_data, [], Ok(()))
+            .verify_return_data(["make"], Some(tree.dir()))
+            .verify_return_data_env(["upbuild"], Some(tree.run_dir()), [("UPBUILD_LEVEL", "1")])
+            .verify_recursed(&tree.dir(), &tree.run_dir())
+            .done();
+
+        // a selection from UPBUILD_TAGS is passed on as flags, which
+        // replace it in the recursive call rather than add to it
+        let file = ClassicFile::parse_lines("make\n@tags=ci\n&&\nupbuild\n@tags=ci,docs\n".lines()).unwrap();
+        let env = |k: &str| (k == "UPBUILD_TAGS").then(|| String::from("ci,!slow"));
+        let recurse = |args: &[&str]| {
+            let (_, cfg) = Config::parse_with_env(args.iter().map(|x| x.to_string()), env);
+            let plan = Exec::plan(Path::new(&tree.file()), &file, &cfg, &[]).unwrap();
+            let argv = plan.last().unwrap().argv.clone();
+            let (_, child) = Config::parse_with_env(argv.clone().into_iter(), env);
+            assert_eq!((child.select(), child.reject()), (cfg.select(), cfg.reject()), "{:?}", argv);
+            argv
+        };
+        assert_eq!(recurse(&["upbuild"]), ["upbuild", "--ub-select=ci", "--ub-reject=slow"]);
+        assert_eq!(recurse(&["upbuild", "--ub-select=docs"]), ["upbuild", "--ub-select=docs"]);
+    }
+
+    #[derive(Default)]
+    struct TestHooks {
+        events: Mutex<Vec<String>>,
+        skip: Vec<String>,
+    }
+
+    impl ExecHooks for Arc<TestHooks> {
+        fn run_start(&self, path: &Path) {
+            self.events.lock().unwrap().push(format!("start {}", path.display()));
+        }
+
+        fn before_command(&self, args: &[String], dir: Option<&Path>) -> bool {
+            self.events.lock().unwrap().push(format!("before {} {:?}", args.join(" "), dir));
+            !self.skip.contains(&args.join(" "))
+        }
+
+        fn after_command(&self, code: RetCode, _duration: std::time::Duration) {
+            self.events.lock().unwrap().push(format!("after {}", code));
+        }
+
+        fn after_failure(&self, error: &Error, _duration: std::time::Duration) {
+            self.events.lock().unwrap().push(format!("failure {}", error));
+        }
+
+        fn run_end(&self, result: &Result<()>) {
+            self.events.lock().unwrap().push(format!("end {}", result.is_ok()));
+        }
+    }
+
+    #[test]
+    fn hooks() {
+        let file = ClassicFile::parse_lines(include_str!("../tests/uv4.upbuild").lines()).unwrap();
+        let runner = MockRunner::new();
+        runner.push_result(Ok(1));
+
+        let hooks = Arc::new(TestHooks::default());
+        let e = Exec::with_hooks(Box::new(runner.clone()), Box::new(hooks.clone()));
+        e.run(Path::new(".upbuild"), &file, &Config::default(), &[]).expect("Should pass");
+        assert_eq!(*hooks.events.lock().unwrap(), [
+            "start .upbuild",
+            "before uv4 -j0 -b project.uvproj -o log.txt None",
+            "after 0",
+            "end true",
+        ]);
+        assert_eq!(runner.runs().len(), 1);
+
+        // skipped commands aren't run
+        let file = ClassicFile::parse_lines(include_str!("../tests/cmake.upbuild").lines()).unwrap();
+        let runner = MockRunner::new();
+        runner.push_result(Ok(2));
+
+        let hooks = Arc::new(TestHooks { skip: vec![String::from("cmake ..")], ..Default::default() });
+        let e = Exec::with_hooks(Box::new(runner.clone()), Box::new(hooks.clone()));
+        e.run(Path::new(".upbuild"), &file, &Config::default(), &[]).expect_err("Should fail");
+        assert_eq!(*hooks.events.lock().unwrap(), [
+            "start .upbuild",
+            "before cmake .. Some(\"build\")",
+            "before cmake --build . Some(\"build\")",
+            "after 2",
+            "end false",
+        ]);
+        let runs = runner.runs();
+        assert_eq!(runs.len(), 1);
+        assert_eq!(runs[0].cmd, ["cmake", "--build", "."]);
+        assert!(runner.mkdirs().is_empty(), "Skipped command shouldn't mkdir {:#?}", runner.mkdirs());
+
+        // as are those that can't be run
+        let file = ClassicFile::parse_lines("make
+&&
+make
+install
+".lines()).unwrap();
+        let runner = MockRunner::new();
+        runner.push_result(Err(Error::ExitWithSignal(9, false)));
+
+        let hooks = Arc::new(TestHooks::default());
+        let e = Exec::with_hooks(Box::new(runner.clone()), Box::new(hooks.clone()));
+        e.run(Path::new(".upbuild"), &file, &Config::default(), &[]).expect_err("Should fail");
+        let failure = Error::InCommand(1, None, String::from("make"), Box::new(Error::ExitWithSignal(9, false)));
+        assert_eq!(*hooks.events.lock().unwrap(), [
+            String::from("start .upbuild"),
+            String::from("before make None"),
+            format!("failure {}", failure),
+            String::from("end false"),
+        ]);
+        assert_eq!(runner.runs().len(), 1);
+    }
+
+    #[test]
+    fn combined_hooks() {
+        let file = ClassicFile::parse_lines(include_str!("../tests/cmake.upbuild").lines()).unwrap();
+        let runner = MockRunner::new();
+        runner.push_result(Ok(0));
+
+        let first = Arc::new(TestHooks { skip: vec![String::from("cmake ..")], ..Default::default() });
+        let second = Arc::new(TestHooks::default());
+        let hooks: Vec<Box<dyn ExecHooks>> = vec![Box::new(first.clone()), Box::new(second.clone())];
+        let e = Exec::with_hooks(Box::new(runner.clone()), Box::new(hooks));
+        e.run(Path::new(".upbuild"), &file, &Config::default(), &[]).expect("Should pass");
+
+        // both see every command, even if the first vetoes it
+        for h in [first, second] {
+            assert_eq!(*h.events.lock().unwrap(), [
+                "start .upbuild",
+                "before cmake .. Some(\"build\")",
+                "before cmake --build . Some(\"build\")",
+                "after 0",
+                "end true",
+            ]);
+        }
+        assert_eq!(runner.runs().len(), 1);
+    }
+
+    #[test]
+    fn level() {
+        let file_data = include_str!("../tests/recurse.upbuild");
+        let tree = ParentTree::new("level");
+        TestRun::new()
+            .level(2)
+            .add_return_data(Ok(0))
+            .add_return_data(Ok(0))
+            .run_with_path(&tree.file(), file_data, [], Ok(()))
+            .verify_return_data(["make", "tests"], Some(tree.dir()))
+            .verify_return_data_env(["upbuild"], Some(tree.run_dir()), [("UPBUILD_LEVEL", "3")])
+            .verify_recursed(&tree.dir(), &tree.run_dir())
+            .done();
+
+        // leaving is shown even on failure
+        TestRun::new()
+            .level(1)
+            .add_return_data(Ok(0))
+            .add_return_data(Ok(2))
+            .run_with_path(&tree.file(), file_data, [], Err(in_command(2, "upbuild", Error::ExitWithExitCode(2))))
+            .verify_return_data(["make", "tests"], Some(tree.dir()))
+            .verify_return_data_env(["upbuild"], Some(tree.run_dir()), [("UPBUILD_LEVEL", "2")])
+            .verify_recursed(&tree.dir(), &tree.run_dir())
+            .done();
+    }
+
+    #[test]
+    fn pinned_file() {
+        let file_data = include_str!("../tests/recurse.upbuild");
+        let tree = ParentTree::new("pinned-file");
+        // the parent searches for its own file
+        TestRun::new()
+            .file("/pinned/.upbuild")
+            .add_return_data(Ok(0))
+            .add_return_data(Ok(0))
+            .run_with_path(&tree.file(), file_data, [], Ok(()))
+            .verify_return_data(["make", "tests"], Some(tree.dir()))
+            .verify_return_data_env(["upbuild"], Some(tree.run_dir()), [("UPBUILD_LEVEL", "1"), ("UPBUILD_FILE", "")])
+            .verify_recursed(&tree.dir(), &tree.run_dir())
+            .done();
+    }
+
+    #[test]
+    fn non_local() {
+        let file_data = include_str!("../tests/manual.upbuild");
+
+        TestRun::new()
+            .add_return_data(Ok(0))
+            .add_return_data(Ok(0))
+            .run_with_path(".upbuild", file_data, [], Ok(()))
+            .verify_return_data(["make", "tests"], None)
+            .verify_return_data(["make", "cross"], None)
+            .done();
+
+        TestRun::new()
+            .add_return_data(Ok(0))
+            .add_return_data(Ok(0))
+            .run_with_path("./upbuild", file_data, [], Ok(()))
+            .verify_return_data(["make", "tests"], None)
+            .verify_return_data(["make", "cross"], None)
+            .done();
+
+        let dot_dot_path = PathBuf::from("..").canonicalize().unwrap().display().to_string();
+        TestRun::new()
+            .add_return_data(Ok(0))
+            .add_return_data(Ok(0))
+            .run_with_path("../.upbuild", file_data, [], Ok(()))
+            .verify_return_data(["make", "tests"], Some("..".into()))
+            .verify_return_data(["make", "cross"], Some("..".into()))
+            .verify_cd_dir(&dot_dot_path)
+            .verify_leave_dir(&dot_dot_path)
+            .done();
+    }
+
+    #[test]
+    fn cmake() {
+        let file_data = include_str!("../tests/cmake.upbuild");
+
+        TestRun::new()
+            .add_return_data(Ok(0))
+            .add_return_data(Ok(0))
+            .run(file_data, [], Ok(()))
+            .verify_return_data(["cmake", ".."], Some("build".into()))
+            .verify_return_data(["cmake", "--build", "."], Some("build".into()))
+            .verify_cd_dir(absolute("build"))
+            .verify_leave_dir(absolute("build"))
+            .verify_mkdir("build")
+            .done();
+    }
+
+    fn mkdir_error() -> Error {
+        Error::UnableToCreateDir("build".to_string(), std::io::Error::from(std::io::ErrorKind::PermissionDenied))
+    }
+
+    #[test]
+    fn mkdir_failure() {
+        let file_data = include_str!("../tests/cmake.upbuild");
+
+        // fatal by default
+        TestRun::new()
+            .add_mkdir_result(Err(mkdir_error()))
+            .run(file_data, [], Err(in_command(1, "cmake", mkdir_error())))
+            .verify_mkdir("build")
+            .done();
+
+        // but can be ignored
+        TestRun::new()
+            .ignore_mkdir_errors()
+            .add_mkdir_result(Err(mkdir_error()))
+            .add_return_data(Ok(0))
+            .add_return_data(Ok(0))
+            .run(file_data, [], Ok(()))
+            .verify_return_data(["cmake", ".."], Some("build".into()))
+            .verify_return_data(["cmake", "--build", "."], Some("build".into()))
+            .verify_cd_dir(absolute("build"))
+            .verify_leave_dir(absolute("build"))
+            .verify_mkdir("build")
+            .done();
+    }
+
+    // The process's own umask
+    #[cfg(target_os = "linux")]
+    fn current_umask() -> u32 {
+        let status = std::fs::read_to_string("/proc/self/status").unwrap();
+        let mask = status.lines().find_map(|l| l.strip_prefix("Umask:")).expect("should have a umask");
+        u32::from_str_radix(mask.trim(), 8).unwrap()
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn mkdir_umask() {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = |d: &Path| std::fs::metadata(d).unwrap().permissions().mode() & 0o777;
+
+        let dir = TempDir::new("mkdir-umask");
+        std::fs::create_dir(dir.join("old")).unwrap();
+        std::fs::set_permissions(dir.join("old"), std::fs::Permissions::from_mode(0o700)).unwrap();
+        let file = ClassicFile::parse_lines("true\n@mkdir=old/new/build\n@umask=027\n".lines()).unwrap();
+        let outside = current_umask();
+        assert_ne!(outside, 0o027);
+
+        // --ub-print has no side effects
+        let cfg = Config::builder().print(true).build();
+        Exec::new(print_runner()).run(&dir.join(".upbuild"), &file, &cfg, &[]).unwrap();
+        assert!(!dir.join("old/new").exists());
+
+        // only what's created is given the mode, upbuild's mask is untouched
+        Exec::new(process_runner()).run(&dir.join(".upbuild"), &file, &Config::default(), &[]).unwrap();
+        assert_eq!(mode(&dir.join("old")), 0o700);
+        assert_eq!(mode(&dir.join("old/new")), 0o750);
+        assert_eq!(mode(&dir.join("old/new/build")), 0o750);
+        assert_eq!(current_umask(), outside);
+    }
+
+    #[test]
+    fn missing_dir() {
+        let file_data = include_str!("../tests/cmake.upbuild");
+
+        TestRun::new()
+            .missing_dir("build")
+            .run(file_data, [], Err(in_command(1, "cmake", Error::MissingDir("build".to_string(), "cmake ..".to_string()))))
+            .verify_mkdir("build")
+            .done();
+
+        // ignored mkdir failure is still caught before running
+        TestRun::new()
+            .ignore_mkdir_errors()
+            .missing_dir("build")
+            .add_mkdir_result(Err(mkdir_error()))
+            .run(file_data, [], Err(in_command(1, "cmake", Error::MissingDir("build".to_string(), "cmake ..".to_string()))))
+            .verify_mkdir("build")
+            .done();
+    }
+
+    #[test]
+    fn trace() {
+        let file_data = include_str!("../tests/cmake.upbuild");
+
+        TestRun::new()
+            .trace()
+            .add_return_data(Ok(0))
+            .add_return_data(Ok(0))
+            .run(file_data, [], Ok(()))
+            .verify_return_data(["cmake", ".."], Some("build".into()))
+            .verify_return_data(["cmake", "--build", "."], Some("build".into()))
+            .verify_trace("cd build && cmake ..")
+            .verify_trace("cd build && cmake --build .")
+            .verify_cd_dir(absolute("build"))
+            .verify_leave_dir(absolute("build"))
+            .verify_mkdir("build")
+            .done();
+
+        let file_data = include_str!("../tests/args.upbuild");
+        TestRun::new()
+            .trace()
+            .add_return_data(Ok(0))
+            .add_return_data(Ok(0))
+            .run(file_data, ["a b"], Ok(()))
+            .verify_return_data(["make", "-j8", "BUILD_MODE=host_debug", "a b"], None)
+            .verify_return_data(["echo", "foo", "a b"], None)
+            .verify_trace("make -j8 BUILD_MODE=host_debug 'a b'")
+            .verify_trace("echo foo 'a b'")
+            .done();
+
+        assert_eq!(Exec::trace_line("12:03:44", &args_vec(["cmake", ".."]), Some(Path::new("my build"))),
+                   "+ [12:03:44] cd 'my build' && cmake ..");
+    }
+
+    #[test]
+    #[cfg(target_family = "unix")]
+    fn cd() {
+        // Should show when we revert back to original dir (if it wasn't already printed)
+        let dot_dot_path = PathBuf::from("..").canonicalize().unwrap().display().to_string();
+        let dot_path = PathBuf::from(".").canonicalize().unwrap().display().to_string();
+        let file_data = include_str!("../tests/cd.upbuild");
+        TestRun::new()
+            .add_return_data(Ok(0))
+            .add_return_data(Ok(0))
+            .add_return_data(Ok(0))
+            .add_return_data(Ok(0))
+            .add_return_data(Ok(0))
+            .add_return_data(Ok(0))
+            .add_return_data(Ok(0))
+            .add_return_data(Ok(0))
+            .run(file_data, [], Ok(()))
+            .verify_return_data(["echo", "1"], None)
+            .verify_return_data(["echo", "2"], Some("/some/dir".into()))
+            .verify_return_data(["echo", "3"], Some("/some/dir".into()))
+            .verify_return_data(["echo", "4"], None)
+            .verify_return_data(["echo", "5"], Some("/some/dir".into()))
+            .verify_return_data(["echo", "6"], Some("/some/other/dir".into()))
+            .verify_return_data(["echo", "7"], None)
+            .verify_return_data(["echo", "8"], some_path("some/subdir"))
+            .verify_cd_dir("/some/dir")
+            .verify_leave_dir("/some/dir")
+            .verify_cd_dir(&dot_path)
+            .verify_leave_dir(&dot_path)
+            .verify_cd_dir("/some/dir")
+            .verify_leave_dir("/some/dir")
+            .verify_cd_dir("/some/other/dir")
+            .verify_leave_dir("/some/other/dir")
+            .verify_cd_dir(&dot_path)
+            .verify_leave_dir(&dot_path)
+            .verify_cd_dir(absolute("some/subdir"))
+            .verify_leave_dir(absolute("some/subdir"))
+            .done();
+
+        // Should show when we revert back to original dir (if it wasalready printed)
+        TestRun::new()
+            .add_return_data(Ok(0))
+            .add_return_data(Ok(0))
+            .add_return_data(Ok(0))
+            .add_return_data(Ok(0))
+            .add_return_data(Ok(0))
+            .add_return_data(Ok(0))
+            .add_return_data(Ok(0))
+            .add_return_data(Ok(0))
+            .run_with_path("../.upbuild", file_data, [], Ok(()))
+            .verify_return_data(["echo", "1"], Some("..".into()))
+            .verify_return_data(["echo", "2"], Some("/some/dir".into()))
+            .verify_return_data(["echo", "3"], Some("/some/dir".into()))
+            .verify_return_data(["echo", "4"], Some("..".into()))
+            .verify_return_data(["echo", "5"], Some("/some/dir".into()))
+            .verify_return_data(["echo", "6"], Some("/some/other/dir".into()))
+            .verify_return_data(["echo", "7"], Some("..".into()))
+            .verify_return_data(["echo", "8"], some_path("../some/subdir"))
+            .verify_cd_dir(&dot_dot_path)
+            .verify_leave_dir(&dot_dot_path)
+            .verify_cd_dir("/some/dir")
+            .verify_leave_dir("/some/dir")
+            .verify_cd_dir(&dot_dot_path)
+            .verify_leave_dir(&dot_dot_path)
+            .verify_cd_dir("/some/dir")
+            .verify_leave_dir("/some/dir")
+            .verify_cd_dir("/some/other/dir")
+            .verify_leave_dir("/some/other/dir")
+            .verify_cd_dir(&dot_dot_path)
+            .verify_leave_dir(&dot_dot_path)
+            .verify_cd_dir(absolute_from(&dot_dot_path, "some/subdir"))
+            .verify_leave_dir(absolute_from(&dot_dot_path, "some/subdir"))
+            .done();
+    }
+
+    #[test]
+    #[cfg(not(target_family = "unix"))]
+    fn cd() {
+        // Should show when we revert back to original dir (if it wasn't already printed)
+        let dot_dot_path = PathBuf::from("..").canonicalize().unwrap().display().to_string();
+        let dot_path = PathBuf::from(".").canonicalize().unwrap().display().to_string();
+        let file_data = include_str!("../tests/cd.win.upbuild");
+        TestRun::new()
+            .add_return_data(Ok(0))
+            .add_return_data(Ok(0))
+            .add_return_data(Ok(0))
+            .add_return_data(Ok(0))
+            .add_return_data(Ok(0))
+            .add_return_data(Ok(0))
+            .add_return_data(Ok(0))
+            .add_return_data(Ok(0))
+            .run(file_data, [], Ok(()))
+            .verify_return_data(["echo", "1"], None)
+            .verify_return_data(["echo", "2"], Some("\\some\\dir".into()))
+            .verify_return_data(["echo", "3"], Some("\\some\\dir".into()))
+            .verify_return_data(["echo", "4"], None)
+            .verify_return_data(["echo", "5"], Some("\\some\\dir".into()))
+            .verify_return_data(["echo", "6"], Some("\\some\\other\\dir".into()))
+            .verify_return_data(["echo", "7"], None)
+            .verify_return_data(["echo", "8"], some_path("some\\subdir"))
+            .verify_cd_dir(absolute("\\some\\dir"))
+            .verify_leave_dir(absolute("\\some\\dir"))
+            .verify_cd_dir(&dot_path)
+            .verify_leave_dir(&dot_path)
+            .verify_cd_dir(absolute("\\some\\dir"))
+            .verify_leave_dir(absolute("\\some\\dir"))
+            .verify_cd_dir(absolute("\\some\\other\\dir"))
+            .verify_leave_dir(absolute("\\some\\other\\dir"))
+            .verify_cd_dir(&dot_path)
+            .verify_leave_dir(&dot_path)
+            .verify_cd_dir(absolute("some\\subdir"))
+            .verify_leave_dir(absolute("some\\subdir"))
+            .done();
+
+        // Should show when we revert back to original dir (if it wasalready printed)
+        TestRun::new()
+            .add_return_data(Ok(0))
+            .add_return_data(Ok(0))
+            .add_return_data(Ok(0))
+            .add_return_data(Ok(0))
+            .add_return_data(Ok(0))
+            .add_return_data(Ok(0))
+            .add_return_data(Ok(0))
+            .add_return_data(Ok(0))
+            .run_with_path("..\\.upbuild", file_data, [], Ok(()))
+            .verify_return_data(["echo", "1"], Some("..".into()))
+            .verify_return_data(["echo", "2"], Some("\\some\\dir".into()))
+            .verify_return_data(["echo", "3"], Some("\\some\\dir".into()))
+            .verify_return_data(["echo", "4"], Some("..".into()))
+            .verify_return_data(["echo", "5"], Some("\\some\\dir".into()))
+            .verify_return_data(["echo", "6"], Some("\\some\\other\\dir".into()))
+            .verify_return_data(["echo", "7"], Some("..".into()))
+            .verify_return_data(["echo", "8"], some_path("..\\some\\subdir"))
+            .verify_cd_dir(&dot_dot_path)
+            .verify_leave_dir(&dot_dot_path)
+            .verify_cd_dir(absolute("\\some\\dir"))
+            .verify_leave_dir(absolute("\\some\\dir"))
+            .verify_cd_dir(&dot_dot_path)
+            .verify_leave_dir(&dot_dot_path)
+            .verify_cd_dir(absolute("\\some\\dir"))
+            .verify_leave_dir(absolute("\\some\\dir"))
+            .verify_cd_dir(absolute("\\some\\other\\dir"))
+            .verify_leave_dir(absolute("\\some\\other\\dir"))
+            .verify_cd_dir(&dot_dot_path)
+            .verify_leave_dir(&dot_dot_path)
+            .verify_cd_dir(absolute_from(&dot_dot_path, "some\\subdir"))
+            .verify_leave_dir(absolute_from(&dot_dot_path, "some\\subdir"))
+            .done();
+    }
+
+    /// result_is_fail if result is error, or code is non-zero
+    fn result_is_fail(res: &Result<isize>) -> bool {
+        return res.is_err() || *res.as_ref().unwrap() != 0;
+    }
+
+    #[test]
+    fn test_simplified() {
+        let dir = std::env::temp_dir();
+        assert_eq!(simplified(dir.clone()), dir);
+        assert_eq!(simplified(PathBuf::from("relative/dir")), PathBuf::from("relative/dir"));
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn test_simplified_windows() {
+        let cases = [
+            (r"\\?\C:\work\proj", r"C:\work\proj"),
+            (r"\\?\UNC\server\share\proj", r"\\server\share\proj"),
+            (r"\\server\share\proj", r"\\server\share\proj"),
+            (r"C:\work", r"C:\work"),
+            (r"\\?\Volume{0b1e}\work", r"\\?\Volume{0b1e}\work"),
+        ];
+        for (path, expected) in cases {
+            assert_eq!(simplified(PathBuf::from(path)), PathBuf::from(expected), "{}", path);
+        }
+
+        // too long to work without the prefix
+        let long = format!(r"\\?\C:\{}", "d\\".repeat(140));
+        assert_eq!(simplified(PathBuf::from(&long)), PathBuf::from(&long));
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn process_runner_long_dir_test() {
+        let base = TempDir::new("long");
+        let dir = (0..30).fold(base.to_path_buf(), |d, i| d.join(format!("directory{:02}", i)));
+        assert!(dir.as_os_str().len() > MAX_PATH);
+
+        let p = ProcessRunner::default();
+        p.check_mkdir(&dir).expect("long @mkdir");
+        assert!(p.is_dir(&dir));
+
+        // announced as is, the prefix being needed
+        let announced = Exec::announced_dir(&dir);
+        assert!(announced.is_dir(), "{}", announced.display());
+        assert!(announced.ends_with("directory29"));
+
+        // and a short one without it
+        assert!(!Exec::announced_dir(&base).as_os_str().to_string_lossy().starts_with(r"\\?\"));
+    }
+
+    /// On windows std::process::Command evaluates the
+    /// executable _before_ the `current_dir()` is applied
+    #[test]
+    fn process_runner_win32_dir_test() {
+        let p = ProcessRunner::default();
+        let (comm, path) = if cfg!(windows) { (".\\run.bat", "tests/win/") } else { ("./run.sh", "tests/sh/") };
+        let res = p.run(&args_vec([comm]), some_path(path).as_deref(), &RunSettings::default());
+        println!("res={:?}", res);
+        assert_eq!(res.expect("expected OK"), 0);
+
+        // Try alternate formats to see how the runner works
+        if cfg!(windows) {
+            let (comm, path) = ("./run.bat", "tests/win/");
+            let res = p.run(&args_vec([comm]), some_path(path).as_deref(), &RunSettings::default());
+            println!("res={:?}", res);
+            assert_eq!(res.expect("expected OK"), 0);
+
+            let (comm, path) = ("./run.bat", "tests\\win\\");
+            let res = p.run(&args_vec([comm]), some_path(path).as_deref(), &RunSettings::default());
+            println!("res={:?}", res);
+            assert_eq!(res.expect("expected OK"), 0);
+
+            // in DOS you don't need ./
+            let (comm, path) = ("run.bat", "tests\\win\\");
+            let res = p.run(&args_vec([comm]), some_path(path).as_deref(), &RunSettings::default());
+            println!("res={:?}", res);
+            assert_eq!(res.expect("expected OK"), 0);
+
+            // Ensure it fails if not in
+            let (comm, path) = ("run.bat", "tests\\");
+            let res = p.run(&args_vec([comm]), some_path(path).as_deref(), &RunSettings::default());
+            println!("res={:?}", res);
+            assert!(result_is_fail(&res), "Expected fail got {:?}", res);
+        }
+    }
+
+    #[test]
+    fn process_runner_arg_test() {
+        let p = ProcessRunner::default();
+        let (comm, path) = if cfg!(windows) { (".\\run.bat", "tests/win/") } else { ("./run.sh", "tests/sh/") };
+        let res = p.run(&args_vec([comm, "1"]), some_path(path).as_deref(), &RunSettings::default());
+        println!("res={:?}", res);
+        assert_eq!(res.expect("expected OK(1)"), 1);
+
+        let res = p.run(&args_vec([comm, "100"]), some_path(path).as_deref(), &RunSettings::default());
+        println!("res={:?}", res);
+        assert_eq!(res.expect("expected OK(100)"), 100);
+    }
+
+    fn some_path(s: &str) -> Option<PathBuf> {
+        Some(PathBuf::from(s))
+    }
+
+    #[test]
+    fn run_dir() {
+        fn run_dir(main_working_dir: Option<&str>, cmd_dir: Option<&str>) -> Option<PathBuf> {
+            Exec::run_dir(main_working_dir.map(Path::new), cmd_dir.map(Path::new)).map(Cow::into_owned)
+        }
+
+        let main_working_dir = None;
+        assert_eq!(run_dir(main_working_dir, None), None);
+        assert_eq!(run_dir(main_working_dir, Some("..")), some_path(".."));
+        assert_eq!(run_dir(main_working_dir, Some("/a")), some_path("/a"));
+
+        let main_working_dir = Some("..");
+        assert_eq!(run_dir(main_working_dir, None), some_path(".."));
+        assert_eq!(run_dir(main_working_dir, Some("..")), some_path("../.."));
+        assert_eq!(run_dir(main_working_dir, Some("/a")), some_path("/a"));
+
+        let main_working_dir = Some("/b");
+        assert_eq!(run_dir(main_working_dir, None), some_path("/b"));
+        assert_eq!(run_dir(main_working_dir, Some("..")), some_path("/b/.."));
+        assert_eq!(run_dir(main_working_dir, Some("/a")), some_path("/a"));
+
+        let main_working_dir = Some("b");
+        assert_eq!(run_dir(main_working_dir, None), some_path("b"));
+        assert_eq!(run_dir(main_working_dir, Some("..")), some_path("b/.."));
+        assert_eq!(run_dir(main_working_dir, Some("/a")), some_path("/a"));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn process_runner_detach_test() {
+        let p = ProcessRunner::default();
+        // field 6 of /proc/PID/stat is the session id
+        let in_own_session = args_vec(["sh", "-c", "[ $$ -eq $(cut -d' ' -f6 /proc/$$/stat) ]"]);
+
+        let res = p.run(&in_own_session, None, &RunSettings::default());
+        assert_eq!(res.expect("expected OK(1)"), 1);
+
+        let res = p.run(&in_own_session, None, &RunSettings { detach: true, ..RunSettings::default() });
+        assert_eq!(res.expect("expected OK(0)"), 0);
+    }
+
+    #[cfg(target_family = "unix")]
+    #[test]
+    fn process_runner_detach_kill_test() {
+        // killing a detached command's own group - as a timeout must, as
+        // upbuild's doesn't include it - ends it, and it's still reaped
+        // and reported.  Undetached, kill 0 would hit the tests too.
+        let p = ProcessRunner::default();
+        let started = std::time::Instant::now();
+        let res = p.run(&args_vec(["sh", "-c", "kill -TERM 0; sleep 10"]), None, &RunSettings { detach: true, ..RunSettings::default() });
+        assert_eq!(res, Err(Error::ExitWithSignal(15, false)));
+        assert!(started.elapsed() < std::time::Duration::from_secs(10));
+
+        // signalling upbuild - here the test - passes it on to the
+        // group, and upbuild waits for it to end
+        let started = std::time::Instant::now();
+        let res = p.run(&args_vec(["sh", "-c", "kill -TERM $PPID; sleep 10"]), None, &RunSettings { detach: true, ..RunSettings::default() });
+        assert_eq!(res, Err(Error::ExitWithSignal(15, false)));
+        assert!(started.elapsed() < std::time::Duration::from_secs(10));
+
+        let res = p.run_captured(&args_vec(["sh", "-c", "kill -INT $PPID; sleep 10"]), None,
+                                 &RunSettings { detach: true, ..RunSettings::default() }, true);
+        assert_eq!(res.map(|(code, _)| code), Err(Error::ExitWithSignal(2, false)));
+    }
+
+    #[test]
+    fn detach() {
+        let file_data = "make\n&&\nmake\n@detach\ninstall\n";
+
+        TestRun::new()
+            .add_return_data(Ok(0))
+            .add_return_data(Ok(0))
+            .run(file_data, [], Ok(()))
+            .verify_detached(["make"], false)
+            .verify_detached(["make", "install"], true)
             .done();
 
-        // signals should be propagated
         TestRun::new()
-            .add_return_data(Err(Error::ExitWithSignal(6)))
-            .run_without_args(file_data, Err(Error::ExitWithSignal(6)))
-            .verify_return_data(uv4_run, None)
+            .detach()
+            .add_return_data(Ok(0))
+            .add_return_data(Ok(0))
+            .run(file_data, [], Ok(()))
+            .verify_detached(["make"], true)
+            .verify_detached(["make", "install"], true)
             .done();
     }
 
+    #[cfg(target_family = "unix")]
     #[test]
-    fn test_exec_tags() {
-        let file_data = include_str!("../tests/manual.upbuild");
+    fn process_runner_umask_test() {
+        let p = ProcessRunner::default();
+        let has_umask = |mask: &str| args_vec(["sh", "-c", &format!("[ $(umask) = {} ]", mask)]);
+        let masked = RunSettings { umask: Some(0o27), ..RunSettings::default() };
+
+        let res = p.run(&has_umask("0027"), None, &masked);
+        assert_eq!(res.expect("expected OK(0)"), 0);
+        let res = p.run_pipeline(&[(&args_vec(["echo"]), None, &masked), (&has_umask("0027"), None, &masked)]);
+        assert_eq!(res.expect("expected OK([0, 0])"), [0, 0]);
+    }
+
+    #[cfg(target_family = "unix")]
+    #[test]
+    fn process_runner_stdin_test() {
+        let p = ProcessRunner::default();
+        let path = some_path("tests/sh/");
+
+        // reads hit EOF rather than waiting for input
+        let res = p.run(&args_vec(["./read.sh"]), path.as_deref(),
+                        &RunSettings { stdin: StdinSource::Null, ..RunSettings::default() });
+        assert_eq!(res.expect("expected OK(2)"), 2);
+
+        // relative to the run directory
+        let res = p.run(&args_vec(["./read.sh", "hello"]), path.as_deref(),
+                        &RunSettings { stdin: StdinSource::File("stdin.txt".into()), ..RunSettings::default() });
+        assert_eq!(res.expect("expected OK(0)"), 0);
+
+        let res = p.run(&args_vec(["./read.sh"]), path.as_deref(),
+                        &RunSettings { stdin: StdinSource::File("missing.txt".into()), ..RunSettings::default() });
+        assert!(matches!(res, Err(Error::UnableToReadStdin(_, _))), "got {:?}", res);
+    }
+
+    #[cfg(target_family = "unix")]
+    #[test]
+    fn process_runner_enoexec_test() {
+        let p = ProcessRunner::default();
+        let path = some_path("tests/sh/noshebang/");
+
+        let res = p.run(&args_vec(["./run.sh", "3"]), path.as_deref(), &RunSettings::default());
+        assert_eq!(res.expect("expected OK(3)"), 3);
+
+        // other failures aren't retried
+        let res = p.run(&args_vec(["./missing.sh"]), path.as_deref(), &RunSettings::default());
+        assert!(matches!(res, Err(Error::FailedToExec(_, _, _, _))), "got {:?}", res);
+    }
+
+    #[cfg(target_family = "unix")]
+    #[test]
+    fn process_runner_signal_test() {
+        let p = ProcessRunner::default();
+        let path = some_path("tests/sh/");
+
+        let res = p.run(&args_vec(["./kill.sh", "TERM"]), path.as_deref(), &RunSettings::default());
+        assert_eq!(res, Err(Error::ExitWithSignal(15, false)));
+        assert_eq!(res.unwrap_err().to_string(), "Process exitted with signal: 15 (SIGTERM)");
+    }
+
+    #[cfg(target_family = "unix")]
+    #[test]
+    fn process_runner_interrupt_test() {
+        // interrupting upbuild - here the test - too only stops the command
+        let p = ProcessRunner::default();
+        let path = some_path("tests/sh/");
+        let res = p.run(&args_vec(["./interrupt.sh"]), path.as_deref(), &RunSettings::default());
+        assert_eq!(res, Err(Error::ExitWithSignal(2, false)));
+        assert_eq!(res.unwrap_err().to_string(), "Process exitted with signal: 2 (SIGINT)");
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn process_runner_exit_code_test() {
+        let p = ProcessRunner::default();
+        // STATUS_ACCESS_VIOLATION, as from a crash
+        for code in [-1073741819, -1, 300, 65536] {
+            let res = p.run(&args_vec(["cmd", "/c", &format!("exit {}", code)]), None, &RunSettings::default());
+            assert_eq!(res, Ok(code), "exit {}", code);
+        }
+    }
+
+    #[test]
+    fn edit_distance_test() {
+        assert_eq!(edit_distance("cmake", "cmake"), 0);
+        assert_eq!(edit_distance("cmak", "cmake"), 1);
+        assert_eq!(edit_distance("mkae", "make"), 2);
+        assert_eq!(edit_distance("", "make"), 4);
+        assert_eq!(edit_distance("ninja", "ninja-build"), 6);
+    }
+
+    #[test]
+    fn similar_command_test() {
+        let dir = TempDir::new("similar");
+        for name in ["cmake", "ctest", "make", "ninja"] {
+            std::fs::write(dir.join(name), "").unwrap();
+        }
+        let path = std::env::join_paths([dir.join("missing"), dir.to_path_buf()]).unwrap();
+
+        assert_eq!(similar_command("cmak", &path).as_deref(), Some("cmake"));
+        assert_eq!(similar_command("mkae", &path), None); // too short for 2 edits
+        assert_eq!(similar_command("ninj", &path).as_deref(), Some("ninja"));
+        assert_eq!(similar_command("gcc", &path), None);
+        assert_eq!(similar_command("make", &path).as_deref(), Some("cmake")); // exact matches aren't suggestions
+    }
+
+    #[cfg(target_family = "unix")]
+    #[test]
+    fn process_runner_not_found_test() {
+        let p = ProcessRunner::default();
+        let path = some_path("tests/sh/");
+        let dir = Path::new("tests/sh").canonicalize().unwrap();
+
+        let res = p.run(&args_vec(["run.sh"]), path.as_deref(), &RunSettings::default());
+        match res {
+            Err(Error::FailedToExec(argv, what, _, notes)) => {
+                assert_eq!(argv, ["run.sh"]);
+                assert_eq!(what, dir.display().to_string());
+                assert_eq!(notes, ["'run.sh' was not found on PATH".to_string(),
+                                   format!("{}/run.sh exists - did you mean ./run.sh?", dir.display())]);
+            },
+            _ => panic!("unexpected {:?}", res),
+        }
+
+        let res = p.run(&args_vec(["./missing.sh"]), path.as_deref(), &RunSettings::default());
+        match res {
+            Err(Error::FailedToExec(_, _, _, notes)) => assert_eq!(notes, [format!("{}/missing.sh does not exist", dir.display())]),
+            _ => panic!("unexpected {:?}", res),
+        }
+
+        let res = p.run(&args_vec(["./stdin.txt"]), path.as_deref(), &RunSettings::default());
+        match res {
+            Err(Error::FailedToExec(_, _, _, notes)) => assert_eq!(notes, [format!("{}/stdin.txt is not executable", dir.display())]),
+            _ => panic!("unexpected {:?}", res),
+        }
+    }
+
+    #[test]
+    fn exec() {
+        let file_data = "make\n&&\ngdb\n@exec\nfirmware.elf\n&&\nmake\n@manual\n@tags=docs\ndocs\n";
+
         TestRun::new()
             .add_return_data(Ok(0))
             .add_return_data(Ok(0))
-            .run_without_args(file_data, Ok(()))
-            .verify_return_data(["make", "tests"], None)
-            .verify_return_data(["make", "cross"], None)
+            .run(file_data, [], Ok(()))
+            .verify_exec(["make"], false)
+            .verify_exec(["gdb", "firmware.elf"], true)
             .done();
 
+        // checked before anything is run
         TestRun::new()
-            .add_return_data(Ok(1))
-            .run_without_args(file_data, Err(Error::ExitWithExitCode(1)))
-            .verify_return_data(["make", "tests"], None)
+            .run("make\n&&\ngdb\n@exec\nfirmware.elf\n&&\nmake\ndocs\n", [], Err(Error::ExecNotLast("gdb firmware.elf".to_string())))
             .done();
+    }
+
+    #[test]
+    fn container_args() {
+        let args = args_vec(["make", "-j8"]);
+        let env = [(String::from("UPBUILD_LEVEL"), String::from("1"))];
+
+        assert_eq!(Exec::container_args("docker", "toolchain:1.2", Path::new("/src"), Path::new("/src"), &[], &args),
+                   ["docker", "run", "--rm", "-v", "/src:/work", "-w", "/work", "toolchain:1.2", "make", "-j8"]);
+        assert_eq!(Exec::container_args("podman", "toolchain:1.2", Path::new("/src"), Path::new("/src/build/arm"), &env, &args),
+                   ["podman", "run", "--rm", "-v", "/src:/work", "-w", "/work/build/arm",
+                    "-e", "UPBUILD_LEVEL=1", "toolchain:1.2", "make", "-j8"]);
+        // outside the tree
+        assert_eq!(Exec::container_args("docker", "toolchain:1.2", Path::new("/src"), Path::new("/other"), &[], &args),
+                   ["docker", "run", "--rm", "-v", "/other:/work", "-w", "/work", "toolchain:1.2", "make", "-j8"]);
+    }
+
+    #[test]
+    fn container() {
+        let file_data = "cmake\n@cd=build\n@container=toolchain:1.2\n..\n&&\nmake\n";
+        let base = absolute("");
+        let base = base.trim_end_matches(std::path::MAIN_SEPARATOR);
 
-        // select hosts tags
         TestRun::new()
-            .select(["host"])
             .add_return_data(Ok(0))
             .add_return_data(Ok(0))
-            .run_without_args(file_data, Ok(()))
-            .verify_return_data(["make", "tests"], None)
-            .verify_return_data(["make", "install"], None)
+            .run(file_data, [], Ok(()))
+            .verify_return_data(["docker", "run", "--rm", "-v", &format!("{}:/work", base), "-w", "/work/build",
+                                 "toolchain:1.2", "cmake", ".."], Some("build".into()))
+            .verify_return_data(["make"], None)
+            .verify_cd_dir(absolute("build"))
+            .verify_leave_dir(absolute("build"))
+            .verify_cd_dir(base)
+            .verify_leave_dir(base)
             .done();
 
+        // only commands needing the engine fail
         TestRun::new()
-            .select(["release"])
+            .missing_program("docker")
+            .run(file_data, [], Err(in_command(1, "cmake", Error::MissingContainerEngine("docker".to_string(), "cmake ..".to_string()))))
+            .done();
+
+        TestRun::new()
+            .missing_program("docker")
             .add_return_data(Ok(0))
-            .run_without_args(file_data, Ok(()))
+            .run("make\n&&\nmake\n@container=toolchain:1.2\ninstall\n", [], Err(in_command(2, "make", Error::MissingContainerEngine("docker".to_string(), "make install".to_string()))))
+            .verify_return_data(["make"], None)
+            .done();
+    }
+
+    #[test]
+    fn env_required() {
+        let file_data = "make\n@env-required=ARM_TOOLCHAIN_DIR\n&&\nmake\n@env-required=LICENSE_SERVER,EXTRA?,ARM_TOOLCHAIN_DIR\ninstall\n";
+
+        // every missing variable is named, and nothing runs
+        TestRun::new()
+            .set_env("LICENSE_SERVER", "")
+            .run(file_data, [], Err(Error::MissingEnv(vec!["ARM_TOOLCHAIN_DIR".to_string(), "LICENSE_SERVER".to_string(), "EXTRA".to_string()])))
+            .done();
+
+        // empty is only allowed with ?
+        TestRun::new()
+            .set_env("ARM_TOOLCHAIN_DIR", "/opt/arm")
+            .set_env("LICENSE_SERVER", "lic:1234")
+            .set_env("EXTRA", "")
+            .add_return_data(Ok(0))
+            .add_return_data(Ok(0))
+            .run(file_data, [], Ok(()))
+            .verify_return_data(["make"], None)
             .verify_return_data(["make", "install"], None)
             .done();
 
+        // only the commands that would run are checked
         TestRun::new()
-            .select(["target"])
+            .set_env("ARM_TOOLCHAIN_DIR", "/opt/arm")
             .add_return_data(Ok(0))
-            .run_without_args(file_data, Ok(()))
-            .verify_return_data(["make", "cross"], None)
+            .run("make\n@env-required=ARM_TOOLCHAIN_DIR\n&&\nmake\n@manual\n@env-required=LICENSE_SERVER\ninstall\n", [], Ok(()))
+            .verify_return_data(["make"], None)
             .done();
+    }
+
+    #[test]
+    fn ssh_args() {
+        assert_eq!(Exec::ssh_args("builder@signhost", None, &args_vec(["sign", "fw.bin"])),
+                   ["ssh", "builder@signhost", "--", "sign fw.bin"]);
+        assert_eq!(Exec::ssh_args("signhost", Some(Path::new("/srv/my keys")), &args_vec(["sign", "it's.bin"])),
+                   ["ssh", "signhost", "--", "cd '/srv/my keys' && sign 'it'\\''s.bin'"]);
+    }
+
+    #[test]
+    fn ssh() {
+        let file_data = "make\n&&\nsign\n@ssh=builder@signhost\n@cd=/srv/signing\n@retmap=3=>0\nfw.bin\n";
+
+        // the remote directory needn't exist locally
+        TestRun::new()
+            .missing_dir("/srv/signing")
+            .add_return_data(Ok(0))
+            .add_return_data(Ok(3))
+            .run(file_data, [], Ok(()))
+            .verify_return_data(["make"], None)
+            .verify_return_data(["ssh", "builder@signhost", "--", "cd /srv/signing && sign fw.bin"], None)
+            .done();
+    }
+
+    #[test]
+    fn stdin() {
+        let file_data = "make\n&&\nmake\n@stdin=inherit\ninstall\n&&\nmake\n@stdin=answers.txt\nconfig\n";
 
         TestRun::new()
-            .select(["target", "host"])
             .add_return_data(Ok(0))
             .add_return_data(Ok(0))
             .add_return_data(Ok(0))
-            .run_without_args(file_data, Ok(()))
-            .verify_return_data(["make", "tests"], None)
-            .verify_return_data(["make", "cross"], None)
-            .verify_return_data(["make", "install"], None)
+            .run(file_data, [], Ok(()))
+            .verify_stdin(["make"], StdinSource::Inherit)
+            .verify_stdin(["make", "install"], StdinSource::Inherit)
+            .verify_stdin(["make", "config"], StdinSource::File("answers.txt".into()))
             .done();
 
+        // @stdin= overrides --ub-batch
         TestRun::new()
-            .select(["target", "host"])
+            .batch()
+            .add_return_data(Ok(0))
+            .add_return_data(Ok(0))
+            .add_return_data(Ok(0))
+            .run(file_data, [], Ok(()))
+            .verify_stdin(["make"], StdinSource::Null)
+            .verify_stdin(["make", "install"], StdinSource::Inherit)
+            .verify_stdin(["make", "config"], StdinSource::File("answers.txt".into()))
+            .done();
+    }
+
+    #[test]
+    fn scan() {
+        let dir = TempDir::new("scan");
+        let log = dir.join("log.txt");
+        std::fs::write(&log, "a.c:1: error: x\na.c:2: warning: y\na.c:3: warning: z\n").unwrap();
+        let log = log.display().to_string();
+
+        let file_data = format!("uv4\n@outfile={}\n@retmap=1=>0\n&&\nmake\n@outfile={}\n@scan\n", log, log);
+
+        // only the @scan command by default
+        TestRun::new()
+            .add_return_data(Ok(1))
             .add_return_data(Ok(0))
+            .run(&file_data, [], Ok(()))
+            .verify_return_data(["uv4"], None)
+            .verify_return_data(["make"], None)
+            .verify_outfile(&log)
+            .verify_outfile(&log)
+            .verify_cd_comment("upbuild: make: 1 error, 2 warnings")
+            .done();
+
+        // all with --ub-scan
+        TestRun::new()
+            .scan()
             .add_return_data(Ok(1))
-            .run_without_args(file_data, Err(Error::ExitWithExitCode(1)))
-            .verify_return_data(["make", "tests"], None)
+            .add_return_data(Ok(0))
+            .run(&file_data, [], Ok(()))
+            .verify_return_data(["uv4"], None)
+            .verify_return_data(["make"], None)
+            .verify_outfile(&log)
+            .verify_outfile(&log)
+            .verify_cd_comment("upbuild: uv4: 1 error, 2 warnings")
+            .verify_cd_comment("upbuild: make: 1 error, 2 warnings")
+            .done();
+    }
+
+    #[test]
+    fn interrupted() {
+        let dir = TempDir::new("interrupted");
+        let log = dir.join("log.txt");
+        std::fs::write(&log, "a.c:1: error: x\n").unwrap();
+        let log = log.display().to_string();
+
+        // the interrupted command's @outfile isn't shown, nor does it
+        // fall back, but what ran before it is still summarised
+        let file_data = format!("make\n@outfile={}\n&&\nmake\ntest\n@outfile={}\n||\necho\nfailed\n", log, log);
+        TestRun::new()
+            .scan()
+            .add_return_data(Ok(0))
+            .add_return_data(Err(Error::ExitWithSignal(2, false)))
+            .run(&file_data, [], Err(in_command(2, "make", Error::ExitWithSignal(2, false))))
+            .verify_return_data(["make"], None)
+            .verify_return_data(["make", "test"], None)
+            .verify_outfile(&log)
+            .verify_cd_comment("upbuild: make: 1 error, 0 warnings")
+            .done();
+    }
+
+    #[test]
+    fn named_failure() {
+        TestRun::new()
+            .add_return_data(Ok(0))
+            .add_return_data(Ok(2))
+            .run("make\n&&\nmake\n@name=cross\ncross\n", [],
+                 Err(Error::InCommand(2, Some("cross".into()), "make".into(), Box::new(Error::ExitWithExitCode(2)))))
+            .verify_return_data(["make"], None)
             .verify_return_data(["make", "cross"], None)
             .done();
+    }
 
+    #[test]
+    fn outfile_failure() {
+        let file_data = include_str!("../tests/uv4.upbuild");
+        let uv4_run = ["uv4", "-j0", "-b", "project.uvproj", "-o", "log.txt"];
+        let missing = || Error::UnableToReadOutfile("log.txt".into(), String::new(), std::io::ErrorKind::NotFound.into());
+
+        // names the command that should have written it
         TestRun::new()
-            .select(["host"])
-            .reject(["release"])
             .add_return_data(Ok(0))
-            .run_without_args(file_data, Ok(()))
-            .verify_return_data(["make", "tests"], None)
+            .add_outfile_result(Err(missing()))
+            .run_without_args(file_data, Err(in_command(1, "uv4", Error::UnableToReadOutfile("log.txt".into(), shell::join(&uv4_run), std::io::ErrorKind::NotFound.into()))))
+            .verify_return_data(uv4_run, None)
+            .verify_outfile("log.txt")
             .done();
+    }
 
+    #[test]
+    fn outfile_pattern() {
+        let dir = TempDir::new("outfile-pattern");
+        std::fs::create_dir_all(dir.join("logs")).unwrap();
+        std::fs::write(dir.join("logs/project_1.log"), "").unwrap();
+        std::fs::write(dir.join("logs/notes.txt"), "").unwrap();
+        let cd = format!("@cd={}\n", dir.display());
+        let entering = format!("upbuild: Entering directory `{}'", dir.display());
+        let leaving = format!("upbuild: Leaving directory `{}'", dir.display());
+
+        // matched in the directory it ran in
+        let file_data = format!("uv4\n{}@outfile=logs/*.log\n", cd);
         TestRun::new()
-            .reject(["host"])
             .add_return_data(Ok(0))
-            .run_without_args(file_data, Ok(()))
-            .verify_return_data(["make", "cross"], None)
+            .run(&file_data, [], Ok(()))
+            .verify_cd_comment(&entering)
+            .verify_return_data(["uv4"], Some(dir.to_path_buf()))
+            .verify_outfile(&dir.join("logs/project_1.log").display().to_string())
+            .verify_cd_comment(&leaving)
             .done();
 
+        // or all of them
+        std::fs::write(dir.join("logs/project_2.log"), "").unwrap();
+        let file_data = format!("uv4\n{}@outfile=logs/project_?.log\n@outfile-all\n", cd);
+        let run = TestRun::new();
+        run.add_return_data(Ok(0))
+            .run(&file_data, [], Ok(()))
+            .verify_cd_comment(&entering)
+            .verify_return_data(["uv4"], Some(dir.to_path_buf()))
+            .verify_cd_comment(&leaving);
+        let mut shown: Vec<_> = std::iter::from_fn(|| run.runner.take_outfile()).map(|(f, _)| f).collect();
+        shown.sort();
+        assert_eq!(shown, [dir.join("logs/project_1.log"), dir.join("logs/project_2.log")]);
+        run.done();
+
+        // nothing matching names the pattern
+        let file_data = format!("uv4\n{}@outfile=logs/*.txt.gz\n", cd);
+        let e = Error::NoOutfileMatch(String::from("logs/*.txt.gz"), dir.display().to_string(), String::from("uv4"));
         TestRun::new()
-            .select(["target"])
             .add_return_data(Ok(0))
-            .run_without_args(file_data, Ok(()))
-            .verify_return_data(["make", "cross"], None)
+            .run(&file_data, [], Err(in_command(1, "uv4", e)))
+            .verify_cd_comment(&entering)
+            .verify_return_data(["uv4"], Some(dir.to_path_buf()))
+            .verify_cd_comment(&leaving)
             .done();
     }
 
     #[test]
-    fn args() {
-        let file_data = include_str!("../tests/args.upbuild");
+    fn capture() {
+        let file_data = "git\n@capture=VERSION\ndescribe\n&&\ngit\ntag\nv${VERSION}\n&&\nsh\n-c\necho ${VERSION} ${HOME}\n";
+
+        // later commands get the value, without the line end, as an
+        // arg and in the environment - other ${} are left for the shell
         TestRun::new()
+            .add_capture_output("1.2-3-gabc\n")
+            .add_return_data(Ok(0))
             .add_return_data(Ok(0))
             .add_return_data(Ok(0))
             .run(file_data, [], Ok(()))
-            .verify_return_data(["make", "-j8", "BUILD_MODE=host_debug", "test"], None)
-            .verify_return_data(["echo", "foo"], None)
+            .verify_captured(false)
+            .verify_return_data(["git", "describe"], None)
+            .verify_return_data_env(["git", "tag", "v1.2-3-gabc"], None, [("VERSION", "1.2-3-gabc")])
+            .verify_return_data_env(["sh", "-c", "echo 1.2-3-gabc ${HOME}"], None, [("VERSION", "1.2-3-gabc")])
             .done();
 
         TestRun::new()
+            .add_capture_output("a\r\nb\r\n")
             .add_return_data(Ok(0))
             .add_return_data(Ok(0))
-            .run(file_data, ["all"], Ok(()))
-            .verify_return_data(["make", "-j8", "BUILD_MODE=host_debug", "all"], None)
-            .verify_return_data(["echo", "foo", "all"], None)
+            .run("cat\n@capture=OUT\n@silent\nlist\n&&\necho\n${OUT}\n", [], Ok(()))
+            .verify_captured(true)
+            .verify_return_data(["cat", "list"], None)
+            .verify_return_data_env(["echo", "a\r\nb"], None, [("OUT", "a\r\nb")])
             .done();
 
+        // a failure stops the run as usual
         TestRun::new()
-            .add_return_data(Ok(0))
-            .add_return_data(Ok(0))
-            .run(file_data, ["all", "tests"], Ok(()))
-            .verify_return_data(["make", "-j8", "BUILD_MODE=host_debug", "all", "tests"], None)
-            .verify_return_data(["echo", "foo", "all", "tests"], None)
+            .add_return_data(Ok(128))
+            .run(file_data, [], Err(in_command(1, "git", Error::ExitWithExitCode(128))))
+            .verify_captured(false)
+            .verify_return_data(["git", "describe"], None)
+            .done();
+
+        // used before it's captured, or when the capture isn't selected
+        let unset = |cmd: &str| Error::UnsetCapture(String::from("VERSION"), String::from(cmd));
+        TestRun::new()
+            .run("git\ntag\nv${VERSION}\n&&\ngit\n@capture=VERSION\ndescribe\n", [],
+                 Err(in_command(1, "git", unset("git tag 'v${VERSION}'"))))
+            .done();
+        TestRun::new()
+            .run("git\n@capture=VERSION\n@manual\ndescribe\n&&\necho\n${VERSION}\n", [],
+                 Err(in_command(2, "echo", unset("echo '${VERSION}'"))))
             .done();
     }
 
     #[test]
-    fn recurse() {
-        let file_data = include_str!("../tests/recurse.upbuild");
-        let dot_dot_path = PathBuf::from("..").canonicalize().unwrap();
+    fn pipe() {
+        let file_data = "make\n&&\ngit\n@pipe\nlog\n&&\ngrep\n@retmap=1=>0\nfix\n&&\nmake\ninstall\n";
+
+        // the pair runs as one, after the commands before it
         TestRun::new()
             .add_return_data(Ok(0))
             .add_return_data(Ok(0))
+            .add_return_data(Ok(1))
+            .add_return_data(Ok(0))
             .run(file_data, [], Ok(()))
-            .verify_return_data(["make", "tests"], None)
-            .verify_return_data(["upbuild"], Some(PathBuf::from("..")))
-            .verify_cd_dir(dot_dot_path.display().to_string().as_str())
+            .verify_pipeline(2)
+            .verify_return_data(["make"], None)
+            .verify_return_data(["git", "log"], None)
+            .verify_return_data(["grep", "fix"], None)
+            .verify_return_data(["make", "install"], None)
             .done();
 
+        // either failing fails the pair, named by the one that failed
         TestRun::new()
-            .override_argv0("/path/to/upbuild")
             .add_return_data(Ok(0))
+            .add_return_data(Ok(128))
             .add_return_data(Ok(0))
-            .run(file_data, [], Ok(()))
-            .verify_return_data(["make", "tests"], None)
-            .verify_return_data(["/path/to/upbuild"], Some(PathBuf::from("..")))
-            .verify_cd_dir(dot_dot_path.display().to_string().as_str())
+            .run(file_data, [], Err(in_command(2, "git", Error::ExitWithExitCode(128))))
+            .verify_pipeline(2)
+            .verify_return_data(["make"], None)
+            .verify_return_data(["git", "log"], None)
+            .verify_return_data(["grep", "fix"], None)
             .done();
-
-        let file_data = include_str!("../tests/norecurse.upbuild");
         TestRun::new()
-            .override_argv0("/path/to/upbuild")
             .add_return_data(Ok(0))
             .add_return_data(Ok(0))
-            .run(file_data, [], Ok(()))
-            .verify_return_data(["make", "tests"], None)
-            .verify_return_data(["/path/to/upbuild"], Some(PathBuf::from("/path/to/build")))
-            .verify_cd_dir("/path/to/build")
+            .add_return_data(Ok(2))
+            .run(file_data, [], Err(in_command(3, "grep", Error::ExitWithExitCode(2))))
+            .verify_pipeline(2)
+            .verify_return_data(["make"], None)
+            .verify_return_data(["git", "log"], None)
+            .verify_return_data(["grep", "fix"], None)
+            .done();
+
+        // the command fed has to run too, and read from the pipe
+        TestRun::new()
+            .run("git\n@pipe\nlog\n&&\ngrep\n@manual\nfix\n", [], Err(Error::UnconnectedPipe(String::from("git log"))))
+            .done();
+        TestRun::new()
+            .run("git\n@pipe\nlog\n&&\ngrep\n@stdin=fixes\nfix\n", [], Err(Error::ConflictingFlags(
+                String::from("@stdin and @exec can't be used on a command fed by @pipe: grep fix"))))
             .done();
     }
 
     #[test]
-    fn non_local() {
-        let file_data = include_str!("../tests/manual.upbuild");
-
+    fn fallback() {
+        let file_data = "restore\n@tags=cache\n@retmap=3=>0\n@outfile=restore.log\n||\nfetch\n@tags=net\n@outfile=fetch.log\n&&\nmake\n";
+
+        // only run when the command before fails, after @retmap
+        for code in [0, 3] {
+            TestRun::new()
+                .add_return_data(Ok(code))
+                .add_return_data(Ok(0))
+                .run(file_data, [], Ok(()))
+                .verify_return_data(["restore"], None)
+                .verify_outfile("restore.log")
+                .verify_return_data(["make"], None)
+                .done();
+        }
         TestRun::new()
+            .add_return_data(Ok(1))
             .add_return_data(Ok(0))
             .add_return_data(Ok(0))
-            .run_with_path(".upbuild", file_data, [], Ok(()))
-            .verify_return_data(["make", "tests"], None)
-            .verify_return_data(["make", "cross"], None)
+            .run(file_data, [], Ok(()))
+            .verify_return_data(["restore"], None)
+            .verify_return_data(["fetch"], None)
+            .verify_outfile("fetch.log")
+            .verify_return_data(["make"], None)
             .done();
-
         TestRun::new()
-            .add_return_data(Ok(0))
-            .add_return_data(Ok(0))
-            .run_with_path("./upbuild", file_data, [], Ok(()))
-            .verify_return_data(["make", "tests"], None)
-            .verify_return_data(["make", "cross"], None)
+            .add_return_data(Ok(1))
+            .add_return_data(Ok(2))
+            .run(file_data, [], Err(in_command(2, "fetch", Error::ExitWithExitCode(2))))
+            .verify_return_data(["restore"], None)
+            .verify_return_data(["fetch"], None)
             .done();
 
-        let dot_dot_path = PathBuf::from("..").canonicalize().unwrap().display().to_string();
+        // a fallback that isn't selected can't recover the failure
         TestRun::new()
+            .reject(["net"])
+            .add_return_data(Ok(1))
+            .run(file_data, [], Err(in_command(1, "restore", Error::ExitWithExitCode(1))))
+            .verify_return_data(["restore"], None)
+            .done();
+        // and has nothing to fall back from if the command before isn't
+        TestRun::new()
+            .reject(["cache"])
             .add_return_data(Ok(0))
-            .add_return_data(Ok(0))
-            .run_with_path("../.upbuild", file_data, [], Ok(()))
-            .verify_return_data(["make", "tests"], Some("..".into()))
-            .verify_return_data(["make", "cross"], Some("..".into()))
-            .verify_cd_dir(&dot_dot_path)
+            .run(file_data, [], Ok(()))
+            .verify_return_data(["make"], None)
+            .done();
+        TestRun::new()
+            .select(["net"])
+            .run(file_data, [], Err(Error::NothingSelected(vec![String::from("net")], Vec::new())))
             .done();
-    }
-
-    #[test]
-    fn cmake() {
-        let file_data = include_str!("../tests/cmake.upbuild");
 
+        // each fallback only runs if the one before it failed too
+        let file_data = "a\n||\nb\n||\nc\n";
         TestRun::new()
-            .add_return_data(Ok(0))
+            .add_return_data(Ok(1))
             .add_return_data(Ok(0))
             .run(file_data, [], Ok(()))
-            .verify_return_data(["cmake", ".."], Some("build".into()))
-            .verify_return_data(["cmake", "--build", "."], Some("build".into()))
-            .verify_cd_dir("build")
-            .verify_mkdir("build")
+            .verify_return_data(["a"], None)
+            .verify_return_data(["b"], None)
+            .done();
+        TestRun::new()
+            .add_return_data(Ok(1))
+            .add_return_data(Ok(1))
+            .add_return_data(Ok(1))
+            .run(file_data, [], Err(in_command(3, "c", Error::ExitWithExitCode(1))))
+            .verify_return_data(["a"], None)
+            .verify_return_data(["b"], None)
+            .verify_return_data(["c"], None)
             .done();
     }
 
     #[test]
-    #[cfg(target_family = "unix")]
-    fn cd() {
-        // Should show when we revert back to original dir (if it wasn't already printed)
-        let dot_dot_path = PathBuf::from("..").canonicalize().unwrap().display().to_string();
-        let dot_path = PathBuf::from(".").canonicalize().unwrap().display().to_string();
-        let file_data = include_str!("../tests/cd.upbuild");
+    fn outfile_limit() {
+        let file_data = "uv4\n@outfile=a.txt\n&&\nmake\n@outfile=b.txt\n@outfile-limit=1M\n";
+
         TestRun::new()
-            .add_return_data(Ok(0))
-            .add_return_data(Ok(0))
-            .add_return_data(Ok(0))
-            .add_return_data(Ok(0))
-            .add_return_data(Ok(0))
-            .add_return_data(Ok(0))
             .add_return_data(Ok(0))
             .add_return_data(Ok(0))
             .run(file_data, [], Ok(()))
-            .verify_return_data(["echo", "1"], None)
-            .verify_return_data(["echo", "2"], Some("/some/dir".into()))
-            .verify_return_data(["echo", "3"], Some("/some/dir".into()))
-            .verify_return_data(["echo", "4"], None)
-            .verify_return_data(["echo", "5"], Some("/some/dir".into()))
-            .verify_return_data(["echo", "6"], Some("/some/other/dir".into()))
-            .verify_return_data(["echo", "7"], None)
-            .verify_return_data(["echo", "8"], some_path("some/subdir"))
-            .verify_cd_dir("/some/dir")
-            .verify_cd_dir(&dot_path)
-            .verify_cd_dir("/some/dir")
-            .verify_cd_dir("/some/other/dir")
-            .verify_cd_dir(&dot_path)
-            .verify_cd_dir("some/subdir")
+            .verify_return_data(["uv4"], None)
+            .verify_return_data(["make"], None)
+            .verify_outfile_limit("a.txt", Some(4 << 20))
+            .verify_outfile_limit("b.txt", Some(1 << 20))
             .done();
 
-        // Should show when we revert back to original dir (if it wasalready printed)
+        // 0 means no limit
         TestRun::new()
+            .outfile_limit(Some(0))
             .add_return_data(Ok(0))
             .add_return_data(Ok(0))
-            .add_return_data(Ok(0))
-            .add_return_data(Ok(0))
-            .add_return_data(Ok(0))
-            .add_return_data(Ok(0))
-            .add_return_data(Ok(0))
-            .add_return_data(Ok(0))
-            .run_with_path("../.upbuild", file_data, [], Ok(()))
-            .verify_return_data(["echo", "1"], Some("..".into()))
-            .verify_return_data(["echo", "2"], Some("/some/dir".into()))
-            .verify_return_data(["echo", "3"], Some("/some/dir".into()))
-            .verify_return_data(["echo", "4"], Some("..".into()))
-            .verify_return_data(["echo", "5"], Some("/some/dir".into()))
-            .verify_return_data(["echo", "6"], Some("/some/other/dir".into()))
-            .verify_return_data(["echo", "7"], Some("..".into()))
-            .verify_return_data(["echo", "8"], some_path("../some/subdir"))
-            .verify_cd_dir(&dot_dot_path)
-            .verify_cd_dir("/some/dir")
-            .verify_cd_dir(&dot_dot_path)
-            .verify_cd_dir("/some/dir")
-            .verify_cd_dir("/some/other/dir")
-            .verify_cd_dir(&dot_dot_path)
-            .verify_cd_dir("../some/subdir")
+            .run(file_data, [], Ok(()))
+            .verify_return_data(["uv4"], None)
+            .verify_return_data(["make"], None)
+            .verify_outfile_limit("a.txt", None)
+            .verify_outfile_limit("b.txt", Some(1 << 20))
             .done();
     }
 
     #[test]
-    #[cfg(not(target_family = "unix"))]
-    fn cd() {
-        // Should show when we revert back to original dir (if it wasn't already printed)
-        let dot_dot_path = PathBuf::from("..").canonicalize().unwrap().display().to_string();
-        let dot_path = PathBuf::from(".").canonicalize().unwrap().display().to_string();
-        let file_data = include_str!("../tests/cd.win.upbuild");
+    fn color() {
+        let file_data = "uv4\n@outfile=log.txt\n";
+
         TestRun::new()
-            .add_return_data(Ok(0))
-            .add_return_data(Ok(0))
-            .add_return_data(Ok(0))
-            .add_return_data(Ok(0))
-            .add_return_data(Ok(0))
-            .add_return_data(Ok(0))
-            .add_return_data(Ok(0))
+            .color(Color::Never)
             .add_return_data(Ok(0))
             .run(file_data, [], Ok(()))
-            .verify_return_data(["echo", "1"], None)
-            .verify_return_data(["echo", "2"], Some("\\some\\dir".into()))
-            .verify_return_data(["echo", "3"], Some("\\some\\dir".into()))
-            .verify_return_data(["echo", "4"], None)
-            .verify_return_data(["echo", "5"], Some("\\some\\dir".into()))
-            .verify_return_data(["echo", "6"], Some("\\some\\other\\dir".into()))
-            .verify_return_data(["echo", "7"], None)
-            .verify_return_data(["echo", "8"], some_path("some\\subdir"))
-            .verify_cd_dir("\\some\\dir")
-            .verify_cd_dir(&dot_path)
-            .verify_cd_dir("\\some\\dir")
-            .verify_cd_dir("\\some\\other\\dir")
-            .verify_cd_dir(&dot_path)
-            .verify_cd_dir("some\\subdir")
+            .verify_return_data(["uv4"], None)
+            .verify_outfile_strip_ansi("log.txt", true)
             .done();
 
-        // Should show when we revert back to original dir (if it wasalready printed)
         TestRun::new()
+            .color(Color::Always)
             .add_return_data(Ok(0))
-            .add_return_data(Ok(0))
-            .add_return_data(Ok(0))
-            .add_return_data(Ok(0))
-            .add_return_data(Ok(0))
-            .add_return_data(Ok(0))
-            .add_return_data(Ok(0))
-            .add_return_data(Ok(0))
-            .run_with_path("..\\.upbuild", file_data, [], Ok(()))
-            .verify_return_data(["echo", "1"], Some("..".into()))
-            .verify_return_data(["echo", "2"], Some("\\some\\dir".into()))
-            .verify_return_data(["echo", "3"], Some("\\some\\dir".into()))
-            .verify_return_data(["echo", "4"], Some("..".into()))
-            .verify_return_data(["echo", "5"], Some("\\some\\dir".into()))
-            .verify_return_data(["echo", "6"], Some("\\some\\other\\dir".into()))
-            .verify_return_data(["echo", "7"], Some("..".into()))
-            .verify_return_data(["echo", "8"], some_path("..\\some\\subdir"))
-            .verify_cd_dir(&dot_dot_path)
-            .verify_cd_dir("\\some\\dir")
-            .verify_cd_dir(&dot_dot_path)
-            .verify_cd_dir("\\some\\dir")
-            .verify_cd_dir("\\some\\other\\dir")
-            .verify_cd_dir(&dot_dot_path)
-            .verify_cd_dir("..\\some\\subdir")
+            .run(file_data, [], Ok(()))
+            .verify_return_data(["uv4"], None)
+            .verify_outfile_strip_ansi("log.txt", false)
             .done();
     }
 
-    /// result_is_fail if result is error, or code is non-zero
-    fn result_is_fail(res: &Result<isize>) -> bool {
-        return res.is_err() || *res.as_ref().unwrap() != 0;
+    #[test]
+    fn announced_once() {
+        let file = ClassicFile::parse_lines("make\n@cd=src\n&&\nmake\n@cd=tests\n&&\nmake\n@cd=src\n&&\nmake\n@cd=tests\n".lines()).unwrap();
+        let runner = MockRunner::new();
+        runner.push_results([Ok(0), Ok(0), Ok(0), Ok(0)]);
+        let e = Exec::new(Box::new(runner.clone()));
+        e.run(Path::new(".upbuild"), &file, &Config::default(), &[]).expect("Should pass");
+
+        // each directory is only resolved once, so always the same way
+        assert_eq!(runner.canonicalized(), [PathBuf::from("src"), PathBuf::from("tests")]);
+        let entering: Vec<_> = runner.displayed().into_iter().filter(|s| s.contains("Entering")).collect();
+        assert_eq!(entering.len(), 4);
+        assert_eq!(entering[0], entering[2]);
+        assert_eq!(entering[1], entering[3]);
+        assert_eq!(entering[0], format!("upbuild: Entering directory `{}'", absolute("src")));
+
+        // until an @mkdir may have created it
+        let file = ClassicFile::parse_lines("make\n@cd=src\n&&\nmake\n@cd=tests\n@mkdir=.\n&&\nmake\n@cd=src\n".lines()).unwrap();
+        runner.reset();
+        runner.push_results([Ok(0), Ok(0), Ok(0)]);
+        e.run(Path::new(".upbuild"), &file, &Config::default(), &[]).expect("Should pass");
+        assert_eq!(runner.canonicalized(), [PathBuf::from("src"), PathBuf::from("tests"), PathBuf::from("src")]);
+
+        // and each run resolves them afresh
+        runner.push_results([Ok(0), Ok(0), Ok(0)]);
+        e.run(Path::new(".upbuild"), &file, &Config::default(), &[]).expect("Should pass");
+        assert_eq!(runner.canonicalized().len(), 6);
     }
 
-    /// On windows std::process::Command evaluates the
-    /// executable _before_ the `current_dir()` is applied
     #[test]
-    fn process_runner_win32_dir_test() {
-        let p = ProcessRunner::default();
-        let (comm, path) = if cfg!(windows) { (".\\run.bat", "tests/win/") } else { ("./run.sh", "tests/sh/") };
-        let res = p.run(args_vec([comm]), &some_path(path));
-        println!("res={:?}", res);
-        assert_eq!(res.expect("expected OK"), 0);
-
-        // Try alternate formats to see how the runner works
-        if cfg!(windows) {
-            let (comm, path) = ("./run.bat", "tests/win/");
-            let res = p.run(args_vec([comm]), &some_path(path));
-            println!("res={:?}", res);
-            assert_eq!(res.expect("expected OK"), 0);
+    fn run_on_thread() {
+        let file = ClassicFile::parse_lines(include_str!("../tests/uv4.upbuild").lines()).unwrap();
+        let runner = MockRunner::new();
+        runner.push_result(Ok(0));
+
+        let e = Exec::new(Box::new(runner.clone()));
+        std::thread::spawn(move || {
+            e.run(Path::new(".upbuild"), &file, &Config::default(), &[])
+        }).join().unwrap().expect("Should pass");
+        assert_eq!(runner.runs().len(), 1);
+
+        // and the stock runners can be shared
+        fn is_sync<T: Send + Sync>(_: &T) {}
+        is_sync(&Exec::new(process_runner()));
+        is_sync(&Exec::new(print_runner()));
+    }
 
-            let (comm, path) = ("./run.bat", "tests\\win\\");
-            let res = p.run(args_vec([comm]), &some_path(path));
-            println!("res={:?}", res);
-            assert_eq!(res.expect("expected OK"), 0);
+    #[test]
+    fn with_args_borrows() {
+        let args = args_vec(["make", "-j8"]);
+        assert!(matches!(Exec::with_args(&args, &[], None, false).unwrap(), Cow::Borrowed(_)));
+        assert!(matches!(Exec::with_args(&args, &args_vec(["all"]), None, false).unwrap(), Cow::Owned(_)));
+        assert!(matches!(Exec::with_args(&args, &[], Some(&String::from("upbuild")), false).unwrap(), Cow::Owned(_)));
+
+        let args = args_vec(["make", "--", "all"]);
+        assert_eq!(Exec::with_args(&args, &[], None, false).unwrap(), Cow::<[String]>::Owned(args_vec(["make", "all"])));
+    }
 
-            // in DOS you don't need ./
-            let (comm, path) = ("run.bat", "tests\\win\\");
-            let res = p.run(args_vec([comm]), &some_path(path));
-            println!("res={:?}", res);
-            assert_eq!(res.expect("expected OK"), 0);
+    #[test]
+    fn with_args_placeholder() {
+        let args = args_vec(["cargo", "test", "@args-here", "--", "--nocapture"]);
+        assert_eq!(Exec::with_args(&args, &[], None, false).unwrap().as_ref(), args_vec(["cargo", "test", "--", "--nocapture"]));
+        assert_eq!(Exec::with_args(&args, &args_vec(["parse", "-q"]), None, false).unwrap().as_ref(),
+                   args_vec(["cargo", "test", "parse", "-q", "--", "--nocapture"]));
+
+        let args = args_vec(["upbuild", "@args-here", "--ub-print"]);
+        let argv0 = String::from("/path/to/upbuild");
+        assert_eq!(Exec::with_args(&args, &args_vec(["all"]), Some(&argv0), false).unwrap().as_ref(),
+                   args_vec(["/path/to/upbuild", "all", "--ub-print"]));
+    }
 
-            // Ensure it fails if not in
-            let (comm, path) = ("run.bat", "tests\\");
-            let res = p.run(args_vec([comm]), &some_path(path));
-            println!("res={:?}", res);
-            assert!(result_is_fail(&res), "Expected fail got {:?}", res);
+    #[test]
+    fn with_args_tokens() {
+        // expanded args, or the missing position
+        type Expected = std::result::Result<&'static [&'static str], usize>;
+        let table: &[(&[&str], &[&str], Expected)] = &[
+            // file args, provided args, expected
+            (&["git", "tag", "-a", "$1", "-m", "$2"], &["v1", "first"], Ok(&["git", "tag", "-a", "v1", "-m", "first"])),
+            // extra args aren't appended
+            (&["git", "tag", "-a", "$1", "-m", "$2"], &["v1", "first", "extra"], Ok(&["git", "tag", "-a", "v1", "-m", "first"])),
+            // missing args are an error
+            (&["git", "tag", "-a", "$1", "-m", "$2"], &["v1"], Err(2)),
+            (&["git", "tag", "-a", "$1", "-m", "$2"], &[], Err(1)),
+            // within an arg
+            (&["make", "VERSION=$1", "--", "all"], &["1.2"], Ok(&["make", "VERSION=1.2", "--", "all"])),
+            (&["echo", "$1$2", "$10"], &["a", "b", "c", "d", "e", "f", "g", "h", "i", "j"], Ok(&["echo", "ab", "j"])),
+            // $@ is all of them, wherever it is
+            (&["cargo", "test", "$@", "--", "--nocapture"], &[], Ok(&["cargo", "test", "--", "--nocapture"])),
+            (&["cargo", "test", "$@", "--", "--nocapture"], &["a", "b"], Ok(&["cargo", "test", "a", "b", "--", "--nocapture"])),
+            // mixed with positionals, which can repeat
+            (&["cp", "$@", "$1.bak"], &["x", "y"], Ok(&["cp", "x", "y", "x.bak"])),
+            (&["cp", "$@", "$2"], &["x"], Err(2)),
+            // $$ escapes, other $s are left alone
+            (&["echo", "$$1", "$1"], &["a"], Ok(&["echo", "$1", "a"])),
+            (&["sh", "-c", "echo $HOME $0 $", "--", "x"], &[], Ok(&["sh", "-c", "echo $HOME $0 $", "x"])),
+            (&["sh", "-c", "echo $$HOME", "--", "x"], &["y"], Ok(&["sh", "-c", "echo $HOME", "y"])),
+        ];
+        let to_vec = |a: &[&str]| -> Vec<String> { a.iter().map(|x| x.to_string()).collect() };
+        for (args, provided, expected) in table {
+            let file_args = to_vec(args);
+            let result = Exec::with_args(&file_args, &to_vec(provided), None, true);
+            match (result, expected) {
+                (Ok(got), Ok(expected)) => assert_eq!(got.as_ref(), to_vec(expected), "{:?} {:?}", args, provided),
+                (Err(Error::MissingArg(n, _)), Err(expected)) => assert_eq!(n, *expected, "{:?} {:?}", args, provided),
+                (got, _) => panic!("{:?} {:?} gave {:?}, expected {:?}", args, provided, got, expected),
+            }
         }
     }
 
     #[test]
-    fn process_runner_arg_test() {
-        let p = ProcessRunner::default();
-        let (comm, path) = if cfg!(windows) { (".\\run.bat", "tests/win/") } else { ("./run.sh", "tests/sh/") };
-        let res = p.run(args_vec([comm, "1"]), &some_path(path));
-        println!("res={:?}", res);
-        assert_eq!(res.expect("expected OK(1)"), 1);
+    fn with_args_no_positional() {
+        // without @positional-args $s are passed on as they are
+        let args = args_vec(["awk", "{print $1}"]);
+        assert!(matches!(Exec::with_args(&args, &[], None, false).unwrap(), Cow::Borrowed(_)));
+        assert_eq!(Exec::with_args(&args, &args_vec(["x.txt"]), None, false).unwrap().as_ref(),
+                   args_vec(["awk", "{print $1}", "x.txt"]));
+
+        let args = args_vec(["sh", "-c", "echo $1 $$ $@", "x", "@args-here", "y"]);
+        assert_eq!(Exec::with_args(&args, &args_vec(["a"]), None, false).unwrap().as_ref(),
+                   args_vec(["sh", "-c", "echo $1 $$ $@", "x", "a", "y"]));
+    }
 
-        let res = p.run(args_vec([comm, "100"]), &some_path(path));
-        println!("res={:?}", res);
-        assert_eq!(res.expect("expected OK(100)"), 100);
+    #[test]
+    fn show() {
+        let file = ClassicFile::parse_lines("make\n@tags=host\n# faster\n-j8\n--\nall\n&&\nscp\n@name=deploy\n@manual\n@retmap=2=>0,1=>0\n@cd=out\nfw.bin\n&&\nupbuild\n".lines()).unwrap();
+        let tree = ParentTree::new("show");
+        let cwd = tree.dir().canonicalize().unwrap();
+        let path = &tree.file();
+        let path = Path::new(path);
+        let show = |cfg: &Config, which: &str, args: &[&str]| {
+            Exec::show(path, &file, cfg, which, &args_vec_slice(args)).unwrap()
+        };
+        let cfg = Config::default();
+
+        assert_eq!(show(&cfg, "1", &["test"]),
+                   ["make", "@tags=host", "# faster", "-j8", "--", "all", "",
+                    &format!("directory: {}", cwd.display()),
+                    "tags: host", "retmap: none", "runs: yes", "command: make -j8 test"]);
+
+        assert_eq!(show(&cfg, "deploy", &[]),
+                   ["scp", "@name=deploy", "@manual", "@retmap=2=>0,1=>0", "@cd=out", "fw.bin", "",
+                    &format!("directory: {}", cwd.join("out").display()),
+                    "tags: none", "retmap: 1=>0,2=>0", "runs: no, @manual and not selected", "command: scp fw.bin"]);
+
+        let cfg = Config::builder().reject(["host"]).build();
+        assert_eq!(show(&cfg, "1", &[])[10], "runs: no, has a rejected tag");
+        assert_eq!(show(&cfg, "3", &["all"])[2..], [&format!("directory: {}", tree.top.canonicalize().unwrap().display()),
+                                                     "tags: none", "retmap: none", "runs: yes", "command: upbuild --ub-reject=host -- all"]);
+
+        assert_eq!(Exec::show(path, &file, &cfg, "4", &[]), Err(Error::UnknownCommand(String::from("4"))));
+
+        let file = ClassicFile::parse_lines("restore\n@tags=cache\n||\nfetch\n".lines()).unwrap();
+        let runs = |cfg: &Config| Exec::show(path, &file, cfg, "2", &[]).unwrap()[5].clone();
+        assert_eq!(runs(&Config::default()), "runs: if the command before it fails");
+        assert_eq!(runs(&Config::builder().reject(["cache"]).build()), "runs: no, the command it falls back from doesn't run");
     }
 
-    fn some_path(s: &str) -> Option<PathBuf> {
-        Some(PathBuf::from(s))
+    #[test]
+    fn print_env() {
+        let file = ClassicFile::parse_lines("make\n--\nall\n&&\nupbuild\n@cd=..\n".lines()).unwrap();
+        let path = Path::new(".upbuild");
+        let cfg = Config { level: 1, file: Some(PathBuf::from("pinned/.upbuild")), ..Config::default() };
+        let inherited = |k: &str| (k == FILE_ENV).then(String::new);
+
+        assert_eq!(Exec::print_env(path, &file, &cfg, &[], inherited).unwrap(),
+                   ["1: make all", "    unchanged",
+                    "2: upbuild", "    UPBUILD_LEVEL=2 (recursive call)"]);
+        assert_eq!(Exec::print_env(path, &file, &cfg, &args_vec(["test"]), |_| None).unwrap(),
+                   ["1: make test", "    unchanged",
+                    "2: upbuild -- test", "    UPBUILD_LEVEL=2 (recursive call)", "    UPBUILD_FILE= (recursive call)"]);
     }
 
     #[test]
-    fn run_dir() {
-        let main_working_dir = None;
-        assert_eq!(Exec::run_dir(&main_working_dir, None), None);
-        assert_eq!(Exec::run_dir(&main_working_dir, Some("..".into())), some_path(".."));
-        assert_eq!(Exec::run_dir(&main_working_dir, Some("/a".into())), some_path("/a"));
-
-        let main_working_dir = Some(PathBuf::from(".."));
-        assert_eq!(Exec::run_dir(&main_working_dir, None), some_path(".."));
-        assert_eq!(Exec::run_dir(&main_working_dir, Some("..".into())), some_path("../.."));
-        assert_eq!(Exec::run_dir(&main_working_dir, Some("/a".into())), some_path("/a"));
-
-        let main_working_dir = Some(PathBuf::from("/b"));
-        assert_eq!(Exec::run_dir(&main_working_dir, None), some_path("/b"));
-        assert_eq!(Exec::run_dir(&main_working_dir, Some("..".into())), some_path("/b/.."));
-        assert_eq!(Exec::run_dir(&main_working_dir, Some("/a".into())), some_path("/a"));
-
-        let main_working_dir = Some(PathBuf::from("b"));
-        assert_eq!(Exec::run_dir(&main_working_dir, None), some_path("b"));
-        assert_eq!(Exec::run_dir(&main_working_dir, Some("..".into())), some_path("b/.."));
-        assert_eq!(Exec::run_dir(&main_working_dir, Some("/a".into())), some_path("/a"));
+    fn missing_arg() {
+        TestRun::new()
+            .run("git\ntag\n$1\n@positional-args\n", [], Err(Error::MissingArg(1, "git tag '$1'".to_string())))
+            .done();
+    }
+
+    #[test]
+    fn plan() {
+        let file = ClassicFile::parse_lines("make\n@tags=host\n-j8\n--\nall\n&&\nscp\n@name=deploy\n@retmap=1=>0\n@cd=out\n@mkdir=out\n@umask=002\n@outfile=log.txt\nfw.bin\n&&\necho\n@manual\n&&\nupbuild\n".lines()).unwrap();
+        let tree = ParentTree::new("plan");
+        let dir = tree.dir();
+        let path = &dir.join(".upbuild");
+        let cfg = Config::builder().reject(["host"]).build();
+        let plan = Exec::plan(path, &file, &cfg, &args_vec(["a"])).unwrap();
+        assert_eq!(plan.len(), 2);
+
+        assert_eq!(plan[0].index, 1);
+        assert_eq!(plan[0].name.as_deref(), Some("deploy"));
+        assert_eq!(plan[0].args, ["scp", "fw.bin", "a"]);
+        assert_eq!(plan[0].argv, plan[0].args);
+        assert_eq!(plan[0].run_dir, Some(dir.join("out")));
+        assert!(plan[0].check_dir);
+        assert_eq!(plan[0].mkdir, Some(dir.join("out")));
+        assert_eq!(plan[0].outfile.as_deref(), Some(Path::new("log.txt")));
+        assert_eq!((plan[0].map_code(1), plan[0].map_code(2)), (0, 2));
+        assert_eq!(plan[0].settings.env, []);
+        assert_eq!(plan[0].settings.umask, Some(0o2));
+
+        assert_eq!(plan[1].index, 3);
+        assert_eq!(plan[1].args, ["upbuild", "--ub-reject=host", "--", "a"]);
+        assert_eq!(plan[1].run_dir, Some(tree.run_dir()));
+        assert!(plan[1].check_dir);
+        assert_eq!(plan[1].settings.env, [(String::from(LEVEL_ENV), String::from("1"))]);
+        assert_eq!(plan[1].settings.umask, None);
+
+        // errors come before anything runs
+        let file = ClassicFile::parse_lines("echo\n&&\ngit\ntag\n$1\n@positional-args\n".lines()).unwrap();
+        assert_eq!(Exec::plan(path, &file, &Config::default(), &[]), Err(Error::MissingArg(1, String::from("git tag '$1'"))));
+    }
+
+    #[test]
+    fn run_stream() {
+        let runner = MockRunner::new();
+        let e = Exec::new(Box::new(runner.clone()));
+        let run = |lines: &str| {
+            runner.reset();
+            runner.push_results([Ok(0), Ok(0), Ok(0)]);
+            let result = e.run_stream(Path::new(".upbuild"), ClassicFile::stream_lines(lines.lines()), &Config::default(), &[]);
+            let ran: Vec<_> = runner.runs().iter().map(|r| shell::join(&r.cmd)).collect();
+            (result, ran)
+        };
+
+        let (result, ran) = run("echo\na\n&&\necho\nb\n@manual\n&&\necho\nc\n");
+        assert!(result.is_ok());
+        assert_eq!(ran, ["echo a", "echo c"]);
+
+        // commands before a bad entry have already run
+        let (result, ran) = run("echo\na\n&&\necho\nb\n&&\necho\n@bogus\n");
+        assert_eq!(result, Err(Error::InvalidTag(String::from("@bogus"))));
+        assert_eq!(ran, ["echo a", "echo b"]);
+
+        // but the rest is checked before an @exec
+        let (result, ran) = run("echo\na\n&&\nbash\n@exec\n&&\necho\nc\n");
+        assert_eq!(result, Err(Error::ExecNotLast(String::from("bash"))));
+        assert_eq!(ran, ["echo a"]);
+        let (result, ran) = run("echo\na\n&&\nbash\n@exec\n&&\necho\nc\n@manual\n");
+        assert!(result.is_ok());
+        assert_eq!(ran, ["echo a", "bash"]);
+
+        // captured values are only known once read
+        let (result, ran) = run("echo\n${V}\n&&\necho\n@capture=V\n&&\necho\n${V}\n");
+        assert!(result.is_ok());
+        assert_eq!(ran, ["echo '${V}'", "echo", "echo ''"]);
+
+        // @env-required is checked as each command is reached
+        let (result, ran) = run("echo\na\n&&\necho\nb\n@env-required=UPBUILD_TEST_UNSET\n");
+        assert_eq!(result, Err(Error::MissingEnv(vec![String::from("UPBUILD_TEST_UNSET")])));
+        assert_eq!(ran, ["echo a"]);
+
+        // a pipe waits for the command it feeds, which has to be selected
+        let (result, ran) = run("echo\na\n@pipe\n&&\ntr\na\nb\n&&\necho\nc\n");
+        assert!(result.is_ok());
+        assert_eq!(ran, ["echo a", "tr a b", "echo c"]);
+        assert_eq!(runner.pipelines(), [2]);
+        let (result, ran) = run("echo\na\n&&\necho\nb\n@pipe\n&&\ntr\n@manual\n");
+        assert_eq!(result, Err(Error::UnconnectedPipe(String::from("echo b"))));
+        assert_eq!(ran, ["echo a"]);
+
+        // a failure waits to see if a fallback follows it
+        let fail_first = |lines: &str| {
+            runner.reset();
+            runner.push_results([Ok(1), Ok(0), Ok(0)]);
+            let result = e.run_stream(Path::new(".upbuild"), ClassicFile::stream_lines(lines.lines()), &Config::default(), &[]);
+            let ran: Vec<_> = runner.runs().iter().map(|r| shell::join(&r.cmd)).collect();
+            (result, ran)
+        };
+        let (result, ran) = fail_first("false\n||\necho\nb\n&&\necho\nc\n");
+        assert!(result.is_ok());
+        assert_eq!(ran, ["false", "echo b", "echo c"]);
+        let (result, ran) = fail_first("false\n&&\necho\n@bogus\n");
+        assert_eq!(result, Err(in_command(1, "false", Error::ExitWithExitCode(1))));
+        assert_eq!(ran, ["false"]);
+    }
+
+    #[test]
+    fn run_chain() {
+        let dir = TempDir::new("chain");
+        std::fs::create_dir_all(dir.join("a")).unwrap();
+        std::fs::write(dir.join(".upbuild"), "make\ntop\n").unwrap();
+        std::fs::write(dir.join("a/.upbuild"), "make\nnear\n&&\nupbuild\n").unwrap();
+        let paths = [dir.join("a/.upbuild"), dir.join("a/../.upbuild")];
+
+        let runner = MockRunner::new();
+        let e = Exec::new(Box::new(runner.clone()));
+        let cfg = Config { chain: true, ..Config::default() };
+        let run = |codes: &[RetCode]| {
+            runner.reset();
+            runner.push_results(codes.iter().map(|c| Ok(*c)));
+            let result = e.run_chain(&paths, &cfg, &[]);
+            let ran: Vec<_> = runner.runs().iter().map(|r| (shell::join(&r.cmd), r.cd.clone())).collect();
+            (result, ran)
+        };
+
+        // nearest first, each in its own directory, without recursing
+        let (result, ran) = run(&[0, 0]);
+        assert_eq!(result, Ok(()));
+        assert_eq!(ran, [("make near".to_string(), Some(dir.join("a"))),
+                         ("make top".to_string(), Some(dir.join("a/.."))),]);
+
+        // stops at the first failure
+        let (result, ran) = run(&[2]);
+        assert_eq!(result, Err(in_command(1, "make", Error::ExitWithExitCode(2))));
+        assert_eq!(ran, [("make near".to_string(), Some(dir.join("a")))]);
+    }
+
+    #[test]
+    fn run_each() {
+        let dir = TempDir::new("each");
+        for (d, contents) in [("a", "make\na\n"), ("b", "make\nb\n&&\nupbuild\n"), ("c", "@bogus\n")] {
+            std::fs::create_dir_all(dir.join(d)).unwrap();
+            std::fs::write(dir.join(d).join(".upbuild"), contents).unwrap();
+        }
+        let paths = [dir.join("a/.upbuild"), dir.join("b/.upbuild"), dir.join("c/.upbuild")];
+
+        let runner = MockRunner::new();
+        let e = Exec::new(Box::new(runner.clone()));
+        let cfg = Config { discover: true, discover_run: true, ..Config::default() };
+        runner.push_results([Ok(2), Ok(0)]);
+
+        // every file runs in its own directory, and the first failure is returned
+        let result = e.run_each(&paths, &cfg, &[]);
+        assert_eq!(result, Err(in_command(1, "make", Error::ExitWithExitCode(2))));
+        let ran: Vec<_> = runner.runs().iter().map(|r| (shell::join(&r.cmd), r.cd.clone())).collect();
+        assert_eq!(ran, [("make a".to_string(), Some(dir.join("a"))),
+                         ("make b".to_string(), Some(dir.join("b")))]);
+        let reports: Vec<_> = runner.displayed().into_iter().filter(|s| s.ends_with(": ok") || s.ends_with(": failed")).collect();
+        assert_eq!(reports, [format!("upbuild: {}: failed", paths[0].display()),
+                             format!("upbuild: {}: ok", paths[1].display()),
+                             format!("upbuild: {}: failed", paths[2].display())]);
+    }
+
+    #[test]
+    fn print_null() {
+        let cmd: Vec<String> = ["echo", "two words", "it's \"quoted\"", ""].iter().map(|x| x.to_string()).collect();
+        let plain = PrintRunner { null: false };
+        assert_eq!(plain.render(&cmd, Some(Path::new("my dir"))), b"echo two words it's \"quoted\" \n");
+        assert_eq!(plain.render_mkdir(Path::new("out")), b"Checking existence of directory out\n");
+        assert_eq!(plain.render_outfile(Path::new("logs/*.log")), b"# outfile: logs/*.log\n");
+        let settings = RunSettings::default();
+        let grep = [String::from("grep"), String::from("fix")];
+        let pipeline: [Stage<'_>; 2] = [(&cmd[..2], None, &settings), (&grep, Some(Path::new("src")), &settings)];
+        assert_eq!(plain.render_pipeline(&pipeline), b"echo two words | grep fix\n");
+        let masked = RunSettings { umask: Some(0o2), ..RunSettings::default() };
+        assert_eq!(plain.render_umask(&masked), b"# umask 002\n");
+        assert!(plain.render_umask(&settings).is_empty());
+        let mapped = RunSettings { retmap: vec![(1, 0), (3, 0)], ..masked.clone() };
+        assert_eq!(plain.render_settings(&mapped), b"# umask 002\n# retmap: 1=>0,3=>0\n");
+        assert!(plain.render_settings(&settings).is_empty());
+
+        let null = PrintRunner { null: true };
+        assert_eq!(null.render(&cmd, None), b"echo\0two words\0it's \"quoted\"\0\0\0");
+        assert_eq!(null.render(&cmd, Some(Path::new("my dir"))), b"cd\0my dir\0\0echo\0two words\0it's \"quoted\"\0\0\0");
+        assert_eq!(null.render(&cmd[..1], Some(Path::new("/a b"))), b"cd\0/a b\0\0echo\0\0");
+        assert_eq!(null.render_mkdir(Path::new("out dir")), b"mkdir\0out dir\0\0");
+        assert_eq!(null.render_outfile(Path::new("logs/*.log")), b"outfile\0logs/*.log\0\0");
+        assert_eq!(null.render_pipeline(&pipeline), b"echo\0two words\0\0|\0\0cd\0src\0\0grep\0fix\0\0");
+        assert_eq!(null.render_umask(&masked), [&b"umask\0"[..], b"002\0\0"].concat());
+        assert_eq!(null.render_retmap(&mapped), b"retmap\x001=>0,3=>0\0\0");
     }
 }
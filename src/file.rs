@@ -1,11 +1,20 @@
 // SPDX-License-Identifier: GPL-3.0-or-later
 // (C) Copyright 2024 Greg Whiteley
 
+use std::borrow::Cow;
 use std::collections::{HashMap, HashSet};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 use super::{Error, Result};
-use super::exec::RetCode;
+use super::cfg::is_upbuild;
+use super::exec::{RetCode, StdinSource};
+use super::output::parse_size;
+use super::shell;
+use super::tags::TagExpr;
+
+/// Argument standing in for `@args-here`, marking where provided args
+/// are inserted - as it's a flag in the file, no arg can be the same
+pub(crate) const ARGS_PLACEHOLDER: &str = "@args-here";
 
 #[derive(Debug, PartialEq)]
 enum Flags {
@@ -16,8 +25,28 @@ enum Flags {
     RetMap(HashMap<RetCode, RetCode>),
     Cd(String),
     Mkdir(String),
+    Detach,
+    Stdin(StdinSource),
+    Scan,
+    OutfileLimit(u64),
+    OutfileAll,
+    Capture(String),
+    Silent,
+    Pipe,
+    Exec,
+    Container(String),
+    Ssh(String),
+    NoPropagate,
+    NoForwardArgs,
+    Recurse,
+    ArgsHere,
+    PositionalArgs,
+    Name(String),
+    EnvRequired(Vec<(String, bool)>),
+    Umask(u32),
 }
 
+/// A single command from a [`ClassicFile`], with its flags
 #[derive(Debug, Default)]
 pub struct Cmd {
     args: Vec<String>,
@@ -29,6 +58,26 @@ pub struct Cmd {
     disabled: bool,
     manual: bool,
     recurse: bool,
+    detach: bool,
+    stdin: Option<StdinSource>,
+    scan: bool,
+    outfile_limit: Option<u64>,
+    outfile_all: bool,
+    capture: Option<String>,
+    silent: bool,
+    pipe: bool,
+    fallback: bool,
+    exec: bool,
+    container: Option<String>,
+    ssh: Option<String>,
+    no_propagate: bool,
+    no_forward_args: bool,
+    positional_args: bool,
+    name: Option<String>,
+    env_required: Vec<(String, bool)>,
+    umask: Option<u32>,
+    // lines as read, comments and all, written back by edits
+    source: Vec<String>,
 }
 
 impl Cmd {
@@ -48,60 +97,483 @@ impl Cmd {
         }
     }
 
-    pub fn out_file(&self) -> Option<PathBuf> {
-        self.outfile.as_ref().map(|ref f| PathBuf::from(f))
+    /// The command's `@name`, if any
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    /// The file to display once the command has run, see `@outfile`
+    pub fn out_file(&self) -> Option<&Path> {
+        self.outfile.as_deref().map(Path::new)
+    }
+
+    /// The most of the `@outfile` to display, see `@outfile-limit`
+    pub fn outfile_limit(&self) -> Option<u64> {
+        self.outfile_limit
+    }
+
+    /// Whether to display every file an `@outfile` pattern matches,
+    /// not just the newest, see `@outfile-all`
+    pub fn outfile_all(&self) -> bool {
+        self.outfile_all
     }
 
+    /// Whether the command has `@disable`, so never runs
+    pub fn disabled(&self) -> bool {
+        self.disabled
+    }
+
+    /// Whether the command has `@manual`, so only runs when selected
+    pub fn manual(&self) -> bool {
+        self.manual
+    }
+
+    /// Whether the command runs `upbuild` from the parent directory,
+    /// by that name or another it's installed as with `@recurse`
     pub fn recurse(&self) -> bool {
         self.recurse
     }
 
-    pub fn directory(&self) -> Option<PathBuf> {
+    /// Whether the command is a recursive call without `@cd`, so runs
+    /// in the directory of the next `.upbuild` up
+    pub fn runs_in_parent_file(&self) -> bool {
+        self.recurse && self.cd.is_none()
+    }
+
+    /// The directory to run in, relative to the file, if not its own -
+    /// for [`Cmd::runs_in_parent_file`] where the search for the next
+    /// `.upbuild` starts
+    pub fn directory(&self) -> Option<&Path> {
         match self.cd {
-            Some(ref d) => Some(PathBuf::from(d)),
+            Some(ref d) => Some(Path::new(d)),
             None => {
                 if self.recurse {
-                    return Some(PathBuf::from(".."));
+                    return Some(Path::new(".."));
                 }
                 None
             },
         }
     }
 
-    pub fn mk_dir(&self) -> Option<PathBuf> {
-        self.mkdir.as_ref().map(PathBuf::from)
+    /// Whether the command runs detached, see `@detach`
+    pub fn detach(&self) -> bool {
+        self.detach
+    }
+
+    /// The image to run in, see `@container`
+    pub fn container(&self) -> Option<&str> {
+        self.container.as_deref()
+    }
+
+    /// The environment variables that must be set to run, and whether
+    /// each may be empty, see `@env-required`
+    pub fn env_required(&self) -> &[(String, bool)] {
+        &self.env_required
+    }
+
+    /// The host to run on, see `@ssh`
+    pub fn ssh(&self) -> Option<&str> {
+        self.ssh.as_deref()
+    }
+
+    /// Whether a recursive command is passed the selection, see `@no-propagate`
+    pub fn propagate(&self) -> bool {
+        !self.no_propagate
+    }
+
+    /// Whether a recursive command is passed the provided args, see `@no-forward-args`
+    pub fn forward_args(&self) -> bool {
+        !self.no_forward_args
+    }
+
+    /// Whether `$1`, `$@` and `$$` in the args are substituted, see
+    /// `@positional-args`
+    pub fn positional_args(&self) -> bool {
+        self.positional_args
+    }
+
+    /// Whether the command replaces upbuild, see `@exec`
+    pub fn exec(&self) -> bool {
+        self.exec
+    }
+
+    /// Whether the output is scanned for errors, see `@scan`
+    pub fn scan(&self) -> bool {
+        self.scan
+    }
+
+    /// The variable the command's stdout is captured into for later
+    /// commands, see `@capture`
+    pub fn capture(&self) -> Option<&str> {
+        self.capture.as_deref()
+    }
+
+    /// Whether captured output is kept quiet rather than shown as it
+    /// runs, see `@silent`
+    pub fn silent(&self) -> bool {
+        self.silent
+    }
+
+    /// Whether the command's stdout feeds the next command's stdin,
+    /// see `@pipe`
+    pub fn pipe(&self) -> bool {
+        self.pipe
+    }
+
+    /// Whether the command only runs if the one before it failed,
+    /// as it follows a `||` line
+    pub fn fallback(&self) -> bool {
+        self.fallback
+    }
+
+    /// The file mode creation mask to run with, see `@umask`
+    pub fn umask(&self) -> Option<u32> {
+        self.umask
+    }
+
+    /// Where stdin comes from, see `@stdin`
+    pub fn stdin(&self) -> Option<&StdinSource> {
+        self.stdin.as_ref()
+    }
+
+    /// Create a command from its lines, as they would appear in a
+    /// file, eg `["make", "@tags=host", "-j8"]`
+    pub fn from_lines<I, T>(lines: I) -> Result<Cmd>
+    where
+        I: Iterator<Item=T>,
+        T: std::borrow::Borrow<str>
+    {
+        let lines: Vec<T> = lines.collect();
+        if let Some(l) = lines.iter().map(|l| l.borrow()).find(|l| l.contains(['\n', '\r'])) {
+            return Err(Error::UnableToEdit(format!("argument {:?} contains a newline", l)));
+        }
+        let mut file = ClassicFile::parse_lines(lines.iter().map(|l| l.borrow()))?;
+        if file.commands.len() != 1 {
+            return Err(Error::UnableToEdit(String::from("a command can't contain && or ||")));
+        }
+        Ok(file.commands.remove(0))
     }
 
+    // Replace any lines giving the named flag with the given one
+    fn replace_flag(&mut self, name: &str, line: Option<String>) {
+        self.source.retain(|l| !matches!(split_flag(l), Ok((n, _)) if n == name));
+        self.source.extend(line);
+    }
+
+    /// Add or remove `@disable`, returning whether anything changed
+    pub fn set_disabled(&mut self, disabled: bool) -> bool {
+        if self.disabled == disabled {
+            return false;
+        }
+        self.disabled = disabled;
+        self.replace_flag("disable", disabled.then(|| String::from("@disable")));
+        true
+    }
+
+    /// Replace the command's `@tags`
+    pub fn set_tags<I, T>(&mut self, tags: I) -> Result<()>
+    where
+        I: Iterator<Item=T>,
+        T: Into<String>
+    {
+        let tags: HashSet<String> = tags.map(Into::into).collect();
+        if let Some(t) = tags.iter().find(|t| t.is_empty() || t.contains([',', '\n', '\r'])) {
+            return Err(Error::UnableToEdit(format!("invalid tag {:?}", t)));
+        }
+        let mut sorted: Vec<_> = tags.iter().map(String::as_str).collect();
+        sorted.sort_unstable();
+        self.replace_flag("tags", (!sorted.is_empty()).then(|| format!("@tags={}", sorted.join(","))));
+        self.tags = tags;
+        Ok(())
+    }
+
+    /// Replace the command's `@cd`, or remove it with None
+    pub fn set_cd(&mut self, dir: Option<&str>) -> Result<()> {
+        if let Some(d) = dir.filter(|d| d.trim().is_empty() || d.contains(['\n', '\r'])) {
+            return Err(Error::UnableToEdit(format!("invalid directory {:?}", d)));
+        }
+        self.replace_flag("cd", dir.map(|d| format!("@cd={}", quote_value(d))));
+        self.cd = dir.map(String::from);
+        Ok(())
+    }
+
+    // Apply a flag line to the command
+    fn apply_flag(&mut self, f: Flags) {
+        // TODO detect duplicates
+        match f {
+            Flags::Disable => self.disabled = true,
+            Flags::Manual => self.manual = true,
+            Flags::Tags(tags) => self.tags = tags,
+            Flags::Outfile(filename) => self.outfile = Some(filename),
+            Flags::RetMap(map) => self.retmap = map,
+            Flags::Cd(dir) => self.cd = Some(dir),
+            Flags::Mkdir(dir) => self.mkdir = Some(dir),
+            Flags::Detach => self.detach = true,
+            Flags::Stdin(stdin) => self.stdin = Some(stdin),
+            Flags::Scan => self.scan = true,
+            Flags::OutfileLimit(limit) => self.outfile_limit = Some(limit),
+            Flags::Umask(mask) => self.umask = Some(mask),
+            Flags::OutfileAll => self.outfile_all = true,
+            Flags::Capture(name) => self.capture = Some(name),
+            Flags::Silent => self.silent = true,
+            Flags::Pipe => self.pipe = true,
+            Flags::Exec => self.exec = true,
+            Flags::Container(image) => self.container = Some(image),
+            Flags::Ssh(host) => self.ssh = Some(host),
+            Flags::NoPropagate => self.no_propagate = true,
+            Flags::NoForwardArgs => self.no_forward_args = true,
+            Flags::Recurse => self.recurse = true,
+            Flags::ArgsHere => self.args.push(String::from(ARGS_PLACEHOLDER)),
+            Flags::PositionalArgs => self.positional_args = true,
+            Flags::Name(name) => self.name = Some(name),
+            Flags::EnvRequired(names) => self.env_required.extend(names),
+        }
+    }
+
+    fn with_source(mut self, source: Vec<String>) -> Cmd {
+        self.source = source;
+        self
+    }
+
+    // Reject flags that can't work together
+    fn checked(self) -> Result<Cmd> {
+        if self.ssh.is_some() && self.outfile.is_some() {
+            return Err(Error::ConflictingFlags(
+                format!("@outfile can't be read from a @ssh host: {}", shell::join(&self.args))));
+        }
+        if self.capture.is_some() && self.exec {
+            return Err(Error::ConflictingFlags(
+                format!("@capture can't be used with @exec, nothing is left to use it: {}", shell::join(&self.args))));
+        }
+        if self.pipe && (self.exec || self.capture.is_some() || self.detach) {
+            return Err(Error::ConflictingFlags(
+                format!("@pipe can't be used with @exec, @capture or @detach: {}", shell::join(&self.args))));
+        }
+        if self.silent && self.capture.is_none() {
+            return Err(Error::ConflictingFlags(
+                format!("@silent only applies to @capture: {}", shell::join(&self.args))));
+        }
+        if self.recurse && !is_upbuild(&self.args[0]) {
+            return Err(Error::ConflictingFlags(
+                format!("@recurse only applies to a name upbuild is installed as: {}", shell::join(&self.args))));
+        }
+        if self.args.iter().filter(|a| *a == ARGS_PLACEHOLDER).count() > 1 {
+            return Err(Error::MultiplePlaceholders(shell::join(&self.args)));
+        }
+        self.with_home(|k| std::env::var(k).ok())
+    }
+
+    // Expand ~ in local paths - an @ssh command's @cd is on the remote
+    // host, which expands its own
+    fn with_home<E: Fn(&str) -> Option<String>>(mut self, env: E) -> Result<Cmd> {
+        if self.ssh.is_none() {
+            self.cd = self.cd.map(|d| expand_home(d, &env)).transpose()?;
+        }
+        self.mkdir = self.mkdir.map(|d| expand_home(d, &env)).transpose()?;
+        self.outfile = self.outfile.map(|f| expand_home(f, &env)).transpose()?;
+        Ok(self)
+    }
+
+    /// The directory to create first, see `@mkdir`
+    pub fn mk_dir(&self) -> Option<&Path> {
+        self.mkdir.as_deref().map(Path::new)
+    }
+
+    /// The return code after applying `@retmap`
     pub fn map_code(&self, c: RetCode) ->RetCode {
         *self.retmap.get(&c)
             .unwrap_or(&c)
     }
 
+    /// The command and its arguments as given in the file
     pub fn args(&self) -> &[String]  {
         self.args.as_ref()
     }
 
+    /// Whether the command runs with the given selection
     pub fn enabled_with_reject(&self, select_tags: &HashSet<String>, reject_tags: &HashSet<String>) -> bool {
+        self.skip_reason(select_tags, reject_tags).is_none()
+    }
+
+    /// Why the command won't run with the given selection, if it won't
+    pub fn skip_reason(&self, select_tags: &HashSet<String>, reject_tags: &HashSet<String>) -> Option<&'static str> {
         if self.disabled {
-            return false;
+            return Some("@disable");
         }
 
         // reject if matched
-        if !reject_tags.is_disjoint(&self.tags) {
-            return false;
+        if self.has_matching_tag(reject_tags) {
+            return Some("has a rejected tag");
         }
 
         let no_tags = select_tags.is_empty();
         if self.manual &&
-            (no_tags || select_tags.is_disjoint(&self.tags)) {
-            return false;
+            (no_tags || !self.has_matching_tag(select_tags)) {
+            return Some("@manual and not selected");
         }
 
-        if ! no_tags {
+        if ! no_tags && !self.has_matching_tag(select_tags) {
             // There are some tags - must match
-            return !select_tags.is_disjoint(&self.tags);
+            return Some("has no selected tag");
         }
-        true
+        None
+    }
+
+    /// Why the command won't run when selected by the given
+    /// `--ub-tags=` expression, if it won't.  `@manual` commands have
+    /// to be named by it, not just left unexcluded.
+    pub fn skip_reason_by(&self, expr: &TagExpr) -> Option<&'static str> {
+        if self.disabled {
+            return Some("@disable");
+        }
+        if self.manual && !expr.selects(&self.tags) {
+            return Some("@manual and not selected");
+        }
+        if !expr.matches(&self.tags) {
+            return Some("doesn't match --ub-tags");
+        }
+        None
+    }
+
+    // Whether any of the command's tags match any of the patterns
+    fn has_matching_tag(&self, patterns: &HashSet<String>) -> bool {
+        patterns.iter().any(|p| self.tags.iter().any(|t| tag_matches(p, t)))
+    }
+
+    /// The command's `@tags`
+    pub fn tags(&self) -> &HashSet<String> {
+        &self.tags
+    }
+
+    /// The command's `@retmap`
+    pub fn retmap(&self) -> &HashMap<RetCode, RetCode> {
+        &self.retmap
+    }
+
+    /// The command's lines as they appear in the file
+    pub fn source(&self) -> &[String] {
+        &self.source
+    }
+
+    /// The command line to run given the provided args.
+    ///
+    /// With no `@args-here`, or `$@` or `$N` tokens in a command with
+    /// `@positional-args`, the args are:
+    ///
+    /// * those before the first `--`, always used
+    /// * those after the first `--`, defaults replaced by any provided
+    ///   args
+    /// * those after a second `--`, always added after the above
+    ///
+    /// Otherwise the provided args go only where the tokens are:
+    /// `@args-here` or `$@` as a whole arg is replaced by all of them, `$N` is
+    /// replaced by the Nth, and `$$` is a literal `$`.  An `$N`
+    /// beyond the provided args is an [`Error::MissingArg`].
+    ///
+    /// `argv0`, given for a recursive upbuild, replaces the first arg,
+    /// and the provided args are then passed after a `--` so they
+    /// aren't taken as its own flags.
+    pub fn effective_args(&self, provided_args: &[String], argv0: Option<&str>) -> Result<Vec<String>> {
+        Self::args_with(&self.args, provided_args, argv0, self.positional_args).map(Cow::into_owned)
+    }
+
+    // As effective_args, borrowing the original where there is
+    // nothing to change, with $ tokens substituted if positional
+    pub(crate) fn args_with<'a>(args: &'a [String], provided_args: &[String], argv0: Option<&str>, positional: bool) -> Result<Cow<'a, [String]>> {
+
+        let skip = if argv0.is_some() { 1 } else { 0 };
+
+        if ! args.iter().any(|x| x == ARGS_PLACEHOLDER || (positional && x.contains('$'))) {
+            return Ok(Self::with_default_args(args, provided_args, argv0));
+        }
+
+        // the provided args go where the tokens say, and nowhere else
+        if let Some(expanded) = Self::expand_tokens(&args[skip..], provided_args, positional)? {
+            return Ok(argv0.map(String::from).into_iter().chain(expanded).collect());
+        }
+
+        // just $$ escapes
+        let args: Vec<String> = args.iter().map(|x| x.replace("$$", "$")).collect();
+        Ok(Cow::Owned(Self::with_default_args(&args, provided_args, argv0).into_owned()))
+    }
+
+    // Substitute @args-here, and if positional $@ and $N, in the
+    // given args, or None if there are none to substitute
+    fn expand_tokens(args: &[String], provided_args: &[String], positional: bool) -> Result<Option<Vec<String>>> {
+        let mut expanded = Vec::with_capacity(args.len() + provided_args.len());
+        let mut substituted = false;
+        for arg in args {
+            if arg == ARGS_PLACEHOLDER || (positional && arg == "$@") {
+                expanded.extend(provided_args.iter().cloned());
+                substituted = true;
+                continue;
+            }
+            if !positional {
+                expanded.push(arg.clone());
+                continue;
+            }
+
+            let mut out = String::with_capacity(arg.len());
+            let mut rest = arg.as_str();
+            while let Some(at) = rest.find('$') {
+                out.push_str(&rest[..at]);
+                rest = &rest[at + 1..];
+                let digits = rest.find(|c: char| !c.is_ascii_digit()).unwrap_or(rest.len());
+                if let Some(r) = rest.strip_prefix('$') {
+                    out.push('$');
+                    rest = r;
+                } else if let Some(n) = rest[..digits].parse::<usize>().ok().filter(|n| *n > 0) {
+                    let value = provided_args.get(n - 1)
+                        .ok_or_else(|| Error::MissingArg(n, shell::join(args)))?;
+                    out.push_str(value);
+                    rest = &rest[digits..];
+                    substituted = true;
+                } else {
+                    out.push('$');
+                }
+            }
+            out.push_str(rest);
+            expanded.push(out);
+        }
+        Ok(if substituted { Some(expanded) } else { None })
+    }
+
+    // Split the file's args into the mandatory part, the defaults
+    // after the first --, and those always added after a second --
+    fn split_defaults(args: &[String]) -> (&[String], &[String], &[String]) {
+        let sep = |a: &[String]| a.iter().position(|x| x == "--");
+        match sep(args) {
+            None => (args, &[], &[]),
+            Some(first) => {
+                let rest = &args[first + 1..];
+                match sep(rest) {
+                    None => (&args[..first], rest, &[]),
+                    Some(second) => (&args[..first], &rest[..second], &rest[second + 1..]),
+                }
+            }
+        }
+    }
+
+    // Replace the defaults with the provided args, if any
+    fn with_default_args<'a>(args: &'a [String], provided_args: &[String], argv0: Option<&str>) -> Cow<'a, [String]> {
+
+        let skip = if argv0.is_some() { 1 } else { 0 };
+
+        if provided_args.is_empty() && argv0.is_none() && !args.iter().any(|x| x == "--") {
+            return Cow::Borrowed(args);
+        }
+
+        let (mandatory, defaults, always) = Self::split_defaults(&args[skip..]);
+        let replaceable: &[String] = if provided_args.is_empty() { defaults } else { provided_args };
+
+        // a recursive upbuild mustn't take the provided args as its own flags
+        let separator = argv0.filter(|_| !provided_args.is_empty()).map(|_| String::from("--"));
+        argv0.map(String::from).into_iter()
+            .chain(mandatory.iter().cloned())
+            .chain(separator)
+            .chain(replaceable.iter().cloned())
+            .chain(always.iter().cloned())
+            .collect()
     }
 }
 
@@ -111,22 +583,57 @@ pub struct ClassicFile {
     pub(crate) commands: Vec<Cmd>, // TODO - pub(crate) is lazy)
 }
 
+// Write the file back out as read, less any removed commands
+impl std::fmt::Display for ClassicFile {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        for (i, cmd) in self.commands.iter().enumerate() {
+            if i != 0 {
+                writeln!(f, "{}", if cmd.fallback { "||" } else { "&&" })?;
+            }
+            cmd.source.iter().try_for_each(|l| writeln!(f, "{}", l))?;
+        }
+        Ok(())
+    }
+}
+
 #[derive(Debug, PartialEq)]
 enum Line {
     Flag(Flags),
     Arg(String),
     Comment,
-    End
+    End,
+    Fallback,
+}
+
+// Exit codes are truncated to 8 bits on unix, so only allow mapping
+// to codes we can actually return
+#[cfg(target_family = "unix")]
+fn valid_retmap_target(c: RetCode) -> bool {
+    (0..=255).contains(&c)
+}
+
+#[cfg(not(target_family = "unix"))]
+fn valid_retmap_target(_c: RetCode) -> bool {
+    true
 }
 
 // Parse a single @retmap=entry
 fn parse_retmap(def: &str) -> Result<HashMap<RetCode, RetCode>> {
     let mut h: HashMap<RetCode, RetCode> = HashMap::new();
-    for entry in def.split(',') {
+    for entry in def.split(',').map(str::trim) {
+        if entry.is_empty() && !def.trim().is_empty() {
+            return Err(Error::InvalidRetMapDefinition(format!("{} (empty entry, is there a stray comma?)", def)));
+        }
         let parts = entry.split_once("=>").ok_or_else(|| Error::InvalidRetMapDefinition(def.to_string()))?;
-        let a = str::parse::<RetCode>(parts.0).map_err(|_| Error::InvalidRetMapDefinition(parts.0.to_string()))?;
-        let b = str::parse::<RetCode>(parts.1).map_err(|_| Error::InvalidRetMapDefinition(parts.1.to_string()))?;
-        h.insert(a, b);
+        let (from, to) = (parts.0.trim(), parts.1.trim());
+        let a = str::parse::<RetCode>(from).map_err(|_| Error::InvalidRetMapDefinition(from.to_string()))?;
+        let b = str::parse::<RetCode>(to).map_err(|_| Error::InvalidRetMapDefinition(to.to_string()))?;
+        if !valid_retmap_target(b) {
+            return Err(Error::InvalidRetMapDefinition(format!("{} (exit code {} is out of range 0-255)", entry, b)));
+        }
+        if h.insert(a, b).is_some() {
+            return Err(Error::InvalidRetMapDefinition(format!("{} (exit code {} is mapped more than once)", def, a)));
+        }
     }
     Ok(h)
 }
@@ -135,12 +642,26 @@ fn parse_line(l: &str) -> Result<Line> {
     match l {
         "@disable" => Ok(Line::Flag(Flags::Disable)),
         "@manual" => Ok(Line::Flag(Flags::Manual)),
+        "@detach" => Ok(Line::Flag(Flags::Detach)),
+        "@scan" => Ok(Line::Flag(Flags::Scan)),
+        "@outfile-all" => Ok(Line::Flag(Flags::OutfileAll)),
+        "@silent" => Ok(Line::Flag(Flags::Silent)),
+        "@pipe" => Ok(Line::Flag(Flags::Pipe)),
+        "@exec" => Ok(Line::Flag(Flags::Exec)),
+        "@no-propagate" => Ok(Line::Flag(Flags::NoPropagate)),
+        "@no-forward-args" => Ok(Line::Flag(Flags::NoForwardArgs)),
+        "@recurse" => Ok(Line::Flag(Flags::Recurse)),
+        "@args-here" => Ok(Line::Flag(Flags::ArgsHere)),
+        "@positional-args" => Ok(Line::Flag(Flags::PositionalArgs)),
         "&&" => Ok(Line::End),
+        "||" => Ok(Line::Fallback),
         _ => {
             if l.starts_with('#') {
                 Ok(Line::Comment)
             } else if l.starts_with('@') {
-                match split_flag(l)? {
+                let (name, value) = split_flag(l)?;
+                let value = unquote(l, value)?;
+                match (name, value.as_ref()) {
                     ("tags", tags) => Ok(Line::Flag(Flags::Tags(
                         if tags.is_empty() { // explicitly don't split ""
                             HashSet::new()
@@ -151,11 +672,45 @@ fn parse_line(l: &str) -> Result<Line> {
                         }
                     ))),
                     ("retmap", map) => Ok(Line::Flag(Flags::RetMap(parse_retmap(map)?))),
+                    // a path of nothing would only fail confusingly once run
+                    ("outfile" | "cd" | "mkdir", path) if path.trim().is_empty() =>
+                        Err(Error::EmptyValue(format!("@{}", name), l.to_string())),
                     ("outfile", outfile) => Ok(Line::Flag(Flags::Outfile(outfile.to_string()))),
                     ("cd", dir) => Ok(Line::Flag(Flags::Cd(dir.to_string()))),
                     ("mkdir", dir) => Ok(Line::Flag(Flags::Mkdir(dir.to_string()))),
                     ("disable", "") => Ok(Line::Flag(Flags::Disable)),
                     ("manual", "") => Ok(Line::Flag(Flags::Manual)),
+                    ("detach", "") => Ok(Line::Flag(Flags::Detach)),
+                    ("scan", "") => Ok(Line::Flag(Flags::Scan)),
+                    ("outfile-all", "") => Ok(Line::Flag(Flags::OutfileAll)),
+                    ("silent", "") => Ok(Line::Flag(Flags::Silent)),
+                    ("pipe", "") => Ok(Line::Flag(Flags::Pipe)),
+                    ("capture", name) if is_variable_name(name) => Ok(Line::Flag(Flags::Capture(name.to_string()))),
+                    ("exec", "") => Ok(Line::Flag(Flags::Exec)),
+                    ("no-propagate", "") => Ok(Line::Flag(Flags::NoPropagate)),
+                    ("no-forward-args", "") => Ok(Line::Flag(Flags::NoForwardArgs)),
+                    ("recurse", "") => Ok(Line::Flag(Flags::Recurse)),
+                    ("args-here", "") => Ok(Line::Flag(Flags::ArgsHere)),
+                    ("positional-args", "") => Ok(Line::Flag(Flags::PositionalArgs)),
+                    ("container", "") => Err(Error::InvalidTag(l.to_string())),
+                    ("container", image) => Ok(Line::Flag(Flags::Container(image.to_string()))),
+                    ("name", "") => Err(Error::InvalidTag(l.to_string())),
+                    ("name", name) => Ok(Line::Flag(Flags::Name(name.to_string()))),
+                    ("ssh", "") => Err(Error::InvalidTag(l.to_string())),
+                    ("ssh", host) => Ok(Line::Flag(Flags::Ssh(host.to_string()))),
+                    ("env-required", names) => Ok(Line::Flag(Flags::EnvRequired(parse_env_required(l, names)?))),
+                    ("outfile-limit", limit) => match parse_size(limit) {
+                        Some(limit) => Ok(Line::Flag(Flags::OutfileLimit(limit))),
+                        None => Err(Error::InvalidTag(l.to_string())),
+                    },
+                    ("umask", mask) => match parse_umask(mask) {
+                        Some(mask) => Ok(Line::Flag(Flags::Umask(mask))),
+                        None => Err(Error::InvalidTag(l.to_string())),
+                    },
+                    ("stdin", "") => Err(Error::InvalidTag(l.to_string())),
+                    ("stdin", "null") => Ok(Line::Flag(Flags::Stdin(StdinSource::Null))),
+                    ("stdin", "inherit") => Ok(Line::Flag(Flags::Stdin(StdinSource::Inherit))),
+                    ("stdin", file) => Ok(Line::Flag(Flags::Stdin(StdinSource::File(file.into())))),
                     (&_, _) => Err(Error::InvalidTag(l.to_string()))
                 }
             } else {
@@ -165,6 +720,73 @@ fn parse_line(l: &str) -> Result<Line> {
     }
 }
 
+// A @umask in octal, as the shell's umask takes it
+fn parse_umask(mask: &str) -> Option<u32> {
+    if mask.is_empty() || mask.len() > 4 || !mask.bytes().all(|b| (b'0'..=b'7').contains(&b)) {
+        return None;
+    }
+    u32::from_str_radix(mask, 8).ok()
+}
+
+/// Whether a tag matches a pattern given to `--ub-select=` or
+/// `--ub-reject=`, where `*` matches any run of characters and `?` any
+/// one.  Patterns without either must match exactly.
+pub(crate) fn tag_matches(pattern: &str, tag: &str) -> bool {
+    if ! pattern.contains(['*', '?']) {
+        return pattern == tag;
+    }
+    let pattern: Vec<char> = pattern.chars().collect();
+    let tag: Vec<char> = tag.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    // where the last * was, and how much of the tag it has taken
+    let mut star: Option<(usize, usize)> = None;
+    while t < tag.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, t));
+                p += 1;
+            },
+            Some(c) if *c == '?' || *c == tag[t] => {
+                p += 1;
+                t += 1;
+            },
+            _ => match star {
+                // let the * take one more character
+                Some((sp, st)) => {
+                    star = Some((sp, st + 1));
+                    p = sp + 1;
+                    t = st + 1;
+                },
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+// The names from @env-required, each followed by ? if it may be empty
+fn parse_env_required(l: &str, names: &str) -> Result<Vec<(String, bool)>> {
+    names.split(',')
+        .map(|n| match n.strip_suffix('?') {
+            Some(n) => (n, true),
+            None => (n, false),
+        })
+        .map(|(n, allow_empty)| match n {
+            "" => Err(Error::InvalidTag(l.to_string())),
+            n if n.contains(['=', '?', ' ']) => Err(Error::InvalidTag(l.to_string())),
+            n => Ok((n.to_string(), allow_empty)),
+        })
+        .collect()
+}
+
+// Whether a name can be used for an environment variable, as @capture
+// gives them to later commands
+fn is_variable_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars.next().map_or(false, |c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
 fn split_flag(l: &str) -> Result<(&str, &str)> {
     if let Some(rest) = l.strip_prefix('@') {
         return Ok(rest.split_once('=').unwrap_or((rest, "")));
@@ -172,19 +794,76 @@ fn split_flag(l: &str) -> Result<(&str, &str)> {
     Err(Error::InvalidTag(l.to_string()))
 }
 
-impl ClassicFile {
+// A flag's value without its quotes, if it's quoted as "..." or
+// '...'.  Inside, a \ escapes the quote or another \ and is kept
+// before anything else, so Windows paths needn't double them.
+// Unquoted values are used as written.
+fn unquote<'a>(l: &str, value: &'a str) -> Result<Cow<'a, str>> {
+    let quote = match value.chars().next() {
+        Some(q @ ('"' | '\'')) if value.len() > 1 && value.ends_with(q) => q,
+        _ => return Ok(Cow::Borrowed(value)),
+    };
+    let mut unquoted = String::new();
+    let mut chars = value[1..value.len() - 1].chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some(e) if e == quote || e == '\\' => unquoted.push(e),
+                Some(e) => unquoted.extend(['\\', e]),
+                // escaping the closing quote
+                None => return Err(Error::InvalidTag(l.to_string())),
+            },
+            c if c == quote => return Err(Error::InvalidTag(l.to_string())),
+            c => unquoted.push(c),
+        }
+    }
+    Ok(Cow::Owned(unquoted))
+}
 
-    /// Create a [ClassicFile] from the given iterator providing lines
-    pub fn parse_lines<I, T>(lines: I) -> Result<ClassicFile>
-    where
-        I: Iterator<Item=T>,
-        T: std::borrow::Borrow<str>
-    {
-        let mut e: Option<Cmd> = None;
-        let mut entries: Vec<Cmd> = Vec::new();
+// A flag's value as written to a file, quoted if it wouldn't read back
+// as it is - it looks quoted or starts or ends with a space
+fn quote_value(value: &str) -> Cow<'_, str> {
+    let looks_quoted = matches!(unquote("", value), Ok(Cow::Owned(_)) | Err(_));
+    if ! looks_quoted && value.trim() == value {
+        return Cow::Borrowed(value);
+    }
+    let mut quoted = String::from("\"");
+    for c in value.chars() {
+        if c == '"' || c == '\\' {
+            quoted.push('\\');
+        }
+        quoted.push(c);
+    }
+    quoted.push('"');
+    Cow::Owned(quoted)
+}
 
-        for line in lines {
-            let line = parse_line(line.borrow())?;
+// Commands parsed from lines an entry at a time
+struct CmdStream<I> {
+    lines: I,
+    names: HashSet<String>,
+    // the next entry follows a ||
+    fallback: bool,
+    done: bool,
+}
+
+impl<I, T> CmdStream<I>
+where
+    I: Iterator<Item=Result<T>>,
+    T: std::borrow::Borrow<str>
+{
+    // The next entry, or None at the end of the lines
+    fn next_cmd(&mut self) -> Result<Option<Cmd>> {
+        let mut e: Option<Cmd> = None;
+        let mut source: Vec<String> = Vec::new();
+
+        for raw in self.lines.by_ref() {
+            let raw = raw?;
+            let line = parse_line(raw.borrow())?;
+            debug!("classified {:?}", line);
+            if line != Line::End && line != Line::Fallback {
+                source.push(raw.borrow().to_string());
+            }
 
             match line {
 
@@ -199,77 +878,305 @@ impl ClassicFile {
 
                 Line::Flag(f) => {
                     match e {
-                        Some(ref mut cmd) => {
-                            // TODO detect duplicates
-                            match f {
-                                Flags::Disable => cmd.disabled = true,
-                                Flags::Manual => cmd.manual = true,
-                                Flags::Tags(tags) => cmd.tags = tags,
-                                Flags::Outfile(filename) => cmd.outfile = Some(filename),
-                                Flags::RetMap(map) => cmd.retmap = map,
-                                Flags::Cd(dir) => cmd.cd = Some(dir),
-                                Flags::Mkdir(dir) => cmd.mkdir = Some(dir),
-                            }
-                        },
+                        Some(ref mut cmd) => cmd.apply_flag(f),
                         None => { Err(Error::FlagBeforeCommand(format!("{:?}", f)))? },
                     }
                 },
 
                 Line::Comment => (), // Just drop it
 
-                Line::End => {
-                    match e {
-                        Some(_) => entries.push(e.take().expect("isn't none")),
-                        None => Err(Error::EmptyEntry)?,
-                    }
+                Line::End | Line::Fallback => {
+                    return match e.take() {
+                        Some(cmd) if cmd.pipe && line == Line::Fallback => Err(Error::ConflictingFlags(
+                            format!("@pipe can't be followed by ||, it has to feed a command: {}", shell::join(cmd.args())))),
+                        Some(cmd) => {
+                            let cmd = self.complete(cmd.with_source(source));
+                            self.fallback = line == Line::Fallback;
+                            cmd.map(Some)
+                        },
+                        None => Err(Error::EmptyEntry),
+                    };
                 },
             }
         }
 
-        match e {
-            Some(_) => entries.push(e.take().expect("isn't none")),
-            None => Err(Error::EmptyEntry)?,
+        // the last entry has no && - and there must be one
+        self.done = true;
+        match e.take() {
+            Some(cmd) if cmd.pipe => Err(Error::UnconnectedPipe(shell::join(cmd.args()))),
+            Some(cmd) => self.complete(cmd.with_source(source)).map(Some),
+            None => Err(Error::EmptyEntry),
         }
+    }
 
+    fn complete(&mut self, mut cmd: Cmd) -> Result<Cmd> {
+        cmd.fallback = self.fallback;
+        let cmd = cmd.checked()?;
+        if let Some(name) = cmd.name() {
+            if ! self.names.insert(name.to_string()) {
+                return Err(Error::DuplicateName(name.to_string()));
+            }
+        }
+        Ok(cmd)
+    }
+}
+
+impl<I, T> Iterator for CmdStream<I>
+where
+    I: Iterator<Item=Result<T>>,
+    T: std::borrow::Borrow<str>
+{
+    type Item = Result<Cmd>;
+
+    fn next(&mut self) -> Option<Result<Cmd>> {
+        if self.done {
+            return None;
+        }
+        match self.next_cmd() {
+            Ok(cmd) => cmd.map(Ok),
+            Err(e) => {
+                self.done = true;
+                Some(Err(e))
+            },
+        }
+    }
+}
+
+impl ClassicFile {
+
+    /// Create a [ClassicFile] from the given iterator providing lines
+    pub fn parse_lines<I, T>(lines: I) -> Result<ClassicFile>
+    where
+        I: Iterator<Item=T>,
+        T: std::borrow::Borrow<str>
+    {
         Ok(ClassicFile{
-            commands: entries,
+            commands: Self::stream_lines(lines).collect::<Result<_>>()?,
         })
     }
 
-    /// Implement --ub-add, adding the provided_args to the .upbuild file
-    /// at the given path - creating if if required.
-    pub fn add<I, T>(provided_args: I, path: PathBuf) -> Result<()>
+    /// Parse commands from the given iterator providing lines,
+    /// yielding each as its entry completes rather than reading the
+    /// whole file first.  Iteration stops after the first error.
+    pub fn stream_lines<I, T>(lines: I) -> impl Iterator<Item=Result<Cmd>>
     where
         I: Iterator<Item=T>,
         T: std::borrow::Borrow<str>
     {
-        use std::io::{Seek, Write, SeekFrom};
+        CmdStream { lines: lines.map(Ok), names: HashSet::new(), fallback: false, done: false }
+    }
+
+    /// As [`ClassicFile::stream_lines`], reading the lines from the
+    /// given reader.  A line that can't be read, or isn't UTF-8, is an
+    /// error like any other.
+    pub fn stream_reader<R: std::io::BufRead>(reader: R) -> impl Iterator<Item=Result<Cmd>> {
+        let lines = reader.lines().map(|l| l.map_err(Error::from));
+        CmdStream { lines, names: HashSet::new(), fallback: false, done: false }
+    }
+
+    /// Create a file holding the given command
+    pub fn new(cmd: Cmd) -> ClassicFile {
+        ClassicFile { commands: vec![cmd] }
+    }
 
-        let args_str = provided_args
-            .fold(String::new(), |s, x| s + x.borrow() + "\n");
+    /// Read the file at the given path
+    pub fn load(path: &Path) -> Result<ClassicFile> {
+        ClassicFile::parse_lines(std::fs::read_to_string(path)?.lines())
+    }
+
+    /// Replace the file at the given path with this one.  Commands
+    /// read from a file are written back as they were read, comments
+    /// and all, except for any changes made to them.
+    pub fn save(&self, path: &Path) -> Result<()> {
+        Ok(std::fs::write(path, self.to_string())?)
+    }
 
-        if !args_str.is_empty() {
+    /// The commands in the file
+    pub fn commands(&self) -> &[Cmd] {
+        &self.commands
+    }
 
-            let mut f = std::fs::File::options()
-                .create(true)
-                .truncate(false)
-                .write(true).open(path)?;
+    /// The command at the given (0-based) index, to change
+    pub fn command_mut(&mut self, i: usize) -> Option<&mut Cmd> {
+        self.commands.get_mut(i)
+    }
 
-            let pos = f.seek(SeekFrom::End(0))?;
+    /// Add a command to the end of the file
+    pub fn push_command(&mut self, cmd: Cmd) -> Result<()> {
+        self.insert_command(self.commands.len(), cmd)
+    }
 
-            if pos != 0 {
-                f.write_all("&&\n".as_bytes())?;
+    /// Insert a command at the given (0-based) index
+    pub fn insert_command(&mut self, i: usize, cmd: Cmd) -> Result<()> {
+        if i > self.commands.len() {
+            return Err(Error::UnknownCommand((i + 1).to_string()));
+        }
+        if let Some(name) = cmd.name() {
+            if self.commands.iter().any(|c| c.name() == Some(name)) {
+                return Err(Error::DuplicateName(name.to_string()));
             }
-            f.write_all(args_str.as_bytes())?;
         }
+        self.commands.insert(i, cmd);
         Ok(())
     }
+
+    /// Remove the command at the given (0-based) index.  The only
+    /// command can't be removed, as that would leave an invalid file.
+    pub fn remove_command(&mut self, i: usize) -> Result<Cmd> {
+        if i >= self.commands.len() {
+            return Err(Error::UnknownCommand((i + 1).to_string()));
+        }
+        if self.commands.len() == 1 {
+            return Err(Error::UnableToEdit(String::from("can't remove the only command")));
+        }
+        Ok(self.commands.remove(i))
+    }
+
+    /// Find the index of a command given either its 1-based position
+    /// or its `@name`
+    pub fn find_command(&self, which: &str) -> Result<usize> {
+        let found = match which.parse::<usize>() {
+            Ok(n) => n.checked_sub(1).filter(|i| *i < self.commands.len()),
+            Err(_) => self.commands.iter().position(|c| c.name() == Some(which)),
+        };
+        found.ok_or_else(|| Error::UnknownCommand(which.to_string()))
+    }
+
+    /// Implement --ub-rm, removing the given command from the
+    /// .upbuild file at the given path
+    pub fn rm(which: &str, path: &Path) -> Result<()> {
+        let mut file = ClassicFile::load(path)?;
+        let i = file.find_command(which)?;
+        file.remove_command(i)?;
+        file.save(path)
+    }
+
+    /// Implement --ub-disable and --ub-enable, adding or removing
+    /// `@disable` on the given command in the .upbuild file at the
+    /// given path, returning a description of what happened
+    pub fn set_disabled(which: &str, disabled: bool, path: &Path) -> Result<String> {
+        let mut file = ClassicFile::load(path)?;
+        let i = file.find_command(which)?;
+        let cmd = &mut file.commands[i];
+        let what = format!("{}: {}", i + 1, shell::join(cmd.args()));
+        if !cmd.set_disabled(disabled) {
+            return Ok(format!("{} is already {}", what, if disabled { "disabled" } else { "enabled" }));
+        }
+        file.save(path)?;
+        Ok(format!("{} {}", if disabled { "Disabled" } else { "Enabled" }, what))
+    }
+
+    /// Implement --ub-move, moving the command `from` (a position or
+    /// `@name`) to position `to` in the .upbuild file at the given
+    /// path, returning the reordered file
+    pub fn move_command(from: &str, to: &str, path: &Path) -> Result<ClassicFile> {
+        let mut file = ClassicFile::load(path)?;
+        let i = file.find_command(from)?;
+        let j = to.parse::<usize>().ok()
+            .and_then(|n| n.checked_sub(1))
+            .filter(|j| *j < file.commands.len())
+            .ok_or_else(|| Error::UnknownCommand(to.to_string()))?;
+        if i != j {
+            let cmd = file.remove_command(i)?;
+            file.insert_command(j, cmd)?;
+        }
+        file.save(path)?;
+        Ok(file)
+    }
+
+    /// One line per command giving its position, `@name` if any, and
+    /// arguments
+    pub fn summary(&self) -> Vec<String> {
+        self.commands.iter().enumerate().map(|(i, cmd)| {
+            match cmd.name() {
+                Some(name) => format!("{}: {} (@name={})", i + 1, shell::join(cmd.args()), name),
+                None => format!("{}: {}", i + 1, shell::join(cmd.args())),
+            }
+        }).collect()
+    }
+
+    /// Implement --ub-add, adding the provided_args to the .upbuild file
+    /// at the given path - creating if if required.  A `&&` argument
+    /// separates multiple commands to add.
+    pub fn add<I, T>(provided_args: I, path: PathBuf) -> Result<()>
+    where
+        I: Iterator<Item=T>,
+        T: std::borrow::Borrow<str>
+    {
+        let args: Vec<T> = provided_args.collect();
+        if args.is_empty() {
+            return Ok(());
+        }
+
+        // don't write anything unless it all reads back
+        let mut cmds = Vec::new();
+        for entry in args.split(|x| x.borrow() == "&&") {
+            if entry.is_empty() {
+                return Err(Error::EmptyEntry);
+            }
+            // each argument is a line, so it can't span lines or look
+            // like a comment
+            if let Some(x) = entry.iter().map(|x| x.borrow()).find(|x| x.contains(['\n', '\r'])) {
+                return Err(Error::UnableToAdd(format!("argument {:?} contains a newline", x)));
+            }
+            if let Some(x) = entry.iter().map(|x| x.borrow()).find(|x| x.starts_with('#')) {
+                return Err(Error::UnableToAdd(format!("argument {:?} would be read back as a comment", x)));
+            }
+            // there's no escaping, so an argument starting with @ is
+            // always read as a flag
+            let cmd = Cmd::from_lines(entry.iter().map(|x| x.borrow())).map_err(|e| {
+                let added: Vec<&str> = entry.iter().map(|x| x.borrow()).collect();
+                Error::UnableToAdd(format!("{} (adding `{}`, arguments starting with @ are flags)", e, shell::join(&added)))
+            })?;
+            cmds.push(cmd);
+        }
+
+        let mut cmds = cmds.into_iter();
+        let mut file = if std::fs::metadata(&path).map_or(false, |m| m.len() > 0) {
+            ClassicFile::load(&path)?
+        } else {
+            ClassicFile::new(cmds.next().expect("at least one command"))
+        };
+        cmds.try_for_each(|cmd| file.push_command(cmd))?;
+
+        // the file is only replaced once it's known to read back as
+        // intended
+        let text = file.to_string();
+        match ClassicFile::parse_lines(text.lines()) {
+            Ok(read) if read.commands.iter().map(Cmd::args).eq(file.commands.iter().map(Cmd::args)) => (),
+            Ok(_) => return Err(Error::UnableToAdd(String::from("the file would read back differently"))),
+            Err(e) => return Err(Error::UnableToAdd(format!("the file would not read back: {}", e))),
+        }
+        Ok(std::fs::write(&path, text)?)
+    }
+}
+
+// Expand a leading ~ or ~/ in a path to the home directory - from
+// HOME, or USERPROFILE on Windows.  Any other ~ is left alone, except
+// ~user which isn't supported.
+fn expand_home<E: Fn(&str) -> Option<String>>(path: String, env: E) -> Result<String> {
+    let rest = match path.strip_prefix('~') {
+        Some(rest) => rest,
+        None => return Ok(path),
+    };
+    if ! rest.is_empty() && ! rest.starts_with(std::path::is_separator) {
+        return Err(Error::UnableToExpand(path, String::from("only ~ on its own is supported, not ~user")));
+    }
+    let home = env("HOME")
+        .or_else(|| if cfg!(windows) { env("USERPROFILE") } else { None })
+        .filter(|h| !h.is_empty());
+    match home {
+        Some(home) if rest.is_empty() => Ok(home),
+        Some(home) => Ok(format!("{}{}", home.trim_end_matches(std::path::is_separator), rest)),
+        None => Err(Error::UnableToExpand(path, String::from("HOME isn't set"))),
+    }
 }
 
 #[cfg(test)]
 mod tests {
 
     use super::*;
+    use crate::testing::TempDir;
 
     #[test]
     fn test_split_flag() {
@@ -279,17 +1186,69 @@ mod tests {
         assert!(split_flag("").is_err());
     }
 
+    #[test]
+    fn test_unquote() {
+        let unquote = |v| unquote("@cd", v).map(Cow::into_owned);
+        assert_eq!(unquote(r#""my project (v2)""#).unwrap(), "my project (v2)");
+        assert_eq!(unquote("'weird = name.log'").unwrap(), "weird = name.log");
+        assert_eq!(unquote(r#""  spaced  ""#).unwrap(), "  spaced  ");
+        assert_eq!(unquote(r#""say \"hi\"""#).unwrap(), r#"say "hi""#);
+        assert_eq!(unquote(r#"'it\'s'"#).unwrap(), "it's");
+        assert_eq!(unquote(r#""a\\b""#).unwrap(), r"a\b");
+        // other escapes are kept, for Windows paths
+        assert_eq!(unquote(r#""C:\Program Files\x""#).unwrap(), r"C:\Program Files\x");
+        assert_eq!(unquote(r#""it's""#).unwrap(), "it's");
+        assert_eq!(unquote(r#""""#).unwrap(), "");
+
+        // unquoted values are as they were
+        for v in ["/path/to", "a b", r#""open"#, r#"mid"dle"#, "'", r#"x"y""#, r"C:\x"] {
+            assert_eq!(unquote(v).unwrap(), v);
+        }
+
+        assert!(unquote(r#""a"b""#).is_err());
+        assert!(unquote(r#""a\""#).is_err());
+        assert!(unquote("'a'b'").is_err());
+    }
+
     #[test]
     fn test_parse_retmap() {
         assert_eq!(HashMap::from([(1, 0)]), parse_retmap("1=>0").expect("should succeed"));
         assert_eq!(HashMap::from([(1, 0),
                                   (0, 1),
-                                  (200000, 200001)]),
-                   parse_retmap("1=>0,0=>1,200000=>200001").expect("should succeed"));
-        assert!(parse_retmap("").is_err());
+                                  (200000, 255)]),
+                   parse_retmap("1=>0,0=>1,200000=>255").expect("should succeed"));
+        assert_eq!(HashMap::from([(-1, 0)]), parse_retmap("-1=>0").expect("should succeed"));
+        if cfg!(target_family = "unix") {
+            // can't return these on unix
+            assert!(parse_retmap("1=>0,0=>1,200000=>200001").is_err());
+            assert!(parse_retmap("1=>256").is_err());
+            assert!(parse_retmap("1=>-1").is_err());
+        } else {
+            assert_eq!(HashMap::from([(1, 0),
+                                      (0, 1),
+                                      (200000, 200001)]),
+                       parse_retmap("1=>0,0=>1,200000=>200001").expect("should succeed"));
+        }
+        assert_eq!(parse_retmap(""), Err(Error::InvalidRetMapDefinition(String::new())));
         assert!(parse_retmap("foo").is_err());
         assert!(parse_retmap("1=>0,bar").is_err());
         assert!(parse_retmap("1=>0,0").is_err());
+
+        // spaces around entries and => are allowed
+        assert_eq!(HashMap::from([(1, 0), (2, 0)]), parse_retmap("1 => 0, 2 => 0").expect("should succeed"));
+        assert_eq!(HashMap::from([(-1, 3)]), parse_retmap(" -1=> 3 ").expect("should succeed"));
+        assert!(parse_retmap("1 = > 0").is_err());
+
+        // each code can only be mapped once
+        assert_eq!(parse_retmap("1=>0,1=>5"),
+                   Err(Error::InvalidRetMapDefinition(String::from("1=>0,1=>5 (exit code 1 is mapped more than once)"))));
+        assert_eq!(parse_retmap("1=>0, 1 => 0"),
+                   Err(Error::InvalidRetMapDefinition(String::from("1=>0, 1 => 0 (exit code 1 is mapped more than once)"))));
+
+        for stray in ["1=>0,", ",1=>0", "1=>0,,2=>0", "1=>0, "] {
+            assert_eq!(parse_retmap(stray),
+                       Err(Error::InvalidRetMapDefinition(format!("{} (empty entry, is there a stray comma?)", stray))));
+        }
     }
 
     fn string_set<const N: usize>(list: [&str; N]) -> HashSet<String> {
@@ -303,6 +1262,68 @@ mod tests {
         assert!(parse_retmap("@disabl").is_err());
 
         assert_eq!(Line::Flag(Flags::Manual), parse_line("@manual").expect("should succeed"));
+        assert_eq!(Line::Flag(Flags::Detach), parse_line("@detach").expect("should succeed"));
+        assert!(parse_line("@detach=yes").is_err());
+
+        assert_eq!(Line::Flag(Flags::Stdin(StdinSource::Null)), parse_line("@stdin=null").expect("should succeed"));
+        assert_eq!(Line::Flag(Flags::Stdin(StdinSource::Inherit)), parse_line("@stdin=inherit").expect("should succeed"));
+        assert_eq!(Line::Flag(Flags::Stdin(StdinSource::File("in.txt".into()))), parse_line("@stdin=in.txt").expect("should succeed"));
+        assert!(parse_line("@stdin=").is_err());
+        assert!(parse_line("@stdin").is_err());
+
+        assert_eq!(Line::Flag(Flags::Scan), parse_line("@scan").expect("should succeed"));
+        assert!(parse_line("@scan=errors").is_err());
+
+        assert_eq!(Line::Flag(Flags::OutfileAll), parse_line("@outfile-all").expect("should succeed"));
+        assert!(parse_line("@outfile-all=yes").is_err());
+
+        assert_eq!(Line::Flag(Flags::Capture("VERSION".into())), parse_line("@capture=VERSION").expect("should succeed"));
+        assert_eq!(Line::Flag(Flags::Capture("_v2".into())), parse_line("@capture=_v2").expect("should succeed"));
+        for bad in ["@capture", "@capture=", "@capture=2x", "@capture=A-B", "@capture=A B", "@capture=A=B"] {
+            assert!(parse_line(bad).is_err(), "{}", bad);
+        }
+        assert_eq!(Line::Flag(Flags::Silent), parse_line("@silent").expect("should succeed"));
+        assert!(parse_line("@silent=yes").is_err());
+
+        assert_eq!(Line::Flag(Flags::Pipe), parse_line("@pipe").expect("should succeed"));
+        assert!(parse_line("@pipe=next").is_err());
+
+        assert_eq!(Line::Flag(Flags::Exec), parse_line("@exec").expect("should succeed"));
+        assert!(parse_line("@exec=gdb").is_err());
+
+        assert_eq!(Line::Flag(Flags::NoPropagate), parse_line("@no-propagate").expect("should succeed"));
+        assert!(parse_line("@no-propagate=select").is_err());
+
+        assert_eq!(Line::Flag(Flags::Name("deploy".to_string())), parse_line("@name=deploy").expect("should succeed"));
+        assert!(parse_line("@name=").is_err());
+        assert!(parse_line("@name").is_err());
+
+        assert_eq!(Line::Flag(Flags::NoForwardArgs), parse_line("@no-forward-args").expect("should succeed"));
+        assert!(parse_line("@no-forward-args=all").is_err());
+
+        assert_eq!(Line::Flag(Flags::Recurse), parse_line("@recurse").expect("should succeed"));
+        assert!(parse_line("@recurse=ub").is_err());
+
+        assert_eq!(Line::Flag(Flags::Container("ghcr.io/org/toolchain:1.2".to_string())),
+                   parse_line("@container=ghcr.io/org/toolchain:1.2").expect("should succeed"));
+        assert!(parse_line("@container=").is_err());
+
+        assert_eq!(Line::Flag(Flags::Ssh("builder@signhost".to_string())),
+                   parse_line("@ssh=builder@signhost").expect("should succeed"));
+        assert!(parse_line("@ssh=").is_err());
+        assert!(parse_line("@container").is_err());
+
+        assert_eq!(Line::Flag(Flags::OutfileLimit(512 << 10)), parse_line("@outfile-limit=512K").expect("should succeed"));
+        assert_eq!(Line::Flag(Flags::OutfileLimit(0)), parse_line("@outfile-limit=0").expect("should succeed"));
+        assert!(parse_line("@outfile-limit=").is_err());
+        assert!(parse_line("@outfile-limit=big").is_err());
+
+        assert_eq!(Line::Flag(Flags::Umask(0o002)), parse_line("@umask=002").expect("should succeed"));
+        assert_eq!(Line::Flag(Flags::Umask(0o027)), parse_line("@umask=27").expect("should succeed"));
+        assert_eq!(Line::Flag(Flags::Umask(0o7777)), parse_line("@umask=7777").expect("should succeed"));
+        for bad in ["@umask", "@umask=", "@umask=008", "@umask=+02", "@umask=00002", "@umask=0x12"] {
+            assert!(parse_line(bad).is_err(), "{}", bad);
+        }
         assert!(parse_retmap("@manual=").is_err());
         assert!(parse_retmap("@manual").is_err());
 
@@ -314,6 +1335,23 @@ mod tests {
         assert_eq!(Line::Flag(Flags::Cd("/path/to".into())), parse_line("@cd=/path/to").expect("should succeed"));
         assert!(parse_retmap("@cd=").is_err());
         assert!(parse_retmap("@cd").is_err());
+        assert_eq!(Line::Flag(Flags::Cd("my project (v2)".into())), parse_line(r#"@cd="my project (v2)""#).expect("should succeed"));
+        assert_eq!(Line::Flag(Flags::Cd(" a = b ".into())), parse_line("@cd=' a = b '").expect("should succeed"));
+        assert!(parse_line(r#"@cd="a"b""#).is_err());
+
+        // paths can't be empty, but @tags can
+        for flag in ["@cd", "@mkdir", "@outfile"] {
+            for line in [flag.to_string(), format!("{}=", flag), format!("{}=   ", flag), format!("{}=\t", flag), format!("{}=' '", flag)] {
+                assert_eq!(parse_line(&line), Err(Error::EmptyValue(flag.to_string(), line.clone())));
+            }
+        }
+
+        assert_eq!(Line::Flag(Flags::EnvRequired(vec![("ARM_TOOLCHAIN_DIR".into(), false), ("LICENSE_SERVER".into(), true)])),
+                   parse_line("@env-required=ARM_TOOLCHAIN_DIR,LICENSE_SERVER?").expect("should succeed"));
+        for bad in ["@env-required", "@env-required=", "@env-required=A,,B", "@env-required=?", "@env-required=A=1", "@env-required=A??", "@env-required=A B"] {
+            assert!(parse_line(bad).is_err(), "{}", bad);
+        }
+        assert_eq!(Line::Flag(Flags::Outfile("weird = name.log".into())), parse_line("@outfile='weird = name.log'").expect("should succeed"));
 
         assert_eq!(Line::Flag(Flags::Mkdir("/path/to".into())), parse_line("@mkdir=/path/to").expect("should succeed"));
         assert!(parse_retmap("@mkdir=").is_err());
@@ -421,6 +1459,38 @@ install
         assert_eq!(file.commands[1].args, vec!["make", "install"]);
     }
 
+    #[test]
+    fn test_ssh() {
+        let file = parse("sign\n@ssh=builder@signhost\n@cd=/srv/signing\nfw.bin\n");
+        assert_eq!(file.commands[0].ssh(), Some("builder@signhost"));
+        assert_eq!(file.commands[0].directory(), Some(Path::new("/srv/signing")));
+
+        // wherever @outfile appears
+        for s in ["sign\n@ssh=signhost\n@outfile=log.txt\n&&\nmake\n",
+                  "make\n&&\nsign\n@outfile=log.txt\n@ssh=signhost\n"] {
+            match ClassicFile::parse_lines(s.lines()) {
+                Err(Error::ConflictingFlags(msg)) => assert_eq!(msg, "@outfile can't be read from a @ssh host: sign"),
+                r => panic!("expected conflict, got {:?}", r.map(|_| ())),
+            }
+        }
+    }
+
+    #[test]
+    fn test_placeholder() {
+        let file = parse("cargo\ntest\n@args-here\n--\n--nocapture\n&&\nfind\n-exec\nrm\n{}\n;\n");
+        assert_eq!(file.commands[0].args, vec!["cargo", "test", "@args-here", "--", "--nocapture"]);
+        assert_eq!(file.commands[0].effective_args(&[], None).unwrap(), ["cargo", "test", "--", "--nocapture"]);
+        // a {} is any other arg
+        assert_eq!(file.commands[1].effective_args(&[], None).unwrap(), ["find", "-exec", "rm", "{}", ";"]);
+        assert_eq!(file.commands[1].effective_args(&[String::from("x")], None).unwrap(), ["find", "-exec", "rm", "{}", ";", "x"]);
+
+        match ClassicFile::parse_lines("make\n&&\ncp\n@args-here\n@args-here\n".lines()) {
+            Err(Error::MultiplePlaceholders(cmd)) => assert_eq!(cmd, "cp @args-here @args-here"),
+            r => panic!("expected error, got {:?}", r.map(|_| ())),
+        }
+        assert!(matches!(ClassicFile::parse_lines("@args-here\nmake\n".lines()), Err(Error::FlagBeforeCommand(_))));
+    }
+
     #[test]
     fn test_recursive() {
 
@@ -480,11 +1550,17 @@ log.txt
         assert!(!cmd.manual);
         assert!(!cmd.recurse);
         assert_eq!(cmd.retmap, HashMap::from([(1, 0)]));
+        // any other name upbuild is installed as only when asked
+        assert!(!parse("ub\n--ub-select=host\n").commands[0].recurse);
+        assert!(parse("ub\n@recurse\n--ub-select=host\n").commands[0].recurse);
+        assert!(!parse("upbuild-print\n").commands[0].recurse);
+        assert!(parse("upbuild-print\n@recurse\n").commands[0].recurse);
+        assert!(matches!(ClassicFile::parse_lines(["ubx", "@recurse"].into_iter()), Err(Error::ConflictingFlags(_))));
         assert_eq!(cmd.cd, None);
         assert_eq!(cmd.mkdir, None);
         assert_eq!(cmd.outfile, Some(String::from("log.txt")));
         assert_eq!(cmd.args, vec!["uv4", "-j0", "-b", "project.uvproj", "-o", "log.txt"]);
-        assert_eq!(cmd.out_file(), Some(PathBuf::from("log.txt")));
+        assert_eq!(cmd.out_file(), Some(Path::new("log.txt")));
 
         for (v, exp) in [
             (0,0),
@@ -611,6 +1687,32 @@ install
                                  string_set(["release"]), [true, false, false]);
     }
 
+    #[test]
+    fn test_tags_glob() {
+        let file = parse("make\n@tags=board-stm32f4\nfw\n&&\nmake\n@tags=board-nrf52,nrf52-debug\nfw\n&&\nmake\n@tags=host-linux\ntest\n&&\nmake\n@manual\n@tags=board-*\nall\n");
+
+        check_select_tags(&file, string_set(["board-*"]), [true, true, false, true]);
+        check_select_tags(&file, string_set(["*-linux"]), [false, false, true, false]);
+        check_select_tags(&file, string_set(["board-nrf5?"]), [false, true, false, false]);
+        check_select_tags(&file, string_set(["board-nrf5"]), [false, false, false, false]);
+        check_select_tags(&file, string_set(["*"]), [true, true, true, true]);
+        check_select_reject_tags(&file, string_set([]), string_set(["*-debug"]), [true, false, true, false]);
+        check_select_reject_tags(&file, string_set(["board-*"]), string_set(["*nrf*"]), [true, false, false, true]);
+    }
+
+    #[test]
+    fn test_tag_matches() {
+        for (pattern, tag) in [("host", "host"), ("*", ""), ("*", "host"), ("h*", "host"), ("*t", "host"), ("h*s*t", "host"),
+                               ("?ost", "host"), ("h??t", "host"), ("**", "host"), ("*-debug", "nrf52-debug"),
+                               ("a*b*c", "aXbYbZc"), ("a*", "a"), ("é?", "éa"), ("a*b", "a*b")] {
+            assert!(tag_matches(pattern, tag), "{} {}", pattern, tag);
+        }
+        for (pattern, tag) in [("host", "hos"), ("host", "host2"), ("h?st", "hst"), ("*t", "hosts"), ("h*s", "host"),
+                               ("?", ""), ("a*b*c", "aXbYbZ"), ("host*", "hos"), ("ho?", "host")] {
+            assert!(!tag_matches(pattern, tag), "{} {}", pattern, tag);
+        }
+    }
+
     #[test]
     fn test_cd_mkdir() {
 
@@ -651,4 +1753,403 @@ cmake
         assert_eq!(file.commands[1].directory().expect("should exist"), std::path::Path::new("build"));
     }
 
+    #[test]
+    fn test_names() {
+        let file = parse("make\n@name=build\n&&\nmake\ntest\n&&\nscp\n@name=deploy\n");
+        assert_eq!(file.commands[0].name(), Some("build"));
+        assert_eq!(file.commands[1].name(), None);
+        assert_eq!(file.find_command("deploy").unwrap(), 2);
+        assert_eq!(file.find_command("1").unwrap(), 0);
+        assert_eq!(file.find_command("3").unwrap(), 2);
+        for bad in ["0", "4", "test", "-1", ""] {
+            match file.find_command(bad) {
+                Err(Error::UnknownCommand(w)) => assert_eq!(w, bad),
+                r => panic!("expected unknown for {:?}, got {:?}", bad, r),
+            }
+        }
+
+        match ClassicFile::parse_lines("make\n@name=a\n&&\nmake\n@name=a\n".lines()) {
+            Err(Error::DuplicateName(n)) => assert_eq!(n, "a"),
+            r => panic!("expected duplicate, got {:?}", r.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn test_to_string() {
+        let s = "# configure\ncmake\n@cd=build\n..\n&&\nmake\n# in parallel\n-j8\n\n";
+        assert_eq!(parse(s).to_string(), s);
+
+        let mut file = parse(s);
+        file.commands.remove(0);
+        assert_eq!(file.to_string(), "make\n# in parallel\n-j8\n\n");
+
+        let s = "restore\n||\nfetch\n--all\n&&\nmake\n";
+        assert_eq!(parse(s).to_string(), s);
+    }
+
+    #[test]
+    fn test_fallback() {
+        let file = parse("restore\n||\nfetch\n||\nclone\n&&\nmake\n");
+        let fallback: Vec<_> = file.commands.iter().map(Cmd::fallback).collect();
+        assert_eq!(fallback, [false, true, true, false]);
+        assert_eq!(file.commands[1].args(), ["fetch"]);
+    }
+
+    #[test]
+    fn test_rm() {
+        let dir = TempDir::new("rm");
+        let path = dir.join(".upbuild");
+        std::fs::write(&path, "# build\nmake\n&&\nmake\ntest\n&&\nscp\n@name=deploy\nout\n").unwrap();
+        let content = || std::fs::read_to_string(&path).unwrap();
+
+        ClassicFile::rm("deploy", &path).unwrap();
+        assert_eq!(content(), "# build\nmake\n&&\nmake\ntest\n");
+
+        for bad in ["3", "0", "deploy"] {
+            assert!(matches!(ClassicFile::rm(bad, &path), Err(Error::UnknownCommand(_))), "{}", bad);
+        }
+        assert_eq!(content(), "# build\nmake\n&&\nmake\ntest\n");
+
+        ClassicFile::rm("1", &path).unwrap();
+        assert_eq!(content(), "make\ntest\n");
+        assert!(matches!(ClassicFile::rm("1", &path), Err(Error::UnableToEdit(_))));
+        assert_eq!(content(), "make\ntest\n");
+    }
+
+    #[test]
+    fn test_edit_api() {
+        let cmd = |lines: &[&str]| Cmd::from_lines(lines.iter().copied()).unwrap();
+        let mut file = ClassicFile::new(cmd(&["cmake", "-S", ".", "-B", "build"]));
+        file.push_command(cmd(&["cmake", "--build", "build", "@name=build"])).unwrap();
+        file.insert_command(0, cmd(&["# generated", "mkdir", "-p", "build"])).unwrap();
+        assert_eq!(file.to_string(), "# generated\nmkdir\n-p\nbuild\n&&\ncmake\n-S\n.\n-B\nbuild\n&&\ncmake\n--build\nbuild\n@name=build\n");
+
+        assert!(matches!(file.push_command(cmd(&["make", "@name=build"])), Err(Error::DuplicateName(_))));
+        assert!(matches!(file.insert_command(5, cmd(&["make"])), Err(Error::UnknownCommand(_))));
+        assert!(matches!(file.remove_command(3), Err(Error::UnknownCommand(_))));
+
+        let c = file.command_mut(2).unwrap();
+        c.set_tags(["host", "all"].into_iter()).unwrap();
+        c.set_cd(Some("out")).unwrap();
+        assert!(c.set_disabled(true));
+        assert!(c.set_tags(["a,b"].into_iter()).is_err());
+        assert!(c.set_tags([""].into_iter()).is_err());
+        assert!(c.set_cd(Some("a\nb")).is_err());
+        assert!(c.set_cd(Some(" ")).is_err());
+        assert_eq!(c.directory(), Some(Path::new("out")));
+        assert_eq!(c.source(), ["cmake", "--build", "build", "@name=build", "@tags=all,host", "@cd=out", "@disable"]);
+        for dir in [" padded ", r#""quoted""#, r#"'x\y'"#] {
+            c.set_cd(Some(dir)).unwrap();
+            assert_eq!(Cmd::from_lines(c.source().iter().map(String::as_str)).unwrap().directory(), Some(Path::new(dir)));
+        }
+        assert!(c.source().iter().any(|l| l == r#"@cd="'x\\y'""#));
+
+        // replaced in place of any existing lines
+        c.set_tags(std::iter::empty::<String>()).unwrap();
+        c.set_cd(None).unwrap();
+        assert!(c.tags().is_empty());
+        assert_eq!(c.source(), ["cmake", "--build", "build", "@name=build", "@disable"]);
+
+        let removed = file.remove_command(0).unwrap();
+        assert_eq!(removed.args(), ["mkdir", "-p", "build"]);
+        file.remove_command(1).unwrap();
+        assert!(matches!(file.remove_command(0), Err(Error::UnableToEdit(_))));
+        assert_eq!(file.commands().len(), 1);
+
+        for bad in [&["make", "&&", "test"][..], &["make\ntest"], &[], &["# just a comment"]] {
+            assert!(Cmd::from_lines(bad.iter().copied()).is_err(), "{:?}", bad);
+        }
+
+        let dir = TempDir::new("api");
+        let path = dir.join(".upbuild");
+        file.save(&path).unwrap();
+        assert_eq!(ClassicFile::load(&path).unwrap().to_string(), file.to_string());
+    }
+
+    #[test]
+    fn test_set_disabled() {
+        let dir = TempDir::new("disable");
+        let path = dir.join(".upbuild");
+        std::fs::write(&path, "make\n&&\n# ship it\nscp\n@name=deploy\nfw.bin\n").unwrap();
+        let content = || std::fs::read_to_string(&path).unwrap();
+
+        assert_eq!(ClassicFile::set_disabled("deploy", true, &path).unwrap(), "Disabled 2: scp fw.bin");
+        assert_eq!(content(), "make\n&&\n# ship it\nscp\n@name=deploy\nfw.bin\n@disable\n");
+        assert_eq!(ClassicFile::set_disabled("2", true, &path).unwrap(), "2: scp fw.bin is already disabled");
+        assert_eq!(content(), "make\n&&\n# ship it\nscp\n@name=deploy\nfw.bin\n@disable\n");
+
+        assert_eq!(ClassicFile::set_disabled("deploy", false, &path).unwrap(), "Enabled 2: scp fw.bin");
+        assert_eq!(content(), "make\n&&\n# ship it\nscp\n@name=deploy\nfw.bin\n");
+        assert_eq!(ClassicFile::set_disabled("1", false, &path).unwrap(), "1: make is already enabled");
+
+        // however it was written
+        std::fs::write(&path, "make\n@disable=\n-j8\n@disable\n").unwrap();
+        ClassicFile::set_disabled("1", false, &path).unwrap();
+        assert_eq!(content(), "make\n-j8\n");
+
+        assert!(matches!(ClassicFile::set_disabled("deploy", true, &path), Err(Error::UnknownCommand(_))));
+    }
+
+    #[test]
+    fn test_move() {
+        let dir = TempDir::new("move");
+        let path = dir.join(".upbuild");
+        std::fs::write(&path, "a\n&&\nb\n&&\nc\n@name=third\n&&\nd\n").unwrap();
+        let content = || std::fs::read_to_string(&path).unwrap();
+
+        let file = ClassicFile::move_command("4", "1", &path).unwrap();
+        assert_eq!(content(), "d\n&&\na\n&&\nb\n&&\nc\n@name=third\n");
+        assert_eq!(file.summary(), ["1: d", "2: a", "3: b", "4: c (@name=third)"]);
+
+        ClassicFile::move_command("third", "2", &path).unwrap();
+        assert_eq!(content(), "d\n&&\nc\n@name=third\n&&\na\n&&\nb\n");
+
+        // in place
+        ClassicFile::move_command("1", "1", &path).unwrap();
+        assert_eq!(content(), "d\n&&\nc\n@name=third\n&&\na\n&&\nb\n");
+
+        for (from, to) in [("0", "1"), ("5", "1"), ("1", "0"), ("1", "5"), ("1", "last"), ("fourth", "1")] {
+            assert!(matches!(ClassicFile::move_command(from, to, &path), Err(Error::UnknownCommand(_))), "{}:{}", from, to);
+        }
+        assert_eq!(content(), "d\n&&\nc\n@name=third\n&&\na\n&&\nb\n");
+    }
+
+    #[test]
+    fn test_expand_home() {
+        let home = |h: &'static str| move |k: &str| if k == "HOME" { Some(h.to_string()) } else { None };
+        let expand = |p: &str| expand_home(p.to_string(), home("/home/me"));
+        assert_eq!(expand("~"), Ok(String::from("/home/me")));
+        assert_eq!(expand("~/work/fw"), Ok(String::from("/home/me/work/fw")));
+        assert_eq!(expand_home(String::from("~/logs"), home("/")), Ok(String::from("/logs")));
+
+        // only a leading ~ is special
+        for p in ["build", "/opt/~/x", "a~", "build/~", ""] {
+            assert_eq!(expand(p), Ok(p.to_string()));
+        }
+
+        assert_eq!(expand("~bob/work"),
+                   Err(Error::UnableToExpand(String::from("~bob/work"), String::from("only ~ on its own is supported, not ~user"))));
+        let unset = Err(Error::UnableToExpand(String::from("~/x"), String::from("HOME isn't set")));
+        assert_eq!(expand_home(String::from("~/x"), home("")), unset);
+        assert_eq!(expand_home(String::from("~/x"), |_| None), unset);
+    }
+
+    #[test]
+    fn test_with_home() {
+        let env = |k: &str| if k == "HOME" { Some(String::from("/home/me")) } else { None };
+        let local = Cmd {
+            cd: Some(String::from("~/fw")),
+            mkdir: Some(String::from("~/artifacts")),
+            outfile: Some(String::from("~/logs/build.log")),
+            ..Cmd::new("make")
+        }.with_home(env).unwrap();
+        assert_eq!(local.directory(), Some(Path::new("/home/me/fw")));
+        assert_eq!(local.mk_dir(), Some(Path::new("/home/me/artifacts")));
+        assert_eq!(local.out_file(), Some(Path::new("/home/me/logs/build.log")));
+
+        // left to the remote host
+        let remote = Cmd { cd: Some(String::from("~/signing")), ssh: Some(String::from("host")), ..Cmd::new("sign") };
+        assert_eq!(remote.with_home(env).unwrap().directory(), Some(Path::new("~/signing")));
+
+        assert!(matches!(ClassicFile::parse_lines("make\n@cd=~root/x\n".lines()), Err(Error::UnableToExpand(_, _))));
+    }
+
+    #[test]
+    fn test_add() {
+        let dir = TempDir::new("add");
+        let path = dir.join(".upbuild");
+        let add = |args: &[&str]| ClassicFile::add(args.iter().copied(), path.clone());
+        let content = || std::fs::read_to_string(&path).unwrap();
+
+        add(&["cmake", "-S", ".", "-B", "build", "&&", "cmake", "--build", "build"]).unwrap();
+        assert_eq!(content(), "cmake\n-S\n.\n-B\nbuild\n&&\ncmake\n--build\nbuild\n");
+
+        // appended to the existing file
+        add(&["ctest", "&&", "make", "install"]).unwrap();
+        assert_eq!(content(), "cmake\n-S\n.\n-B\nbuild\n&&\ncmake\n--build\nbuild\n&&\nctest\n&&\nmake\ninstall\n");
+        assert_eq!(parse(&content()).commands.len(), 4);
+
+        // each argument is its own line, spaces and all
+        add(&["make", "CFLAGS=-O2 -g", " spaced "]).unwrap();
+        assert_eq!(parse(&content()).commands[4].args, vec!["make", "CFLAGS=-O2 -g", " spaced "]);
+
+        // nothing is written for empty entries, or ones that won't read back
+        for bad in [&["&&", "make"][..], &["make", "&&"], &["make", "&&", "&&", "ctest"], &["&&"], &["make", "@bogus"],
+                    &["echo", "a\nb"], &["echo", "a\r\nb"], &["echo", "#1"]] {
+            assert!(add(bad).is_err(), "{:?}", bad);
+        }
+        assert_eq!(parse(&content()).commands.len(), 5);
+        match add(&["echo", "two\nlines"]) {
+            Err(Error::UnableToAdd(msg)) => assert_eq!(msg, "argument \"two\\nlines\" contains a newline"),
+            r => panic!("expected error, got {:?}", r),
+        }
+        match add(&["cc", "-o", "out", "@ARGS_FILE"]) {
+            Err(Error::UnableToAdd(msg)) =>
+                assert_eq!(msg, "Tag was not understood: @ARGS_FILE (adding `cc -o out @ARGS_FILE`, arguments starting with @ are flags)"),
+            r => panic!("expected error, got {:?}", r),
+        }
+        assert_eq!(parse(&content()).commands.len(), 5);
+
+        // @ flags apply to the command, && always separates
+        add(&["make", "@tags=docs", "docs"]).unwrap();
+        assert_eq!(parse(&content()).commands[5].args, vec!["make", "docs"]);
+        assert!(parse(&content()).commands[5].tags.contains("docs"));
+    }
+
+    #[test]
+    fn test_effective_args() {
+        let file = parse("make\n-j8\n--\nall\n&&\ngit\ntag\n$1\n@positional-args\n&&\nupbuild\n&&\nawk\n{print $1}\n");
+        let args = |i: usize, provided: &[&str], argv0: Option<&str>| {
+            let provided: Vec<String> = provided.iter().map(|x| x.to_string()).collect();
+            file.commands[i].effective_args(&provided, argv0)
+        };
+        assert_eq!(args(0, &[], None).unwrap(), ["make", "-j8", "all"]);
+        assert_eq!(args(0, &["test"], None).unwrap(), ["make", "-j8", "test"]);
+        assert_eq!(args(1, &["v1"], None).unwrap(), ["git", "tag", "v1"]);
+        assert!(matches!(args(1, &[], None), Err(Error::MissingArg(1, _))));
+        assert_eq!(args(2, &["all"], Some("/bin/upbuild")).unwrap(), ["/bin/upbuild", "--", "all"]);
+        // $s are left alone without @positional-args
+        assert_eq!(args(3, &[], None).unwrap(), ["awk", "{print $1}"]);
+        assert_eq!(args(3, &["x.txt"], None).unwrap(), ["awk", "{print $1}", "x.txt"]);
+    }
+
+    // Small deterministic generator so the parser can be hammered
+    // without pulling in a property testing crate
+    struct XorShift(u64);
+
+    impl XorShift {
+        fn next(&mut self, n: usize) -> usize {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            (self.0 % n as u64) as usize
+        }
+    }
+
+    // Parse, and if that worked check the result can be used and
+    // written back out
+    fn check_parse(lines: &[String]) {
+        let file = match ClassicFile::parse_lines(lines.iter().map(String::as_str)) {
+            Ok(file) => file,
+            Err(_) => return,
+        };
+        let _ = file.summary();
+        for cmd in file.commands() {
+            let _ = cmd.effective_args(&[], None);
+            let _ = cmd.effective_args(&[String::from("x")], Some("upbuild"));
+        }
+        let written = file.to_string();
+        let again = ClassicFile::parse_lines(written.split('\n'))
+            .unwrap_or_else(|e| panic!("{:?} wrote {:?} which fails with {}", lines, written, e));
+        assert_eq!(again.commands.len(), file.commands.len(), "{:?}", lines);
+    }
+
+    #[test]
+    fn test_parse_arbitrary() {
+        const PIECES: &[&str] = &[
+            "@", "=", "@=", "=>", ",", "&&", "--", "{}", "$", "$1", "$0", "$@", "$$", "#", " ", "\t", "\r", "\0", "é",
+            "-1", "0", "255", "256", "99999999999999999999999", "k", "G",
+            "make", "upbuild", "..", "/", "null",
+            "@tags", "@tags=", "@retmap", "@retmap=", "@cd=", "@mkdir=", "@outfile=", "@outfile-limit=",
+            "@name=", "@stdin=", "@container=", "@ssh=", "@capture=", "@umask=", "@umask=022",
+            "@disable", "@manual", "@detach", "@scan", "@outfile-all", "@silent", "@pipe", "@exec", "@no-propagate", "@no-forward-args",
+            "@recurse", "ub", "@args-here", "@positional-args",
+        ];
+        let mut rng = XorShift(0x5eed_u64);
+        for _ in 0..5000 {
+            let lines: Vec<String> = (0..rng.next(12))
+                .map(|_| (0..1 + rng.next(3)).map(|_| PIECES[rng.next(PIECES.len())]).collect())
+                .collect();
+            check_parse(&lines);
+        }
+    }
+
+    #[test]
+    fn test_parse_regressions() {
+        let invalid = |lines: &[&str]| {
+            let lines: Vec<String> = lines.iter().map(|x| x.to_string()).collect();
+            check_parse(&lines);
+            assert!(ClassicFile::parse_lines(lines.iter().map(String::as_str)).is_err(), "{:?}", lines);
+        };
+        // an empty flag name is just an unknown flag
+        invalid(&["make", "@"]);
+        invalid(&["make", "@="]);
+        invalid(&["make", "@=x"]);
+        invalid(&["make", "@retmap="]);
+        invalid(&["make", "@retmap==>"]);
+        invalid(&["make", "@retmap=1=>"]);
+        invalid(&["make", "@retmap=1=>2=>3"]);
+        invalid(&["make", "@retmap=99999999999999999999999=>0"]);
+        invalid(&["make", "@outfile-limit="]);
+        invalid(&["make", "@outfile-limit=k"]);
+        invalid(&["make", "@outfile-limit=99999999999999G"]);
+        invalid(&["make", "@name="]);
+        invalid(&["make", "@stdin="]);
+        invalid(&["&&"]);
+        invalid(&["make", "&&", "&&"]);
+        invalid(&["@manual"]);
+
+        let valid = |lines: &[&str]| {
+            let lines: Vec<String> = lines.iter().map(|x| x.to_string()).collect();
+            check_parse(&lines);
+            assert!(ClassicFile::parse_lines(lines.iter().map(String::as_str)).is_ok(), "{:?}", lines);
+        };
+        valid(&["é", "\0", "$", "$0", "$$1", "{}{}"]);
+        valid(&["make", "@tags=,,"]);
+        invalid(&["make", "@cd="]);
+        invalid(&["make", "@mkdir= "]);
+        invalid(&["make", "@outfile="]);
+        valid(&["make", "&&\r"]);
+        let tags: Vec<String> = (0..100_000).map(|i| i.to_string()).collect();
+        valid(&["make", &format!("@tags={}", tags.join(","))]);
+    }
+
+    #[test]
+    fn test_stream_lines() {
+        // each command is available without reading further
+        let mut stream = ClassicFile::stream_lines(["echo", "&&", "make", "@name=m", "&&"].into_iter().cycle());
+        assert_eq!(stream.next().unwrap().unwrap().args(), ["echo"]);
+        assert_eq!(stream.next().unwrap().unwrap().name(), Some("m"));
+        assert_eq!(stream.next().unwrap().unwrap().args(), ["echo"]);
+        assert!(matches!(stream.next(), Some(Err(Error::DuplicateName(_)))));
+        assert!(stream.next().is_none());
+
+        let stream = ClassicFile::stream_lines("echo\n&&\nmake\n&&\n".lines());
+        let cmds: Vec<_> = stream.collect();
+        assert_eq!(cmds.len(), 3);
+        assert!(matches!(cmds[2], Err(Error::EmptyEntry)));
+
+        // an unreadable line ends the stream as an error, not the file
+        let stream = ClassicFile::stream_reader(&b"echo\none\n&&\necho\n\xff\xfe\n&&\necho\nthree\n"[..]);
+        let cmds: Vec<_> = stream.collect();
+        assert_eq!(cmds.len(), 2);
+        assert_eq!(cmds[0].as_ref().unwrap().args(), ["echo", "one"]);
+        assert!(matches!(cmds[1], Err(Error::IoFailed(_))), "{:?}", cmds[1]);
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let cases = [
+            ("", Error::EmptyEntry),
+            ("make\n&&\n", Error::EmptyEntry),
+            ("@manual\nmake\n", Error::FlagBeforeCommand(String::from("Manual"))),
+            ("make\n@bogus\n", Error::InvalidTag(String::from("@bogus"))),
+            ("make\n@retmap=1=>x\n", Error::InvalidRetMapDefinition(String::from("x"))),
+            ("make\n@retmap=1=>256\n", Error::InvalidRetMapDefinition(String::from("1=>256 (exit code 256 is out of range 0-255)"))),
+            ("cp\n@args-here\n@args-here\n", Error::MultiplePlaceholders(String::from("cp @args-here @args-here"))),
+            ("a\n@name=x\n&&\nb\n@name=x\n", Error::DuplicateName(String::from("x"))),
+            ("scp\n@ssh=host\n@outfile=log\n", Error::ConflictingFlags(String::from("@outfile can't be read from a @ssh host: scp"))),
+            ("git\n@capture=V\n@exec\n", Error::ConflictingFlags(String::from("@capture can't be used with @exec, nothing is left to use it: git"))),
+            ("git\n@silent\n", Error::ConflictingFlags(String::from("@silent only applies to @capture: git"))),
+            ("make\n&&\ngen\n@pipe\n", Error::UnconnectedPipe(String::from("gen"))),
+            ("gen\n@pipe\n@exec\n&&\nless\n", Error::ConflictingFlags(String::from("@pipe can't be used with @exec, @capture or @detach: gen"))),
+            ("||\nmake\n", Error::EmptyEntry),
+            ("make\n||\n", Error::EmptyEntry),
+            ("make\n&&\n||\nfetch\n", Error::EmptyEntry),
+            ("gen\n@pipe\n||\nless\n", Error::ConflictingFlags(String::from("@pipe can't be followed by ||, it has to feed a command: gen"))),
+        ];
+        for (input, expected) in cases {
+            assert_eq!(ClassicFile::parse_lines(input.lines()).map(|_| ()), Err(expected), "{:?}", input);
+        }
+    }
 }
@@ -1,82 +1,556 @@
 // SPDX-License-Identifier: GPL-3.0-or-later
 // (C) Copyright 2024 Greg Whiteley
 
-use std::{fs, path::PathBuf};
-use super::{Error, Result};
+use std::{collections::HashSet, fs, path::{Path, PathBuf}};
+use super::{Config, Error, Result};
+use super::exec::simplified;
 
-fn readable(p: &PathBuf) -> bool {
+fn readable(p: &Path) -> bool {
     fs::File::open(p).is_ok()
 }
 
+/// Environment variable that keeps the search on one filesystem when
+/// set, see `--ub-one-file-system`
+pub(crate) const ONE_FILESYSTEM_ENV: &str = "UPBUILD_ONE_FILESYSTEM";
+
+// The device a directory is on
+#[cfg(target_family = "unix")]
+fn filesystem(dir: &Path) -> Option<u64> {
+    use std::os::unix::fs::MetadataExt;
+    fs::metadata(dir).ok().map(|m| m.dev())
+}
+
+// The volume a directory is on - its drive or share, as the volume
+// serial number isn't available on stable
+#[cfg(not(target_family = "unix"))]
+fn filesystem(dir: &Path) -> Option<std::ffi::OsString> {
+    match dir.canonicalize().ok()?.components().next()? {
+        std::path::Component::Prefix(p) => Some(p.as_os_str().to_os_string()),
+        _ => None,
+    }
+}
+
+// Whether the directory's parent is on a different filesystem, as
+// identified by the given function.  Unknown counts as different.
+fn leaves_filesystem<F, T>(dir: &Path, filesystem: F) -> bool
+where
+    F: Fn(&Path) -> Option<T>,
+    T: PartialEq
+{
+    match (filesystem(dir), filesystem(&dir.join(".."))) {
+        (Some(d), Some(parent)) => d != parent,
+        _ => true,
+    }
+}
+
+// What makes a directory the same one when reached another way
 #[cfg(target_family = "unix")]
-fn inode(p: &PathBuf) -> u64 {
+fn identity(dir: &Path) -> Option<(u64, u64)> {
     use std::os::unix::fs::MetadataExt;
-    fs::metadata(p).unwrap().ino()
+    fs::metadata(dir).ok().map(|m| (m.dev(), m.ino()))
 }
 
 #[cfg(not(target_family = "unix"))]
-fn inode(_: &PathBuf) -> fake_inode::Inode {
-    // since these never compare we should stop at MAX_DEPTH instead
-    fake_inode::Inode{}
+fn identity(dir: &Path) -> Option<PathBuf> {
+    dir.canonicalize().ok()
 }
 
-#[cfg(any(test, not(target_family = "unix")))]
-mod fake_inode {
-    #[derive(Debug, Copy, Clone)]
-    pub(super) struct Inode {
+// Whether the directory is the root of its filesystem - its parent is
+// itself.  A directory that can't be resolved ends the search too.
+fn at_root(dir: &Path) -> bool {
+    match (dir.canonicalize(), dir.join("..").canonicalize()) {
+        (Ok(d), Ok(parent)) => d == parent,
+        _ => true,
     }
+}
 
-    impl PartialEq for Inode {
-        // never equal
-        fn eq(&self, _other: &Self) -> bool {
-            false
+// Only a safety net, the search ends at the root
+const MAX_DEPTH: usize = 128;
+
+/// Why the search for `.upbuild` went no further, as reported when
+/// nothing was found
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum SearchEnd {
+    /// A directory with `.upbuild-root` or `.upbuild-stop`
+    Marker,
+    /// The top of a repository, see `--ub-no-vcs-boundary`
+    Repository,
+    /// The root of the filesystem
+    Root,
+    /// The parent is on another filesystem, see `--ub-one-file-system`
+    FileSystem,
+    /// Too many directories were searched
+    MaxDepth,
+    /// The given directory had already been searched
+    Cycle(PathBuf),
+}
+
+impl std::fmt::Display for SearchEnd {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            SearchEnd::Marker => write!(f, "found .upbuild-root or .upbuild-stop"),
+            SearchEnd::Repository => write!(f, "reached the top of the repository (see --ub-no-vcs-boundary)"),
+            SearchEnd::Root => write!(f, "reached the root of the filesystem"),
+            SearchEnd::FileSystem => write!(f, "reached the edge of the filesystem (see --ub-one-file-system)"),
+            SearchEnd::MaxDepth => write!(f, "gave up after {} directories", MAX_DEPTH),
+            SearchEnd::Cycle(dir) => write!(f, "detected directory cycle at {}", dir.display()),
         }
     }
+}
+
+/// Names of the file searched for by [`find`]
+const DEFAULT_NAMES: [&str; 1] = [".upbuild"];
+
+/// Entries marking the top of a repository, see `--ub-no-vcs-boundary`
+const VCS_DIRS: [&str; 3] = [".git", ".hg", ".svn"];
+
+/// Directories [`discover`] doesn't look inside, as they hold
+/// repository metadata or build output rather than sources
+const DISCOVER_SKIPPED: [&str; 5] = [".git", ".hg", ".svn", "target", "node_modules"];
+
+/// How far below the current directory `--ub-discover` looks
+pub const DISCOVER_DEPTH: usize = 8;
+
+/// Files marking a directory the search shouldn't go above
+const ROOT_MARKERS: [&str; 2] = [".upbuild-root", ".upbuild-stop"];
+
+// Whether the directory is marked as the top of the search
+fn marked_root(dir: &Path) -> bool {
+    ROOT_MARKERS.iter().any(|m| dir.join(m).is_file())
+}
+
+// Whether the directory is the top of a repository - .git is a file in
+// worktrees and submodules
+fn repository_root(dir: &Path) -> bool {
+    VCS_DIRS.iter().any(|d| dir.join(d).exists())
+}
+
+/// Locate the `.upbuild` file relative to  the given path
+pub fn find<P: AsRef<Path>>(start: P) -> Result<PathBuf> {
+    find_with(start, &Config::default())
+}
+
+/// Locate the `.upbuild` file relative to the given path, searching
+/// as the config says.  The search doesn't go above a
+/// directory containing `.upbuild-root` or `.upbuild-stop`, nor -
+/// unless `--ub-no-vcs-boundary` was given - leave the repository it
+/// starts in.  A file given by `--ub-file=` or `UPBUILD_FILE` is used
+/// instead of searching, as long as it can be read.
+pub fn find_with<P: AsRef<Path>>(start: P, cfg: &Config) -> Result<PathBuf> {
+    match cfg.file() {
+        Some(file) => pinned(file),
+        None => search(start.as_ref(), cfg, &DEFAULT_NAMES, false).map(|mut found| found.remove(0)),
+    }
+}
+
+/// Locate the `.upbuild` file a recursive call run from the given path
+/// would, within the same bounds as [`find_with`] - but always
+/// searching, as recursive calls don't rerun a file given by
+/// `--ub-file=`
+pub(crate) fn find_from(start: &Path, cfg: &Config) -> Result<PathBuf> {
+    search(start, cfg, &DEFAULT_NAMES, false).map(|mut found| found.remove(0))
+}
 
-    #[cfg(test)]
-    mod tests {
+/// Locate every `.upbuild` file from the given path up,
+/// nearest first, within the same bounds as [`find_with`], for
+/// `--ub-chain`.  A file given by `--ub-file=` takes the place of the
+/// nearest, and the search carries on from its directory.
+pub fn find_all<P: AsRef<Path>>(start: P, cfg: &Config) -> Result<Vec<PathBuf>> {
+    let file = match cfg.file() {
+        Some(file) => pinned(file)?,
+        None => return search(start.as_ref(), cfg, &DEFAULT_NAMES, true),
+    };
+    let dir = match file.parent().filter(|p| !p.as_os_str().is_empty()) {
+        Some(dir) => dir.to_path_buf(),
+        None => PathBuf::from("."),
+    };
+    let above = match search(&dir, cfg, &DEFAULT_NAMES, true) {
+        Ok(found) => found,
+        Err(Error::NotFound(_, _, _)) => Vec::new(),
+        Err(e) => return Err(e),
+    };
+    Ok(std::iter::once(file).chain(above.into_iter().filter(|f| f.parent() != Some(&dir))).collect())
+}
+
+/// Locate every `.upbuild` file in the given directory and those
+/// below it, to at most `max_depth` levels down, for
+/// `--ub-discover`.  Repository metadata, `target` and `node_modules`
+/// directories aren't searched, nor are symlinks followed.  The files
+/// are sorted by path, relative to the current directory when starting
+/// from `.`.
+pub fn discover<P: AsRef<Path>>(start: P, max_depth: usize) -> Vec<PathBuf> {
+    discover_with(start, max_depth, &Config::default())
+}
 
-        #[test]
-        fn fake_inode() {
-            use super::Inode;
-            let i = Inode{};
-            let j = Inode{};
-            assert_ne!(i, j);
-            assert_ne!(i, i);
-            assert_ne!(j, i);
+/// As [`discover`], reporting files passed over in favour of another
+/// in the same directory as for the given config
+pub fn discover_with<P: AsRef<Path>>(start: P, max_depth: usize, cfg: &Config) -> Vec<PathBuf> {
+    let mut found = Vec::new();
+    let mut pending = vec![(start.as_ref().to_path_buf(), 0)];
+    while let Some((dir, depth)) = pending.pop() {
+        if let Some(file) = probe(&dir, &DEFAULT_NAMES, cfg) {
+            found.push(file);
+        }
+        if depth == max_depth {
+            continue;
+        }
+        let entries = match fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(e) => {
+                debug!("not searching {}: {}", dir.display(), e);
+                continue;
+            },
+        };
+        for entry in entries.filter_map(std::result::Result::ok) {
+            let is_dir = entry.file_type().map_or(false, |t| t.is_dir());
+            if is_dir && ! DISCOVER_SKIPPED.iter().any(|s| entry.file_name() == *s) {
+                pending.push((entry.path(), depth + 1));
+            }
         }
     }
+    found.sort();
+    found.into_iter()
+        .map(|p| p.strip_prefix(".").map(Path::to_path_buf).unwrap_or(p))
+        .collect()
 }
 
+// The file given by --ub-file= or UPBUILD_FILE, if it can be read
+fn pinned(file: &Path) -> Result<PathBuf> {
+    debug!("using {}", file.display());
+    match fs::File::open(file) {
+        Ok(_) if file.is_dir() =>
+            Err(Error::UnableToOpen(file.to_path_buf(), std::io::Error::new(std::io::ErrorKind::InvalidInput, "is a directory"))),
+        Ok(_) => Ok(file.to_path_buf()),
+        Err(e) => Err(Error::UnableToOpen(file.to_path_buf(), e)),
+    }
+}
 
-// Ensure we don't recurse forever
-const MAX_DEPTH: usize = 128;
+/// Locate a file relative to the given path with any of
+/// the given names, in order of preference.  The nearest directory
+/// with any of them wins, and within it the earliest name - any others
+/// are reported as being ignored.
+pub fn find_with_names<P: AsRef<Path>>(start: P, names: &[&str]) -> Result<PathBuf> {
+    search(start.as_ref(), &Config::default(), names, false).map(|mut found| found.remove(0))
+}
 
-/// Locate the `.upbuild` file relative to  the given path (as string)
-pub fn find(start: &str) -> Result<PathBuf> {
-    let mut curr = PathBuf::from(start);
+// The first of the names present in the directory, warning about any
+// later ones it shadows
+fn probe(dir: &Path, names: &[&str], cfg: &Config) -> Option<PathBuf> {
+    let mut candidates = names.iter().map(|name| dir.join(name));
+    let found = candidates.find(|p| {
+        debug!("probing {}", p.display());
+        p.is_file() && readable(p)
+    })?;
+    debug!("found {}", found.display());
+    for shadowed in candidates.filter(|p| p.is_file()) {
+        eprintln!("{}: ignoring {} in favour of {}", cfg.prefix(), shadowed.display(), found.display());
+    }
+    Some(found)
+}
+
+// Why the search can't go above the directory, if it can't
+fn boundary(dir: &Path, cfg: &Config) -> Option<SearchEnd> {
+    if marked_root(dir) {
+        Some(SearchEnd::Marker)
+    } else if cfg.vcs_boundary() && repository_root(dir) {
+        Some(SearchEnd::Repository)
+    } else if at_root(dir) {
+        Some(SearchEnd::Root)
+    } else if cfg.one_file_system() && leaves_filesystem(dir, filesystem) {
+        Some(SearchEnd::FileSystem)
+    } else {
+        None
+    }
+}
+
+// Search up from the start for the first file, or all of them.
+// Finds at least one or fails.
+fn search(start: &Path, cfg: &Config, names: &[&str], all: bool) -> Result<Vec<PathBuf>> {
+    let mut curr = start.to_path_buf();
     if ! curr.is_dir() {
-        return Err(Error::InvalidDir(curr.display().to_string()));
+        return Err(Error::InvalidDir(curr));
     }
 
+    let mut found = Vec::new();
+    let mut searched = Vec::new();
+    let mut visited = HashSet::new();
+    let mut end = SearchEnd::MaxDepth;
     for _ in 0..MAX_DEPTH {
-        curr.push(".upbuild");
-        if curr.is_file() && readable(&curr) {
-            return Ok(curr)
+        let dir = curr.canonicalize().map(simplified).unwrap_or_else(|_| curr.clone());
+        if let Some(id) = identity(&curr) {
+            if ! visited.insert(id) {
+                debug!("stopping at {}: already searched", curr.display());
+                end = SearchEnd::Cycle(dir);
+                break;
+            }
+        }
+        searched.push(dir);
+        if let Some(file) = probe(&curr, names, cfg) {
+            found.push(file);
+            if ! all {
+                return Ok(found);
+            }
         }
-        curr.pop();
 
-        let i = inode(&curr);
+        if let Some(reason) = boundary(&curr, cfg) {
+            debug!("stopping at {}: {}", curr.display(), reason);
+            end = reason;
+            break;
+        }
         curr.push("..");
+    }
 
-        if ! curr.is_dir() {
-            break;
+    if found.is_empty() {
+        return Err(Error::NotFound(start.to_path_buf(), searched, end));
+    }
+    Ok(found)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::testing::TempDir;
+
+    // Why the search found nothing
+    fn stopped<T: std::fmt::Debug>(result: Result<T>) -> SearchEnd {
+        match result {
+            Err(Error::NotFound(_, _, end)) => end,
+            r => panic!("expected NotFound, got {:?}", r),
         }
-        if i == inode(&curr) {
-            // reached the root level
-            break;
+    }
+
+    #[test]
+    fn test_at_root() {
+        let tmp = std::env::temp_dir();
+        let root = tmp.ancestors().last().unwrap();
+        assert!(at_root(root), "{}", root.display());
+        assert!(!at_root(&tmp));
+        assert!(at_root(&tmp.join("upbuild-missing-dir")));
+
+        // from the root itself the search ends there
+        if !root.join(".upbuild").exists() {
+            assert_eq!(find(root), Err(Error::NotFound(root.to_path_buf(), vec![root.canonicalize().unwrap()], SearchEnd::Root)));
         }
     }
 
-    Err(Error::NotFound(start.to_string()))
+    #[test]
+    fn test_leaves_filesystem() {
+        let dir = TempDir::new("fs");
+        std::fs::create_dir_all(dir.join("mnt/a")).unwrap();
+
+        // a filesystem mounted at dir/mnt
+        let mounted = |p: &Path| -> Option<u32> {
+            Some(if p.canonicalize().ok()?.starts_with(dir.join("mnt")) { 2 } else { 1 })
+        };
+        assert!(!leaves_filesystem(&dir.join("mnt/a"), mounted));
+        assert!(leaves_filesystem(&dir.join("mnt"), mounted));
+        assert!(!leaves_filesystem(&dir, mounted));
+        assert!(leaves_filesystem(&dir.join("missing"), mounted));
+        assert!(leaves_filesystem(&dir, |_| None::<u32>));
+
+        // the real thing - a temporary directory and its parent are
+        // rarely on different filesystems
+        assert!(!leaves_filesystem(&dir.join("mnt/a"), filesystem));
+    }
+
+    #[test]
+    fn test_vcs_boundary() {
+        let dir = TempDir::new("find");
+        let start = dir.join("repo/a/b");
+        std::fs::create_dir_all(&start).unwrap();
+        std::fs::write(dir.join(".upbuild"), "decoy\n").unwrap();
+        let crossing = Config { vcs_boundary: false, ..Config::default() };
+
+        // without a repository the search carries on up
+        assert_eq!(find(&start).unwrap(), dir.join("repo/a/b/../../../.upbuild"));
+
+        for vcs in VCS_DIRS {
+            std::fs::create_dir(dir.join("repo").join(vcs)).unwrap();
+            assert_eq!(stopped(find(&start)), SearchEnd::Repository, "{}", vcs);
+            assert_eq!(find_with(&start, &crossing).unwrap(), dir.join("repo/a/b/../../../.upbuild"));
+            std::fs::remove_dir(dir.join("repo").join(vcs)).unwrap();
+        }
+
+        // the repository's own file is still found, as is one within it
+        std::fs::write(dir.join("repo/.git"), "gitdir: ../.git/worktrees/repo\n").unwrap();
+        let repo = dir.join("repo").canonicalize().unwrap();
+        assert_eq!(find(&start), Err(Error::NotFound(start.clone(), vec![repo.join("a/b"), repo.join("a"), repo.clone()], SearchEnd::Repository)));
+        std::fs::write(dir.join("repo/.upbuild"), "make\n").unwrap();
+        assert_eq!(find(&start).unwrap(), dir.join("repo/a/b/../../.upbuild"));
+        std::fs::write(dir.join("repo/a/.upbuild"), "make\n").unwrap();
+        assert_eq!(find(&start).unwrap(), dir.join("repo/a/b/../.upbuild"));
+    }
+
+    #[test]
+    fn test_root_marker() {
+        let dir = TempDir::new("marker");
+        let start = dir.join("a/b/c");
+        std::fs::create_dir_all(&start).unwrap();
+        std::fs::write(dir.join("a/.upbuild"), "make\n").unwrap();
+        let found = dir.join("a/b/c/../../.upbuild");
+        let crossing = Config { vcs_boundary: false, ..Config::default() };
+
+        for marker in ROOT_MARKERS {
+            // above the file
+            std::fs::write(dir.join(marker), "").unwrap();
+            assert_eq!(find(&start).unwrap(), found, "{}", marker);
+            std::fs::remove_file(dir.join(marker)).unwrap();
+
+            // at the file
+            std::fs::write(dir.join("a").join(marker), "").unwrap();
+            assert_eq!(find(&start).unwrap(), found, "{}", marker);
+            std::fs::remove_file(dir.join("a").join(marker)).unwrap();
+
+            // below the file, whatever the repository boundary
+            std::fs::write(dir.join("a/b").join(marker), "").unwrap();
+            assert_eq!(stopped(find(&start)), SearchEnd::Marker, "{}", marker);
+            assert_eq!(stopped(find_with(&start, &crossing)), SearchEnd::Marker, "{}", marker);
+            std::fs::remove_file(dir.join("a/b").join(marker)).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_names() {
+        let dir = TempDir::new("names");
+        let start = dir.join("a/b");
+        std::fs::create_dir_all(&start).unwrap();
+        let names = ["upbuild.toml", "upbuild.txt", ".upbuild"];
+
+        std::fs::write(dir.join("upbuild.toml"), "").unwrap();
+        std::fs::write(dir.join("a/.upbuild"), "make\n").unwrap();
+        assert_eq!(find_with_names(&start, &names).unwrap(), dir.join("a/b/../.upbuild"));
+        assert_eq!(find(&start).unwrap(), dir.join("a/b/../.upbuild"));
+
+        // the nearest directory wins, then the name's priority
+        std::fs::write(dir.join("a/upbuild.txt"), "make\n").unwrap();
+        assert_eq!(find_with_names(&start, &names).unwrap(), dir.join("a/b/../upbuild.txt"));
+        assert_eq!(find_with_names(&start, &[".upbuild", "upbuild.txt"]).unwrap(), dir.join("a/b/../.upbuild"));
+        assert_eq!(find(&start).unwrap(), dir.join("a/b/../.upbuild"));
+
+        // only the names asked for
+        std::fs::remove_file(dir.join("a/.upbuild")).unwrap();
+        std::fs::remove_file(dir.join("a/upbuild.txt")).unwrap();
+        assert_eq!(find_with_names(&start, &names).unwrap(), dir.join("a/b/../../upbuild.toml"));
+        assert_eq!(stopped(find(&start)), SearchEnd::Root);
+        assert_eq!(stopped(find_with_names(&start, &[])), SearchEnd::Root);
+    }
+
+    #[test]
+    fn test_find_all() {
+        let dir = TempDir::new("all");
+        let start = dir.join("repo/a/b");
+        std::fs::create_dir_all(&start).unwrap();
+        for d in ["", "repo", "repo/a/b"] {
+            std::fs::write(dir.join(d).join(".upbuild"), "make\n").unwrap();
+        }
+
+        assert_eq!(find_all(&start, &Config::default()).unwrap(),
+                   [dir.join("repo/a/b/.upbuild"), dir.join("repo/a/b/../../.upbuild"), dir.join("repo/a/b/../../../.upbuild")]);
+
+        // within the same bounds as find
+        std::fs::create_dir(dir.join("repo/.git")).unwrap();
+        assert_eq!(find_all(&start, &Config::default()).unwrap(),
+                   [dir.join("repo/a/b/.upbuild"), dir.join("repo/a/b/../../.upbuild")]);
+        std::fs::write(dir.join("repo/a/.upbuild-root"), "").unwrap();
+        assert_eq!(find_all(&start, &Config::default()).unwrap(), [dir.join("repo/a/b/.upbuild")]);
+
+        std::fs::remove_file(dir.join("repo/a/b/.upbuild")).unwrap();
+        assert_eq!(stopped(find_all(&start, &Config::default())), SearchEnd::Marker);
+    }
+
+    #[test]
+    fn test_discover() {
+        let dir = TempDir::new("discover");
+        for d in ["", "b", "a", "a/x/y", "target", "node_modules/m", ".git", "empty"] {
+            std::fs::create_dir_all(dir.join(d)).unwrap();
+            if d != "empty" {
+                std::fs::write(dir.join(d).join(".upbuild"), "make\n").unwrap();
+            }
+        }
+        #[cfg(target_family = "unix")]
+        std::os::unix::fs::symlink("a", dir.join("link")).unwrap();
+
+        assert_eq!(discover(&dir, DISCOVER_DEPTH),
+                   [dir.join(".upbuild"), dir.join("a/.upbuild"), dir.join("a/x/y/.upbuild"), dir.join("b/.upbuild")]);
+        assert_eq!(discover(&dir, 1), [dir.join(".upbuild"), dir.join("a/.upbuild"), dir.join("b/.upbuild")]);
+        assert_eq!(discover(&dir, 0), [dir.join(".upbuild")]);
+        assert_eq!(discover_with(&dir, 1, &Config::builder().level(2).build()), discover(&dir, 1));
+        assert!(discover(dir.join("empty"), DISCOVER_DEPTH).is_empty());
+        assert!(discover(dir.join("missing"), DISCOVER_DEPTH).is_empty());
+    }
+
+    #[test]
+    fn test_pinned() {
+        let dir = TempDir::new("pinned");
+        let start = dir.join("repo/a");
+        std::fs::create_dir_all(&start).unwrap();
+        std::fs::create_dir(dir.join("repo/.git")).unwrap();
+        std::fs::write(dir.join("repo/.upbuild"), "make\n").unwrap();
+        std::fs::write(dir.join("repo/a/.upbuild"), "make\n").unwrap();
+        std::fs::write(dir.join("repo/a/build.upbuild"), "make\n").unwrap();
+
+        // used without searching, wherever it is
+        let file = dir.join("repo/a/build.upbuild");
+        let cfg = Config::builder().file(&file).build();
+        assert_eq!(find_with(dir.join("missing"), &cfg).unwrap(), file);
+        assert_eq!(find_all(dir.join("missing"), &cfg).unwrap(), [file, dir.join("repo/a/../.upbuild")]);
+
+        let file = dir.join("repo/a/.upbuild");
+        let cfg = Config::builder().file(&file).build();
+        assert_eq!(find_all(&start, &cfg).unwrap(), [file, dir.join("repo/a/../.upbuild")]);
+
+        let file = dir.join("repo/.upbuild");
+        let cfg = Config::builder().file(&file).build();
+        assert_eq!(find_all(&start, &cfg).unwrap(), [file]);
+
+        for file in [dir.join("repo/b/.upbuild"), dir.join("repo/a")] {
+            let cfg = Config::builder().file(&file).build();
+            match find_with(&start, &cfg) {
+                Err(Error::UnableToOpen(p, _)) => assert_eq!(p, file),
+                other => panic!("unexpected {:?}", other),
+            }
+            assert!(find_all(&start, &cfg).is_err());
+        }
+    }
+
+    #[cfg(target_family = "unix")]
+    #[test]
+    fn test_non_utf8() {
+        use std::os::unix::ffi::OsStrExt;
+        let dir = TempDir::new("utf8");
+        let start = dir.join(std::ffi::OsStr::from_bytes(b"caf\xe9"));
+        std::fs::create_dir_all(&start).unwrap();
+        std::fs::write(dir.join(".upbuild"), "make\n").unwrap();
+
+        assert_eq!(find(&start).unwrap(), start.join("../.upbuild"));
+        std::fs::write(start.join(".upbuild"), "make\n").unwrap();
+        assert_eq!(find(&start).unwrap(), start.join(".upbuild"));
+        assert_eq!(find(start.join("missing")), Err(Error::InvalidDir(start.join("missing"))));
+    }
+
+    #[cfg(target_family = "unix")]
+    #[test]
+    fn test_symlink_cycle() {
+        let dir = TempDir::new("cycle");
+        std::fs::create_dir_all(dir.join("a")).unwrap();
+        std::os::unix::fs::symlink("..", dir.join("a/loop")).unwrap();
+        std::fs::write(dir.join(".upbuild-root"), "").unwrap();
+        let start = dir.join("a/loop/a/loop/a");
+
+        // each directory is searched once however it was reached
+        let cfg = Config::default();
+        match search(&start, &cfg, &DEFAULT_NAMES, false) {
+            Err(Error::NotFound(_, searched, end)) => {
+                let dir = dir.canonicalize().unwrap();
+                assert_eq!(searched, [dir.join("a"), dir.clone()]);
+                assert_eq!(end, SearchEnd::Marker);
+            },
+            other => panic!("unexpected {:?}", other),
+        }
+        assert_eq!(identity(&start), identity(&dir.join("a")));
+        assert_eq!(SearchEnd::Cycle(dir.join("a")).to_string(),
+                   format!("detected directory cycle at {}", dir.join("a").display()));
+    }
 }
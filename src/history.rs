@@ -0,0 +1,353 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// (C) Copyright 2024 Greg Whiteley
+
+use std::io::{BufRead, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use super::{Config, Result};
+use super::exec::{ExecHooks, RetCode};
+use super::json::{self, Value};
+use super::report::{Recorder, RunReport};
+
+/// Environment variable that disables recording history when set
+pub(crate) const NO_HISTORY_ENV: &str = "UPBUILD_NO_HISTORY";
+
+/// Runs kept in the history file, older ones are dropped as it grows
+const HISTORY_RUNS: usize = 1000;
+
+/// Directory for upbuild's persistent state - history and statistics
+pub(crate) fn state_dir() -> Option<PathBuf> {
+    fn from_env(var: &str) -> Option<PathBuf> {
+        std::env::var_os(var).filter(|v| !v.is_empty()).map(PathBuf::from)
+    }
+
+    if cfg!(windows) {
+        return from_env("LOCALAPPDATA").map(|d| d.join("upbuild"));
+    }
+    from_env("XDG_STATE_HOME")
+        .or_else(|| from_env("HOME").map(|d| d.join(".local").join("state")))
+        .map(|d| d.join("upbuild"))
+}
+
+/// Default location of the history file
+pub(crate) fn history_file() -> Option<PathBuf> {
+    state_dir().map(|d| d.join("history.jsonl"))
+}
+
+// Format as an RFC 3339 UTC timestamp
+pub(crate) fn utc_timestamp(t: SystemTime) -> String {
+    let secs = t.duration_since(SystemTime::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let (days, rem) = (secs / 86400, secs % 86400);
+
+    // civil from days - see http://howardhinnant.github.io/date_algorithms.html
+    let z = days as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z", year, month, day,
+            rem / 3600, (rem / 60) % 60, rem % 60)
+}
+
+fn path_value(p: &Path) -> Value {
+    Value::from(p.to_string_lossy().into_owned())
+}
+
+fn sorted(tags: &std::collections::HashSet<String>) -> Value {
+    let mut tags: Vec<&String> = tags.iter().collect();
+    tags.sort();
+    Value::strings(tags)
+}
+
+// The JSON line recorded for a run
+fn entry(timestamp: &str, cwd: &Path, cfg: &Config, report: &RunReport, result: &Result<()>) -> Value {
+    let commands = report.commands.iter().map(|c| {
+        Value::object([
+            ("args", Value::strings(&c.args)),
+            ("dir", c.dir.as_deref().map_or(Value::Null, path_value)),
+            ("code", c.code.map(|c| c as f64).into()),
+            ("duration", Value::from(c.duration.as_secs_f64())),
+        ])
+    }).collect();
+
+    Value::object([
+        ("timestamp", Value::from(timestamp)),
+        ("cwd", path_value(cwd)),
+        ("file", path_value(&report.file)),
+        ("select", sorted(&cfg.select)),
+        ("reject", sorted(&cfg.reject)),
+        ("commands", Value::Array(commands)),
+        ("success", Value::from(result.is_ok())),
+        ("error", result.as_ref().err().map(|e| e.to_string()).into()),
+    ])
+}
+
+/// [`ExecHooks`] recording each run as a line in the history file,
+/// see `--ub-history`.  Recording is best effort - failing to write
+/// the history never affects the result of the run.
+pub struct History {
+    file: Option<PathBuf>,
+    cfg: Config,
+    recorder: Recorder,
+}
+
+impl History {
+
+    /// Record runs using the given config to the default history file
+    pub fn new(cfg: &Config) -> History {
+        History::with_file(cfg, history_file())
+    }
+
+    /// Record runs using the given config to the given history file
+    pub fn with_file(cfg: &Config, file: Option<PathBuf>) -> History {
+        History {
+            file,
+            cfg: cfg.clone(),
+            recorder: Recorder::default(),
+        }
+    }
+
+    fn append(file: &Path, line: &str) -> std::io::Result<()> {
+        if let Some(dir) = file.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let mut f = std::fs::File::options()
+            .create(true)
+            .append(true)
+            .open(file)?;
+        f.write_all(format!("{}\n", line).as_bytes())?;
+        drop(f);
+        Self::trim(file, HISTORY_RUNS)
+    }
+
+    // Keep only the last `keep` runs, rewriting the file once it has
+    // grown a tenth past that rather than on every run
+    fn trim(file: &Path, keep: usize) -> std::io::Result<()> {
+        let data = std::fs::read(file)?;
+        let runs = data.iter().filter(|b| **b == b'\n').count();
+        if runs <= keep + keep / 10 {
+            return Ok(());
+        }
+        let start = data.iter().enumerate()
+            .filter(|(_, b)| **b == b'\n')
+            .nth(runs - keep - 1)
+            .map_or(0, |(i, _)| i + 1);
+
+        // replaced whole, so a reader never sees it half written
+        let tmp = file.with_extension("jsonl.tmp");
+        std::fs::write(&tmp, &data[start..])?;
+        std::fs::rename(&tmp, file)
+    }
+}
+
+impl ExecHooks for History {
+    fn run_start(&self, path: &Path) {
+        self.recorder.run_start(&path.canonicalize().unwrap_or_else(|_| path.to_path_buf()))
+    }
+
+    fn before_command(&self, args: &[String], dir: Option<&Path>) -> bool {
+        self.recorder.before_command(args, dir)
+    }
+
+    fn after_command(&self, code: RetCode, duration: Duration) {
+        self.recorder.after_command(code, duration)
+    }
+
+    fn run_end(&self, result: &Result<()>) {
+        self.recorder.run_end(result);
+
+        if let Some(ref file) = self.file {
+            let cwd = std::env::current_dir().unwrap_or_default();
+            let line = entry(&utc_timestamp(SystemTime::now()), &cwd, &self.cfg, &self.recorder.report(), result);
+            if let Err(e) = Self::append(file, &line.to_string()) {
+                eprintln!("{}: unable to record history in {}: {}", self.cfg.prefix(), file.display(), e);
+            }
+        }
+    }
+}
+
+// Describe a recorded run for --ub-history
+fn describe(entry: &Value) -> Option<String> {
+    let commands = entry.get("commands")?.as_array()?;
+    let duration: f64 = commands.iter()
+        .filter_map(|c| c.get("duration").and_then(Value::as_f64))
+        .sum();
+
+    let mut s = format!("{} {} ({} command{}, {:.1}s)",
+                        entry.get("timestamp")?.as_str()?,
+                        if entry.get("success")?.as_bool()? { "succeeded" } else { "failed" },
+                        commands.len(),
+                        if commands.len() == 1 { "" } else { "s" },
+                        duration);
+    if let Some(e) = entry.get("error").and_then(Value::as_str) {
+        s += &format!(": {}", e);
+    }
+
+    for c in commands {
+        let args: Vec<&str> = c.get("args")?.as_array()?.iter().filter_map(Value::as_str).collect();
+        let code = c.get("code").and_then(Value::as_f64)
+            .map_or_else(|| String::from("-"), |c| c.to_string());
+        s += &format!("\n  [{:>3}] {:>7.1}s {}", code,
+                      c.get("duration").and_then(Value::as_f64).unwrap_or(0.0),
+                      super::shell::join(&args));
+    }
+    Some(s)
+}
+
+// Last n descriptions of runs of upbuild_file in the history read from r
+fn recent<R: BufRead>(r: R, upbuild_file: &Path, n: usize) -> Vec<String> {
+    let file = upbuild_file.to_string_lossy();
+    let mut found: Vec<String> = r.lines()
+        .map_while(std::result::Result::ok)
+        .filter_map(|l| json::parse(&l)) // skip corrupt lines
+        .filter(|e| e.get("file").and_then(Value::as_str) == Some(&file))
+        .filter_map(|e| describe(&e))
+        .collect();
+    found.drain(..found.len().saturating_sub(n));
+    found
+}
+
+/// Implement `--ub-history`, showing the last `n` recorded runs of
+/// the given `.upbuild` file
+pub fn show_history(upbuild_file: &Path, n: usize) -> Result<()> {
+    let upbuild_file = upbuild_file.canonicalize()?;
+    let history = match history_file() {
+        Some(f) => f,
+        None => return Ok(()),
+    };
+
+    let f = match std::fs::File::open(history) {
+        Ok(f) => f,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+
+    for run in recent(std::io::BufReader::new(f), &upbuild_file, n) {
+        println!("{}", run);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::testing::TempDir;
+    use super::super::Error;
+    use super::super::report::CommandReport;
+
+    #[test]
+    fn test_utc_timestamp() {
+        let at = |secs| utc_timestamp(SystemTime::UNIX_EPOCH + Duration::from_secs(secs));
+        assert_eq!(at(0), "1970-01-01T00:00:00Z");
+        assert_eq!(at(951782400), "2000-02-29T00:00:00Z");
+        assert_eq!(at(1709251199), "2024-02-29T23:59:59Z");
+        assert_eq!(at(1792152000), "2026-10-16T12:00:00Z");
+    }
+
+    fn report() -> RunReport {
+        RunReport {
+            file: PathBuf::from("/src/.upbuild"),
+            commands: vec![
+                CommandReport {
+                    args: vec!["make".into(), "-j8".into()],
+                    dir: None,
+                    code: Some(0),
+                    duration: Duration::from_millis(1500),
+                },
+                CommandReport {
+                    args: vec!["make".into(), "test it".into()],
+                    dir: Some(PathBuf::from("build")),
+                    code: Some(2),
+                    duration: Duration::from_millis(250),
+                },
+            ],
+        }
+    }
+
+    #[test]
+    fn test_entry() {
+        let cfg = Config { select: ["b", "a"].map(String::from).into(), ..Config::default() };
+        let e = entry("2026-10-16T12:00:00Z", Path::new("/src/sub"), &cfg, &report(), &Err(Error::ExitWithExitCode(2)));
+        assert_eq!(e.to_string(),
+                   concat!(r#"{"timestamp":"2026-10-16T12:00:00Z","cwd":"/src/sub","file":"/src/.upbuild","#,
+                           r#""select":["a","b"],"reject":[],"commands":["#,
+                           r#"{"args":["make","-j8"],"dir":null,"code":0,"duration":1.5},"#,
+                           r#"{"args":["make","test it"],"dir":"build","code":2,"duration":0.25}],"#,
+                           r#""success":false,"error":"Process exitted with code: 2"}"#));
+    }
+
+    #[test]
+    fn test_recent() {
+        let cfg = Config::default();
+        let line = |file: &str, ts: &str| {
+            let mut r = report();
+            r.file = file.into();
+            entry(ts, Path::new("/src"), &cfg, &r, &Ok(())).to_string()
+        };
+        let history = [
+            line("/src/.upbuild", "1"),
+            String::from("{corrupt"),
+            line("/other/.upbuild", "2"),
+            line("/src/.upbuild", "3"),
+            line("/src/.upbuild", "4"),
+        ].join("\n");
+
+        let runs = recent(history.as_bytes(), Path::new("/src/.upbuild"), 2);
+        assert_eq!(runs, [
+            "3 succeeded (2 commands, 1.8s)\n  [  0]     1.5s make -j8\n  [  2]     0.2s make 'test it'",
+            "4 succeeded (2 commands, 1.8s)\n  [  0]     1.5s make -j8\n  [  2]     0.2s make 'test it'",
+        ]);
+        assert_eq!(recent(history.as_bytes(), Path::new("/src/.upbuild"), 10).len(), 3);
+        assert!(recent(history.as_bytes(), Path::new("/none/.upbuild"), 10).is_empty());
+    }
+
+    #[test]
+    fn test_history_hooks() {
+        let dir = TempDir::new("history");
+        let file = dir.join("state").join("history.jsonl");
+        let h = History::with_file(&Config::default(), Some(file.clone()));
+
+        h.run_start(Path::new("/no/such/.upbuild"));
+        h.before_command(&["true".to_string()], None);
+        h.after_command(0, Duration::from_secs(1));
+        h.run_end(&Ok(()));
+
+        let runs = recent(std::io::BufReader::new(std::fs::File::open(&file).unwrap()),
+                          Path::new("/no/such/.upbuild"), 5);
+        assert_eq!(runs.len(), 1);
+        assert!(runs[0].ends_with("succeeded (1 command, 1.0s)\n  [  0]     1.0s true"), "{}", runs[0]);
+    }
+
+    #[test]
+    fn test_trim() {
+        let dir = TempDir::new("history-trim");
+        let file = dir.join("history.jsonl");
+        let lines = |from: usize, to: usize| (from..=to).map(|i| i.to_string() + "\n").collect::<String>();
+
+        // left alone until well past the limit
+        std::fs::write(&file, lines(1, 11)).unwrap();
+        History::trim(&file, 10).unwrap();
+        assert_eq!(std::fs::read_to_string(&file).unwrap(), lines(1, 11));
+
+        std::fs::write(&file, lines(1, 12)).unwrap();
+        History::trim(&file, 10).unwrap();
+        assert_eq!(std::fs::read_to_string(&file).unwrap(), lines(3, 12));
+        assert!(!dir.join("history.jsonl.tmp").exists());
+
+        // as each run is recorded
+        std::fs::remove_file(&file).unwrap();
+        for _ in 0..=HISTORY_RUNS + HISTORY_RUNS / 10 {
+            History::append(&file, "{}").unwrap();
+        }
+        assert_eq!(std::fs::read_to_string(&file).unwrap().lines().count(), HISTORY_RUNS);
+    }
+}
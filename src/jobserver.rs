@@ -0,0 +1,273 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// (C) Copyright 2024 Greg Whiteley
+
+use std::borrow::Cow;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use super::{Config, Error, Result};
+use super::exec::{OutputSettings, RetCode, RunSettings, Runner, Stage};
+
+/// GNU make compatible jobserver shared with child processes via
+/// `MAKEFLAGS`.  upbuild holds the implicit token itself and the pipe
+/// holds one for each further job, so nested `make`s sharing it keep
+/// the total within the limit.
+#[derive(Debug)]
+pub(crate) struct Jobserver {
+    jobs: usize,
+    implicit: AtomicBool,
+    pipe: sys::Pipe,
+}
+
+/// A job slot, returned to the jobserver when dropped
+#[derive(Debug)]
+pub(crate) struct Token<'a> {
+    server: &'a Jobserver,
+    implicit: bool,
+}
+
+impl Jobserver {
+    /// Create a jobserver allowing `jobs` concurrent jobs in total
+    pub(crate) fn new(jobs: usize) -> io::Result<Jobserver> {
+        let jobs = jobs.max(1);
+        let pipe = sys::Pipe::new()?;
+        pipe.release(jobs - 1)?;
+        Ok(Jobserver { jobs, implicit: AtomicBool::new(true), pipe })
+    }
+
+    /// Take a job slot, waiting for one to be returned if required
+    pub(crate) fn acquire(&self) -> io::Result<Token<'_>> {
+        if self.implicit.swap(false, Ordering::AcqRel) {
+            return Ok(Token { server: self, implicit: true });
+        }
+        self.pipe.acquire()?;
+        Ok(Token { server: self, implicit: false })
+    }
+
+    /// `MAKEFLAGS` for children, keeping any flags from `existing`
+    /// other than those describing another jobserver.  None where
+    /// the platform has no jobserver to share.
+    pub(crate) fn makeflags(&self, existing: Option<&str>) -> Option<String> {
+        let auth = self.pipe.auth()?;
+        let mut flags: Vec<String> = existing.unwrap_or("")
+            .split_whitespace()
+            .filter(|f| !(f.starts_with("-j") || f.starts_with("--jobserver-")))
+            .map(String::from)
+            .collect();
+        flags.push(format!("-j{}", self.jobs));
+        // --jobserver-fds for make before 4.2
+        flags.push(format!("--jobserver-fds={}", auth));
+        flags.push(format!("--jobserver-auth={}", auth));
+        Some(flags.join(" "))
+    }
+}
+
+impl Drop for Token<'_> {
+    fn drop(&mut self) {
+        if self.implicit {
+            self.server.implicit.store(true, Ordering::Release);
+        } else {
+            // can't do better than leak the slot
+            let _ = self.server.pipe.release(1);
+        }
+    }
+}
+
+// Runner holding a job slot for each command, and handing the
+// jobserver on to it
+struct JobsRunner {
+    inner: Box<dyn Runner>,
+    jobserver: Jobserver,
+}
+
+impl JobsRunner {
+    // The settings with MAKEFLAGS naming the jobserver, if it can be
+    // shared
+    fn settings<'a>(&self, settings: &'a RunSettings) -> Cow<'a, RunSettings> {
+        match self.jobserver.makeflags(std::env::var("MAKEFLAGS").ok().as_deref()) {
+            Some(flags) => {
+                let mut settings = settings.clone();
+                settings.env.push((String::from("MAKEFLAGS"), flags));
+                Cow::Owned(settings)
+            },
+            None => Cow::Borrowed(settings),
+        }
+    }
+}
+
+impl Runner for JobsRunner {
+    fn run(&self, cmd: &[String], cd: Option<&Path>, settings: &RunSettings) -> Result<RetCode> {
+        let _token = self.jobserver.acquire().map_err(Error::JobserverFailed)?;
+        self.inner.run(cmd, cd, &self.settings(settings))
+    }
+
+    fn run_captured(&self, cmd: &[String], cd: Option<&Path>, settings: &RunSettings, silent: bool) -> Result<(RetCode, Vec<u8>)> {
+        let _token = self.jobserver.acquire().map_err(Error::JobserverFailed)?;
+        self.inner.run_captured(cmd, cd, &self.settings(settings), silent)
+    }
+
+    // the pipeline is one job, however many commands it has
+    fn run_pipeline(&self, cmds: &[Stage<'_>]) -> Result<Vec<RetCode>> {
+        let _token = self.jobserver.acquire().map_err(Error::JobserverFailed)?;
+        let settings: Vec<_> = cmds.iter().map(|(_, _, settings)| self.settings(settings)).collect();
+        let cmds: Vec<Stage<'_>> = cmds.iter().zip(&settings).map(|((cmd, cd, _), settings)| (*cmd, *cd, settings.as_ref())).collect();
+        self.inner.run_pipeline(&cmds)
+    }
+
+    fn check_mkdir(&self, d: &Path) -> Result<()> {
+        self.inner.check_mkdir(d)
+    }
+
+    fn check_mkdir_umask(&self, d: &Path, umask: u32) -> Result<()> {
+        self.inner.check_mkdir_umask(d, umask)
+    }
+
+    fn is_dir(&self, d: &Path) -> bool {
+        self.inner.is_dir(d)
+    }
+
+    fn canonicalize(&self, d: &Path) -> io::Result<PathBuf> {
+        self.inner.canonicalize(d)
+    }
+
+    fn has_program(&self, name: &str) -> bool {
+        self.inner.has_program(name)
+    }
+
+    fn has_env(&self, name: &str, allow_empty: bool) -> bool {
+        self.inner.has_env(name, allow_empty)
+    }
+
+    fn display_output(&self, file: &Path, settings: &OutputSettings) -> Result<()> {
+        self.inner.display_output(file, settings)
+    }
+
+    fn find_outfiles(&self, pattern: &Path, cd: Option<&Path>) -> Vec<PathBuf> {
+        self.inner.find_outfiles(pattern, cd)
+    }
+
+    fn display(&self, s: &str) {
+        self.inner.display(s)
+    }
+
+    fn trace(&self, s: &str) {
+        self.inner.trace(s)
+    }
+}
+
+/// Wrap a runner to share a jobserver of the size given by
+/// `--ub-jobs=`, if any, with the commands it runs.  On platforms
+/// other than unix there is no jobserver and commands are run as is.
+pub fn jobs_runner(inner: Box<dyn Runner>, cfg: &Config) -> Result<Box<dyn Runner>> {
+    match cfg.jobs() {
+        Some(jobs) => {
+            let jobserver = Jobserver::new(jobs).map_err(Error::JobserverFailed)?;
+            Ok(Box::new(JobsRunner { inner, jobserver }))
+        },
+        None => Ok(inner),
+    }
+}
+
+#[cfg(target_family = "unix")]
+mod sys {
+    use std::fs::File;
+    use std::io::{self, Read, Write};
+    use std::os::unix::io::{AsRawFd, FromRawFd};
+
+    extern "C" {
+        fn pipe(fds: *mut i32) -> i32;
+    }
+
+    // Deliberately not close-on-exec so children inherit it
+    #[derive(Debug)]
+    pub(super) struct Pipe {
+        read: File,
+        write: File,
+    }
+
+    impl Pipe {
+        pub(super) fn new() -> io::Result<Pipe> {
+            let mut fds = [0i32; 2];
+            // SAFETY: pipe fills in exactly two descriptors
+            if unsafe { pipe(fds.as_mut_ptr()) } != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            // SAFETY: both descriptors were just opened and are owned here
+            Ok(unsafe { Pipe { read: File::from_raw_fd(fds[0]), write: File::from_raw_fd(fds[1]) } })
+        }
+
+        pub(super) fn acquire(&self) -> io::Result<()> {
+            (&self.read).read_exact(&mut [0u8])
+        }
+
+        pub(super) fn release(&self, n: usize) -> io::Result<()> {
+            (&self.write).write_all(&vec![b'+'; n])
+        }
+
+        pub(super) fn auth(&self) -> Option<String> {
+            Some(format!("{},{}", self.read.as_raw_fd(), self.write.as_raw_fd()))
+        }
+    }
+}
+
+// No jobserver to share - children are left to their own -j
+#[cfg(not(target_family = "unix"))]
+mod sys {
+    use std::io;
+
+    #[derive(Debug)]
+    pub(super) struct Pipe {}
+
+    impl Pipe {
+        pub(super) fn new() -> io::Result<Pipe> {
+            Ok(Pipe {})
+        }
+
+        pub(super) fn acquire(&self) -> io::Result<()> {
+            Ok(())
+        }
+
+        pub(super) fn release(&self, _n: usize) -> io::Result<()> {
+            Ok(())
+        }
+
+        pub(super) fn auth(&self) -> Option<String> {
+            None
+        }
+    }
+}
+
+#[cfg(all(test, target_family = "unix"))]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_tokens() {
+        let js = Jobserver::new(3).unwrap();
+        let a = js.acquire().unwrap();
+        assert!(a.implicit);
+        let b = js.acquire().unwrap();
+        let c = js.acquire().unwrap();
+        assert!(!b.implicit && !c.implicit);
+        drop(a);
+        // the implicit slot is reused first
+        let d = js.acquire().unwrap();
+        assert!(d.implicit);
+        drop((b, c, d));
+        let _e = js.acquire().unwrap();
+        let _f = js.acquire().unwrap();
+        let _g = js.acquire().unwrap();
+    }
+
+    #[test]
+    fn test_makeflags() {
+        let js = Jobserver::new(4).unwrap();
+        let auth = js.pipe.auth().unwrap();
+        assert_eq!(js.makeflags(None).unwrap(),
+                   format!("-j4 --jobserver-fds={0} --jobserver-auth={0}", auth));
+        assert_eq!(js.makeflags(Some(" -k -j2 --jobserver-auth=7,8 s")).unwrap(),
+                   format!("-k s -j4 --jobserver-fds={0} --jobserver-auth={0}", auth));
+    }
+}
@@ -0,0 +1,341 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// (C) Copyright 2024 Greg Whiteley
+
+//! Just enough JSON to read and write upbuild's own state files
+//! without pulling in a dependency.
+
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Value {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Value>),
+    Object(Vec<(String, Value)>),
+}
+
+impl Value {
+
+    pub(crate) fn object<K: Into<String>, I: IntoIterator<Item=(K, Value)>>(fields: I) -> Value {
+        Value::Object(fields.into_iter().map(|(k, v)| (k.into(), v)).collect())
+    }
+
+    pub(crate) fn strings<T: AsRef<str>, I: IntoIterator<Item=T>>(strings: I) -> Value {
+        Value::Array(strings.into_iter().map(|s| Value::from(s.as_ref())).collect())
+    }
+
+    pub(crate) fn get(&self, key: &str) -> Option<&Value> {
+        match self {
+            Value::Object(fields) => fields.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn as_f64(&self) -> Option<f64> {
+        match self {
+            Value::Number(n) => Some(*n),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn as_bool(&self) -> Option<bool> {
+        match self {
+            Value::Bool(b) => Some(*b),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn as_array(&self) -> Option<&[Value]> {
+        match self {
+            Value::Array(a) => Some(a),
+            _ => None,
+        }
+    }
+}
+
+impl From<&str> for Value {
+    fn from(s: &str) -> Value {
+        Value::String(s.to_string())
+    }
+}
+
+impl From<String> for Value {
+    fn from(s: String) -> Value {
+        Value::String(s)
+    }
+}
+
+impl From<bool> for Value {
+    fn from(b: bool) -> Value {
+        Value::Bool(b)
+    }
+}
+
+impl From<f64> for Value {
+    fn from(n: f64) -> Value {
+        Value::Number(n)
+    }
+}
+
+impl<T: Into<Value>> From<Option<T>> for Value {
+    fn from(v: Option<T>) -> Value {
+        v.map_or(Value::Null, Into::into)
+    }
+}
+
+fn write_str(f: &mut fmt::Formatter<'_>, s: &str) -> fmt::Result {
+    f.write_str("\"")?;
+    for c in s.chars() {
+        match c {
+            '"' => f.write_str("\\\"")?,
+            '\\' => f.write_str("\\\\")?,
+            '\n' => f.write_str("\\n")?,
+            '\r' => f.write_str("\\r")?,
+            '\t' => f.write_str("\\t")?,
+            c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+            c => write!(f, "{}", c)?,
+        }
+    }
+    f.write_str("\"")
+}
+
+// Compact single-line output, so it can be used for JSON lines
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Null => f.write_str("null"),
+            Value::Bool(b) => write!(f, "{}", b),
+            Value::Number(n) if n.is_finite() => write!(f, "{}", n),
+            Value::Number(_) => f.write_str("null"),
+            Value::String(s) => write_str(f, s),
+            Value::Array(a) => {
+                f.write_str("[")?;
+                for (i, v) in a.iter().enumerate() {
+                    if i != 0 {
+                        f.write_str(",")?;
+                    }
+                    write!(f, "{}", v)?;
+                }
+                f.write_str("]")
+            },
+            Value::Object(o) => {
+                f.write_str("{")?;
+                for (i, (k, v)) in o.iter().enumerate() {
+                    if i != 0 {
+                        f.write_str(",")?;
+                    }
+                    write_str(f, k)?;
+                    write!(f, ":{}", v)?;
+                }
+                f.write_str("}")
+            },
+        }
+    }
+}
+
+struct Parser<'a> {
+    s: &'a str,
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+
+    fn peek(&self) -> Option<char> {
+        self.s[self.pos..].chars().next()
+    }
+
+    fn next(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.pos += c.len_utf8();
+        Some(c)
+    }
+
+    fn skip_ws(&mut self) {
+        while let Some(' ' | '\t' | '\n' | '\r') = self.peek() {
+            self.pos += 1;
+        }
+    }
+
+    fn expect(&mut self, c: char) -> Option<()> {
+        self.skip_ws();
+        if self.next()? == c { Some(()) } else { None }
+    }
+
+    fn literal(&mut self, word: &str, v: Value) -> Option<Value> {
+        if self.s[self.pos..].starts_with(word) {
+            self.pos += word.len();
+            return Some(v);
+        }
+        None
+    }
+
+    fn value(&mut self) -> Option<Value> {
+        self.skip_ws();
+        match self.peek()? {
+            'n' => self.literal("null", Value::Null),
+            't' => self.literal("true", Value::Bool(true)),
+            'f' => self.literal("false", Value::Bool(false)),
+            '"' => self.string().map(Value::String),
+            '[' => self.array(),
+            '{' => self.object(),
+            _ => self.number(),
+        }
+    }
+
+    fn number(&mut self) -> Option<Value> {
+        let start = self.pos;
+        while let Some('0'..='9' | '-' | '+' | '.' | 'e' | 'E') = self.peek() {
+            self.pos += 1;
+        }
+        self.s[start..self.pos].parse().ok().map(Value::Number)
+    }
+
+    fn hex4(&mut self) -> Option<u32> {
+        let hex = self.s.get(self.pos..self.pos + 4)?;
+        self.pos += 4;
+        u32::from_str_radix(hex, 16).ok()
+    }
+
+    fn string(&mut self) -> Option<String> {
+        self.expect('"')?;
+        let mut out = String::new();
+        loop {
+            match self.next()? {
+                '"' => return Some(out),
+                '\\' => {
+                    let c = match self.next()? {
+                        '"' => '"',
+                        '\\' => '\\',
+                        '/' => '/',
+                        'b' => '\u{8}',
+                        'f' => '\u{c}',
+                        'n' => '\n',
+                        'r' => '\r',
+                        't' => '\t',
+                        'u' => {
+                            let mut c = self.hex4()?;
+                            if (0xd800..0xdc00).contains(&c) {
+                                // surrogate pair
+                                if self.next()? != '\\' || self.next()? != 'u' {
+                                    return None;
+                                }
+                                let low = self.hex4()?;
+                                c = 0x10000 + ((c - 0xd800) << 10) + (low.checked_sub(0xdc00)? & 0x3ff);
+                            }
+                            char::from_u32(c)?
+                        },
+                        _ => return None,
+                    };
+                    out.push(c);
+                },
+                c => out.push(c),
+            }
+        }
+    }
+
+    fn array(&mut self) -> Option<Value> {
+        self.expect('[')?;
+        let mut a = Vec::new();
+        self.skip_ws();
+        if self.peek()? == ']' {
+            self.pos += 1;
+            return Some(Value::Array(a));
+        }
+        loop {
+            a.push(self.value()?);
+            self.skip_ws();
+            match self.next()? {
+                ',' => {},
+                ']' => return Some(Value::Array(a)),
+                _ => return None,
+            }
+        }
+    }
+
+    fn object(&mut self) -> Option<Value> {
+        self.expect('{')?;
+        let mut o = Vec::new();
+        self.skip_ws();
+        if self.peek()? == '}' {
+            self.pos += 1;
+            return Some(Value::Object(o));
+        }
+        loop {
+            self.skip_ws();
+            let k = self.string()?;
+            self.expect(':')?;
+            o.push((k, self.value()?));
+            self.skip_ws();
+            match self.next()? {
+                ',' => {},
+                '}' => return Some(Value::Object(o)),
+                _ => return None,
+            }
+        }
+    }
+}
+
+/// Parse a complete JSON document, returning None if it is invalid
+pub(crate) fn parse(s: &str) -> Option<Value> {
+    let mut p = Parser { s, pos: 0 };
+    let v = p.value()?;
+    p.skip_ws();
+    if p.pos != s.len() {
+        return None;
+    }
+    Some(v)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_write() {
+        assert_eq!(Value::Null.to_string(), "null");
+        assert_eq!(Value::from(1.5).to_string(), "1.5");
+        assert_eq!(Value::from(3.0).to_string(), "3");
+        assert_eq!(Value::from(f64::NAN).to_string(), "null");
+        assert_eq!(Value::from("a \"b\"\\\n\u{1}").to_string(), r#""a \"b\"\\\n\u0001""#);
+        assert_eq!(Value::strings(["a", "b"]).to_string(), r#"["a","b"]"#);
+        assert_eq!(Value::object([("a", Value::from(true)), ("b", Value::from(None::<&str>))]).to_string(),
+                   r#"{"a":true,"b":null}"#);
+    }
+
+    #[test]
+    fn test_parse() {
+        let v = parse(r#" {"a": [1, -2.5e1, "x\u00e9\ud83d\ude00"], "b": {}, "c": [], "d": null, "e": false} "#)
+            .expect("should parse");
+        assert_eq!(v.get("a").and_then(Value::as_array).map(|a| a.len()), Some(3));
+        assert_eq!(v.get("a").unwrap().as_array().unwrap()[1].as_f64(), Some(-25.0));
+        assert_eq!(v.get("a").unwrap().as_array().unwrap()[2].as_str(), Some("x\u{e9}\u{1f600}"));
+        assert_eq!(v.get("b"), Some(&Value::Object(vec![])));
+        assert_eq!(v.get("d"), Some(&Value::Null));
+        assert_eq!(v.get("e").and_then(Value::as_bool), Some(false));
+        assert_eq!(v.get("missing"), None);
+
+        for bad in ["", "{", "[1,]", "{\"a\"}", "\"abc", "1 2", "tru", "{\"a\":1,}", "\"\\ud800\""] {
+            assert_eq!(parse(bad), None, "{:?} should fail", bad);
+        }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let v = Value::object([
+            ("s", Value::from("tab\there \u{7f} \u{1f600}")),
+            ("n", Value::from(12345.25)),
+            ("a", Value::Array(vec![Value::Null, Value::from(true), Value::strings(["x"])])),
+        ]);
+        assert_eq!(parse(&v.to_string()), Some(v));
+    }
+}
@@ -6,22 +6,106 @@
 
 #![warn(missing_docs)]
 
+// Internal debug tracing, compiled out without the `logging` feature
+#[cfg(feature = "logging")]
+macro_rules! debug {
+    ($($arg:tt)*) => { log::debug!($($arg)*) };
+}
+
+#[cfg(not(feature = "logging"))]
+macro_rules! debug {
+    ($($arg:tt)*) => { if false { let _ = format_args!($($arg)*); } };
+}
+
 mod error;
 mod file;
 mod exec;
 mod find;
 mod cfg;
+mod shell;
+mod json;
+mod report;
+mod history;
+mod notify;
+mod stats;
+mod scan;
+mod output;
+mod lock;
+mod ci;
+mod completion;
+mod run;
+mod tags;
+mod list;
+mod umask;
+mod signals;
+#[cfg(feature = "jobs")]
+mod jobserver;
+#[cfg(any(test, feature = "test-util"))]
+pub mod testing;
 
 pub use file::ClassicFile;
+pub use file::Cmd;
 
 pub use exec::Exec;
+pub use exec::ExecHooks;
+pub use exec::Runner;
+pub use exec::RetCode;
+pub use exec::RunSettings;
+pub use exec::Stage;
+pub use exec::StdinSource;
+pub use exec::OutputSettings;
+pub use exec::PlannedCommand;
 pub use exec::process_runner;
 pub use exec::print_runner;
+pub use exec::print_null_runner;
+
+pub use report::RunReport;
+pub use report::CommandReport;
+
+pub use history::History;
+pub use history::show_history;
+
+pub use notify::Notify;
+
+pub use stats::Timing;
+pub use stats::show_stats;
+pub use stats::reset_stats;
+
+pub use lock::Lock;
+
+pub use ci::Ci;
+pub use ci::ci_runner;
+
+pub use completion::Completion;
+pub use completion::Shell;
+pub use completion::generate_bash_completion;
+pub use completion::generate_powershell_completion;
+pub use completion::list_tags;
+
+#[cfg(feature = "jobs")]
+pub use jobserver::jobs_runner;
 
 pub use find::find;
+pub use find::find_with;
+pub use find::find_with_names;
+pub use find::find_all;
+pub use find::discover;
+pub use find::discover_with;
+pub use find::DISCOVER_DEPTH;
+pub use find::SearchEnd;
 pub use cfg::Config;
+pub use cfg::ConfigBuilder;
+pub use run::run;
+pub use run::RunOptions;
+pub use run::RunnerChoice;
+pub use tags::TagExpr;
+pub use list::ListEntry;
+pub use list::ListFormat;
+pub use list::list_commands;
 
 /// The Error type for this tool
 pub type Error = error::Error;
 /// Bind the implied Error type for convenience
 pub type Result<T> = std::result::Result<T, Error>;
+
+pub use error::ErrorKind;
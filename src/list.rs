@@ -0,0 +1,174 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// (C) Copyright 2024 Greg Whiteley
+
+use std::path::{Path, PathBuf};
+
+use super::{ClassicFile, Config, Exec, Result};
+use super::json::Value;
+use super::shell;
+
+/// How `--ub-list` shows the commands
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum ListFormat {
+    /// A line per command, to read
+    #[default]
+    Text,
+    /// A JSON array with an object per command, for editors and
+    /// other tools
+    Json,
+    /// A line per command of tab separated columns, for `awk` and
+    /// the like
+    Tsv,
+}
+
+/// A command as listed by `--ub-list`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ListEntry {
+    /// Position of the command in the file, from 1 as for `--ub-show=`
+    pub index: usize,
+    /// The command's `@name`, if any
+    pub name: Option<String>,
+    /// The command and its arguments as given in the file
+    pub args: Vec<String>,
+    /// The command's `@tags`, sorted
+    pub tags: Vec<String>,
+    /// The command's `@cd`, if any
+    pub cd: Option<PathBuf>,
+    /// The command's `@mkdir`, if any
+    pub mkdir: Option<PathBuf>,
+    /// The command's `@outfile`, if any
+    pub outfile: Option<PathBuf>,
+    /// Whether the command has `@disable`
+    pub disabled: bool,
+    /// Whether the command has `@manual`
+    pub manual: bool,
+    /// Whether the command would run with the config's selection, as
+    /// `upbuild` would decide
+    pub would_run: bool,
+}
+
+/// The commands in the file and whether each would run with the
+/// config's selection, for `--ub-list`
+pub fn list_commands(file: &ClassicFile, cfg: &Config) -> Result<Vec<ListEntry>> {
+    cfg.tags()?;
+    let running = Exec::running(file.commands(), cfg);
+    Ok(file.commands().iter().zip(running).enumerate().map(|(i, (cmd, would_run))| {
+        let mut tags: Vec<_> = cmd.tags().iter().cloned().collect();
+        tags.sort_unstable();
+        ListEntry {
+            index: i + 1,
+            name: cmd.name().map(String::from),
+            args: cmd.args().to_vec(),
+            tags,
+            cd: cmd.directory().map(Path::to_path_buf),
+            mkdir: cmd.mk_dir().map(Path::to_path_buf),
+            outfile: cmd.out_file().map(Path::to_path_buf),
+            disabled: cmd.disabled(),
+            manual: cmd.manual(),
+            would_run,
+        }
+    }).collect())
+}
+
+impl ListFormat {
+    /// The listing as printed, ending in a line end
+    pub fn render(&self, entries: &[ListEntry]) -> String {
+        let lines: Vec<String> = match self {
+            ListFormat::Text => entries.iter().map(text).collect(),
+            ListFormat::Json => {
+                let objects: Vec<_> = entries.iter().map(|e| json(e).to_string()).collect();
+                if objects.is_empty() {
+                    return String::from("[]\n");
+                }
+                return format!("[\n{}\n]\n", objects.join(",\n"));
+            },
+            ListFormat::Tsv => entries.iter().map(|e| tsv(e).join("\t")).collect(),
+        };
+        lines.into_iter().map(|l| l + "\n").collect()
+    }
+}
+
+// eg `2: make install (@name=inst) [host,release] - not run`
+fn text(e: &ListEntry) -> String {
+    let mut line = format!("{}: {}", e.index, shell::join(&e.args));
+    if let Some(name) = &e.name {
+        line += &format!(" (@name={})", name);
+    }
+    if ! e.tags.is_empty() {
+        line += &format!(" [{}]", e.tags.join(","));
+    }
+    if ! e.would_run {
+        line += " - not run";
+    }
+    line
+}
+
+fn json(e: &ListEntry) -> Value {
+    let path = |p: &Option<PathBuf>| Value::from(p.as_ref().map(|p| p.display().to_string()));
+    Value::object([
+        ("index", Value::from(e.index as f64)),
+        ("name", Value::from(e.name.clone())),
+        ("args", Value::strings(&e.args)),
+        ("tags", Value::strings(&e.tags)),
+        ("cd", path(&e.cd)),
+        ("mkdir", path(&e.mkdir)),
+        ("outfile", path(&e.outfile)),
+        ("disabled", Value::from(e.disabled)),
+        ("manual", Value::from(e.manual)),
+        ("would_run", Value::from(e.would_run)),
+    ])
+}
+
+// The columns, in an order that won't change - new ones go on the
+// end, after the command.  Tabs and line ends are escaped, as \t and
+// \n, so each command stays one line.
+fn tsv(e: &ListEntry) -> Vec<String> {
+    let flag = |b: bool| String::from(if b { "1" } else { "0" });
+    let path = |p: &Option<PathBuf>| p.as_ref().map(|p| p.display().to_string()).unwrap_or_default();
+    [
+        e.index.to_string(),
+        flag(e.would_run),
+        flag(e.disabled),
+        flag(e.manual),
+        e.name.clone().unwrap_or_default(),
+        e.tags.join(","),
+        path(&e.cd),
+        path(&e.mkdir),
+        path(&e.outfile),
+        shell::join(&e.args),
+    ].into_iter()
+        .map(|c| c.replace('\\', "\\\\").replace('\t', "\\t").replace('\n', "\\n").replace('\r', "\\r"))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_list() {
+        let file = ClassicFile::parse_lines(include_str!("../tests/list.upbuild").lines()).unwrap();
+        let cfg = Config::builder().select(["host"]).build();
+        let entries = list_commands(&file, &cfg).unwrap();
+
+        assert_eq!(ListFormat::Json.render(&entries), include_str!("../tests/list.json"));
+        // and it reads back
+        let read = crate::json::parse(&ListFormat::Json.render(&entries)).unwrap();
+        assert_eq!(read.as_array().map(<[_]>::len), Some(4));
+
+        assert_eq!(ListFormat::Text.render(&entries),
+                   "1: make tests [host]\n\
+                    2: make cross [target] - not run\n\
+                    3: make install (@name=inst) [host,release]\n\
+                    4: tar czf 'out dir.tgz' out - not run\n");
+        assert_eq!(ListFormat::Tsv.render(&entries).lines().nth(2),
+                   Some("3\t1\t0\t1\tinst\thost,release\tbuild\tbuild\tinstall.log\tmake install"));
+
+        // the same selection as running them
+        let cfg = Config::builder().tags("release | !host").build();
+        let would_run: Vec<_> = list_commands(&file, &cfg).unwrap().iter().map(|e| e.would_run).collect();
+        assert_eq!(would_run, [false, true, true, false]);
+        assert_eq!(ListFormat::Json.render(&[]), "[]\n");
+    }
+}
@@ -0,0 +1,204 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// (C) Copyright 2024 Greg Whiteley
+
+use std::fs::File;
+use std::io::{Read, Seek, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use super::{Error, Result};
+use super::cfg::{env_level, prefix};
+
+// How often to retry a held lock
+const POLL: Duration = Duration::from_millis(100);
+
+/// Advisory lock serialising runs of the same `.upbuild` file, see
+/// `--ub-lock`.  The lock is released when dropped, or by the
+/// operating system if upbuild exits any other way.
+#[derive(Debug)]
+pub struct Lock {
+    file: File,
+}
+
+/// The lock file used for the given `.upbuild` file
+fn lock_file(upbuild_file: &Path) -> PathBuf {
+    upbuild_file.with_file_name(".upbuild.lock")
+}
+
+impl Lock {
+
+    /// Lock the given `.upbuild` file, waiting up to `timeout` (or
+    /// forever) for another upbuild to finish with it.  The wait is
+    /// reported at the level `UPBUILD_LEVEL` gives.
+    pub fn acquire(upbuild_file: &Path, timeout: Option<Duration>) -> Result<Lock> {
+        Self::acquire_as(upbuild_file, timeout, &prefix(env_level()))
+    }
+
+    // As acquire, reporting the wait with the given message prefix
+    pub(crate) fn acquire_as(upbuild_file: &Path, timeout: Option<Duration>, prefix: &str) -> Result<Lock> {
+        let path = lock_file(upbuild_file);
+        let err = |e| Error::UnableToLock(path.display().to_string(), e);
+        let mut file = File::options()
+            .read(true)
+            .write(true)
+            .create(true)
+            .open(&path)
+            .map_err(err)?;
+
+        let started = Instant::now();
+        let mut waiting = false;
+        while !try_lock(&file).map_err(err)? {
+            if !waiting {
+                eprintln!("{}: waiting for other upbuild ({})", prefix, holder(&mut file));
+                waiting = true;
+            }
+            if timeout.map_or(false, |t| started.elapsed() >= t) {
+                return Err(Error::LockTimeout(path.display().to_string(), holder(&mut file)));
+            }
+            std::thread::sleep(POLL);
+        }
+
+        // best effort - only used to tell others who they're waiting for
+        let _ = file.set_len(0)
+            .and_then(|_| file.rewind())
+            .and_then(|_| write!(file, "{}", std::process::id()));
+        Ok(Lock { file })
+    }
+}
+
+impl Drop for Lock {
+    fn drop(&mut self) {
+        unlock(&self.file);
+    }
+}
+
+// Describe the holder of the lock from the pid it recorded
+fn holder(file: &mut File) -> String {
+    let mut pid = String::new();
+    match file.rewind().and_then(|_| file.read_to_string(&mut pid)) {
+        Ok(_) if !pid.trim().is_empty() => format!("pid {}", pid.trim()),
+        _ => String::from("unknown pid"),
+    }
+}
+
+#[cfg(target_family = "unix")]
+mod sys {
+    use std::os::unix::io::AsRawFd;
+
+    const LOCK_EX: i32 = 2;
+    const LOCK_NB: i32 = 4;
+    const LOCK_UN: i32 = 8;
+
+    extern "C" {
+        fn flock(fd: i32, operation: i32) -> i32;
+    }
+
+    pub(super) fn try_lock(file: &std::fs::File) -> std::io::Result<bool> {
+        // SAFETY: flock only operates on the descriptor
+        if unsafe { flock(file.as_raw_fd(), LOCK_EX | LOCK_NB) } == 0 {
+            return Ok(true);
+        }
+        let e = std::io::Error::last_os_error();
+        match e.kind() {
+            std::io::ErrorKind::WouldBlock => Ok(false),
+            _ => Err(e),
+        }
+    }
+
+    pub(super) fn unlock(file: &std::fs::File) {
+        // SAFETY: as above
+        unsafe { flock(file.as_raw_fd(), LOCK_UN) };
+    }
+}
+
+#[cfg(windows)]
+mod sys {
+    use std::ffi::c_void;
+    use std::os::windows::io::AsRawHandle;
+
+    const LOCKFILE_FAIL_IMMEDIATELY: u32 = 1;
+    const LOCKFILE_EXCLUSIVE_LOCK: u32 = 2;
+    const ERROR_LOCK_VIOLATION: i32 = 33;
+
+    #[repr(C)]
+    struct Overlapped {
+        internal: usize,
+        internal_high: usize,
+        offset: u32,
+        offset_high: u32,
+        event: *mut c_void,
+    }
+
+    // Lock a byte well past the pid so it can still be read
+    fn region() -> Overlapped {
+        Overlapped { internal: 0, internal_high: 0, offset: 0, offset_high: 1, event: std::ptr::null_mut() }
+    }
+
+    extern "system" {
+        fn LockFileEx(file: *mut c_void, flags: u32, reserved: u32, low: u32, high: u32, overlapped: *mut Overlapped) -> i32;
+        fn UnlockFileEx(file: *mut c_void, reserved: u32, low: u32, high: u32, overlapped: *mut Overlapped) -> i32;
+    }
+
+    pub(super) fn try_lock(file: &std::fs::File) -> std::io::Result<bool> {
+        let mut region = region();
+        // SAFETY: the handle is open and region outlives the call
+        let locked = unsafe {
+            LockFileEx(file.as_raw_handle() as *mut c_void,
+                       LOCKFILE_EXCLUSIVE_LOCK | LOCKFILE_FAIL_IMMEDIATELY, 0, 1, 0, &mut region)
+        };
+        if locked != 0 {
+            return Ok(true);
+        }
+        let e = std::io::Error::last_os_error();
+        match e.raw_os_error() {
+            Some(ERROR_LOCK_VIOLATION) => Ok(false),
+            _ => Err(e),
+        }
+    }
+
+    pub(super) fn unlock(file: &std::fs::File) {
+        let mut region = region();
+        // SAFETY: as above
+        unsafe { UnlockFileEx(file.as_raw_handle() as *mut c_void, 0, 1, 0, &mut region) };
+    }
+}
+
+#[cfg(not(any(target_family = "unix", windows)))]
+mod sys {
+    pub(super) fn try_lock(_file: &std::fs::File) -> std::io::Result<bool> {
+        Ok(true)
+    }
+
+    pub(super) fn unlock(_file: &std::fs::File) {
+    }
+}
+
+use sys::{try_lock, unlock};
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::testing::TempDir;
+
+    #[test]
+    fn test_lock() {
+        let dir = TempDir::new("lock");
+        let upbuild_file = dir.join(".upbuild");
+
+        let first = Lock::acquire(&upbuild_file, None).expect("should lock");
+        assert_eq!(std::fs::read_to_string(dir.join(".upbuild.lock")).unwrap(), std::process::id().to_string());
+
+        match Lock::acquire(&upbuild_file, Some(Duration::from_millis(150))) {
+            Err(Error::LockTimeout(file, holder)) => {
+                assert_eq!(file, dir.join(".upbuild.lock").display().to_string());
+                assert_eq!(holder, format!("pid {}", std::process::id()));
+            },
+            r => panic!("expected timeout, got {:?}", r),
+        }
+
+        drop(first);
+        let again = Lock::acquire(&upbuild_file, Some(Duration::ZERO)).expect("should lock once released");
+        drop(again);
+    }
+}
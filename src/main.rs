@@ -5,57 +5,161 @@
 #![warn(missing_docs)]
 
 use std::process::ExitCode;
-use std::io::BufRead;
 
-use upbuild_rs::{ClassicFile, Config, Exec, Result};
+use upbuild_rs::{ClassicFile, Config, Exec, Result, RetCode, RunOptions};
 
-fn run() -> Result<()> {
+fn run(args: std::iter::Peekable<std::env::Args>, cfg: Config) -> Result<()> {
 
-    let (args, cfg) = Config::parse(std::env::args());
+    if let Some(completion) = cfg.completion() {
+        return completion.print();
+    }
 
     if cfg.add() {
         return upbuild_rs::ClassicFile::add(args, ".upbuild".into());
     }
 
-    let upbuild_file = upbuild_rs::find(".")?;
+    if cfg.discover() && !cfg.discover_run() {
+        upbuild_rs::discover_with(".", upbuild_rs::DISCOVER_DEPTH, &cfg).iter().for_each(|p| println!("{}", p.display()));
+        return Ok(());
+    }
 
-    let parsed_file = ClassicFile::parse_lines(
-        std::fs::File::open(&upbuild_file)
-            .map(std::io::BufReader::new)?
-            .lines()
-            .map_while(std::result::Result::ok))?;
+    if cfg.discover_run() {
+        return run_pipeline(args, cfg);
+    }
 
-    let exec = Exec::new(
-        if cfg.print() {
-            upbuild_rs::print_runner()
-        } else {
-            upbuild_rs::process_runner()
-        }
-    );
+    let upbuild_file = upbuild_rs::find_with(".", &cfg)?;
+
+    if let Some(which) = cfg.rm() {
+        return ClassicFile::rm(which, &upbuild_file);
+    }
+
+    if let Some((which, disabled)) = cfg.disable() {
+        println!("{}", ClassicFile::set_disabled(which, disabled, &upbuild_file)?);
+        return Ok(());
+    }
+
+    if let Some((from, to)) = cfg.move_command() {
+        let file = ClassicFile::move_command(from, to, &upbuild_file)?;
+        file.summary().iter().for_each(|l| println!("{}", l));
+        return Ok(());
+    }
 
-    let args: Vec<String> = args.collect(); // TODO - don't require conversion
-    exec.run(upbuild_file.as_path(), &parsed_file, &cfg, &args)
+    if let Some(n) = cfg.history() {
+        return upbuild_rs::show_history(&upbuild_file, n);
+    }
+
+    if cfg.stats_reset() {
+        upbuild_rs::reset_stats(&upbuild_file)?;
+    }
+    if cfg.stats() {
+        return upbuild_rs::show_stats(&upbuild_file);
+    }
+    if cfg.stats_reset() {
+        return Ok(());
+    }
+
+    if let Some(format) = cfg.list() {
+        let file = ClassicFile::load(&upbuild_file)?;
+        print!("{}", format.render(&upbuild_rs::list_commands(&file, &cfg)?));
+        return Ok(());
+    }
+
+    if let Some(which) = cfg.show() {
+        let args: Vec<String> = args.collect();
+        let file = ClassicFile::load(&upbuild_file)?;
+        Exec::show(&upbuild_file, &file, &cfg, which, &args)?.iter().for_each(|l| println!("{}", l));
+        return Ok(());
+    }
+
+    if cfg.print_env() {
+        let args: Vec<String> = args.collect();
+        let file = ClassicFile::load(&upbuild_file)?;
+        Exec::print_env(&upbuild_file, &file, &cfg, &args, |k| std::env::var(k).ok())?.iter().for_each(|l| println!("{}", l));
+        return Ok(());
+    }
+
+    run_pipeline(args, cfg)
+}
+
+// Run the .upbuild file the same way as library users do
+fn run_pipeline(args: std::iter::Peekable<std::env::Args>, cfg: Config) -> Result<()> {
+    upbuild_rs::run(RunOptions { config: cfg, args: args.collect(), ..RunOptions::default() }).map(|_| ())
+}
+
+// Convert a return code to one the process can exit with, or Err
+// for one wider than ExitCode can carry.  On unix exit codes are
+// only 8 bits, and truncating could turn a failure such as 256 into
+// success, so anything out of range exits with 255 and says so.
+#[cfg(target_family = "unix")]
+fn exit_code(c: RetCode, cfg: &Config) -> std::result::Result<u8, i32> {
+    u8::try_from(c).or_else(|_| {
+        eprintln!("{}: return code {} is out of range, exiting with {}", cfg.prefix(), c, u8::MAX);
+        Ok(u8::MAX)
+    })
+}
+
+// Windows exit codes are 32 bits, such as the negative NTSTATUS of a
+// crash, and are passed on whole
+#[cfg(not(target_family = "unix"))]
+fn exit_code(c: RetCode, _cfg: &Config) -> std::result::Result<u8, i32> {
+    u8::try_from(c).map_err(|_| c as i32)
+}
+
+// Show the library's debug tracing on stderr with UPBUILD_LOG=debug
+#[cfg(feature = "logging")]
+fn init_logging() {
+    env_logger::Builder::from_env(env_logger::Env::new().filter("UPBUILD_LOG"))
+        .format_timestamp(None)
+        .init();
+}
+
+#[cfg(not(feature = "logging"))]
+fn init_logging() {
 }
 
 fn main() -> ExitCode {
-    match run() {
-        Ok(_) => (),
-        Err(upbuild_rs::Error::ExitWithExitCode(c)) => {
-            match u8::try_from(c) {
-                Ok(c) => {
-                    return ExitCode::from(c);
-                },
-                Err(e) => {
-                    eprintln!("Unable to return process return code {}: {}", c, e);
-                    return ExitCode::FAILURE;
-                }
-            }
-        },
-        Err(e) => {
-            eprintln!("{}", e);
-            return ExitCode::FAILURE;
-        },
-    };
+    init_logging();
+    let (args, cfg) = Config::parse(std::env::args());
+    if let Err(e) = run(args, cfg.clone()) {
+        match cfg.ci().and_then(|ci| ci.annotate_as(&e, &cfg.prefix())) {
+            Some(a) => eprintln!("{}", a),
+            // the command has already reported its own failure
+            None if e.exit_code().is_some() => (),
+            None => eprintln!("{}: {}", cfg.prefix(), e),
+        }
+        return match exit_code(e.exit_status(), &cfg) {
+            Ok(c) => ExitCode::from(c),
+            Err(c) => std::process::exit(c),
+        };
+    }
 
     ExitCode::SUCCESS
 }
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_exit_code() {
+        let cfg = Config::default();
+        assert_eq!(exit_code(0, &cfg), Ok(0));
+        assert_eq!(exit_code(1, &cfg), Ok(1));
+        assert_eq!(exit_code(255, &cfg), Ok(255));
+        // interrupted, as SIGINT
+        assert_eq!(exit_code(upbuild_rs::Error::ExitWithSignal(2, false).exit_status(), &cfg), Ok(130));
+
+        if cfg!(target_family = "unix") {
+            // never truncated to success
+            assert_eq!(exit_code(256, &cfg), Ok(255));
+            assert_eq!(exit_code(257, &cfg), Ok(255));
+            assert_eq!(exit_code(200001, &cfg), Ok(255));
+            assert_eq!(exit_code(-1, &cfg), Ok(255));
+        } else {
+            assert_eq!(exit_code(256, &cfg), Err(256));
+            assert_eq!(exit_code(-1, &cfg), Err(-1));
+            assert_eq!(exit_code(-1073741819, &cfg), Err(-1073741819));
+        }
+    }
+}
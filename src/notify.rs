@@ -0,0 +1,91 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// (C) Copyright 2024 Greg Whiteley
+
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use super::Result;
+use super::exec::ExecHooks;
+
+/// Environment variable that enables `--ub-notify` by default when set
+pub(crate) const NOTIFY_ENV: &str = "UPBUILD_NOTIFY";
+
+/// [`ExecHooks`] announcing the end of a run, see `--ub-notify`.  Rings
+/// the terminal bell, and with the `desktop-notify` feature also
+/// raises a desktop notification.  Failing to notify never affects
+/// the result of the run.
+#[derive(Default)]
+pub struct Notify {
+    started: Mutex<Option<(Instant, PathBuf)>>,
+}
+
+impl Notify {
+    /// Create a notifier for a run
+    pub fn new() -> Notify {
+        Notify::default()
+    }
+}
+
+// Summary line for the notification
+fn summary(dir: &Path, duration: Duration, result: &Result<()>) -> String {
+    match result {
+        Ok(_) => format!("{} succeeded after {:.1}s", dir.display(), duration.as_secs_f64()),
+        Err(e) => format!("{} failed after {:.1}s: {}", dir.display(), duration.as_secs_f64(), e),
+    }
+}
+
+#[cfg(feature = "desktop-notify")]
+fn desktop_notify(body: &str, ok: bool) {
+    use std::process::{Command, Stdio};
+
+    let title = if ok { "upbuild succeeded" } else { "upbuild failed" };
+    let mut cmd = if cfg!(target_os = "macos") {
+        let mut cmd = Command::new("osascript");
+        cmd.arg("-e").arg(format!("display notification {:?} with title {:?}", body, title));
+        cmd
+    } else if cfg!(windows) {
+        return; // no stock command-line notifier
+    } else {
+        let mut cmd = Command::new("notify-send");
+        cmd.arg(title).arg(body);
+        cmd
+    };
+    let _ = cmd.stdin(Stdio::null()).stdout(Stdio::null()).stderr(Stdio::null()).status();
+}
+
+#[cfg(not(feature = "desktop-notify"))]
+fn desktop_notify(_body: &str, _ok: bool) {
+}
+
+impl ExecHooks for Notify {
+    fn run_start(&self, path: &Path) {
+        let dir = path.parent().unwrap_or(path);
+        let dir = dir.canonicalize().unwrap_or_else(|_| dir.to_path_buf());
+        *self.started.lock().unwrap() = Some((Instant::now(), dir));
+    }
+
+    fn run_end(&self, result: &Result<()>) {
+        if let Some((started, dir)) = self.started.lock().unwrap().take() {
+            let mut stderr = std::io::stderr();
+            let _ = stderr.write_all(b"\x07").and_then(|_| stderr.flush());
+            desktop_notify(&summary(&dir, started.elapsed(), result), result.is_ok());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use super::super::Error;
+
+    #[test]
+    fn test_summary() {
+        assert_eq!(summary(Path::new("/src"), Duration::from_millis(61500), &Ok(())),
+                   "/src succeeded after 61.5s");
+        assert_eq!(summary(Path::new("/src"), Duration::from_millis(250), &Err(Error::ExitWithExitCode(2))),
+                   "/src failed after 0.2s: Process exitted with code: 2");
+    }
+}
@@ -0,0 +1,509 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// (C) Copyright 2024 Greg Whiteley
+
+use std::io::{BufRead, ErrorKind, Read, Write};
+use std::cmp::Reverse;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use super::{Error, Result};
+use super::exec::OutputSettings;
+use super::file::tag_matches;
+
+const BUFFER_SIZE: usize = 64 * 1024;
+
+/// Describe a size in bytes for humans
+pub(crate) fn human_size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KB", "MB", "GB", "TB"];
+    if bytes < 1024 {
+        return format!("{} bytes", bytes);
+    }
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit + 1 < UNITS.len() {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", size, UNITS[unit])
+}
+
+/// Parse a size in bytes, with an optional K, M or G (binary) suffix
+pub(crate) fn parse_size(s: &str) -> Option<u64> {
+    let (digits, scale) = match s.char_indices().last()? {
+        (i, 'k' | 'K') => (&s[..i], 1 << 10),
+        (i, 'm' | 'M') => (&s[..i], 1 << 20),
+        (i, 'g' | 'G') => (&s[..i], 1 << 30),
+        _ => (s, 1),
+    };
+    digits.parse::<u64>().ok()?.checked_mul(scale)
+}
+
+// Copy r to w, stopping at a line end before `limit` bytes have been
+// written.  Returns true if the output was truncated.
+fn copy_limited<R: Read, W: Write>(mut r: R, w: &mut W, limit: u64) -> std::io::Result<bool> {
+    let mut buf = vec![0; BUFFER_SIZE];
+    let mut written: u64 = 0;
+    let mut last = b'\n';
+    loop {
+        let n = match r.read(&mut buf) {
+            Ok(0) => return Ok(false),
+            Ok(n) => n,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        let chunk = &buf[..n];
+        let room = limit - written;
+        if n as u64 <= room {
+            w.write_all(chunk)?;
+            written += n as u64;
+            last = chunk[n - 1];
+            continue;
+        }
+
+        // cut at the last line that fits, or mid-line if there isn't one
+        let fits = &chunk[..room as usize];
+        let cut = fits.iter().rposition(|b| *b == b'\n').map_or(fits.len(), |p| p + 1);
+        w.write_all(&chunk[..cut])?;
+        if cut > 0 {
+            last = chunk[cut - 1];
+        }
+        if last != b'\n' {
+            w.write_all(b"\n")?;
+        }
+        return Ok(true);
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Utf16 {
+    Le,
+    Be,
+}
+
+// Tools on Windows (uv4 in particular) may write UTF-16 logs
+fn utf16_bom(start: &[u8]) -> Option<Utf16> {
+    match start {
+        [0xff, 0xfe, ..] => Some(Utf16::Le),
+        [0xfe, 0xff, ..] => Some(Utf16::Be),
+        _ => None,
+    }
+}
+
+// Reader transcoding UTF-16 (after the BOM) to UTF-8 as it streams.
+// Invalid or truncated data is replaced with U+FFFD.
+struct Utf16Reader<R> {
+    inner: R,
+    order: Utf16,
+    raw: Vec<u8>,
+    out: Vec<u8>,
+    pos: usize,
+    eof: bool,
+}
+
+impl<R: Read> Utf16Reader<R> {
+    fn new(inner: R, order: Utf16) -> Utf16Reader<R> {
+        Utf16Reader { inner, order, raw: Vec::new(), out: Vec::new(), pos: 0, eof: false }
+    }
+
+    // Decode the next chunk of input into out
+    fn fill(&mut self) -> std::io::Result<()> {
+        let start = self.raw.len();
+        self.raw.resize(start + BUFFER_SIZE, 0);
+        let n = loop {
+            match self.inner.read(&mut self.raw[start..]) {
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                r => break r,
+            }
+        };
+        self.raw.truncate(start + *n.as_ref().unwrap_or(&0));
+        self.eof = n? == 0;
+
+        let whole = self.raw.len() / 2 * 2;
+        let mut units: Vec<u16> = self.raw[..whole].chunks_exact(2)
+            .map(|b| match self.order {
+                Utf16::Le => u16::from_le_bytes([b[0], b[1]]),
+                Utf16::Be => u16::from_be_bytes([b[0], b[1]]),
+            })
+            .collect();
+        let mut keep = self.raw.len() - whole;
+        // a surrogate pair may be split between reads
+        if !self.eof && units.last().map_or(false, |u| (0xd800..0xdc00).contains(u)) {
+            units.pop();
+            keep += 2;
+        }
+
+        let mut utf8 = [0; 4];
+        for c in char::decode_utf16(units) {
+            let c = c.unwrap_or(char::REPLACEMENT_CHARACTER);
+            self.out.extend_from_slice(c.encode_utf8(&mut utf8).as_bytes());
+        }
+        if self.eof && keep != 0 {
+            self.out.extend_from_slice(char::REPLACEMENT_CHARACTER.encode_utf8(&mut utf8).as_bytes());
+            keep = 0;
+        }
+        self.raw.drain(..self.raw.len() - keep);
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for Utf16Reader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        while self.pos == self.out.len() {
+            if self.eof {
+                return Ok(0);
+            }
+            self.out.clear();
+            self.pos = 0;
+            self.fill()?;
+        }
+        let n = buf.len().min(self.out.len() - self.pos);
+        buf[..n].copy_from_slice(&self.out[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Ansi {
+    Text,
+    Esc,
+    // ESC [ ... final byte
+    Csi,
+    // ESC followed by intermediate bytes ... final byte
+    EscIntermediate,
+    // OSC, DCS etc - ESC ] ... BEL or ESC \
+    Str,
+    StrEsc,
+}
+
+const ESC: u8 = 0x1b;
+const BEL: u8 = 0x07;
+
+impl Ansi {
+    // Next state, and whether the byte is part of the text
+    fn step(self, b: u8) -> (Ansi, bool) {
+        match (self, b) {
+            (Ansi::Text, ESC) => (Ansi::Esc, false),
+            (Ansi::Text, _) => (Ansi::Text, true),
+            (Ansi::Esc, b'[') => (Ansi::Csi, false),
+            (Ansi::Esc, b']' | b'P' | b'X' | b'^' | b'_') => (Ansi::Str, false),
+            (Ansi::Esc, 0x20..=0x2f) => (Ansi::EscIntermediate, false),
+            (Ansi::Esc, _) => (Ansi::Text, false),
+            (Ansi::Csi, 0x40..=0x7e) => (Ansi::Text, false),
+            (Ansi::EscIntermediate, 0x30..=0x7e) => (Ansi::Text, false),
+            (Ansi::Str, BEL) => (Ansi::Text, false),
+            (Ansi::Str | Ansi::StrEsc, ESC) => (Ansi::StrEsc, false),
+            (Ansi::StrEsc, b'\\') => (Ansi::Text, false),
+            (Ansi::StrEsc, _) => (Ansi::Str, false),
+            (state, _) => (state, false),
+        }
+    }
+}
+
+// Reader dropping ANSI escape sequences (colours, titles etc), which
+// may be split between reads
+struct AnsiFilter<R> {
+    inner: R,
+    state: Ansi,
+}
+
+impl<R: Read> AnsiFilter<R> {
+    fn new(inner: R) -> AnsiFilter<R> {
+        AnsiFilter { inner, state: Ansi::Text }
+    }
+}
+
+impl<R: Read> Read for AnsiFilter<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        loop {
+            let n = self.inner.read(buf)?;
+            let mut kept = 0;
+            for i in 0..n {
+                let (state, keep) = self.state.step(buf[i]);
+                self.state = state;
+                if keep {
+                    buf[kept] = buf[i];
+                    kept += 1;
+                }
+            }
+            // only report end of file at the end of the file
+            if kept != 0 || n == 0 {
+                return Ok(kept);
+            }
+        }
+    }
+}
+
+fn copy<R: Read, W: Write>(mut r: R, w: &mut W, limit: Option<u64>) -> std::io::Result<bool> {
+    match limit {
+        Some(limit) => copy_limited(r, w, limit),
+        None => std::io::copy(&mut r, w).map(|_| false),
+    }
+}
+
+// Copy the output to w applying the settings
+fn copy_output<R: Read, W: Write>(r: R, w: &mut W, settings: &OutputSettings) -> std::io::Result<bool> {
+    if settings.strip_ansi {
+        copy(AnsiFilter::new(r), w, settings.limit)
+    } else {
+        copy(r, w, settings.limit)
+    }
+}
+
+/// Returns true if stdout is a terminal
+pub(crate) fn stdout_is_terminal() -> bool {
+    #[cfg(target_family = "unix")]
+    {
+        extern "C" {
+            fn isatty(fd: i32) -> i32;
+        }
+        // SAFETY: isatty only inspects the descriptor
+        unsafe { isatty(1) == 1 }
+    }
+
+    #[cfg(windows)]
+    {
+        extern "system" {
+            fn GetStdHandle(which: u32) -> *mut std::ffi::c_void;
+            fn GetConsoleMode(handle: *mut std::ffi::c_void, mode: *mut u32) -> i32;
+        }
+        const STD_OUTPUT_HANDLE: u32 = -11i32 as u32;
+        let mut mode = 0;
+        // SAFETY: GetConsoleMode fails cleanly for non-console handles
+        unsafe { GetConsoleMode(GetStdHandle(STD_OUTPUT_HANDLE), &mut mode) != 0 }
+    }
+
+    #[cfg(not(any(target_family = "unix", windows)))]
+    {
+        false
+    }
+}
+
+/// Display the contents of an `@outfile` on stdout
+pub(crate) fn display_output(file: &Path, settings: &OutputSettings) -> Result<()> {
+    let err = |e| Error::UnableToReadOutfile(file.display().to_string(), String::new(), e);
+    let f = std::fs::File::open(file).map_err(err)?;
+    let mut r = std::io::BufReader::with_capacity(BUFFER_SIZE, &f);
+    let mut stdout = std::io::stdout().lock();
+    let truncated = match utf16_bom(r.fill_buf().map_err(err)?) {
+        Some(order) => {
+            r.consume(2);
+            copy_output(Utf16Reader::new(r, order), &mut stdout, settings)
+        },
+        None => copy_output(r, &mut stdout, settings),
+    };
+    if truncated.map_err(err)? {
+        let size = f.metadata().map(|m| human_size(m.len())).unwrap_or_default();
+        // the trailer is best effort - the output itself was fine
+        let _ = writeln!(stdout, "... truncated, full file at {} ({})", file.display(), size);
+    }
+    Ok(())
+}
+
+/// Whether an `@outfile` is a pattern to match once the command has
+/// run, rather than a file name
+pub(crate) fn is_pattern(file: &Path) -> bool {
+    file.to_string_lossy().contains(['*', '?'])
+}
+
+/// The files matching an `@outfile` pattern, relative to the given
+/// directory, most recently modified first.  `*` and `?` match within
+/// a single path component, as for `--ub-select=`.
+pub(crate) fn find_matches(pattern: &Path, cd: Option<&Path>) -> Vec<PathBuf> {
+    let mut found = vec![cd.map_or_else(PathBuf::new, Path::to_path_buf)];
+    for part in pattern.components() {
+        let part = part.as_os_str();
+        match part.to_str().filter(|p| p.contains(['*', '?'])) {
+            Some(p) => found = found.iter().flat_map(|d| matching_entries(d, p)).collect(),
+            None => found.iter_mut().for_each(|f| f.push(part)),
+        }
+    }
+    newest_first(found.into_iter()
+                 .filter(|f| f.is_file())
+                 .map(|f| {
+                     let modified = f.metadata().and_then(|m| m.modified()).ok();
+                     (f, modified)
+                 })
+                 .collect())
+}
+
+// Entries of the directory whose names match the pattern - none if it
+// can't be read
+fn matching_entries(dir: &Path, pattern: &str) -> Vec<PathBuf> {
+    let read = if dir.as_os_str().is_empty() { Path::new(".") } else { dir };
+    let entries = match std::fs::read_dir(read) {
+        Ok(entries) => entries,
+        Err(_) => return Vec::new(),
+    };
+    entries.filter_map(|e| e.ok())
+        .filter(|e| e.file_name().to_str().map_or(false, |name| tag_matches(pattern, name)))
+        .map(|e| dir.join(e.file_name()))
+        .collect()
+}
+
+// Order matches by modification time, newest first, then by name
+fn newest_first(mut files: Vec<(PathBuf, Option<SystemTime>)>) -> Vec<PathBuf> {
+    files.sort_by(|(a, t), (b, u)| Reverse(t).cmp(&Reverse(u)).then_with(|| a.cmp(b)));
+    files.into_iter().map(|(f, _)| f).collect()
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::testing::TempDir;
+
+    fn limited(input: &str, limit: u64) -> (String, bool) {
+        let mut out = Vec::new();
+        let truncated = copy_output(input.as_bytes(), &mut out, &OutputSettings { limit: Some(limit), ..OutputSettings::default() }).unwrap();
+        (String::from_utf8(out).unwrap(), truncated)
+    }
+
+    #[test]
+    fn test_copy_limited() {
+        assert_eq!(limited("one\ntwo\nthree\n", 100), ("one\ntwo\nthree\n".into(), false));
+        assert_eq!(limited("one\ntwo\nthree\n", 14), ("one\ntwo\nthree\n".into(), false));
+        assert_eq!(limited("one\ntwo\nthree\n", 13), ("one\ntwo\n".into(), true));
+        assert_eq!(limited("one\ntwo\nthree\n", 8), ("one\ntwo\n".into(), true));
+        assert_eq!(limited("one\ntwo\nthree\n", 7), ("one\n".into(), true));
+        // no line end to cut at
+        assert_eq!(limited("onetwothree\n", 6), ("onetwo\n".into(), true));
+        assert_eq!(limited("one\n", 0), ("".into(), true));
+        assert_eq!(limited("", 0), ("".into(), false));
+
+        // across buffers
+        let line = format!("{}\n", "x".repeat(1000));
+        let input = line.repeat(200);
+        let (out, truncated) = limited(&input, 150_500);
+        assert!(truncated);
+        assert_eq!(out, line.repeat(150));
+
+        let mut out = Vec::new();
+        assert!(!copy_output(input.as_bytes(), &mut out, &OutputSettings::default()).unwrap());
+        assert_eq!(out, input.as_bytes());
+    }
+
+    // Reader handing out a byte at a time to split everything possible
+    struct Trickle<'a>(&'a [u8]);
+
+    impl Read for Trickle<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            match self.0.split_first() {
+                Some((b, rest)) if !buf.is_empty() => {
+                    buf[0] = *b;
+                    self.0 = rest;
+                    Ok(1)
+                },
+                _ => Ok(0),
+            }
+        }
+    }
+
+    fn transcode<R: Read>(r: R) -> Vec<u8> {
+        let mut out = Vec::new();
+        std::io::copy(&mut { r }, &mut out).unwrap();
+        out
+    }
+
+    #[test]
+    fn test_utf16() {
+        let expected = "*** Using Compiler 'V5.06', folder: 'C:\\Keil\\ARM\\ARMCC\\Bin'\r\n\
+                        Build target 'Target 1'\r\n\
+                        compiling main.c...\r\n\
+                        main.c(12): warning:  #550-D: variable \"\u{b5}\" was set but never used \u{1f41b}\r\n\
+                        \"project.axf\" - 0 Error(s), 1 Warning(s).\r\n";
+
+        for (data, order) in [(&include_bytes!("../tests/utf16le.txt")[..], Utf16::Le),
+                              (&include_bytes!("../tests/utf16be.txt")[..], Utf16::Be)] {
+            assert_eq!(utf16_bom(data), Some(order));
+            let out = transcode(Utf16Reader::new(&data[2..], order));
+            assert_eq!(std::str::from_utf8(&out).expect("valid UTF-8"), expected);
+            let out = transcode(Utf16Reader::new(Trickle(&data[2..]), order));
+            assert_eq!(std::str::from_utf8(&out).expect("valid UTF-8"), expected);
+        }
+
+        assert_eq!(utf16_bom(b"plain"), None);
+        assert_eq!(utf16_bom(b"\xff"), None);
+
+        // lone surrogates and odd lengths don't fail
+        assert_eq!(transcode(Utf16Reader::new(&b"a\0\x00\xd8b\0c"[..], Utf16::Le)),
+                   "a\u{fffd}b\u{fffd}".as_bytes());
+        assert_eq!(transcode(Utf16Reader::new(&b"\x00\xd8"[..], Utf16::Le)), "\u{fffd}".as_bytes());
+    }
+
+    fn strip(r: impl Read) -> String {
+        let mut out = Vec::new();
+        copy_output(r, &mut out, &OutputSettings { strip_ansi: true, ..OutputSettings::default() }).unwrap();
+        String::from_utf8(out).expect("valid UTF-8")
+    }
+
+    #[test]
+    fn test_strip_ansi() {
+        let expected = "In file included from main.c:1:\n\
+                        main.c:12:5: warning: unused variable 'x' [-Wunused-variable]\n\
+                        \x20  12 |     int x;\n\
+                        \x20     |         ^\n\
+                        main.c:20:1: error: expected ';' before '}' token\n\
+                        1 warning and 1 error generated.\n";
+        let data = include_bytes!("../tests/ansi.log");
+        assert_eq!(strip(&data[..]), expected);
+        assert_eq!(strip(Trickle(&data[..])), expected);
+
+        assert_eq!(strip(&b"\x1b]0;title\x07a\x1b]8;;http://x\x1b\\b\x1b(Bc\x1bMd"[..]), "abcd");
+        assert_eq!(strip(&b"cut \x1b[1"[..]), "cut ");
+
+        // untouched unless asked
+        let mut out = Vec::new();
+        copy_output(&data[..], &mut out, &OutputSettings::default()).unwrap();
+        assert_eq!(out, data);
+    }
+
+    #[test]
+    fn test_find_matches() {
+        let dir = TempDir::new("outfile");
+        std::fs::create_dir_all(dir.join("logs/old")).unwrap();
+        for f in ["logs/a_1.log", "logs/b_2.log", "logs/notes.txt", "top.log"] {
+            std::fs::write(dir.join(f), "").unwrap();
+        }
+
+        assert!(is_pattern(Path::new("logs/*.log")));
+        assert!(is_pattern(Path::new("build?/log.txt")));
+        assert!(!is_pattern(Path::new("logs/build.log")));
+
+        let mut found = find_matches(Path::new("logs/*.log"), Some(&dir));
+        found.sort();
+        assert_eq!(found, [dir.join("logs/a_1.log"), dir.join("logs/b_2.log")]);
+        assert_eq!(find_matches(Path::new("*/?_2.*"), Some(&dir)), [dir.join("logs/b_2.log")]);
+        // only files
+        assert_eq!(find_matches(Path::new("logs/*"), Some(&dir)).len(), 3);
+        assert!(find_matches(Path::new("*.txt"), Some(&dir)).is_empty());
+        assert!(find_matches(Path::new("missing/*.log"), Some(&dir)).is_empty());
+        // absolute patterns ignore the directory
+        let pattern = dir.join("*.log");
+        assert_eq!(find_matches(&pattern, Some(Path::new("elsewhere"))), [dir.join("top.log")]);
+
+        let at = |secs| Some(SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(secs));
+        assert_eq!(newest_first(vec![("a".into(), at(1)), ("b".into(), at(3)), ("c".into(), None), ("d".into(), at(3))]),
+                   [PathBuf::from("b"), "d".into(), "a".into(), "c".into()]);
+    }
+
+    #[test]
+    fn test_sizes() {
+        assert_eq!(human_size(0), "0 bytes");
+        assert_eq!(human_size(1023), "1023 bytes");
+        assert_eq!(human_size(1536), "1.5 KB");
+        assert_eq!(human_size(123 << 20), "123.0 MB");
+        assert_eq!(human_size(3 << 30), "3.0 GB");
+
+        assert_eq!(parse_size("100"), Some(100));
+        assert_eq!(parse_size("4k"), Some(4096));
+        assert_eq!(parse_size("4M"), Some(4 << 20));
+        assert_eq!(parse_size("1G"), Some(1 << 30));
+        assert_eq!(parse_size("0"), Some(0));
+        assert_eq!(parse_size(""), None);
+        assert_eq!(parse_size("M"), None);
+        assert_eq!(parse_size("-1"), None);
+        assert_eq!(parse_size("4MB"), None);
+        assert_eq!(parse_size("99999999999999999999G"), None);
+    }
+}
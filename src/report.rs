@@ -0,0 +1,87 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// (C) Copyright 2024 Greg Whiteley
+
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use super::Result;
+use super::exec::{ExecHooks, RetCode};
+
+/// Summary of a single command run by [`Exec::run`](crate::Exec::run)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CommandReport {
+    /// The arguments the command was run with
+    pub args: Vec<String>,
+    /// The directory the command was run from, if not the current directory
+    pub dir: Option<PathBuf>,
+    /// The exit code after `@retmap` was applied, or None if the
+    /// command couldn't be run
+    pub code: Option<RetCode>,
+    /// How long the command took
+    pub duration: Duration,
+}
+
+/// Summary of the commands run by [`Exec::run`](crate::Exec::run)
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RunReport {
+    /// The `.upbuild` file that was run
+    pub file: PathBuf,
+    /// The commands that were run, in order
+    pub commands: Vec<CommandReport>,
+}
+
+impl RunReport {
+    /// Total time spent running commands
+    pub fn duration(&self) -> Duration {
+        self.commands.iter().map(|c| c.duration).sum()
+    }
+}
+
+// ExecHooks building up a RunReport as commands are run
+#[derive(Default)]
+pub(crate) struct Recorder {
+    report: Mutex<RunReport>,
+    started: Mutex<Option<Instant>>,
+}
+
+impl Recorder {
+    pub(crate) fn report(&self) -> RunReport {
+        self.report.lock().unwrap().clone()
+    }
+}
+
+impl ExecHooks for Recorder {
+    fn run_start(&self, path: &Path) {
+        let mut report = self.report.lock().unwrap();
+        *report = RunReport { file: path.to_path_buf(), commands: Vec::new() };
+    }
+
+    fn before_command(&self, args: &[String], dir: Option<&Path>) -> bool {
+        self.report.lock().unwrap().commands.push(CommandReport {
+            args: args.to_vec(),
+            dir: dir.map(Path::to_path_buf),
+            code: None,
+            duration: Duration::ZERO,
+        });
+        *self.started.lock().unwrap() = Some(Instant::now());
+        true
+    }
+
+    fn after_command(&self, code: RetCode, duration: Duration) {
+        if let Some(cmd) = self.report.lock().unwrap().commands.last_mut() {
+            cmd.code = Some(code);
+            cmd.duration = duration;
+        }
+        *self.started.lock().unwrap() = None;
+    }
+
+    fn run_end(&self, _result: &Result<()>) {
+        // account for the time spent on a command that failed to run
+        if let Some(started) = self.started.lock().unwrap().take() {
+            if let Some(cmd) = self.report.lock().unwrap().commands.last_mut() {
+                cmd.duration = started.elapsed();
+            }
+        }
+    }
+}
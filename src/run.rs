@@ -0,0 +1,240 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// (C) Copyright 2024 Greg Whiteley
+
+use std::collections::BTreeSet;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use super::{ClassicFile, Config, Error, Exec, ExecHooks, History, Lock, Notify, Result, Runner, Timing};
+use super::completion::list_tags;
+use super::file::tag_matches;
+use super::exec::{print_null_runner, print_runner, process_runner};
+use super::find::{discover_with, find_all, find_with, DISCOVER_DEPTH};
+use super::report::{Recorder, RunReport};
+
+/// How [`run`] runs the commands
+pub enum RunnerChoice {
+    /// Run them, as `upbuild` does
+    Process,
+    /// Print them instead, as `--ub-print`
+    Print,
+    /// Hand them to the given runner, eg to capture them rather than
+    /// run them
+    Custom(Box<dyn Runner>),
+}
+
+/// What [`run`] should run, and how
+pub struct RunOptions {
+    /// Where to start the search for the `.upbuild` file - to run a
+    /// particular file give it with [`ConfigBuilder::file`](crate::ConfigBuilder::file)
+    pub start: PathBuf,
+    /// The flags, as parsed by [`Config::parse`] - which takes the
+    /// `UPBUILD_*` environment variables into account - or built by
+    /// [`Config::builder`], which doesn't
+    pub config: Config,
+    /// The arguments given for the commands
+    pub args: Vec<String>,
+    /// How the commands are run.  `--ub-print` in the config always
+    /// prints them.
+    pub runner: RunnerChoice,
+}
+
+impl Default for RunOptions {
+    fn default() -> Self {
+        Self {
+            start: PathBuf::from("."),
+            config: Config::default(),
+            args: Vec::new(),
+            runner: RunnerChoice::Process,
+        }
+    }
+}
+
+/// Find the `.upbuild` file and run it, as `upbuild` does once it
+/// has parsed its arguments - including `--ub-chain`,
+/// `--ub-discover-run`, `--ub-stream`, `--ub-lock`, and the history,
+/// timing and notification hooks.  Returns what was run, or for
+/// several files what the last of them ran.
+///
+/// ```no_run
+/// # use upbuild_rs::{Config, RunOptions};
+/// let report = upbuild_rs::run(RunOptions {
+///     config: Config::builder().select(["host"]).build(),
+///     args: vec![String::from("test")],
+///     ..RunOptions::default()
+/// })?;
+/// println!("ran {} commands in {:?}", report.commands.len(), report.duration());
+/// # Ok::<(), upbuild_rs::Error>(())
+/// ```
+pub fn run(options: RunOptions) -> Result<RunReport> {
+    let RunOptions { start, config: cfg, args, runner } = options;
+    let recorder = Arc::new(Recorder::default());
+    let exec = executor(&cfg, runner, recorder.clone())?;
+
+    if cfg.discover_run() {
+        exec.run_each(&discover_with(&start, DISCOVER_DEPTH, &cfg), &cfg, &args)?;
+        return Ok(recorder.report());
+    }
+
+    let upbuild_file = find_with(&start, &cfg)?;
+    let file = if cfg.stream() && !cfg.chain() { None } else { Some(ClassicFile::load(&upbuild_file)?) };
+    let chain = if cfg.chain() { find_all(&start, &cfg)? } else { vec![upbuild_file.clone()] };
+
+    // --ub-stream leaves reading the file to the run
+    if file.is_some() {
+        check_tags(&cfg, &chain)?;
+    }
+
+    // held until the run is over
+    let _lock = if cfg.lock() && !cfg.print() {
+        Some(Lock::acquire_as(&upbuild_file, cfg.lock_timeout(), &cfg.prefix())?)
+    } else {
+        None
+    };
+
+    if cfg.chain() {
+        exec.run_chain(&chain, &cfg, &args)?;
+    } else if let Some(file) = file {
+        exec.run(&upbuild_file, &file, &cfg, &args)?;
+    } else {
+        let reader = std::fs::File::open(&upbuild_file).map(std::io::BufReader::new)?;
+        exec.run_stream(&upbuild_file, ClassicFile::stream_reader(reader), &cfg, &args)?;
+    }
+    Ok(recorder.report())
+}
+
+// Warn about, or for --ub-strict-tags refuse, tags given to
+// --ub-select/--ub-reject/--ub-tags that aren't in the files, or those
+// their recursive calls run - most likely a typo.  Recursive calls are
+// given the whole selection, so only the first upbuild checks.
+fn check_tags(cfg: &Config, paths: &[PathBuf]) -> Result<()> {
+    let expr = cfg.tags()?;
+    let patterns: Vec<&str> = cfg.select().iter().chain(cfg.reject())
+        .map(String::as_str)
+        .chain(expr.iter().flat_map(|e| e.names()))
+        .collect();
+    if cfg.level > 0 || patterns.is_empty() {
+        return Ok(());
+    }
+    let mut known = BTreeSet::new();
+    for path in paths {
+        known.extend(list_tags(path)?);
+    }
+    let unknown: BTreeSet<_> = patterns.into_iter()
+        .filter(|p| !known.iter().any(|t| tag_matches(p, t)))
+        .map(String::from)
+        .collect();
+    if unknown.is_empty() {
+        return Ok(());
+    }
+    let e = Error::UnknownTags(unknown.into_iter().collect());
+    if cfg.strict_tags() {
+        return Err(e);
+    }
+    eprintln!("{}: warning: {}", cfg.prefix(), e);
+    Ok(())
+}
+
+// Runs commands as chosen, with the hooks the config asks for
+fn executor(cfg: &Config, runner: RunnerChoice, recorder: Arc<Recorder>) -> Result<Exec> {
+    let mut hooks: Vec<Box<dyn ExecHooks>> = vec![Box::new(recorder)];
+    let runner = match runner {
+        _ if cfg.print_null() => print_null_runner(),
+        _ if cfg.print() => print_runner(),
+        RunnerChoice::Print => print_runner(),
+        RunnerChoice::Process => super::ci::ci_runner(jobs_runner(process_runner(), cfg)?, cfg),
+        RunnerChoice::Custom(runner) => runner,
+    };
+    if !cfg.print() {
+        if cfg.record_history() {
+            hooks.push(Box::new(History::new(cfg)));
+        }
+        if cfg.time() {
+            hooks.push(Box::new(Timing::new().prefixed(cfg.prefix())));
+        }
+        if cfg.notify() {
+            hooks.push(Box::new(Notify::new()));
+        }
+    }
+    Ok(Exec::with_hooks(runner, Box::new(hooks)))
+}
+
+#[cfg(feature = "jobs")]
+use super::jobserver::jobs_runner;
+
+// Without a jobserver --ub-jobs= has nothing to share
+#[cfg(not(feature = "jobs"))]
+fn jobs_runner(inner: Box<dyn Runner>, _cfg: &Config) -> Result<Box<dyn Runner>> {
+    Ok(inner)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::testing::{MockRunner, TempDir};
+    use crate::Error;
+
+    #[test]
+    fn test_run() {
+        let dir = TempDir::new("run");
+        std::fs::create_dir_all(dir.join("a/b")).unwrap();
+        std::fs::write(dir.join(".upbuild-root"), "").unwrap();
+        std::fs::write(dir.join("a/.upbuild"), "make\n--\nall\n&&\nmake\n@tags=docs\ndocs\n&&\nfalse\n@tags=check\n@manual\n").unwrap();
+
+        let runner = MockRunner::new();
+        let options = |config: Config, args: &[&str]| RunOptions {
+            start: dir.join("a/b"),
+            config,
+            args: args.iter().map(|x| x.to_string()).collect(),
+            runner: RunnerChoice::Custom(Box::new(runner.clone())),
+        };
+        let ran = || std::iter::from_fn(|| runner.take_run()).map(|r| r.cmd.join(" ")).collect::<Vec<_>>();
+        let no_history = || Config { no_history: true, ..Config::default() };
+
+        runner.push_results([Ok(0), Ok(0)]);
+        let report = run(options(no_history(), &["test"])).unwrap();
+        assert_eq!(report.file, dir.join("a/b/../.upbuild"));
+        let args: Vec<_> = report.commands.iter().map(|c| c.args.join(" ")).collect();
+        assert_eq!(args, ["make test", "make docs test"]);
+        assert_eq!(ran(), ["make test", "make docs test"]);
+
+        // the same selection and failures as the command line
+        runner.push_result(Ok(1));
+        let cfg = Config { select: [String::from("check")].into(), ..no_history() };
+        assert_eq!(run(options(cfg, &[])).map(|_| ()), Err(Error::InCommand(3, None, String::from("false"), Box::new(Error::ExitWithExitCode(1)))));
+        assert_eq!(ran(), ["false"]);
+
+        // unknown tags are only a warning, unless strict
+        let cfg = Config { select: [String::from("hots")].into(), reject: [String::from("docs")].into(), ..no_history() };
+        assert_eq!(run(options(cfg.clone(), &[])).map(|_| ()),
+                   Err(Error::NothingSelected(vec![String::from("hots")], vec![String::from("docs")])));
+        assert!(ran().is_empty());
+        let strict = Config { strict_tags: true, ..cfg };
+        assert_eq!(run(options(strict.clone(), &[])).map(|_| ()), Err(Error::UnknownTags(vec![String::from("hots")])));
+        assert!(ran().is_empty());
+        // recursive calls get the whole selection
+        assert!(run(options(Config { level: 1, ..strict.clone() }, &[])).is_ok());
+        // patterns are known if they match a tag
+        runner.push_result(Ok(0));
+        assert!(run(options(Config { select: [String::from("d?c*")].into(), reject: Default::default(), ..strict }, &[])).is_ok());
+        assert_eq!(ran(), ["make docs"]);
+
+        // or by expression, with the same checks
+        runner.push_result(Ok(0));
+        let tags = |expr: &str| Config { no_history: true, ..Config::builder().tags(expr).build() };
+        assert!(run(options(tags("!docs & !check"), &[])).is_ok());
+        assert_eq!(ran(), ["make all"]);
+        assert_eq!(run(options(tags("docs & check"), &[])).map(|_| ()), Err(Error::NothingMatched(String::from("docs & check"))));
+        assert_eq!(run(options(Config { strict_tags: true, ..tags("docs | hots") }, &[])).map(|_| ()),
+                   Err(Error::UnknownTags(vec![String::from("hots")])));
+        assert_eq!(run(options(tags("docs |"), &[])).map(|_| ()),
+                   Err(Error::InvalidTagExpression(String::from("docs |"), 6, String::from("expected a tag"))));
+        assert!(matches!(run(options(Config { reject: [String::from("docs")].into(), ..tags("docs") }, &[])),
+                         Err(Error::ConflictingFlags(_))));
+
+        std::fs::remove_file(dir.join("a/.upbuild")).unwrap();
+        assert!(matches!(run(options(no_history(), &[])), Err(Error::NotFound(_, _, _))));
+        assert_eq!(runner.pending_results(), 0);
+    }
+}
@@ -0,0 +1,172 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// (C) Copyright 2024 Greg Whiteley
+
+use std::fmt;
+use std::io::BufRead;
+use std::path::Path;
+
+use regex::Regex;
+
+use super::{Config, Error, Result};
+
+// Built-in patterns - matched case-insensitively.  Keil's summary of
+// "N Error(s), M Warning(s)" counts once unless N or M is 0
+const ERRORS: &str = r"(?i)error:|undefined reference|\b[1-9][0-9]* error\(s\)";
+const WARNINGS: &str = r"(?i)warning:|\b[1-9][0-9]* warning\(s\)";
+
+/// A regular expression given to `--ub-scan-error=` or
+/// `--ub-scan-warning=`, compiled as it's parsed so a bad one is
+/// reported before anything runs
+#[derive(Debug, Clone)]
+pub(crate) struct Pattern {
+    option: String,
+    regex: std::result::Result<Regex, String>,
+}
+
+impl Pattern {
+    pub(crate) fn new(option: &str, pattern: &str) -> Pattern {
+        Pattern {
+            option: format!("{}={}", option, pattern),
+            regex: Regex::new(pattern).map_err(|e| e.to_string()),
+        }
+    }
+
+    fn regex(&self) -> Result<&Regex> {
+        self.regex.as_ref().map_err(|why| Error::InvalidScanPattern(self.option.clone(), why.clone()))
+    }
+}
+
+// compiled regexes aren't comparable, but what they came from is
+impl PartialEq for Pattern {
+    fn eq(&self, other: &Self) -> bool {
+        self.option == other.option
+    }
+}
+
+impl Eq for Pattern {}
+
+/// Count of the errors and warnings found by `@scan`
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct ScanCounts {
+    pub(crate) errors: usize,
+    pub(crate) warnings: usize,
+}
+
+fn plural(n: usize, what: &str) -> String {
+    format!("{} {}{}", n, what, if n == 1 { "" } else { "s" })
+}
+
+impl fmt::Display for ScanCounts {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}, {}", plural(self.errors, "error"), plural(self.warnings, "warning"))
+    }
+}
+
+/// Classifies lines of output as errors or warnings using the
+/// built-in patterns and those given by `--ub-scan-error=` and
+/// `--ub-scan-warning=`
+pub(crate) struct Scanner<'a> {
+    errors: Regex,
+    warnings: Regex,
+    user_errors: Vec<&'a Regex>,
+    user_warnings: Vec<&'a Regex>,
+}
+
+impl<'a> Scanner<'a> {
+
+    /// Fails with the first of the user's patterns that didn't compile
+    pub(crate) fn new(cfg: &'a Config) -> Result<Scanner<'a>> {
+        let user = |patterns: &'a [Pattern]| patterns.iter().map(Pattern::regex).collect::<Result<Vec<_>>>();
+        let builtin = |p| Regex::new(p).expect("built-in scan patterns are valid");
+        Ok(Scanner {
+            errors: builtin(ERRORS),
+            warnings: builtin(WARNINGS),
+            user_errors: user(&cfg.scan_errors)?,
+            user_warnings: user(&cfg.scan_warnings)?,
+        })
+    }
+
+    fn matches(line: &str, builtin: &Regex, user: &[&Regex]) -> bool {
+        builtin.is_match(line) ||
+            user.iter().any(|r| r.is_match(line))
+    }
+
+    pub(crate) fn scan<R: BufRead>(&self, mut r: R) -> std::io::Result<ScanCounts> {
+        let mut counts = ScanCounts::default();
+        let mut buf = Vec::new();
+        while r.read_until(b'\n', &mut buf)? != 0 {
+            // logs aren't always valid UTF-8
+            let line = String::from_utf8_lossy(&buf);
+            if Self::matches(&line, &self.errors, &self.user_errors) {
+                counts.errors += 1;
+            } else if Self::matches(&line, &self.warnings, &self.user_warnings) {
+                counts.warnings += 1;
+            }
+            buf.clear();
+        }
+        Ok(counts)
+    }
+
+    pub(crate) fn scan_file(&self, file: &Path) -> std::io::Result<ScanCounts> {
+        self.scan(std::io::BufReader::new(std::fs::File::open(file)?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_scan() {
+        let cfg = Config::default();
+        let s = Scanner::new(&cfg).unwrap();
+        let log = "compiling main.c\n\
+                   main.c(12): error:  #20: identifier \"x\" is undefined\n\
+                   main.c(13): Warning: #550-D: variable set but never used\n\
+                   ld: main.o: undefined reference to `foo'\n\
+                   \"project.axf\" - 2 Error(s), 1 Warning(s).\n\
+                   WARNING: last line without a newline";
+        assert_eq!(s.scan(log.as_bytes()).unwrap(), ScanCounts { errors: 3, warnings: 2 });
+
+        // the summary counts as the worst it reports
+        let summary = |e, w| format!("\"project.axf\" - {} Error(s), {} Warning(s).\n", e, w);
+        assert_eq!(s.scan(summary(0, 17).as_bytes()).unwrap(), ScanCounts { errors: 0, warnings: 1 });
+        assert_eq!(s.scan(summary(10, 0).as_bytes()).unwrap(), ScanCounts { errors: 1, warnings: 0 });
+        assert_eq!(s.scan(summary(0, 0).as_bytes()).unwrap(), ScanCounts::default());
+        assert_eq!(s.scan(&b"bad \xff utf8 error: here\n"[..]).unwrap(), ScanCounts { errors: 1, warnings: 0 });
+        assert_eq!(s.scan(&b""[..]).unwrap(), ScanCounts::default());
+    }
+
+    #[test]
+    fn test_scan_user_patterns() {
+        let cfg = Config {
+            scan_errors: vec![Pattern::new("--ub-scan-error", "FAILED"), Pattern::new("--ub-scan-error", r"^thread '.*' panicked")],
+            scan_warnings: vec![Pattern::new("--ub-scan-warning", "(?i)deprecated")],
+            ..Config::default()
+        };
+        let s = Scanner::new(&cfg).unwrap();
+        let log = "test a ... FAILED\ntest b ... failed\nuse of Deprecated API\nerror: and deprecated\n\
+                   thread 'main' panicked at src/main.rs:2:5:\nnot thread 'main' panicked\n";
+        assert_eq!(s.scan(log.as_bytes()).unwrap(), ScanCounts { errors: 3, warnings: 1 });
+    }
+
+    #[test]
+    fn test_scan_invalid_pattern() {
+        let cfg = Config {
+            scan_warnings: vec![Pattern::new("--ub-scan-warning", "ok"), Pattern::new("--ub-scan-warning", "deprecated(")],
+            ..Config::default()
+        };
+        match Scanner::new(&cfg) {
+            Err(Error::InvalidScanPattern(option, _)) => assert_eq!(option, "--ub-scan-warning=deprecated("),
+            Err(e) => panic!("unexpected {:?}", e),
+            Ok(_) => panic!("expected an error"),
+        }
+    }
+
+    #[test]
+    fn test_display() {
+        assert_eq!(ScanCounts { errors: 1, warnings: 17 }.to_string(), "1 error, 17 warnings");
+        assert_eq!(ScanCounts::default().to_string(), "0 errors, 0 warnings");
+    }
+}
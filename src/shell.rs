@@ -0,0 +1,49 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// (C) Copyright 2024 Greg Whiteley
+
+use std::borrow::Cow;
+
+fn is_safe(c: char) -> bool {
+    c.is_ascii_alphanumeric() || "_-./=:,+@%^".contains(c)
+}
+
+/// Quote a single argument for display as a POSIX shell word
+pub(crate) fn quote(s: &str) -> Cow<'_, str> {
+    if !s.is_empty() && s.chars().all(is_safe) {
+        return Cow::Borrowed(s);
+    }
+    Cow::Owned(format!("'{}'", s.replace('\'', "'\\''")))
+}
+
+/// Quote and join the arguments as a shell command-line
+pub(crate) fn join<T: AsRef<str>>(args: &[T]) -> String {
+    args.iter()
+        .map(|x| quote(x.as_ref()))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_quote() {
+        assert_eq!(quote("make"), "make");
+        assert_eq!(quote("-j8"), "-j8");
+        assert_eq!(quote("BUILD_MODE=host_debug"), "BUILD_MODE=host_debug");
+        assert_eq!(quote("/path/to/file.txt"), "/path/to/file.txt");
+        assert_eq!(quote(""), "''");
+        assert_eq!(quote("a b"), "'a b'");
+        assert_eq!(quote("$HOME"), "'$HOME'");
+        assert_eq!(quote("it's"), "'it'\\''s'");
+    }
+
+    #[test]
+    fn test_join() {
+        assert_eq!(join(&["cmake", ".."]), "cmake ..");
+        assert_eq!(join(&["sh", "-c", "exit 1"]), "sh -c 'exit 1'");
+        assert_eq!(join::<&str>(&[]), "");
+    }
+}
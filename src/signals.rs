@@ -0,0 +1,199 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// (C) Copyright 2024 Greg Whiteley
+
+use std::process::{Child, Command};
+
+/// `SIGINT` and `SIGQUIT` ignored by upbuild while a command runs in
+/// the foreground, as `system()` does, so Ctrl-C stops the command and
+/// upbuild lives to report it.  Restored when the last is dropped.
+/// Windows delivers Ctrl-C differently, so there it does nothing.
+pub(crate) struct Interrupts {
+    _private: (),
+}
+
+impl Interrupts {
+    pub(crate) fn ignore() -> Interrupts {
+        sys::ignore_interrupts();
+        Interrupts { _private: () }
+    }
+}
+
+impl Drop for Interrupts {
+    fn drop(&mut self) {
+        sys::restore_interrupts();
+    }
+}
+
+// Start the command with the signals upbuild ignores back as they were
+pub(crate) fn restore_in_child(cmd: &mut Command) {
+    sys::restore_in_child(cmd)
+}
+
+/// `SIGINT`, `SIGTERM` and `SIGHUP` passed on to a detached command's
+/// process group until dropped - it has left upbuild's, so would
+/// otherwise carry on without it once upbuild exits.  Start forwarding
+/// before spawning the command, and give it the child once spawned;
+/// a signal in between is passed on then.  Windows can't send Ctrl-C
+/// to another process group, so there it does nothing.
+pub(crate) struct Forward(sys::Forward);
+
+impl Forward {
+    pub(crate) fn start() -> Forward {
+        Forward(sys::Forward::start())
+    }
+
+    pub(crate) fn to(&self, child: &Child) {
+        self.0.to(child.id())
+    }
+}
+
+#[cfg(target_family = "unix")]
+mod sys {
+    use std::process::Command;
+    use std::sync::atomic::{AtomicI32, AtomicUsize, Ordering};
+    use std::sync::Mutex;
+
+    // the same on Linux, macOS and the BSDs
+    const SIGHUP: i32 = 1;
+    const SIGINT: i32 = 2;
+    const SIGQUIT: i32 = 3;
+    const SIGTERM: i32 = 15;
+    const SIG_IGN: usize = 1;
+    // not yet replaced, so nothing to restore
+    const UNSET: usize = usize::MAX;
+
+    extern "C" {
+        fn signal(sig: i32, handler: usize) -> usize;
+        fn kill(pid: i32, sig: i32) -> i32;
+        fn raise(sig: i32) -> i32;
+    }
+
+    // commands running in the foreground, across threads
+    static FOREGROUND: Mutex<usize> = Mutex::new(0);
+    // read in the child, so can't be behind the lock
+    static SAVED_INT: AtomicUsize = AtomicUsize::new(UNSET);
+    static SAVED_QUIT: AtomicUsize = AtomicUsize::new(UNSET);
+
+    pub(super) fn ignore_interrupts() {
+        let mut running = FOREGROUND.lock().unwrap_or_else(|e| e.into_inner());
+        if *running == 0 {
+            // SAFETY: SIG_IGN installs no code to run
+            unsafe {
+                SAVED_INT.store(signal(SIGINT, SIG_IGN), Ordering::SeqCst);
+                SAVED_QUIT.store(signal(SIGQUIT, SIG_IGN), Ordering::SeqCst);
+            }
+        }
+        *running += 1;
+    }
+
+    pub(super) fn restore_interrupts() {
+        let mut running = FOREGROUND.lock().unwrap_or_else(|e| e.into_inner());
+        *running -= 1;
+        if *running == 0 {
+            // SAFETY: restores what was there before
+            unsafe {
+                signal(SIGINT, SAVED_INT.swap(UNSET, Ordering::SeqCst));
+                signal(SIGQUIT, SAVED_QUIT.swap(UNSET, Ordering::SeqCst));
+            }
+        }
+    }
+
+    pub(super) fn restore_in_child(cmd: &mut Command) {
+        use std::os::unix::process::CommandExt;
+        // SAFETY: signal() is async-signal-safe, and the atomics are
+        // only read
+        unsafe {
+            cmd.pre_exec(|| {
+                for (sig, saved) in [(SIGINT, &SAVED_INT), (SIGQUIT, &SAVED_QUIT)] {
+                    let saved = saved.load(Ordering::SeqCst);
+                    if saved != UNSET {
+                        signal(sig, saved);
+                    }
+                }
+                Ok(())
+            });
+        }
+    }
+
+    // the group signals are passed on to, 0 until it's started, and
+    // the last signal that came before then
+    static GROUP: AtomicI32 = AtomicI32::new(0);
+    static PENDING: AtomicI32 = AtomicI32::new(0);
+    // one detached command is waited on at a time
+    static FORWARDING: Mutex<()> = Mutex::new(());
+
+    extern "C" fn forward(sig: i32) {
+        let group = GROUP.load(Ordering::SeqCst);
+        if group > 0 {
+            // SAFETY: kill() is async-signal-safe
+            unsafe { kill(-group, sig); }
+        } else {
+            PENDING.store(sig, Ordering::SeqCst);
+        }
+    }
+
+    pub(super) struct Forward {
+        saved: [(i32, usize); 3],
+        _lock: std::sync::MutexGuard<'static, ()>,
+    }
+
+    impl Forward {
+        pub(super) fn start() -> Forward {
+            let lock = FORWARDING.lock().unwrap_or_else(|e| e.into_inner());
+            let handler = forward as extern "C" fn(i32) as usize;
+            // SAFETY: the handler only uses atomics and kill()
+            let saved = unsafe { [SIGINT, SIGTERM, SIGHUP].map(|sig| (sig, signal(sig, handler))) };
+            Forward { saved, _lock: lock }
+        }
+
+        pub(super) fn to(&self, pid: u32) {
+            GROUP.store(pid as i32, Ordering::SeqCst);
+            let pending = PENDING.swap(0, Ordering::SeqCst);
+            if pending != 0 {
+                // SAFETY: kill() touches no memory
+                unsafe { kill(-(pid as i32), pending); }
+            }
+        }
+    }
+
+    impl Drop for Forward {
+        fn drop(&mut self) {
+            for (sig, handler) in self.saved {
+                // SAFETY: restores what was there before
+                unsafe { signal(sig, handler); }
+            }
+            GROUP.store(0, Ordering::SeqCst);
+            // the command never started, so the signal is upbuild's
+            let pending = PENDING.swap(0, Ordering::SeqCst);
+            if pending != 0 {
+                // SAFETY: raise() touches no memory
+                unsafe { raise(pending); }
+            }
+        }
+    }
+}
+
+#[cfg(not(target_family = "unix"))]
+mod sys {
+    use std::process::Command;
+
+    pub(super) fn ignore_interrupts() {
+    }
+
+    pub(super) fn restore_interrupts() {
+    }
+
+    pub(super) fn restore_in_child(_cmd: &mut Command) {
+    }
+
+    pub(super) struct Forward;
+
+    impl Forward {
+        pub(super) fn start() -> Forward {
+            Forward
+        }
+
+        pub(super) fn to(&self, _pid: u32) {
+        }
+    }
+}
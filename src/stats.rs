@@ -0,0 +1,352 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// (C) Copyright 2024 Greg Whiteley
+
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use super::Result;
+use super::exec::{ExecHooks, RetCode};
+use super::history::state_dir;
+use super::json::{self, Value};
+use super::shell;
+
+// Averages are over (roughly) this many recent runs
+const WINDOW: f64 = 10.0;
+// Need a few samples before comparing against the average
+const MIN_SAMPLES: f64 = 3.0;
+// Report steps this much slower than average...
+const SLOWER: f64 = 1.5;
+// ...unless they're too quick to matter
+const MIN_INTERESTING: f64 = 1.0;
+
+/// Default location of the timing statistics
+pub(crate) fn stats_file() -> Option<PathBuf> {
+    state_dir().map(|d| d.join("stats.json"))
+}
+
+// FNV-1a - stable across releases, unlike DefaultHasher
+fn fnv1a(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf29ce484222325, |h, b| (h ^ u64::from(*b)).wrapping_mul(0x100000001b3))
+}
+
+fn command_key(file: &Path, args: &[String]) -> String {
+    let mut data = file.to_string_lossy().into_owned();
+    for a in args {
+        data.push('\0');
+        data.push_str(a);
+    }
+    format!("{:016x}", fnv1a(data.as_bytes()))
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Entry {
+    key: String,
+    file: String,
+    args: Vec<String>,
+    count: f64,
+    mean: f64,
+}
+
+impl Entry {
+    fn from_json(v: &Value) -> Option<Entry> {
+        Some(Entry {
+            key: v.get("key")?.as_str()?.to_string(),
+            file: v.get("file")?.as_str()?.to_string(),
+            args: v.get("args")?.as_array()?.iter()
+                .map(|a| a.as_str().map(String::from))
+                .collect::<Option<Vec<_>>>()?,
+            count: v.get("count")?.as_f64()?,
+            mean: v.get("mean")?.as_f64()?,
+        })
+    }
+
+    fn to_json(&self) -> Value {
+        Value::object([
+            ("key", Value::from(self.key.as_str())),
+            ("file", Value::from(self.file.as_str())),
+            ("args", Value::strings(&self.args)),
+            ("count", Value::from(self.count)),
+            ("mean", Value::from(self.mean)),
+        ])
+    }
+
+    // Fold in a new sample - a running mean that turns into an
+    // exponential moving average once the window is full
+    fn add(&mut self, secs: f64) {
+        self.count += 1.0;
+        self.mean += (secs - self.mean) / self.count.min(WINDOW);
+    }
+}
+
+#[derive(Debug, Default, PartialEq)]
+struct Stats {
+    entries: Vec<Entry>,
+}
+
+impl Stats {
+    // Anything unreadable is ignored and the statistics start afresh
+    fn parse(s: &str) -> Stats {
+        let entries = json::parse(s)
+            .and_then(|v| {
+                v.get("commands")?.as_array()?.iter()
+                    .map(Entry::from_json)
+                    .collect::<Option<Vec<_>>>()
+            })
+            .unwrap_or_default();
+        Stats { entries }
+    }
+
+    fn load(file: &Path) -> Stats {
+        std::fs::read_to_string(file)
+            .map(|s| Stats::parse(&s))
+            .unwrap_or_default()
+    }
+
+    fn save(&self, file: &Path) -> std::io::Result<()> {
+        if let Some(dir) = file.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let v = Value::object([
+            ("version", Value::from(1.0)),
+            ("commands", Value::Array(self.entries.iter().map(Entry::to_json).collect())),
+        ]);
+        // don't leave a half-written file if interrupted
+        let tmp = file.with_extension(format!("tmp.{}", std::process::id()));
+        std::fs::write(&tmp, format!("{}\n", v))?;
+        std::fs::rename(&tmp, file)
+    }
+
+    fn get(&self, key: &str) -> Option<&Entry> {
+        self.entries.iter().find(|e| e.key == key)
+    }
+
+    fn add(&mut self, file: &Path, args: &[String], secs: f64) {
+        let key = command_key(file, args);
+        match self.entries.iter_mut().find(|e| e.key == key) {
+            Some(e) => e.add(secs),
+            None => self.entries.push(Entry {
+                key,
+                file: file.to_string_lossy().into_owned(),
+                args: args.to_vec(),
+                count: 1.0,
+                mean: secs,
+            }),
+        }
+    }
+
+    fn for_file<'a>(&'a self, file: &'a Path) -> impl Iterator<Item=&'a Entry> {
+        let file = file.to_string_lossy().into_owned();
+        self.entries.iter().filter(move |e| e.file == file)
+    }
+}
+
+// Note for a step running much slower than its average
+fn regression(entry: Option<&Entry>, secs: f64) -> Option<String> {
+    let entry = entry?;
+    if entry.count < MIN_SAMPLES || secs < MIN_INTERESTING || secs < entry.mean * SLOWER {
+        return None;
+    }
+    Some(format!("note: {} took {:.1}s, {:.0}% longer than its average of {:.1}s",
+                 shell::join(&entry.args), secs, (secs / entry.mean - 1.0) * 100.0, entry.mean))
+}
+
+#[derive(Default)]
+struct TimingState {
+    file: PathBuf,
+    stats: Stats,
+    current: Option<(Vec<String>, Instant)>,
+    total: Duration,
+}
+
+/// [`ExecHooks`] implementing `--ub-time` - reports how long each
+/// command took, and remembers typical durations to point out steps
+/// that have become much slower.  Failing to save the statistics
+/// never affects the result of the run.
+pub struct Timing {
+    stats_file: Option<PathBuf>,
+    prefix: String,
+    state: Mutex<TimingState>,
+}
+
+impl Timing {
+    /// Time runs, keeping statistics in the default location
+    pub fn new() -> Timing {
+        Timing::with_file(stats_file())
+    }
+
+    /// Time runs, keeping statistics in the given file (if any)
+    pub fn with_file(stats_file: Option<PathBuf>) -> Timing {
+        Timing { stats_file, prefix: String::from("upbuild"), state: Mutex::new(TimingState::default()) }
+    }
+
+    // Report with the given message prefix, as for recursive calls
+    pub(crate) fn prefixed(self, prefix: String) -> Timing {
+        Timing { prefix, ..self }
+    }
+}
+
+impl Default for Timing {
+    fn default() -> Self {
+        Timing::new()
+    }
+}
+
+impl ExecHooks for Timing {
+    fn run_start(&self, path: &Path) {
+        let mut state = self.state.lock().unwrap();
+        state.file = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+        state.stats = self.stats_file.as_deref().map(Stats::load).unwrap_or_default();
+        state.total = Duration::ZERO;
+    }
+
+    fn before_command(&self, args: &[String], _dir: Option<&Path>) -> bool {
+        self.state.lock().unwrap().current = Some((args.to_vec(), Instant::now()));
+        true
+    }
+
+    fn after_command(&self, _code: RetCode, duration: Duration) {
+        let mut state = self.state.lock().unwrap();
+        if let Some((args, _)) = state.current.take() {
+            let secs = duration.as_secs_f64();
+            eprintln!("{}: {} took {:.1}s", self.prefix, shell::join(&args), secs);
+            let key = command_key(&state.file, &args);
+            if let Some(note) = regression(state.stats.get(&key), secs) {
+                eprintln!("{}: {}", self.prefix, note);
+            }
+            let file = state.file.clone();
+            state.stats.add(&file, &args, secs);
+            state.total += duration;
+        }
+    }
+
+    fn run_end(&self, _result: &Result<()>) {
+        let mut state = self.state.lock().unwrap();
+        if let Some((_, started)) = state.current.take() {
+            state.total += started.elapsed();
+        }
+        eprintln!("{}: total {:.1}s", self.prefix, state.total.as_secs_f64());
+        if let Some(ref file) = self.stats_file {
+            if let Err(e) = state.stats.save(file) {
+                eprintln!("{}: unable to save timing statistics in {}: {}", self.prefix, file.display(), e);
+            }
+        }
+    }
+}
+
+fn stats_table(stats: &Stats, upbuild_file: &Path) -> Vec<String> {
+    stats.for_file(upbuild_file)
+        .map(|e| format!("{:>8.1}s {:>5} {}", e.mean, e.count, shell::join(&e.args)))
+        .collect()
+}
+
+/// Implement `--ub-stats`, showing the average duration of each
+/// command of the given `.upbuild` file
+pub fn show_stats(upbuild_file: &Path) -> Result<()> {
+    let upbuild_file = upbuild_file.canonicalize()?;
+    if let Some(file) = stats_file() {
+        let table = stats_table(&Stats::load(&file), &upbuild_file);
+        if !table.is_empty() {
+            println!("{:>9} {:>5} command", "average", "runs");
+        }
+        for line in table {
+            println!("{}", line);
+        }
+    }
+    Ok(())
+}
+
+/// Implement `--ub-stats-reset`, forgetting the timing statistics for
+/// the given `.upbuild` file
+pub fn reset_stats(upbuild_file: &Path) -> Result<()> {
+    let upbuild_file = upbuild_file.canonicalize()?;
+    let upbuild_file = upbuild_file.to_string_lossy();
+    if let Some(file) = stats_file() {
+        let mut stats = Stats::load(&file);
+        let before = stats.entries.len();
+        stats.entries.retain(|e| e.file != upbuild_file);
+        if stats.entries.len() != before {
+            stats.save(&file)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::testing::TempDir;
+
+    fn args<const N: usize>(a: [&str; N]) -> Vec<String> {
+        a.map(String::from).to_vec()
+    }
+
+    #[test]
+    fn test_command_key() {
+        let f = Path::new("/src/.upbuild");
+        assert_eq!(command_key(f, &args(["make"])), command_key(f, &args(["make"])));
+        assert_ne!(command_key(f, &args(["make", "a"])), command_key(f, &args(["make a"])));
+        assert_ne!(command_key(f, &args(["make"])), command_key(Path::new("/other/.upbuild"), &args(["make"])));
+        assert_eq!(fnv1a(b""), 0xcbf29ce484222325);
+        assert_eq!(fnv1a(b"a"), 0xaf63dc4c8601ec8c);
+    }
+
+    #[test]
+    fn test_stats() {
+        let f = Path::new("/src/.upbuild");
+        let mut stats = Stats::default();
+        for secs in [2.0, 4.0, 3.0] {
+            stats.add(f, &args(["make"]), secs);
+        }
+        stats.add(Path::new("/other/.upbuild"), &args(["make"]), 1.0);
+
+        let e = stats.get(&command_key(f, &args(["make"]))).expect("should exist");
+        assert_eq!(e.count, 3.0);
+        assert_eq!(e.mean, 3.0);
+        assert_eq!(stats_table(&stats, f), ["     3.0s     3 make"]);
+
+        // round trips
+        let saved = Value::object([
+            ("version", Value::from(1.0)),
+            ("commands", Value::Array(stats.entries.iter().map(Entry::to_json).collect())),
+        ]).to_string();
+        assert_eq!(Stats::parse(&saved), stats);
+
+        // corrupt data restarts
+        assert_eq!(Stats::parse("{\"commands\": [{\"key\": 1}]}"), Stats::default());
+        assert_eq!(Stats::parse("garbage"), Stats::default());
+    }
+
+    #[test]
+    fn test_regression() {
+        let mut e = Entry { key: String::new(), file: String::new(), args: args(["make"]), count: 1.0, mean: 2.0 };
+        assert_eq!(regression(Some(&e), 10.0), None); // too few samples
+        e.add(2.0);
+        e.add(2.0);
+        assert_eq!(regression(Some(&e), 2.9), None);
+        assert_eq!(regression(Some(&e), 3.0).as_deref(),
+                   Some("note: make took 3.0s, 50% longer than its average of 2.0s"));
+        assert_eq!(regression(None, 3.0), None);
+
+        e.mean = 0.1;
+        assert_eq!(regression(Some(&e), 0.5), None); // too quick to care
+    }
+
+    #[test]
+    fn test_timing_hooks() {
+        let dir = TempDir::new("stats");
+        let file = dir.join("stats.json");
+        let t = Timing::with_file(Some(file.clone()));
+
+        for _ in 0..2 {
+            t.run_start(Path::new("/no/such/.upbuild"));
+            t.before_command(&args(["true"]), None);
+            t.after_command(0, Duration::from_secs(1));
+            t.run_end(&Ok(()));
+        }
+
+        let stats = Stats::load(&file);
+        assert_eq!(stats_table(&stats, Path::new("/no/such/.upbuild")), ["     1.0s     2 true"]);
+    }
+}
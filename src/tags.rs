@@ -0,0 +1,310 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// (C) Copyright 2024 Greg Whiteley
+
+use std::collections::HashSet;
+use std::fmt;
+use std::iter::Peekable;
+use std::str::CharIndices;
+
+use super::{Error, Result};
+use super::file::tag_matches;
+
+/// A boolean expression over a command's tags, as given to
+/// `--ub-tags=`.  `!` binds tighter than `&`, which binds tighter
+/// than `|`, and tags may be `*` and `?` patterns as for
+/// `--ub-select=`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TagExpr {
+    /// The command has a tag matching the pattern
+    Tag(String),
+    /// The expression doesn't hold
+    Not(Box<TagExpr>),
+    /// Both expressions hold
+    And(Box<TagExpr>, Box<TagExpr>),
+    /// Either expression holds
+    Or(Box<TagExpr>, Box<TagExpr>),
+}
+
+// Why an expression didn't parse, and the character it failed at
+pub(crate) type ParseError = (usize, &'static str);
+
+impl TagExpr {
+    /// Parse an expression such as `(host | target) & !slow`
+    pub fn parse(expr: &str) -> Result<TagExpr> {
+        Self::parse_at(expr).map_err(|(pos, why)| Error::InvalidTagExpression(expr.to_string(), pos, why.to_string()))
+    }
+
+    pub(crate) fn parse_at(expr: &str) -> std::result::Result<TagExpr, ParseError> {
+        let mut parser = Parser { chars: expr.char_indices().peekable(), expr, pos: 0 };
+        let parsed = parser.or()?;
+        match parser.next_token() {
+            None => Ok(parsed),
+            Some((pos, _)) => Err((pos, "expected & or |")),
+        }
+    }
+
+    /// Whether a command with the given tags matches the expression
+    pub fn matches(&self, tags: &HashSet<String>) -> bool {
+        match self {
+            TagExpr::Tag(p) => tags.iter().any(|t| tag_matches(p, t)),
+            TagExpr::Not(e) => !e.matches(tags),
+            TagExpr::And(a, b) => a.matches(tags) && b.matches(tags),
+            TagExpr::Or(a, b) => a.matches(tags) || b.matches(tags),
+        }
+    }
+
+    /// Whether the expression names one of the given tags other than
+    /// to exclude it - as an `@manual` command has to be asked for
+    pub fn selects(&self, tags: &HashSet<String>) -> bool {
+        self.selects_(tags, false)
+    }
+
+    fn selects_(&self, tags: &HashSet<String>, negated: bool) -> bool {
+        match self {
+            TagExpr::Tag(p) => !negated && tags.iter().any(|t| tag_matches(p, t)),
+            TagExpr::Not(e) => e.selects_(tags, !negated),
+            TagExpr::And(a, b) | TagExpr::Or(a, b) => a.selects_(tags, negated) || b.selects_(tags, negated),
+        }
+    }
+
+    /// returns the tags and patterns the expression names
+    pub fn names(&self) -> Vec<&str> {
+        match self {
+            TagExpr::Tag(p) => vec![p.as_str()],
+            TagExpr::Not(e) => e.names(),
+            TagExpr::And(a, b) | TagExpr::Or(a, b) => {
+                let mut names = a.names();
+                names.extend(b.names());
+                names
+            },
+        }
+    }
+
+    // Binds tighter than the operator - to be shown without brackets
+    fn precedence(&self) -> u8 {
+        match self {
+            TagExpr::Or(_, _) => 0,
+            TagExpr::And(_, _) => 1,
+            TagExpr::Tag(_) | TagExpr::Not(_) => 2,
+        }
+    }
+
+    fn fmt_within(&self, f: &mut fmt::Formatter<'_>, precedence: u8) -> fmt::Result {
+        if self.precedence() < precedence {
+            write!(f, "({})", self)
+        } else {
+            write!(f, "{}", self)
+        }
+    }
+}
+
+// Reproduces the expression, bracketed only where needed
+impl fmt::Display for TagExpr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TagExpr::Tag(p) => write!(f, "{}", p),
+            TagExpr::Not(e) => {
+                write!(f, "!")?;
+                e.fmt_within(f, 2)
+            },
+            TagExpr::And(a, b) => {
+                a.fmt_within(f, 1)?;
+                write!(f, " & ")?;
+                b.fmt_within(f, 2)
+            },
+            TagExpr::Or(a, b) => {
+                a.fmt_within(f, 0)?;
+                write!(f, " | ")?;
+                b.fmt_within(f, 1)
+            },
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Token<'a> {
+    And,
+    Or,
+    Not,
+    Open,
+    Close,
+    Tag(&'a str),
+}
+
+// Recursive descent over the expression, positions counted in
+// characters for the caret under it
+struct Parser<'a> {
+    chars: Peekable<CharIndices<'a>>,
+    expr: &'a str,
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn is_operator(c: char) -> bool {
+        matches!(c, '&' | '|' | '!' | '(' | ')')
+    }
+
+    fn peek_token(&mut self) -> Option<(usize, Token<'a>)> {
+        let (chars, pos) = (self.chars.clone(), self.pos);
+        let token = self.next_token();
+        self.chars = chars;
+        self.pos = pos;
+        token
+    }
+
+    fn next_token(&mut self) -> Option<(usize, Token<'a>)> {
+        while self.chars.next_if(|(_, c)| c.is_whitespace()).is_some() {
+            self.pos += 1;
+        }
+        let (start, c) = self.chars.next()?;
+        let pos = self.pos;
+        self.pos += 1;
+        let token = match c {
+            '&' => Token::And,
+            '|' => Token::Or,
+            '!' => Token::Not,
+            '(' => Token::Open,
+            ')' => Token::Close,
+            _ => {
+                let mut end = start + c.len_utf8();
+                while let Some((i, c)) = self.chars.next_if(|(_, c)| !c.is_whitespace() && !Self::is_operator(*c)) {
+                    end = i + c.len_utf8();
+                    self.pos += 1;
+                }
+                Token::Tag(&self.expr[start..end])
+            },
+        };
+        Some((pos, token))
+    }
+
+    fn or(&mut self) -> std::result::Result<TagExpr, ParseError> {
+        let mut expr = self.and()?;
+        while let Some((_, Token::Or)) = self.peek_token() {
+            self.next_token();
+            expr = TagExpr::Or(Box::new(expr), Box::new(self.and()?));
+        }
+        Ok(expr)
+    }
+
+    fn and(&mut self) -> std::result::Result<TagExpr, ParseError> {
+        let mut expr = self.unary()?;
+        while let Some((_, Token::And)) = self.peek_token() {
+            self.next_token();
+            expr = TagExpr::And(Box::new(expr), Box::new(self.unary()?));
+        }
+        Ok(expr)
+    }
+
+    fn unary(&mut self) -> std::result::Result<TagExpr, ParseError> {
+        match self.next_token() {
+            Some((_, Token::Tag(t))) => Ok(TagExpr::Tag(t.to_string())),
+            Some((_, Token::Not)) => Ok(TagExpr::Not(Box::new(self.unary()?))),
+            Some((_, Token::Open)) => {
+                let expr = self.or()?;
+                match self.next_token() {
+                    Some((_, Token::Close)) => Ok(expr),
+                    Some((pos, _)) => Err((pos, "expected )")),
+                    None => Err((self.pos, "expected )")),
+                }
+            },
+            Some((pos, _)) => Err((pos, "expected a tag, ! or (")),
+            None => Err((self.pos, "expected a tag")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn tag(t: &str) -> Box<TagExpr> {
+        Box::new(TagExpr::Tag(t.to_string()))
+    }
+
+    fn tags(t: &[&str]) -> HashSet<String> {
+        t.iter().map(|x| x.to_string()).collect()
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(TagExpr::parse_at("host"), Ok(*tag("host")));
+        assert_eq!(TagExpr::parse_at(" !slow "), Ok(TagExpr::Not(tag("slow"))));
+        assert_eq!(TagExpr::parse_at("host & !slow"), Ok(TagExpr::And(tag("host"), Box::new(TagExpr::Not(tag("slow"))))));
+        // ! then & then |
+        assert_eq!(TagExpr::parse_at("a|b&!c"),
+                   Ok(TagExpr::Or(tag("a"), Box::new(TagExpr::And(tag("b"), Box::new(TagExpr::Not(tag("c"))))))));
+        assert_eq!(TagExpr::parse_at("(a | b) & c"),
+                   Ok(TagExpr::And(Box::new(TagExpr::Or(tag("a"), tag("b"))), tag("c"))));
+        assert_eq!(TagExpr::parse_at("a & b & c"),
+                   Ok(TagExpr::And(Box::new(TagExpr::And(tag("a"), tag("b"))), tag("c"))));
+        assert_eq!(TagExpr::parse_at("!!a"), Ok(TagExpr::Not(Box::new(TagExpr::Not(tag("a"))))));
+        assert_eq!(TagExpr::parse_at("x86-64 & d?c*"), Ok(TagExpr::And(tag("x86-64"), tag("d?c*"))));
+
+        // positions are in characters
+        for (expr, pos, why) in [
+            ("", 0, "expected a tag"),
+            ("   ", 3, "expected a tag"),
+            ("host &", 6, "expected a tag"),
+            ("host & | slow", 7, "expected a tag, ! or ("),
+            ("host slow", 5, "expected & or |"),
+            ("(host | target", 14, "expected )"),
+            ("(host target)", 6, "expected )"),
+            ("host)", 4, "expected & or |"),
+            ("()", 1, "expected a tag, ! or ("),
+            ("hôst & )", 7, "expected a tag, ! or ("),
+        ] {
+            assert_eq!(TagExpr::parse_at(expr), Err((pos, why)), "{:?}", expr);
+        }
+
+        match TagExpr::parse("host &") {
+            Err(Error::InvalidTagExpression(expr, 6, why)) => {
+                assert_eq!(expr, "host &");
+                assert_eq!(why, "expected a tag");
+            },
+            e => panic!("unexpected {:?}", e),
+        }
+    }
+
+    #[test]
+    fn test_matches() {
+        let expr = TagExpr::parse("(host | target) & !slow").unwrap();
+        assert!(expr.matches(&tags(&["host"])));
+        assert!(expr.matches(&tags(&["target", "docs"])));
+        assert!(!expr.matches(&tags(&["host", "slow"])));
+        assert!(!expr.matches(&tags(&["docs"])));
+        assert!(!expr.matches(&tags(&[])));
+
+        assert!(TagExpr::parse("!slow").unwrap().matches(&tags(&[])));
+        assert!(TagExpr::parse("d?c* & !s*").unwrap().matches(&tags(&["docs"])));
+        assert!(!TagExpr::parse("d?c* & !s*").unwrap().matches(&tags(&["docs", "slow"])));
+
+        // @manual commands need to be named
+        assert!(expr.selects(&tags(&["host"])));
+        assert!(!expr.selects(&tags(&["slow"])));
+        assert!(!TagExpr::parse("!slow").unwrap().selects(&tags(&["docs"])));
+        assert!(TagExpr::parse("!!docs").unwrap().selects(&tags(&["docs"])));
+
+        assert_eq!(expr.names(), ["host", "target", "slow"]);
+    }
+
+    #[test]
+    fn test_display() {
+        for (expr, shown) in [
+            ("host", "host"),
+            ("host&!slow", "host & !slow"),
+            ("(host | target) & !slow", "(host | target) & !slow"),
+            ("a | (b & c)", "a | b & c"),
+            ("a & (b & c)", "a & (b & c)"),
+            ("(a | b) | c", "a | b | c"),
+            ("a | (b | c)", "a | (b | c)"),
+            ("!(a | b)", "!(a | b)"),
+            ("!(!a)", "!!a"),
+        ] {
+            let parsed = TagExpr::parse(expr).unwrap();
+            assert_eq!(parsed.to_string(), shown);
+            // shown as it parses
+            assert_eq!(TagExpr::parse(shown).unwrap(), parsed);
+        }
+    }
+}
@@ -0,0 +1,383 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// (C) Copyright 2024 Greg Whiteley
+
+//! A [`Runner`] for testing code that drives [`Exec`](crate::Exec),
+//! available with the `test-util` feature.
+//!
+//! [`MockRunner`] runs nothing: it returns results queued ahead of the
+//! run, and records what it was asked to run, display and create to be
+//! checked afterwards.  Clones share their state, so keep one to check
+//! and hand a clone to the [`Exec`](crate::Exec).
+//!
+//! ```
+//! # use std::path::Path;
+//! # use upbuild_rs::{ClassicFile, Config, Exec, testing::MockRunner};
+//! let file = ClassicFile::parse_lines(["make", "all", "&&", "make", "test"].into_iter())?;
+//! let runner = MockRunner::new();
+//! runner.push_result(Ok(0)).push_result(Ok(2));
+//!
+//! let exec = Exec::new(Box::new(runner.clone()));
+//! assert!(exec.run(Path::new(".upbuild"), &file, &Config::default(), &[]).is_err());
+//!
+//! runner.expect_run(&["make", "all"], None)
+//!     .expect_run(&["make", "test"], None)
+//!     .assert_done();
+//! # Ok::<(), upbuild_rs::Error>(())
+//! ```
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use super::{OutputSettings, Result, RetCode, RunSettings, Runner, Stage};
+
+/// A command [`MockRunner`] was asked to run
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct RunCall {
+    /// The command and its arguments
+    pub cmd: Vec<String>,
+    /// The directory it was to run in
+    pub cd: Option<PathBuf>,
+    /// How it was to run
+    pub settings: RunSettings,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    // queued ahead of the run
+    results: VecDeque<Result<RetCode>>,
+    mkdir_results: VecDeque<Result<()>>,
+    outfile_results: VecDeque<Result<()>>,
+    capture_output: VecDeque<Vec<u8>>,
+    missing_dirs: HashSet<PathBuf>,
+    missing_programs: HashSet<String>,
+    env: HashMap<String, String>,
+    // recorded by the run
+    runs: VecDeque<RunCall>,
+    outfiles: VecDeque<(PathBuf, OutputSettings)>,
+    displayed: VecDeque<String>,
+    traced: VecDeque<String>,
+    mkdirs: VecDeque<PathBuf>,
+    captures: VecDeque<bool>,
+    pipelines: VecDeque<usize>,
+    canonicalized: Vec<PathBuf>,
+}
+
+/// A [`Runner`] returning queued results and recording its calls,
+/// see the [module documentation](self)
+#[derive(Debug, Default, Clone)]
+pub struct MockRunner {
+    state: Arc<Mutex<State>>,
+}
+
+impl MockRunner {
+    /// A runner with nothing queued, where every directory and
+    /// program exists but no environment variable is set
+    pub fn new() -> MockRunner {
+        MockRunner::default()
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Queue the result of the next command run - running a command
+    /// with none queued panics
+    pub fn push_result(&self, result: Result<RetCode>) -> &Self {
+        self.state().results.push_back(result);
+        self
+    }
+
+    /// Queue the results of the next commands run
+    pub fn push_results<I: IntoIterator<Item=Result<RetCode>>>(&self, results: I) -> &Self {
+        self.state().results.extend(results);
+        self
+    }
+
+    /// Queue the result of the next `@mkdir` check, which otherwise
+    /// succeeds
+    pub fn push_mkdir_result(&self, result: Result<()>) -> &Self {
+        self.state().mkdir_results.push_back(result);
+        self
+    }
+
+    /// Queue the result of showing the next `@outfile`, which
+    /// otherwise succeeds
+    pub fn push_outfile_result(&self, result: Result<()>) -> &Self {
+        self.state().outfile_results.push_back(result);
+        self
+    }
+
+    /// Queue the output of the next `@capture` command, which is
+    /// otherwise empty
+    pub fn push_capture_output<T: Into<Vec<u8>>>(&self, output: T) -> &Self {
+        self.state().capture_output.push_back(output.into());
+        self
+    }
+
+    /// Have the directory a command runs in, as given by `@cd`,
+    /// be missing
+    pub fn missing_dir<P: Into<PathBuf>>(&self, dir: P) -> &Self {
+        self.state().missing_dirs.insert(dir.into());
+        self
+    }
+
+    /// Have a program a command needs, such as the container engine
+    /// for `@container`, be missing
+    pub fn missing_program(&self, name: &str) -> &Self {
+        self.state().missing_programs.insert(String::from(name));
+        self
+    }
+
+    /// Set an environment variable as seen by `@env-required`
+    pub fn set_env(&self, name: &str, value: &str) -> &Self {
+        self.state().env.insert(String::from(name), String::from(value));
+        self
+    }
+
+    /// Forget everything queued and recorded
+    pub fn reset(&self) {
+        *self.state() = State::default();
+    }
+
+    /// returns the number of queued results not yet used
+    pub fn pending_results(&self) -> usize {
+        self.state().results.len()
+    }
+
+    /// returns the commands run and not yet taken, oldest first
+    pub fn runs(&self) -> Vec<RunCall> {
+        self.state().runs.iter().cloned().collect()
+    }
+
+    /// returns the lines displayed and not yet taken, oldest first
+    pub fn displayed(&self) -> Vec<String> {
+        self.state().displayed.iter().cloned().collect()
+    }
+
+    /// returns the directories checked by `@mkdir` and not yet taken
+    pub fn mkdirs(&self) -> Vec<PathBuf> {
+        self.state().mkdirs.iter().cloned().collect()
+    }
+
+    /// returns the length of each pipeline run and not yet taken
+    pub fn pipelines(&self) -> Vec<usize> {
+        self.state().pipelines.iter().copied().collect()
+    }
+
+    /// returns every directory resolved to announce it, in order -
+    /// these aren't taken, nor checked by [`assert_done`](Self::assert_done)
+    pub fn canonicalized(&self) -> Vec<PathBuf> {
+        self.state().canonicalized.clone()
+    }
+
+    /// Take the oldest command run
+    pub fn take_run(&self) -> Option<RunCall> {
+        self.state().runs.pop_front()
+    }
+
+    /// Take the oldest `@outfile` shown, with how it was shown
+    pub fn take_outfile(&self) -> Option<(PathBuf, OutputSettings)> {
+        self.state().outfiles.pop_front()
+    }
+
+    /// Take the oldest line displayed
+    pub fn take_display(&self) -> Option<String> {
+        self.state().displayed.pop_front()
+    }
+
+    /// Take the oldest `--ub-trace` line, including its timestamp
+    pub fn take_trace(&self) -> Option<String> {
+        self.state().traced.pop_front()
+    }
+
+    /// Take the oldest directory checked by `@mkdir`
+    pub fn take_mkdir(&self) -> Option<PathBuf> {
+        self.state().mkdirs.pop_front()
+    }
+
+    /// Take the oldest captured run, and whether it was silent
+    pub fn take_capture(&self) -> Option<bool> {
+        self.state().captures.pop_front()
+    }
+
+    /// Take the length of the oldest pipeline run
+    pub fn take_pipeline(&self) -> Option<usize> {
+        self.state().pipelines.pop_front()
+    }
+
+    /// Check the oldest command run, and where
+    #[track_caller]
+    pub fn expect_run(&self, cmd: &[&str], cd: Option<&Path>) -> &Self {
+        let run = self.take_run().expect("expected a command to run");
+        assert_eq!(run.cmd, cmd);
+        assert_eq!(run.cd.as_deref(), cd);
+        self
+    }
+
+    /// Check the oldest line displayed
+    #[track_caller]
+    pub fn expect_display(&self, expected: &str) -> &Self {
+        assert_eq!(self.take_display().expect("expected a line displayed"), expected);
+        self
+    }
+
+    /// Check the oldest `--ub-trace` line, without its timestamp
+    #[track_caller]
+    pub fn expect_trace(&self, expected: &str) -> &Self {
+        let trace = self.take_trace().expect("expected a trace");
+        let (prefix, rest) = trace.split_once("] ").expect("expected a timestamp");
+        assert!(prefix.starts_with("+ ["), "unexpected trace prefix {}", prefix);
+        assert_eq!(rest, expected);
+        self
+    }
+
+    /// Check the oldest `@outfile` shown
+    #[track_caller]
+    pub fn expect_outfile<P: AsRef<Path>>(&self, expected: P) -> &Self {
+        let (outfile, _) = self.take_outfile().expect("expected an outfile");
+        assert_eq!(outfile, expected.as_ref());
+        self
+    }
+
+    /// Check the oldest directory checked by `@mkdir`
+    #[track_caller]
+    pub fn expect_mkdir<P: AsRef<Path>>(&self, expected: P) -> &Self {
+        assert_eq!(self.take_mkdir().expect("expected a mkdir"), expected.as_ref());
+        self
+    }
+
+    /// Check the oldest captured run was, or wasn't, silent
+    #[track_caller]
+    pub fn expect_captured(&self, silent: bool) -> &Self {
+        assert_eq!(self.take_capture(), Some(silent), "expected a capture");
+        self
+    }
+
+    /// Check the length of the oldest pipeline run
+    #[track_caller]
+    pub fn expect_pipeline(&self, len: usize) -> &Self {
+        assert_eq!(self.take_pipeline(), Some(len), "expected a pipeline");
+        self
+    }
+
+    /// Check every queued result was used, and everything recorded
+    /// was taken
+    #[track_caller]
+    pub fn assert_done(&self) {
+        let state = self.state();
+        assert!(state.results.is_empty(), "didn't use results {:#?}", state.results);
+        assert!(state.runs.is_empty(), "didn't take runs {:#?}", state.runs);
+        assert!(state.outfiles.is_empty(), "didn't take outfiles {:#?}", state.outfiles);
+        assert!(state.displayed.is_empty(), "didn't take displayed {:#?}", state.displayed);
+        assert!(state.traced.is_empty(), "didn't take traces {:#?}", state.traced);
+        assert!(state.mkdirs.is_empty(), "didn't take mkdirs {:#?}", state.mkdirs);
+        assert!(state.captures.is_empty(), "didn't take captures {:#?}", state.captures);
+        assert!(state.pipelines.is_empty(), "didn't take pipelines {:#?}", state.pipelines);
+    }
+}
+
+impl Runner for MockRunner {
+    fn run(&self, cmd: &[String], cd: Option<&Path>, settings: &RunSettings) -> Result<RetCode> {
+        let mut state = self.state();
+        state.runs.push_back(RunCall { cmd: cmd.to_vec(), cd: cd.map(PathBuf::from), settings: settings.clone() });
+        state.results.pop_front().unwrap_or_else(|| panic!("no result queued for {:?}", cmd))
+    }
+
+    fn run_captured(&self, cmd: &[String], cd: Option<&Path>, settings: &RunSettings, silent: bool) -> Result<(RetCode, Vec<u8>)> {
+        let output = {
+            let mut state = self.state();
+            state.captures.push_back(silent);
+            state.capture_output.pop_front().unwrap_or_default()
+        };
+        Ok((self.run(cmd, cd, settings)?, output))
+    }
+
+    fn run_pipeline(&self, cmds: &[Stage<'_>]) -> Result<Vec<RetCode>> {
+        self.state().pipelines.push_back(cmds.len());
+        cmds.iter().map(|(cmd, cd, settings)| self.run(cmd, *cd, settings)).collect()
+    }
+
+    fn display_output(&self, file: &Path, settings: &OutputSettings) -> Result<()> {
+        let mut state = self.state();
+        state.outfiles.push_back((PathBuf::from(file), settings.clone()));
+        state.outfile_results.pop_front().unwrap_or(Ok(()))
+    }
+
+    fn display(&self, s: &str) {
+        self.state().displayed.push_back(String::from(s));
+    }
+
+    fn trace(&self, s: &str) {
+        self.state().traced.push_back(String::from(s));
+    }
+
+    fn check_mkdir(&self, d: &Path) -> Result<()> {
+        let mut state = self.state();
+        state.mkdirs.push_back(PathBuf::from(d));
+        state.mkdir_results.pop_front().unwrap_or(Ok(()))
+    }
+
+    fn is_dir(&self, d: &Path) -> bool {
+        !self.state().missing_dirs.contains(d)
+    }
+
+    fn canonicalize(&self, d: &Path) -> std::io::Result<PathBuf> {
+        self.state().canonicalized.push(PathBuf::from(d));
+        d.canonicalize()
+    }
+
+    fn has_program(&self, name: &str) -> bool {
+        !self.state().missing_programs.contains(name)
+    }
+
+    fn has_env(&self, name: &str, allow_empty: bool) -> bool {
+        self.state().env.get(name).map_or(false, |v| allow_empty || !v.is_empty())
+    }
+}
+
+/// A scratch directory under the system temporary directory, removed
+/// with everything in it when dropped
+///
+/// The name is made unique to the test process, so give each test its
+/// own.
+#[derive(Debug)]
+pub struct TempDir {
+    path: PathBuf,
+}
+
+impl TempDir {
+    /// Create an empty `upbuild-<name>-<pid>`, clearing anything a
+    /// previous run left behind
+    pub fn new(name: &str) -> TempDir {
+        let path = std::env::temp_dir().join(format!("upbuild-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+        std::fs::create_dir_all(&path).unwrap();
+        TempDir { path }
+    }
+
+    /// returns the directory
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl std::ops::Deref for TempDir {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl AsRef<Path> for TempDir {
+    fn as_ref(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.path);
+    }
+}
@@ -0,0 +1,78 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// (C) Copyright 2024 Greg Whiteley
+
+use std::io::ErrorKind;
+use std::path::Path;
+use std::process::Command;
+
+// Run the command with the given mask, leaving upbuild's own as it is
+pub(crate) fn apply(cmd: &mut Command, mask: u32) {
+    sys::apply(cmd, mask)
+}
+
+/// Create the directory and any missing parents with the permissions
+/// the given `@umask` would give them.  The process's own mask is left
+/// alone, as changing it would affect every thread.  Windows has no
+/// mask, so there they are created as usual.
+pub(crate) fn create_dir_all(d: &Path, mask: u32) -> std::io::Result<()> {
+    if d.is_dir() {
+        return Ok(());
+    }
+    if let Some(parent) = d.parent().filter(|p| !p.as_os_str().is_empty()) {
+        create_dir_all(parent, mask)?;
+    }
+    match sys::create_dir(d, mask) {
+        // lost a race to create it
+        Err(e) if e.kind() == ErrorKind::AlreadyExists && d.is_dir() => Ok(()),
+        result => result,
+    }
+}
+
+#[cfg(target_family = "unix")]
+mod sys {
+    use std::path::Path;
+    use std::process::Command;
+
+    #[cfg(any(target_os = "macos", target_os = "ios", target_os = "freebsd", target_os = "dragonfly"))]
+    type Mode = u16;
+    #[cfg(not(any(target_os = "macos", target_os = "ios", target_os = "freebsd", target_os = "dragonfly")))]
+    type Mode = u32;
+
+    extern "C" {
+        fn umask(mask: Mode) -> Mode;
+    }
+
+    pub(super) fn create_dir(d: &Path, mask: u32) -> std::io::Result<()> {
+        use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+        let mode = 0o777 & !mask;
+        // never more open than asked for, even before it's set
+        std::fs::DirBuilder::new().mode(mode).create(d)?;
+        // which upbuild's own mask may have narrowed
+        std::fs::set_permissions(d, std::fs::Permissions::from_mode(mode))
+    }
+
+    pub(super) fn apply(cmd: &mut Command, mask: u32) {
+        use std::os::unix::process::CommandExt;
+        // SAFETY: umask() is async-signal-safe and touches no memory
+        unsafe {
+            cmd.pre_exec(move || {
+                umask(mask as Mode);
+                Ok(())
+            });
+        }
+    }
+}
+
+#[cfg(not(target_family = "unix"))]
+mod sys {
+    use std::path::Path;
+    use std::process::Command;
+
+    pub(super) fn create_dir(d: &Path, _mask: u32) -> std::io::Result<()> {
+        std::fs::create_dir(d)
+    }
+
+    pub(super) fn apply(_cmd: &mut Command, _mask: u32) {
+    }
+}
+
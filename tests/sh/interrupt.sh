@@ -0,0 +1,5 @@
+#!/bin/sh
+
+# as Ctrl-C does, interrupt both upbuild and the command
+kill -INT $PPID
+kill -INT $$
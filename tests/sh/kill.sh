@@ -0,0 +1,3 @@
+#!/bin/sh
+
+kill -${1:-TERM} $$
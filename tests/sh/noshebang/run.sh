@@ -0,0 +1,3 @@
+# no #! line - the kernel refuses to exec this
+
+exit ${1:-0}
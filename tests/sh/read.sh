@@ -0,0 +1,5 @@
+#!/bin/sh
+
+# fails if stdin is at EOF, otherwise checks the line read
+read line || exit 2
+[ "$line" = "${1:-hello}" ]
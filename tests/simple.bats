@@ -34,6 +34,9 @@ setup() {
   test_dir=$(mktemp -d)
   mkdir -p "${test_dir}"/1/1.1
   pushd "${test_dir}"
+
+  # keep history and stats out of the real state directory
+  export XDG_STATE_HOME="${test_dir}/state"
   cat > .upbuild <<EOF
 echo
 toplevel
@@ -106,6 +109,168 @@ echo 2 3" ]
   fi
 }
 
+@test "basic run --ub-print-null" {
+  cd 1
+
+  run bash -c "'$upbuild' --ub-print-null | tr '\\0' '|'"
+  [ "$status" -eq 0 ]
+  [ "$output" = "echo|dir|1||echo|2||" ]
+}
+
+@test "upbuild-print alias" {
+  cd 1
+  ln -s "$upbuild" upbuild-print
+
+  run ./upbuild-print
+  [ "$status" -eq 0 ]
+  [ "$output" = "echo dir 1
+echo 2" ]
+}
+
+@test "recursing through aliases" {
+  cd 1/1.1
+  mkdir bin
+  printf '#!/bin/sh\necho "not upbuild"\n' > bin/ub
+  chmod +x bin/ub
+  cp bin/ub bin/upbuild-print
+
+  # programs like any other without @recurse
+  printf 'ub\n&&\nupbuild-print\n' > .upbuild
+  PATH="$PWD/bin:$PATH" run "$upbuild"
+  [ "$status" -eq 0 ]
+  [ "$output" = "not upbuild
+not upbuild" ]
+
+  printf 'ub\n@recurse\n' > .upbuild
+  run "$upbuild"
+  [ "$status" -eq 0 ]
+  [ "${lines[1]}" = "dir 1" ]
+  [ "${lines[2]}" = "2" ]
+
+  # printed, not run
+  printf 'upbuild-print\n@recurse\n' > .upbuild
+  run "$upbuild"
+  [ "$status" -eq 0 ]
+  [ "${lines[1]}" = "echo dir 1" ]
+  [ "${lines[2]}" = "echo 2" ]
+  [ "${#lines[@]}" -eq 4 ]
+}
+
+@test "--ub-print retmap and outfile" {
+  cp "$BATS_TEST_DIRNAME/uv4.upbuild" .upbuild
+
+  # nothing is read from log.txt, which is yet to be written
+  run "$upbuild" --ub-print
+  [ "$status" -eq 0 ]
+  [ "$output" = "# retmap: 1=>0
+uv4 -j0 -b project.uvproj -o log.txt
+# outfile: log.txt" ]
+  [ ! -e log.txt ]
+
+  run bash -c "'$upbuild' --ub-print-null | tr '\\0' '|'"
+  [ "$status" -eq 0 ]
+  [ "$output" = "retmap|1=>0||uv4|-j0|-b|project.uvproj|-o|log.txt||outfile|log.txt||" ]
+}
+
+@test "--ub-print-env" {
+  cd 1/1.1
+
+  run "$upbuild" --ub-print-env
+  [ "$status" -eq 0 ]
+  [ "$output" = "1: echo 1.1
+    unchanged
+2: $upbuild
+    UPBUILD_LEVEL=1 (recursive call)" ]
+}
+
+@test "unknown tags" {
+  cd 1
+
+  run "$upbuild" --ub-reject=of
+  [ "$status" -eq 0 ]
+  [ "$output" = "upbuild: warning: No command has the tags given to --ub-select/--ub-reject/--ub-tags: of
+dir 1
+2" ]
+
+  run "$upbuild" --ub-strict-tags --ub-select=on --ub-select=no
+  [ "$status" -eq 125 ]
+  [ "$output" = "upbuild: No command has the tags given to --ub-select/--ub-reject/--ub-tags: no" ]
+}
+
+@test "tag patterns" {
+  cd 1
+
+  run "$upbuild" --ub-strict-tags --ub-select='o?'
+  [ "$status" -eq 0 ]
+  [ "$output" = "2
+3" ]
+
+  run "$upbuild" --ub-strict-tags --ub-reject='*n'
+  [ "$status" -eq 0 ]
+  [ "$output" = "dir 1" ]
+}
+
+@test "tag expression" {
+  cd 1
+
+  run "$upbuild" --ub-tags='!on | on'
+  [ "$status" -eq 0 ]
+  [ "$output" = "dir 1
+2
+3" ]
+
+  run "$upbuild" --ub-tags='!on'
+  [ "$status" -eq 0 ]
+  [ "$output" = "dir 1" ]
+
+  run "$upbuild" --ub-tags='on &'
+  [ "$status" -eq 125 ]
+  [ "${lines[1]}" = "  on &" ]
+  [ "${lines[2]}" = "      ^ expected a tag" ]
+
+  run "$upbuild" --ub-tags=on --ub-select=on
+  [ "$status" -eq 125 ]
+}
+
+@test "UPBUILD_TAGS" {
+  cd 1
+
+  UPBUILD_TAGS='!on' run "$upbuild"
+  [ "$status" -eq 0 ]
+  [ "$output" = "dir 1" ]
+
+  UPBUILD_TAGS='!on' run "$upbuild" --ub-select=on
+  [ "$status" -eq 0 ]
+  [ "$output" = "2
+3" ]
+}
+
+@test "nothing selected" {
+  cd 1
+
+  run "$upbuild" --ub-reject=on --ub-reject=of
+  [ "$status" -eq 0 ]
+
+  run "$upbuild" --ub-select=of
+  [ "$status" -eq 125 ]
+  [ "${lines[1]}" = "upbuild: No commands matched selection {of} with rejection {}" ]
+
+  run "$upbuild" --ub-select=of --ub-allow-empty
+  [ "$status" -eq 0 ]
+}
+
+@test "errors carry the level" {
+  cd 1
+
+  run "$upbuild" "--ub-tags=on |"
+  [ "$status" -eq 125 ]
+  [ "${lines[0]}" = "upbuild: Invalid --ub-tags expression:" ]
+
+  UPBUILD_LEVEL=2 run "$upbuild" "--ub-tags=on |"
+  [ "$status" -eq 125 ]
+  [ "${lines[0]}" = "upbuild[2]: Invalid --ub-tags expression:" ]
+}
+
 @test "basic run args" {
   cd 1
 
@@ -136,6 +301,28 @@ echo 2 3" ]
 fi
 }
 
+@test "positional args" {
+  mkdir 2
+  cd 2
+  cat > .upbuild <<'EOF'
+echo
+tag
+$1
+$$2
+@positional-args
+&&
+sh
+-c
+echo "$1" $@
+x
+EOF
+
+  run "$upbuild" v1 extra
+  [ "$status" -eq 0 ]
+  [ "$output" = "tag v1 \$2
+v1 v1 extra" ]
+}
+
 @test "run --" {
   cd 1
 
@@ -183,7 +370,22 @@ fi
   [ "$output" = "1.1
 upbuild: Entering directory \`$test_dir/1'
 dir 1
-2" ]
+2
+upbuild: Leaving directory \`$test_dir/1'" ]
+}
+
+@test "recurse skips directories without .upbuild" {
+  mkdir -p 1/a/b
+  cd 1/a/b
+  printf 'echo\ndeep\n&&\nupbuild\n' > .upbuild
+
+  run "$upbuild"
+  [ "$status" -eq 0 ]
+  [ "$output" = "deep
+upbuild: Entering directory \`$test_dir/1'
+dir 1
+2
+upbuild: Leaving directory \`$test_dir/1'" ]
 }
 
 # recurse calls to shell, not actually recursing
@@ -202,10 +404,24 @@ dir 3
     [ "$output" = "3
 upbuild: Entering directory \`$test_dir/1'
 dir 3
-2 3" ]
+2 3
+upbuild: Leaving directory \`$test_dir/1'" ]
   fi
 }
 
+@test "recurse run no-forward-args" {
+  cd 1/1.1
+  echo "@no-forward-args" >> .upbuild
+
+  run "$upbuild" 3
+  [ "$status" -eq 0 ]
+  [ "$output" = "3
+upbuild: Entering directory \`$test_dir/1'
+dir 1
+2
+upbuild: Leaving directory \`$test_dir/1'" ]
+}
+
 @test "outfile" {
   mkdir 2
   cd 2
@@ -230,6 +446,187 @@ bar" ]
   [ "$status" -eq 0 ]
 }
 
+@test "outfile pattern" {
+  mkdir 2
+  cd 2
+  cat > .upbuild <<EOF
+sh
+-c
+mkdir -p logs && echo built > logs/project_\$\$.log
+@outfile=logs/*.log
+EOF
+
+  run "$upbuild"
+  [ "$status" -eq 0 ]
+  [ "$output" = "built" ]
+
+  run "$upbuild" --ub-print
+  [ "$status" -eq 0 ]
+  [ "${lines[1]}" = "# outfile: logs/*.log" ]
+
+  rm -r logs
+  sed -i 's/^mkdir.*/true/' .upbuild
+  run "$upbuild"
+  [ "$status" -eq 125 ]
+  echo "$output" | grep -q "No file in . matches @outfile=logs/\\*.log after sh -c true"
+}
+
+@test "scan patterns" {
+  mkdir 2
+  cd 2
+  cat > .upbuild <<'EOF'
+sh
+-c
+touch ran; echo 'thread main panicked' > log.txt; echo '"project.axf" - 1 Error(s), 0 Warning(s).' >> log.txt
+@outfile=log.txt
+@scan
+EOF
+
+  run "$upbuild" --ub-scan-error='^thread .* panicked('
+  [ "$status" -eq 125 ]
+  echo "$output" | grep -q "^upbuild: Invalid --ub-scan-error=^thread .\* panicked(: regex parse error"
+  [ ! -e ran ]
+
+  run "$upbuild" --ub-scan-error='^thread .* panicked'
+  [ "$status" -eq 0 ]
+  echo "${lines[2]}" | grep -q "^upbuild: sh -c .*: 2 errors, 0 warnings$"
+}
+
+@test "capture" {
+  mkdir 2
+  cd 2
+  cat > .upbuild <<'EOF'
+echo
+@capture=VERSION
+1.2
+&&
+echo
+v${VERSION}
+&&
+sh
+-c
+echo "$VERSION"
+EOF
+
+  run "$upbuild"
+  [ "$status" -eq 0 ]
+  [ "$output" = "1.2
+v1.2
+1.2" ]
+
+  sed -i 's/^@capture.*/&\n@silent/' .upbuild
+  run "$upbuild"
+  [ "$status" -eq 0 ]
+  [ "$output" = "v1.2
+1.2" ]
+}
+
+@test "pipe" {
+  mkdir 2
+  cd 2
+  cat > .upbuild <<'EOF'
+echo
+abc
+@pipe
+&&
+tr
+a-c
+A-C
+EOF
+
+  run "$upbuild"
+  [ "$status" -eq 0 ]
+  [ "$output" = "ABC" ]
+
+  run "$upbuild" --ub-print
+  [ "$status" -eq 0 ]
+  [ "$output" = "echo abc | tr a-c A-C" ]
+
+  sed -i 's/^A-C$/&\n@manual/' .upbuild
+  run "$upbuild"
+  [ "$status" -ne 0 ]
+}
+
+@test "fallback" {
+  mkdir 2
+  cd 2
+  cat > .upbuild <<'EOF'
+false
+||
+echo
+fallback
+&&
+echo
+next
+EOF
+
+  run "$upbuild"
+  [ "$status" -eq 0 ]
+  [ "$output" = "fallback
+next" ]
+
+  sed -i 's/^false$/true/' .upbuild
+  run "$upbuild"
+  [ "$status" -eq 0 ]
+  [ "$output" = "next" ]
+
+  sed -i 's/^true$/false/; s/^fallback$/&\n@manual/' .upbuild
+  run "$upbuild"
+  [ "$status" -eq 1 ]
+  [ "$output" = "" ]
+}
+
+@test "umask" {
+  mkdir 2
+  cd 2
+  cat > .upbuild <<'EOF'
+touch
+@cd=out
+@mkdir=out
+@umask=002
+built
+EOF
+
+  umask 077
+  run "$upbuild"
+  [ "$status" -eq 0 ]
+  [ "$(stat -c %a out)" = "775" ]
+  [ "$(stat -c %a out/built)" = "664" ]
+
+  run "$upbuild" --ub-print
+  [ "$status" -eq 0 ]
+  [ "${lines[1]}" = "# umask 002" ]
+  [ "${lines[2]}" = "touch built" ]
+}
+
+@test "list" {
+  mkdir 2
+  cd 2
+  cat > .upbuild <<'EOF'
+make
+@tags=host
+tests
+&&
+make
+@name=cross
+@tags=target
+cross
+EOF
+
+  run "$upbuild" --ub-list --ub-select=host
+  [ "$status" -eq 0 ]
+  [ "$output" = "1: make tests [host]
+2: make cross (@name=cross) [target] - not run" ]
+
+  run "$upbuild" --ub-list=tsv --ub-select=host
+  [ "$status" -eq 0 ]
+  [ "$(echo "$output" | awk -F'\t' '$2 { print $NF }')" = "make tests" ]
+
+  run "$upbuild" --ub-list=json
+  [ "$status" -eq 0 ]
+  echo "$output" | grep -q '"name":"cross","args":\["make","cross"\]'
+}
+
 @test "multi --" {
   mkdir 3
   cd 3
@@ -277,6 +674,27 @@ cat .upbuild
   [ "$status" -eq 4 ]
 }
 
+@test "exit status of upbuild failures" {
+  mkdir 5
+  cd 5
+
+  echo upbuild-no-such-command > .upbuild
+  run "$upbuild" --ub-show=2
+  [ "$status" -eq 125 ]
+
+  run "$upbuild"
+  [ "$status" -eq 127 ]
+
+  touch not-executable
+  echo ./not-executable > .upbuild
+  run "$upbuild"
+  [ "$status" -eq 126 ]
+
+  echo false > .upbuild
+  run "$upbuild"
+  [ "$status" -eq 1 ]
+}
+
 @test "find not local" {
   mkdir -p 1/2/3/4
   cd 1/2/3/4
@@ -285,7 +703,177 @@ cat .upbuild
   [ "$status" -eq 0 ]
   [ "$output" = "upbuild: Entering directory \`$test_dir/1'
 dir 1
-2" ]
+2
+upbuild: Leaving directory \`$test_dir/1'" ]
+}
+
+@test "--ub-chain" {
+  cd 1/1.1
+
+  run "$upbuild" --ub-chain
+  [ "$status" -eq 1 ]
+  [ "$output" = "1.1
+upbuild: Entering directory \`$test_dir/1'
+dir 1
+2
+upbuild: Leaving directory \`$test_dir/1'
+upbuild: Entering directory \`$test_dir'
+toplevel
+upbuild: Leaving directory \`$test_dir'" ]
+}
+
+@test "find stops at repository" {
+  mkdir -p 1/2/3/.git
+  cd 1/2/3
+
+  run "$upbuild"
+  [ "$status" -eq 125 ]
+  echo "$output" | grep -q "Unable to locate .upbuild"
+
+  run "$upbuild" --ub-no-vcs-boundary
+  [ "$status" -eq 0 ]
+  [ "$output" = "upbuild: Entering directory \`$test_dir/1'
+dir 1
+2
+upbuild: Leaving directory \`$test_dir/1'" ]
+}
+
+@test "UPBUILD_FILE and --ub-file" {
+  mkdir -p 1/2/3/.git
+  cd 1/2/3
+
+  UPBUILD_FILE="$test_dir/1/.upbuild" run "$upbuild"
+  [ "$status" -eq 0 ]
+  [ "$output" = "upbuild: Entering directory \`$test_dir/1'
+dir 1
+2
+upbuild: Leaving directory \`$test_dir/1'" ]
+
+  UPBUILD_FILE="$test_dir/1/.upbuild" run "$upbuild" --ub-file="$test_dir/.upbuild"
+  [ "$status" -eq 0 ]
+  [ "$output" = "upbuild: Entering directory \`$test_dir'
+toplevel
+upbuild: Leaving directory \`$test_dir'" ]
+
+  UPBUILD_FILE="$test_dir/missing" run "$upbuild"
+  [ "$status" -eq 125 ]
+  echo "$output" | grep -q "Unable to open .upbuild file '$test_dir/missing'"
+}
+
+@test "--ub-discover" {
+  mkdir -p 1/2 1/target
+  printf 'false\n' > 1/2/.upbuild
+  printf 'echo\nbuilt\n' > 1/target/.upbuild
+  cd 1
+
+  run "$upbuild" --ub-discover
+  [ "$status" -eq 0 ]
+  [ "$output" = ".upbuild
+1.1/.upbuild
+2/.upbuild" ]
+
+  run "$upbuild" --ub-discover-run --ub-no-history
+  [ "$status" -eq 1 ]
+  echo "$output" | grep -qx "upbuild: .upbuild: ok"
+  echo "$output" | grep -qx "upbuild: 1.1/.upbuild: ok"
+  echo "$output" | grep -qx "upbuild: 2/.upbuild: failed"
+  ! echo "$output" | grep -q built
+}
+
+@test "interrupted" {
+  cat > .upbuild <<EOF
+echo
+before
+&&
+$BATS_TEST_DIRNAME/sh/interrupt.sh
+&&
+echo
+after
+EOF
+
+  # upbuild outlives the interrupt to report it, and record the run
+  run "$upbuild"
+  [ "$status" -eq 130 ]
+  [ "$output" = "before
+upbuild: while running [2: $BATS_TEST_DIRNAME/sh/interrupt.sh]: Process exitted with signal: 2 (SIGINT)" ]
+
+  run "$upbuild" --ub-history=1
+  [ "$status" -eq 0 ]
+  [[ "${lines[0]}" == *"failed (2 commands, "*"signal: 2 (SIGINT)" ]]
+}
+
+@test "--ub-history" {
+  cd 1
+
+  run "$upbuild" --ub-history
+  [ "$status" -eq 0 ]
+  [ "$output" = "" ]
+
+  run "$upbuild"
+  run "$upbuild" 3
+  run "$upbuild" --ub-print
+  run "$upbuild" --ub-history
+  [ "$status" -eq 0 ]
+  [ "${#lines[@]}" -eq 6 ]
+  echo "${lines[0]}" | grep -qE '^[0-9-]+T[0-9:]+Z succeeded \(2 commands, [0-9.]+s\)$'
+  echo "${lines[1]}" | grep -qE '^  \[  0\] +[0-9.]+s echo dir 1$'
+  echo "${lines[5]}" | grep -qE '^  \[  0\] +[0-9.]+s echo 2 3$'
+  [ -f "$test_dir/state/upbuild/history.jsonl" ]
+
+  run "$upbuild" --ub-history=1
+  [ "$status" -eq 0 ]
+  [ "${#lines[@]}" -eq 3 ]
+  echo "${lines[1]}" | grep -qE 'echo dir 3$'
+
+  cd ..
+  run "$upbuild" --ub-no-history
+  run "$upbuild" --ub-history
+  [ "$status" -eq 0 ]
+  [ "$output" = "" ]
+}
+
+@test "@cd=~" {
+  mkdir -p home/proj
+  cat > .upbuild <<EOF
+pwd
+@cd=~/proj
+EOF
+
+  HOME="$test_dir/home" run "$upbuild"
+  [ "$status" -eq 0 ]
+  [ "$output" = "upbuild: Entering directory \`$test_dir/home/proj'
+$test_dir/home/proj
+upbuild: Leaving directory \`$test_dir/home/proj'" ]
+}
+
+@test "@env-required" {
+  cat > .upbuild <<EOF
+echo
+ran
+@env-required=UPBUILD_TEST_A,UPBUILD_TEST_B?,UPBUILD_TEST_C
+EOF
+
+  UPBUILD_TEST_A= run "$upbuild"
+  [ "$status" -eq 125 ]
+  [ "$output" = "upbuild: Required environment variables are not set (see @env-required): UPBUILD_TEST_A, UPBUILD_TEST_B, UPBUILD_TEST_C" ]
+
+  UPBUILD_TEST_A=1 UPBUILD_TEST_B= UPBUILD_TEST_C=1 run "$upbuild"
+  [ "$status" -eq 0 ]
+  [ "$output" = "ran" ]
+}
+
+@test "@cd quoted" {
+  mkdir -p "my project (v2)"
+  cat > .upbuild <<EOF
+pwd
+@cd="my project (v2)"
+EOF
+
+  run "$upbuild"
+  [ "$status" -eq 0 ]
+  [ "$output" = "upbuild: Entering directory \`$test_dir/my project (v2)'
+$test_dir/my project (v2)
+upbuild: Leaving directory \`$test_dir/my project (v2)'" ]
 }
 
 @test "find not local - actual directory" {
@@ -300,7 +888,8 @@ EOF
   run "$upbuild"
   [ "$status" -eq 0 ]
   [ "$output" = "upbuild: Entering directory \`$test_dir/1/2'
-$test_dir/1/2" ]
+$test_dir/1/2
+upbuild: Leaving directory \`$test_dir/1/2'" ]
 }
 
 @test "cd in and out" {
@@ -323,8 +912,10 @@ EOF
     [ "$output" = "$test_dir/1/2
 upbuild: Entering directory \`$test_dir/1/2/3'
 $test_dir/1/2/3
+upbuild: Leaving directory \`$test_dir/1/2/3'
 upbuild: Entering directory \`$test_dir/1/2'
-$test_dir/1/2" ]
+$test_dir/1/2
+upbuild: Leaving directory \`$test_dir/1/2'" ]
   else
     # Old rb version didn't report return back to original dir
     [ "$output" = "$test_dir/1/2
@@ -351,10 +942,13 @@ EOF
   [ "$status" -eq 0 ]
   if [ -z "$rb_ref" ]; then
     [ "$output" = "upbuild: Entering directory \`$test_dir/1'
+upbuild: Leaving directory \`$test_dir/1'
 upbuild: Entering directory \`$test_dir/1/2'
 $test_dir/1/2
+upbuild: Leaving directory \`$test_dir/1/2'
 upbuild: Entering directory \`$test_dir/1/2/3'
-$test_dir/1/2/3" ]
+$test_dir/1/2/3
+upbuild: Leaving directory \`$test_dir/1/2/3'" ]
   else
     # Old rb version didn't report return back to original dir
     [ "$output" = "upbuild: Entering directory \`$test_dir/1'
@@ -389,6 +983,63 @@ echo
 $test_dir/1/2" ]
 }
 
+@test "--ub-stream" {
+  mkdir 5
+  cd 5
+  cat > .upbuild <<EOF
+echo
+a
+&&
+echo
+b
+&&
+@bogus
+EOF
+
+  run "$upbuild"
+  [ "$status" -ne 0 ]
+  [ "$output" = "upbuild: Tag was not understood: @bogus" ]
+
+  run "$upbuild" --ub-stream
+  [ "$status" -ne 0 ]
+  [ "$output" = "a
+b
+upbuild: Tag was not understood: @bogus" ]
+}
+
+@test "--ub-stream unreadable line" {
+  printf 'echo\none\n&&\necho\n\377\376\n&&\necho\nthree\n' > .upbuild
+
+  # fails the same as reading the whole file
+  run "$upbuild" --ub-print
+  [ "$status" -eq 125 ]
+  [ "$output" = "upbuild: stream did not contain valid UTF-8" ]
+
+  run "$upbuild" --ub-print --ub-stream
+  [ "$status" -eq 125 ]
+  [ "$output" = "echo one
+upbuild: stream did not contain valid UTF-8" ]
+}
+
+@test "--ub-completion" {
+  mkdir -p 6
+  cd 6
+
+  # no .upbuild needed
+  run "$upbuild" --ub-completion
+  [ "$status" -eq 0 ]
+  echo "$output" | grep -q "^complete -o default -F _upbuild upbuild$"
+
+  run "$upbuild" --ub-completion=powershell
+  [ "$status" -eq 0 ]
+  echo "$output" | grep -q "^Register-ArgumentCompleter -Native -CommandName upbuild"
+
+  cd ../1/1.1
+  run "$upbuild" --ub-completion-list-tags
+  [ "$status" -eq 0 ]
+  [ "$output" = "on" ]
+}
+
 @test "@mkdir" {
 
   ! test -f build
@@ -407,7 +1058,8 @@ EOF
   [ "$status" -eq 0 ]
   [ "$output" = "upbuild: Entering directory \`$test_dir/build'
 ${test_dir}/build
-${test_dir}/build" ]
+${test_dir}/build
+upbuild: Leaving directory \`$test_dir/build'" ]
 
   # should have created build
   test -d build
@@ -445,9 +1097,11 @@ EOF
   run "$upbuild"
   [ "$status" -eq 0 ]
   [ "$output" = "upbuild: Entering directory \`$test_dir'
+upbuild: Leaving directory \`$test_dir'
 upbuild: Entering directory \`$test_dir/build'
 ${test_dir}/build
-${test_dir}/build" ]
+${test_dir}/build
+upbuild: Leaving directory \`$test_dir/build'" ]
 
   cd "${d}"
 
@@ -487,7 +1141,8 @@ echo "op=${output}=op"
   [ "$status" -eq 0 ]
   [ "$output" = "upbuild: Entering directory \`$test_dir/build/2'
 ${test_dir}/build/2
-${test_dir}/build/2" ]
+${test_dir}/build/2
+upbuild: Leaving directory \`$test_dir/build/2'" ]
 
   # should have created build
   test -d build/2
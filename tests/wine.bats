@@ -197,7 +197,8 @@ display_dir() {
   [ "$output" = "1.1
 upbuild: Entering directory $(display_dir ${test_dir}/1)
 dir 1
-2" ]
+2
+upbuild: Leaving directory $(display_dir ${test_dir}/1)" ]
 }
 
 # recurse calls to shell, not actually recursing
@@ -209,7 +210,8 @@ dir 1
   [ "$output" = "3
 upbuild: Entering directory $(display_dir ${test_dir}/1)
 dir 3
-2 3" ]
+2 3
+upbuild: Leaving directory $(display_dir ${test_dir}/1)" ]
 }
 
 @test "${target} outfile" {
@@ -291,7 +293,8 @@ cat .upbuild
   [ "$status" -eq 0 ]
   [ "$output" = "upbuild: Entering directory $(display_dir ${test_dir}/1)
 dir 1
-2" ]
+2
+upbuild: Leaving directory $(display_dir ${test_dir}/1)" ]
 }
 
 @test "${target} find not local - actual directory" {
@@ -308,7 +311,8 @@ EOF
   run_win "$upbuild"
   [ "$status" -eq 0 ]
   [ "$output" = "upbuild: Entering directory $(display_dir ${test_dir}/1/2)
-$(convert_dir ${test_dir}/1/2)" ]
+$(convert_dir ${test_dir}/1/2)
+upbuild: Leaving directory $(display_dir ${test_dir}/1/2)" ]
 }
 
 @test "${target} cd in and out" {
@@ -336,8 +340,10 @@ EOF
   [ "$output" = "$(convert_dir ${test_dir}/1/2)
 upbuild: Entering directory $(display_dir ${test_dir}/1/2/3)
 $(convert_dir ${test_dir}/1/2/3)
+upbuild: Leaving directory $(display_dir ${test_dir}/1/2/3)
 upbuild: Entering directory $(display_dir ${test_dir}/1/2)
-$(convert_dir ${test_dir}/1/2)" ]
+$(convert_dir ${test_dir}/1/2)
+upbuild: Leaving directory $(display_dir ${test_dir}/1/2)" ]
 }
 
 @test "${target} cd in and out - relative" {
@@ -360,10 +366,13 @@ EOF
   run_win "$upbuild"
   [ "$status" -eq 0 ]
   [ "$output" = "upbuild: Entering directory $(display_dir ${test_dir}/1)
+upbuild: Leaving directory $(display_dir ${test_dir}/1)
 upbuild: Entering directory $(display_dir ${test_dir}/1/2)
 $(convert_dir ${test_dir}/1/2)
+upbuild: Leaving directory $(display_dir ${test_dir}/1/2)
 upbuild: Entering directory $(display_dir ${test_dir}/1/2/3)
-$(convert_dir ${test_dir}/1/2/3)" ]
+$(convert_dir ${test_dir}/1/2/3)
+upbuild: Leaving directory $(display_dir ${test_dir}/1/2/3)" ]
 }
 
 @test "${target} --ub-add" {
@@ -412,7 +421,8 @@ EOF
   [ "$status" -eq 0 ]
   [ "$output" = "upbuild: Entering directory $(display_dir ${test_dir}/build)
 $(convert_dir ${test_dir}/build)
-$(convert_dir ${test_dir}/build)" ]
+$(convert_dir ${test_dir}/build)
+upbuild: Leaving directory $(display_dir ${test_dir}/build)" ]
 
   # should have created build
   test -d build
@@ -454,9 +464,11 @@ EOF
   run_win "$upbuild"
   [ "$status" -eq 0 ]
   [ "$output" = "upbuild: Entering directory $(display_dir ${test_dir})
+upbuild: Leaving directory $(display_dir ${test_dir})
 upbuild: Entering directory $(display_dir ${test_dir}/build)
 $(convert_dir ${test_dir}/build)
-$(convert_dir ${test_dir}/build)" ]
+$(convert_dir ${test_dir}/build)
+upbuild: Leaving directory $(display_dir ${test_dir}/build)" ]
 
   cd "${d}"
 
@@ -499,7 +511,8 @@ EOF
   [ "$status" -eq 0 ]
   [ "$output" = "upbuild: Entering directory $(display_dir ${test_dir}/build/2)
 $(convert_dir ${test_dir}/build/2)
-$(convert_dir ${test_dir}/build/2)" ]
+$(convert_dir ${test_dir}/build/2)
+upbuild: Leaving directory $(display_dir ${test_dir}/build/2)" ]
 
   # should have created build
   test -d build/2